@@ -24,6 +24,33 @@ pub enum Block {
         /// The stack adjustment required as a consequence of executing this unknown code.
         stack_adjust: isize,
     },
+    /// Control flow was interrupted by an asynchronous event (e.g. a signal or a transactional
+    /// abort).
+    Interrupted {
+        /// `true` if execution later resumed where it was interrupted, in which case the code run
+        /// in the meantime (e.g. a signal handler) is not reported. `false` if execution never
+        /// returned to the interrupted code: the blocks which follow are where control went
+        /// instead.
+        resumed: bool,
+    },
+    /// Some of the trace was lost (e.g. because the trace buffer overflowed), so the blocks either
+    /// side of this one aren't contiguous.
+    ///
+    /// Only reported by decoders configured to recover from lost trace data.
+    Gap {
+        /// The offset, in bytes, into the raw trace at which trace data was found to be missing.
+        off: usize,
+        /// The number of bytes of the raw trace skipped in order to resynchronise with the packet
+        /// stream.
+        skipped: usize,
+    },
+    /// Control left the preceding block along an edge that the compiler assumed would never be
+    /// taken (e.g. after a call to a `noreturn` function, or into code marked `unreachable`). The
+    /// blocks which follow are where control actually went, as recovered from the trace.
+    Divergent,
+    /// Control entered `longjmp()` (or one of its variants), and so won't return to the caller.
+    /// The blocks which follow are `longjmp()` itself and then wherever it jumped to.
+    Longjmp,
 }
 
 impl fmt::Debug for Block {
@@ -39,6 +66,14 @@ impl fmt::Debug for Block {
             Self::Unknown { stack_adjust } => {
                 write!(f, "UnkonwnBlock(stack_adjust={stack_adjust})")
             }
+            Self::Interrupted { resumed } => {
+                write!(f, "Interrupted(resumed={resumed})")
+            }
+            Self::Gap { off, skipped } => {
+                write!(f, "Gap(off={off}, skipped={skipped})")
+            }
+            Self::Divergent => write!(f, "Divergent"),
+            Self::Longjmp => write!(f, "Longjmp"),
         }
     }
 }
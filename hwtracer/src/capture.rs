@@ -0,0 +1,534 @@
+//! Self-contained captures of traces.
+//!
+//! A trace on its own can only be decoded by the process that collected it, because the decoder
+//! needs that process's code and blockmaps. A [TraceCapture] snapshots those alongside the raw trace
+//! bytes, so that the trace can be decoded later, in another process, or on a machine without PT
+//! hardware. Captures are how we make decoder bugs reproducible.
+//!
+//! Captures can be saved to, and loaded from, files in the following format (with all integers
+//! little-endian):
+//!
+//! ```text
+//! magic:        b"YKPTCAP\0"
+//! version:      u32
+//! num_objs:     u32
+//! num_objs * {
+//!   is_main:    u8
+//!   path_len:   u32
+//!   path:       [u8; path_len]    (UTF-8)
+//!   base:       u64
+//!   num_segs:   u32
+//!   num_segs * {
+//!     vaddr:    u64
+//!     memsz:    u64
+//!     has_code: u8
+//!     code:     [u8; memsz]       (only if `has_code` is 1)
+//!   }
+//!   blockmap_len: u64             (0 if the object wasn't built with ykllvm)
+//!   blockmap:     [u8; blockmap_len]
+//! }
+//! longjmps:     [u64; 4]          (`longjmp`, `_longjmp`, `siglongjmp`, `__longjmp_chk`, or 0 if
+//!                                  not found)
+//! trace_len:    u64
+//! trace:        [u8; trace_len]
+//! ```
+//!
+//! Only executable segments have their contents captured, but since that includes every shared
+//! object's code, captures of processes linked against large libraries can be large.
+
+use crate::{
+    errors::HWTracerError,
+    llvm_blockmap::{find_blockmap_section, BlockMap, BlockMapError},
+    Trace,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use intervaltree::IntervalTree;
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    fmt::{self, Debug},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+use ykutil::obj::{PHDR_OBJECT_CACHE, SELF_BIN_PATH};
+
+pub(crate) const CAPTURE_MAGIC: &[u8; 8] = b"YKPTCAP\0";
+pub(crate) const CAPTURE_VERSION: u32 = 3;
+
+/// The functions which the decoder can't (yet) follow into, in the order they are stored in a
+/// capture.
+pub(crate) const LONGJMP_FUNCS: [&str; 4] = ["longjmp", "_longjmp", "siglongjmp", "__longjmp_chk"];
+
+/// A trace along with a [Sideband] describing the address space of the process that collected it.
+pub struct TraceCapture {
+    bytes: Vec<u8>,
+    sideband: Sideband,
+}
+
+impl TraceCapture {
+    /// Capture `trace`, which must have been collected by the current process, along with the
+    /// parts of the current process's address space needed to decode it.
+    pub fn capture(trace: &dyn Trace) -> Self {
+        let mut objs = Vec::new();
+        for obj in PHDR_OBJECT_CACHE.get().iter() {
+            // The main object has an empty name in the program header table.
+            let is_main = obj.name().to_bytes().is_empty();
+            let path = if is_main {
+                SELF_BIN_PATH.clone()
+            } else {
+                PathBuf::from(obj.name().to_str().unwrap())
+            };
+            let base = usize::try_from(obj.addr()).unwrap();
+            let mut segs = Vec::new();
+            for hdr in obj.phdrs() {
+                if hdr.type_() != libc::PT_LOAD {
+                    continue;
+                }
+                let vaddr = usize::try_from(obj.addr() + hdr.vaddr()).unwrap();
+                let memsz = usize::try_from(hdr.memsz()).unwrap();
+                let code = if (hdr.flags() & libc::PF_X) != 0 {
+                    Some(unsafe { slice::from_raw_parts(vaddr as *const u8, memsz) }.to_vec())
+                } else {
+                    None
+                };
+                segs.push(CapturedSegment {
+                    vaddrs: vaddr..(vaddr + memsz),
+                    code,
+                });
+            }
+            let blockmap =
+                find_blockmap_section(&path).map(|bm| CapturedBlockMap::new(bm.to_vec()));
+            objs.push(CapturedObject {
+                path,
+                is_main,
+                base,
+                segs,
+                blockmap,
+            });
+        }
+
+        let longjmps = LONGJMP_FUNCS.map(|f| {
+            let func = CString::new(f).unwrap();
+            // `as usize` is a safe cast from raw pointer to pointer-sized integer.
+            u64::try_from(unsafe { libc::dlsym(ptr::null_mut(), func.as_ptr()) } as usize).unwrap()
+        });
+
+        Self {
+            bytes: trace.bytes().to_vec(),
+            sideband: Sideband::new(objs, longjmps),
+        }
+    }
+
+    /// Load a capture previously saved with [TraceCapture::save].
+    pub fn load(path: &Path) -> Result<Self, HWTracerError> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Save this capture to the file `path`.
+    pub fn save(&self, path: &Path) -> Result<(), HWTracerError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read a capture from `r`.
+    pub fn read_from(r: &mut dyn Read) -> Result<Self, HWTracerError> {
+        let mut magic = [0; CAPTURE_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(HWTracerError::TraceParseError(
+                "not a trace capture".to_owned(),
+            ));
+        }
+        let version = r.read_u32::<LittleEndian>()?;
+        if version != CAPTURE_VERSION {
+            return Err(HWTracerError::TraceParseError(format!(
+                "unsupported trace capture version {version} (expected {CAPTURE_VERSION})"
+            )));
+        }
+
+        let mut objs = Vec::new();
+        for _ in 0..r.read_u32::<LittleEndian>()? {
+            let is_main = r.read_u8()? != 0;
+            let path_len = r.read_u32::<LittleEndian>()?;
+            let path = String::from_utf8(read_bytes(r, path_len.into())?)
+                .map_err(|e| HWTracerError::Custom(Box::new(e)))?;
+            let base = read_usize(r)?;
+            let mut segs = Vec::new();
+            for _ in 0..r.read_u32::<LittleEndian>()? {
+                let vaddr = read_usize(r)?;
+                let memsz = read_usize(r)?;
+                let end = vaddr.checked_add(memsz).ok_or_else(|| {
+                    HWTracerError::TraceParseError(format!(
+                        "segment at {vaddr:#x} of size {memsz:#x} overflows the address space"
+                    ))
+                })?;
+                let code = if r.read_u8()? != 0 {
+                    // `read_bytes` checks that there are `memsz` bytes left to read.
+                    Some(read_bytes(r, u64::try_from(memsz).unwrap())?)
+                } else {
+                    None
+                };
+                segs.push(CapturedSegment {
+                    vaddrs: vaddr..end,
+                    code,
+                });
+            }
+            let blockmap = match r.read_u64::<LittleEndian>()? {
+                0 => None,
+                blockmap_len => Some(CapturedBlockMap::new(read_bytes(r, blockmap_len)?)),
+            };
+            objs.push(CapturedObject {
+                path: PathBuf::from(path),
+                is_main,
+                base,
+                segs,
+                blockmap,
+            });
+        }
+
+        let mut longjmps = [0; LONGJMP_FUNCS.len()];
+        for lj in &mut longjmps {
+            *lj = r.read_u64::<LittleEndian>()?;
+        }
+        let trace_len = r.read_u64::<LittleEndian>()?;
+        let bytes = read_bytes(r, trace_len)?;
+
+        Ok(Self {
+            bytes,
+            sideband: Sideband::new(objs, longjmps),
+        })
+    }
+
+    /// Write this capture to `w`.
+    pub fn write_to(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        w.write_all(CAPTURE_MAGIC)?;
+        w.write_u32::<LittleEndian>(CAPTURE_VERSION)?;
+        let sb = &self.sideband;
+        w.write_u32::<LittleEndian>(u32::try_from(sb.objs.len()).unwrap())?;
+        for obj in &sb.objs {
+            w.write_u8(u8::from(obj.is_main))?;
+            let path = obj.path.to_str().unwrap().as_bytes();
+            w.write_u32::<LittleEndian>(u32::try_from(path.len()).unwrap())?;
+            w.write_all(path)?;
+            w.write_u64::<LittleEndian>(u64::try_from(obj.base).unwrap())?;
+            w.write_u32::<LittleEndian>(u32::try_from(obj.segs.len()).unwrap())?;
+            for seg in &obj.segs {
+                w.write_u64::<LittleEndian>(u64::try_from(seg.vaddrs.start).unwrap())?;
+                w.write_u64::<LittleEndian>(u64::try_from(seg.vaddrs.len()).unwrap())?;
+                match &seg.code {
+                    Some(code) => {
+                        w.write_u8(1)?;
+                        w.write_all(code)?;
+                    }
+                    None => w.write_u8(0)?,
+                }
+            }
+            let blockmap = obj.blockmap.as_ref().map_or(&[][..], |bm| &bm.bytes);
+            w.write_u64::<LittleEndian>(u64::try_from(blockmap.len()).unwrap())?;
+            w.write_all(blockmap)?;
+        }
+        for lj in sb.longjmps {
+            w.write_u64::<LittleEndian>(lj)?;
+        }
+        w.write_u64::<LittleEndian>(u64::try_from(self.bytes.len()).unwrap())?;
+        w.write_all(&self.bytes)?;
+        Ok(())
+    }
+}
+
+impl Trace for TraceCapture {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn sideband(&self) -> Option<&Sideband> {
+        Some(&self.sideband)
+    }
+}
+
+impl Debug for TraceCapture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceCapture")
+            .field("len", &self.bytes.len())
+            .field("objs", &self.sideband.objs)
+            .finish()
+    }
+}
+
+/// Read exactly `len` bytes from `r`.
+fn read_bytes(r: &mut dyn Read, len: u64) -> Result<Vec<u8>, HWTracerError> {
+    // We don't preallocate `len` bytes, since a corrupt length could be enormous.
+    let mut buf = Vec::new();
+    if r.take(len).read_to_end(&mut buf)? as u64 != len {
+        return Err(HWTracerError::TraceParseError(
+            "truncated trace capture".to_owned(),
+        ));
+    }
+    Ok(buf)
+}
+
+/// Read a 64-bit address or size from `r`.
+fn read_usize(r: &mut dyn Read) -> Result<usize, HWTracerError> {
+    usize::try_from(r.read_u64::<LittleEndian>()?).map_err(|e| HWTracerError::Custom(Box::new(e)))
+}
+
+/// A snapshot of the parts of a process's address space needed to decode a trace it collected.
+pub struct Sideband {
+    objs: Vec<CapturedObject>,
+    /// The addresses of [LONGJMP_FUNCS].
+    longjmps: [u64; LONGJMP_FUNCS.len()],
+    /// Maps the address range of each segment to its `(object index, segment index)`.
+    segs: IntervalTree<usize, (usize, usize)>,
+}
+
+impl Sideband {
+    fn new(objs: Vec<CapturedObject>, longjmps: [u64; LONGJMP_FUNCS.len()]) -> Self {
+        let segs = objs
+            .iter()
+            .enumerate()
+            .flat_map(|(oi, obj)| {
+                obj.segs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, seg)| !seg.vaddrs.is_empty())
+                    .map(move |(si, seg)| (seg.vaddrs.clone(), (oi, si)))
+            })
+            .collect::<IntervalTree<_, _>>();
+        Self {
+            objs,
+            longjmps,
+            segs,
+        }
+    }
+
+    /// Return the object, and the segment within it, containing `vaddr`.
+    fn lookup(&self, vaddr: usize) -> Option<(&CapturedObject, &CapturedSegment)> {
+        self.segs.query_point(vaddr).next().map(|e| {
+            let (oi, si) = e.value;
+            (&self.objs[oi], &self.objs[oi].segs[si])
+        })
+    }
+
+    /// Return the address range and captured contents of the executable segment containing
+    /// `vaddr`, or `None` if `vaddr` isn't in an executable segment.
+    pub(crate) fn code(&self, vaddr: usize) -> Option<(&Range<usize>, &[u8])> {
+        match self.lookup(vaddr) {
+            Some((_, seg)) => seg.code.as_ref().map(|c| (&seg.vaddrs, c.as_slice())),
+            None => None,
+        }
+    }
+
+    /// If `vaddr` is in an object built with ykllvm, return the address that object was loaded at
+    /// and its blockmap. Returns `Ok(None)` if `vaddr` is in another object, or `Err` if it isn't
+    /// in any object.
+    pub(crate) fn blockmap_obj(
+        &self,
+        vaddr: usize,
+    ) -> Result<Option<(usize, &BlockMap)>, HWTracerError> {
+        match self.lookup(vaddr) {
+            Some((obj, _)) => match &obj.blockmap {
+                Some(bm) => match &bm.map {
+                    Ok(map) => Ok(Some((obj.base, map))),
+                    Err(e) => Err(e.clone().into()),
+                },
+                None => Ok(None),
+            },
+            None => Err(HWTracerError::TraceParseError(
+                "failed to convert a virtual address to an offset".to_owned(),
+            )),
+        }
+    }
+
+    /// Return the addresses of [LONGJMP_FUNCS] (or 0 for any that weren't found) in the captured
+    /// process.
+    pub(crate) fn longjmp_vaddrs(&self) -> [u64; LONGJMP_FUNCS.len()] {
+        self.longjmps
+    }
+}
+
+/// An object (the main binary or a shared object) loaded into a captured process.
+#[derive(Debug)]
+struct CapturedObject {
+    path: PathBuf,
+    is_main: bool,
+    /// The address the object was loaded at.
+    base: usize,
+    segs: Vec<CapturedSegment>,
+    /// The object's blockmap, if it was built with ykllvm.
+    blockmap: Option<CapturedBlockMap>,
+}
+
+/// The blockmap of a [CapturedObject].
+struct CapturedBlockMap {
+    /// The raw blockmap section, so that the capture can be written out again.
+    bytes: Vec<u8>,
+    /// The parsed blockmap. A blockmap that fails to parse is still captured, so that the error
+    /// is reported when decoding the capture, just as it would be when decoding a live trace.
+    map: Result<BlockMap, BlockMapError>,
+}
+
+impl CapturedBlockMap {
+    fn new(bytes: Vec<u8>) -> Self {
+        let map = BlockMap::from_bytes(&bytes);
+        Self { bytes, map }
+    }
+}
+
+impl Debug for CapturedBlockMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} byte blockmap>", self.bytes.len())
+    }
+}
+
+/// A loaded segment of a [CapturedObject].
+struct CapturedSegment {
+    vaddrs: Range<usize>,
+    /// The segment's contents, if it is executable.
+    code: Option<Vec<u8>>,
+}
+
+impl Debug for CapturedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:x}..0x{:x}{}",
+            self.vaddrs.start,
+            self.vaddrs.end,
+            if self.code.is_some() { " (code)" } else { "" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llvm_blockmap::BLOCKMAP_VERSION;
+    use std::io::Cursor;
+
+    /// A capture of a made-up process with a main object (built with ykllvm) and one shared object,
+    /// both loaded at page-aligned addresses.
+    fn mk_capture() -> TraceCapture {
+        // A single 4-byte block at offset 0 that returns.
+        let mut blockmap = vec![BLOCKMAP_VERSION, 0];
+        blockmap.extend(0u64.to_ne_bytes());
+        blockmap.extend([1, 0, 4, 0, 0, 0, 2]);
+        let objs = vec![
+            CapturedObject {
+                path: PathBuf::from("/bin/prog"),
+                is_main: true,
+                base: 0x1000,
+                segs: vec![
+                    CapturedSegment {
+                        vaddrs: 0x1000..0x1004,
+                        code: Some(vec![0x90, 0x90, 0x90, 0xc3]),
+                    },
+                    CapturedSegment {
+                        vaddrs: 0x2000..0x3000,
+                        code: None,
+                    },
+                ],
+                blockmap: Some(CapturedBlockMap::new(blockmap)),
+            },
+            CapturedObject {
+                path: PathBuf::from("/lib/libc.so.6"),
+                is_main: false,
+                base: 0x10000,
+                segs: vec![CapturedSegment {
+                    vaddrs: 0x10000..0x10001,
+                    code: Some(vec![0xc3]),
+                }],
+                blockmap: None,
+            },
+        ];
+        TraceCapture {
+            bytes: vec![0x02, 0x82, 0x02, 0x82],
+            sideband: Sideband::new(objs, [0x10000, 0, 0, 0]),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cap = mk_capture();
+        let mut buf = Vec::new();
+        cap.write_to(&mut buf).unwrap();
+        let cap2 = TraceCapture::read_from(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(cap2.bytes(), cap.bytes());
+        assert_eq!(format!("{cap2:?}"), format!("{cap:?}"));
+        assert_eq!(cap2.sideband.longjmp_vaddrs(), [0x10000, 0, 0, 0]);
+
+        // Writing the loaded capture gives the same bytes again.
+        let mut buf2 = Vec::new();
+        cap2.write_to(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn sideband_lookups() {
+        let cap = mk_capture();
+        let sb = cap.sideband().unwrap();
+        let (vaddrs, code) = sb.code(0x1002).unwrap();
+        assert_eq!(*vaddrs, 0x1000..0x1004);
+        assert_eq!(code, &[0x90, 0x90, 0x90, 0xc3]);
+        // Data segments aren't captured.
+        assert!(sb.code(0x2000).is_none());
+
+        let (base, bm) = sb.blockmap_obj(0x1003).unwrap().unwrap();
+        assert_eq!(base, 0x1000);
+        assert_eq!(bm.len(), 1);
+        assert_eq!(sb.blockmap_obj(0x2010).unwrap().unwrap().0, 0x1000);
+        // The shared object wasn't built with ykllvm.
+        assert!(sb.blockmap_obj(0x10000).unwrap().is_none());
+        assert!(sb.blockmap_obj(0x5000).is_err());
+    }
+
+    #[test]
+    fn bad_captures_are_rejected() {
+        let mut buf = Vec::new();
+        mk_capture().write_to(&mut buf).unwrap();
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            TraceCapture::read_from(&mut Cursor::new(&bad_magic)),
+            Err(HWTracerError::TraceParseError(_))
+        ));
+
+        let mut bad_version = buf.clone();
+        bad_version[CAPTURE_MAGIC.len()] = 0xff;
+        assert!(matches!(
+            TraceCapture::read_from(&mut Cursor::new(&bad_version)),
+            Err(HWTracerError::TraceParseError(_))
+        ));
+
+        let truncated = &buf[..buf.len() - 1];
+        assert!(matches!(
+            TraceCapture::read_from(&mut Cursor::new(truncated)),
+            Err(HWTracerError::TraceParseError(_))
+        ));
+
+        // The first segment's `vaddr` comes after the header, the first object's `is_main`,
+        // `path_len`, `path` ("/bin/prog"), `base`, and `num_segs`.
+        let vaddr_off = CAPTURE_MAGIC.len() + 4 + 4 + 1 + 4 + 9 + 8 + 4;
+        assert_eq!(buf[vaddr_off..vaddr_off + 8], 0x1000u64.to_le_bytes());
+        let mut overflowing_seg = buf.clone();
+        overflowing_seg[vaddr_off..vaddr_off + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            TraceCapture::read_from(&mut Cursor::new(&overflowing_seg)),
+            Err(HWTracerError::TraceParseError(_))
+        ));
+    }
+}
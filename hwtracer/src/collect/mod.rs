@@ -36,11 +36,23 @@ pub trait Tracer: Send + Sync {
 pub trait ThreadTracer {
     /// Stop collecting a trace of the current thread.
     fn stop_collector(self: Box<Self>) -> Result<Box<dyn Trace>, HWTracerError>;
+    /// Return the number of bytes of trace data collected so far, including any that the collector
+    /// has buffered but not yet copied into the trace. This may briefly over-estimate the length
+    /// while the collector is copying data, but never under-estimates it.
+    fn trace_len(&self) -> usize;
 }
 
 pub fn default_tracer_for_platform() -> Result<Arc<dyn Tracer>, HWTracerError> {
+    tracer_for_platform_with_config(PerfCollectorConfig::default())
+}
+
+/// Like [default_tracer_for_platform], but configures the tracer with `config` rather than the
+/// defaults.
+pub fn tracer_for_platform_with_config(
+    config: PerfCollectorConfig,
+) -> Result<Arc<dyn Tracer>, HWTracerError> {
     if pt_supported() {
-        Ok(PerfTracer::new(PerfCollectorConfig::default())?)
+        Ok(PerfTracer::new(config)?)
     } else {
         Err(HWTracerError::NoHWSupport(
             "CPU doesn't support the Processor Trace (PT) feature".to_owned(),
@@ -63,6 +63,8 @@ struct hwt_perf_ctx {
   size_t aux_bufsize;       // The size of the AUX buffer's mmap(2).
   void *base_buf;           // Ptr to the start of the base buffer.
   size_t base_bufsize;      // The size the base buffer's mmap(2).
+  // The trace being collected, or NULL.
+  struct hwt_perf_trace *trace;
 };
 
 /*
@@ -89,6 +91,10 @@ struct hwt_perf_trace_buf {
  * Storage for a trace.
  *
  * Shared with Rust code. Must stay in sync.
+ *
+ * While the collector thread is running, it is the only writer of `len`,
+ * which it publishes with an atomic store so that `hwt_perf_trace_len` can
+ * read it from the traced thread.
  */
 struct hwt_perf_trace {
   struct hwt_perf_trace_buf buf;
@@ -136,6 +142,7 @@ static bool read_aux(void *, struct perf_event_mmap_page *,
                      struct hwt_perf_trace *, struct hwt_cerror *);
 static bool poll_loop(int, int, struct perf_event_mmap_page *, void *,
                       struct hwt_perf_trace *, struct hwt_cerror *);
+static __u64 aux_pending(struct perf_event_mmap_page *, __u64);
 static void *collector_thread(void *);
 static int open_perf(size_t, struct hwt_cerror *);
 void hwt_set_cerr(struct hwt_cerror *, int, int);
@@ -147,6 +154,7 @@ bool hwt_perf_start_collector(struct hwt_perf_ctx *, struct hwt_perf_trace *,
                               struct hwt_cerror *);
 bool hwt_perf_stop_collector(struct hwt_perf_ctx *tr_ctx, struct hwt_cerror *);
 bool hwt_perf_free_collector(struct hwt_perf_ctx *tr_ctx, struct hwt_cerror *);
+size_t hwt_perf_trace_len(struct hwt_perf_ctx *tr_ctx);
 
 // Sets C error information (if not already set).
 void hwt_set_cerr(struct hwt_cerror *err, int kind, int code) {
@@ -241,32 +249,44 @@ static bool handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
 }
 
 /*
- * Read data out of the AUX buffer.
- *
- * Reads from `aux_buf` (whose meta-data is in `hdr`) into `trace`.
+ * Returns how many bytes of data are waiting in the AUX buffer (whose
+ * meta-data is in `hdr`), given that the consumer has read up to `tail`.
  */
-bool read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
-              struct hwt_perf_trace *trace, struct hwt_cerror *err) {
-  // Use of atomics here for the same reasons as for handle_sample().
+static __u64 aux_pending(struct perf_event_mmap_page *hdr, __u64 tail) {
   __u64 head_monotonic = atomic_load_explicit((_Atomic __u64 *)&hdr->aux_head,
                                               memory_order_acquire);
   __u64 size = hdr->aux_size;         // No atomic load. Constant value.
   __u64 head = head_monotonic % size; // Head must be manually wrapped.
-  __u64 tail = atomic_load_explicit((_Atomic __u64 *)&hdr->aux_tail,
-                                    memory_order_relaxed);
-
-  // Figure out how much more space we need in the trace storage buffer.
-  __u64 new_data_size;
   if (tail <= head) {
     // No wrap-around.
-    new_data_size = head - tail;
+    return head - tail;
   } else {
     // Wrap-around.
-    new_data_size = (size - tail) + head;
+    return (size - tail) + head;
   }
+}
+
+/*
+ * Read data out of the AUX buffer.
+ *
+ * Reads from `aux_buf` (whose meta-data is in `hdr`) into `trace`.
+ */
+bool read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
+              struct hwt_perf_trace *trace, struct hwt_cerror *err) {
+  // Use of atomics here for the same reasons as for handle_sample().
+  __u64 size = hdr->aux_size; // No atomic load. Constant value.
+  __u64 tail = atomic_load_explicit((_Atomic __u64 *)&hdr->aux_tail,
+                                    memory_order_relaxed);
+  // Figure out how much more space we need in the trace storage buffer.
+  __u64 new_data_size = aux_pending(hdr, tail);
+  __u64 head = (tail + new_data_size) % size;
 
   // Reallocate the trace storage buffer if more space is required.
-  __u64 required_capacity = trace->len + new_data_size;
+  //
+  // We are the only writer of `trace->len`, so we can read it without an
+  // atomic load.
+  __u64 len = trace->len;
+  __u64 required_capacity = len + new_data_size;
   if (required_capacity > trace->capacity) {
     // Over-allocate to 2x what we need, checking that the result fits in
     // the size_t argument of realloc(3).
@@ -287,14 +307,15 @@ bool read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
 
   // Finally append the new AUX data to the end of the trace storage buffer.
   if (tail <= head) {
-    memcpy(trace->buf.p + trace->len, aux_buf + tail, head - tail);
-    trace->len += head - tail;
+    memcpy(trace->buf.p + len, aux_buf + tail, head - tail);
   } else {
-    memcpy(trace->buf.p + trace->len, aux_buf + tail, size - tail);
-    trace->len += size - tail;
-    memcpy(trace->buf.p + trace->len, aux_buf, head);
-    trace->len += size + head;
+    memcpy(trace->buf.p + len, aux_buf + tail, size - tail);
+    memcpy(trace->buf.p + len + (size - tail), aux_buf, head);
   }
+  // The length must be published before the tail is moved on, so that
+  // `hwt_perf_trace_len` never misses the data we have just copied.
+  atomic_store_explicit((_Atomic __u64 *)&trace->len, len + new_data_size,
+                        memory_order_release);
   atomic_store_explicit((_Atomic __u64 *)&hdr->aux_tail, head,
                         memory_order_release);
   return true;
@@ -615,6 +636,8 @@ bool hwt_perf_start_collector(struct hwt_perf_ctx *tr_ctx,
   tr_ctx->collector_thread_err.kind = hwt_cerror_unused;
   tr_ctx->collector_thread_err.code = 0;
 
+  tr_ctx->trace = trace;
+
   // Build the arguments struct for the collector thread.
   struct collector_thread_args thr_args = {
       tr_ctx->perf_fd,
@@ -673,6 +696,27 @@ clean:
   return ret;
 }
 
+/*
+ * Return how many bytes of trace data have been collected so far.
+ *
+ * Must only be called by the traced thread while the collector is running.
+ * Data still in the AUX buffer is counted, so the result doesn't depend on how
+ * often the collector thread wakes up. The collector thread may be copying
+ * data out of the AUX buffer concurrently, in which case that data may
+ * briefly be counted twice: the result can over-estimate, but never
+ * under-estimate, the trace length.
+ */
+size_t hwt_perf_trace_len(struct hwt_perf_ctx *tr_ctx) {
+  struct perf_event_mmap_page *hdr = tr_ctx->base_buf;
+  // Load the tail before the length: `read_aux` publishes the length before
+  // it moves the tail, so any data behind the tail we see is in the length.
+  __u64 tail = atomic_load_explicit((_Atomic __u64 *)&hdr->aux_tail,
+                                    memory_order_acquire);
+  __u64 len = atomic_load_explicit((_Atomic __u64 *)&tr_ctx->trace->len,
+                                   memory_order_acquire);
+  return len + aux_pending(hdr, tail);
+}
+
 /*
  * Turn off trace collection.
  *
@@ -22,6 +22,7 @@ extern "C" {
     ) -> bool;
     fn hwt_perf_stop_collector(tr_ctx: *mut c_void, err: *mut PerfPTCError) -> bool;
     fn hwt_perf_free_collector(tr_ctx: *mut c_void, err: *mut PerfPTCError) -> bool;
+    fn hwt_perf_trace_len(tr_ctx: *mut c_void) -> size_t;
 }
 
 const PERF_PERMS_PATH: &str = "/proc/sys/kernel/perf_event_paranoid";
@@ -105,6 +106,12 @@ impl ThreadTracer for PerfThreadTracer {
 
         Ok(self.trace)
     }
+
+    fn trace_len(&self) -> usize {
+        // The collector thread mutates the trace behind our back, so we must not read it from
+        // Rust: the C side reads the length atomically for us.
+        unsafe { hwt_perf_trace_len(self.ctx) }
+    }
 }
 
 impl PerfThreadTracer {
@@ -50,6 +50,7 @@ pub trait TraceDecoder {
 
 pub struct TraceDecoderBuilder {
     kind: TraceDecoderKind,
+    recover_overflows: bool,
 }
 
 impl TraceDecoderBuilder {
@@ -57,6 +58,7 @@ impl TraceDecoderBuilder {
     pub fn new() -> Self {
         Self {
             kind: TraceDecoderKind::default_for_platform().unwrap(),
+            recover_overflows: false,
         }
     }
 
@@ -66,6 +68,18 @@ impl TraceDecoderBuilder {
         self
     }
 
+    /// Select whether the decoder recovers from lost trace data (e.g. due to the trace buffer
+    /// overflowing).
+    ///
+    /// If `true`, the decoder resynchronises with the trace at the next PSB packet and marks the
+    /// discontinuity with a [Block::Gap], leaving the consumer to decide if a partial trace is
+    /// usable. If `false` (the default), lost trace data ends iteration with
+    /// `HWTracerError::HWBufferOverflow`.
+    pub fn recover_overflows(mut self, recover: bool) -> Self {
+        self.recover_overflows = recover;
+        self
+    }
+
     /// Build the trace decoder.
     ///
     /// An error is returned if the requested decoder is inappropriate for the platform or the
@@ -75,7 +89,9 @@ impl TraceDecoderBuilder {
         match self.kind {
             TraceDecoderKind::YkPT => {
                 #[cfg(decoder_ykpt)]
-                return Ok(Box::new(YkPTTraceDecoder::new()));
+                return Ok(Box::new(YkPTTraceDecoder::with_overflow_recovery(
+                    self.recover_overflows,
+                )));
                 #[cfg(not(decoder_ykpt))]
                 return Err(HWTracerError::DecoderUnavailable(self.kind));
             }
@@ -0,0 +1,109 @@
+//! A cache of disassembled foreign code.
+//!
+//! Foreign code (code not built with ykllvm, e.g. libc) can only be decoded by disassembling it,
+//! and since the same foreign code tends to be executed over and over (e.g. from a hot loop in the
+//! interpreter), we cache what we learn from disassembling it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+use ykutil::obj::{ObjectMap, PHDR_OBJECT_CACHE};
+
+/// The foreign blocks of the objects loaded in this process.
+///
+/// The cache is only valid for as long as the set of loaded objects doesn't change (a newly loaded
+/// object may occupy the addresses of an unloaded one), so it is discarded when it does.
+static LIVE_FOREIGN_BLOCKS: LazyLock<Mutex<(Arc<ObjectMap>, Arc<ForeignBlocks>)>> =
+    LazyLock::new(|| Mutex::new((PHDR_OBJECT_CACHE.get(), Arc::new(ForeignBlocks::new()))));
+
+/// How control flow leaves a [ForeignBlock].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Terminator {
+    /// There's no control flow instruction: execution continues at the end of the block (which is
+    /// either the start of code with blockmap info or the start of another segment).
+    FallThrough,
+    /// A near return.
+    Return,
+    /// An indirect jump.
+    IndirectBranch,
+    /// An indirect near call.
+    IndirectCall,
+    /// A conditional jump to `target` (falling through if not taken).
+    Conditional { target: usize },
+    /// A direct jump to `target`.
+    Unconditional { target: usize },
+    /// A direct near call to `target`.
+    Call { target: usize },
+}
+
+/// A run of foreign machine code ending in (and including) a control flow instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct ForeignBlock {
+    /// The virtual address immediately after the block's last instruction.
+    pub(super) end: usize,
+    /// How control flow leaves the block.
+    pub(super) term: Terminator,
+}
+
+/// A thread-safe cache mapping the start address of a foreign block to the block.
+pub(super) struct ForeignBlocks {
+    blocks: Mutex<HashMap<usize, ForeignBlock>>,
+}
+
+impl ForeignBlocks {
+    pub(super) fn new() -> Self {
+        Self {
+            blocks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the cache for the objects loaded in this process, as per the snapshot `objs`.
+    pub(super) fn live(objs: &Arc<ObjectMap>) -> Arc<Self> {
+        let mut live = LIVE_FOREIGN_BLOCKS.lock().unwrap();
+        if !Arc::ptr_eq(objs, &live.0) {
+            *live = (Arc::clone(objs), Arc::new(Self::new()));
+        }
+        Arc::clone(&live.1)
+    }
+
+    /// Return the block starting at `vaddr`, if it has been cached.
+    pub(super) fn get(&self, vaddr: usize) -> Option<ForeignBlock> {
+        self.blocks.lock().unwrap().get(&vaddr).copied()
+    }
+
+    /// Cache `block` as the block starting at `vaddr`.
+    pub(super) fn insert(&self, vaddr: usize, block: ForeignBlock) {
+        self.blocks.lock().unwrap().insert(vaddr, block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ForeignBlock, ForeignBlocks, Terminator};
+    use std::sync::Arc;
+    use ykutil::obj::PHDR_OBJECT_CACHE;
+
+    #[test]
+    fn get_insert() {
+        let fbs = ForeignBlocks::new();
+        assert_eq!(fbs.get(0x1000), None);
+        let blk = ForeignBlock {
+            end: 0x1005,
+            term: Terminator::Call { target: 0x2000 },
+        };
+        fbs.insert(0x1000, blk);
+        assert_eq!(fbs.get(0x1000), Some(blk));
+        assert_eq!(fbs.get(0x1001), None);
+    }
+
+    /// While no objects are loaded or unloaded, every decoder shares the same cache.
+    #[test]
+    fn live_is_shared() {
+        let objs = PHDR_OBJECT_CACHE.get();
+        assert!(Arc::ptr_eq(
+            &ForeignBlocks::live(&objs),
+            &ForeignBlocks::live(&objs)
+        ));
+    }
+}
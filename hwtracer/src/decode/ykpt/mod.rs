@@ -14,7 +14,8 @@
 //! The latter mode is a fallback for decoding portions of the trace that are for "foreign code"
 //! (code not built with ykllvm). For foreign code there is no static control flow edge information
 //! available, so to decode these parts of the trace we have to disassemble the instruction stream
-//! (like libipt does).
+//! (like libipt does). Since that's slow, the results of disassembly are cached and shared by all
+//! decoders (see the [foreign] module).
 //!
 //! You may now be asking: why not just skip the parts of the trace that are for foreign code?
 //! After all, if a portion of code wasn't built with ykllvm, then we won't have IR for it anyway,
@@ -38,9 +39,10 @@
 //! code that are compiled with ykllvm.
 
 use crate::{
+    capture::{Sideband, LONGJMP_FUNCS},
     decode::TraceDecoder,
     errors::HWTracerError,
-    llvm_blockmap::{BlockMapEntry, SuccessorKind, LLVM_BLOCK_MAP},
+    llvm_blockmap::{BlockMap, BlockMapEntry, SuccessorKind, LLVM_BLOCK_MAPS},
     Block, Trace,
 };
 use iced_x86;
@@ -51,16 +53,18 @@ use std::{
     convert::TryFrom,
     ffi::CString,
     fmt::{self, Debug},
+    mem,
     ops::Range,
-    path::{Path, PathBuf},
     ptr, slice,
-    sync::LazyLock,
+    sync::{Arc, LazyLock, Mutex},
 };
 use ykutil::{
     self,
-    obj::{PHDR_MAIN_OBJ, PHDR_OBJECT_CACHE, SELF_BIN_PATH},
+    obj::{ObjectMap, PHDR_OBJECT_CACHE},
 };
 
+mod foreign;
+use foreign::{ForeignBlock, ForeignBlocks, Terminator};
 mod packet_parser;
 use packet_parser::{
     packets::Bitness,
@@ -68,38 +72,34 @@ use packet_parser::{
     PacketParser,
 };
 
-pub(crate) struct YkPTTraceDecoder {}
+pub(crate) struct YkPTTraceDecoder {
+    /// Recover from lost trace data by resynchronising at the next PSB packet?
+    recover_overflows: bool,
+}
+
+impl YkPTTraceDecoder {
+    pub(crate) fn with_overflow_recovery(recover_overflows: bool) -> Self {
+        Self { recover_overflows }
+    }
+}
 
 impl TraceDecoder for YkPTTraceDecoder {
     fn new() -> Self {
-        Self {}
+        Self::with_overflow_recovery(false)
     }
 
     fn iter_blocks<'t>(
         &'t self,
         trace: &'t dyn Trace,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + '_> {
-        Box::new(YkPTBlockIterator::new(trace))
+        Box::new(YkPTBlockIterator::new(trace, self.recover_overflows))
     }
 }
 
-/// The virtual address ranges of segments that we may need to disassemble.
-static CODE_SEGS: LazyLock<CodeSegs> = LazyLock::new(|| {
-    let mut segs = Vec::new();
-    for obj in PHDR_OBJECT_CACHE.iter() {
-        let obj_base = obj.addr();
-        for hdr in obj.phdrs() {
-            if (hdr.flags() & libc::PF_W) == 0 {
-                let vaddr = usize::try_from(obj_base + hdr.vaddr()).unwrap();
-                let memsz = usize::try_from(hdr.memsz()).unwrap();
-                let key = vaddr..(vaddr + memsz);
-                segs.push((key.clone(), ()));
-            }
-        }
-    }
-    let tree = segs.into_iter().collect::<IntervalTree<usize, ()>>();
-    CodeSegs { tree }
-});
+/// The virtual address ranges of segments that we may need to disassemble, for the most recent
+/// snapshot of the objects loaded in this process.
+static CODE_SEGS: LazyLock<Mutex<Arc<CodeSegs>>> =
+    LazyLock::new(|| Mutex::new(Arc::new(CodeSegs::new(PHDR_OBJECT_CACHE.get()))));
 
 /// The number of compressed returns that a CPU implementing Intel Processor Trace can keep track
 /// of. This is a bound baked into the hardware, but the decoder needs to be aware of it for its
@@ -109,55 +109,188 @@ const PT_MAX_COMPRETS: usize = 64;
 /// A data structure providing convenient access to virtual address ranges and memory slices for
 /// segments.
 ///
-/// FIXME: For now this assumes that no dlopen()/dlclose() is happening.
+/// The segments are those of one snapshot of the loaded objects: if an object is loaded or
+/// unloaded, a new `CodeSegs` must be built.
 struct CodeSegs {
+    /// The objects that the segments were computed from.
+    objs: Arc<ObjectMap>,
     tree: IntervalTree<usize, ()>,
 }
 
 impl CodeSegs {
-    /// Obtain the virtual address range and a slice of memory for the segment containing the
-    /// specified virtual address.
-    fn seg<'s: 'a, 'a>(&'s self, vaddr: usize) -> Segment<'a> {
-        let mut hits = self.tree.query(vaddr..(vaddr + 1));
-        match hits.next() {
-            Some(x) => {
-                // Segments can't overlap.
-                debug_assert_eq!(hits.next(), None);
-
-                let slice = unsafe {
-                    slice::from_raw_parts(x.range.start as *const u8, x.range.end - x.range.start)
-                };
-                Segment {
-                    vaddrs: &x.range,
-                    slice,
+    fn new(objs: Arc<ObjectMap>) -> Self {
+        let mut segs = Vec::new();
+        for obj in objs.iter() {
+            let obj_base = obj.addr();
+            for hdr in obj.phdrs() {
+                if (hdr.flags() & libc::PF_W) == 0 {
+                    let vaddr = usize::try_from(obj_base + hdr.vaddr()).unwrap();
+                    let memsz = usize::try_from(hdr.memsz()).unwrap();
+                    let key = vaddr..(vaddr + memsz);
+                    segs.push((key.clone(), ()));
                 }
             }
-            None => todo!(), // Has an object been loaded or unloaded at runtime?
         }
+        let tree = segs.into_iter().collect::<IntervalTree<usize, ()>>();
+        CodeSegs { objs, tree }
+    }
+
+    /// Return the segments of the objects in `objs`, reusing the previous decoder's segments if
+    /// they were computed from the same snapshot.
+    fn live(objs: &Arc<ObjectMap>) -> Arc<Self> {
+        let mut segs = CODE_SEGS.lock().unwrap();
+        if !Arc::ptr_eq(objs, &segs.objs) {
+            *segs = Arc::new(CodeSegs::new(Arc::clone(objs)));
+        }
+        Arc::clone(&segs)
+    }
+
+    /// Obtain the virtual address range and a slice of memory for the segment containing the
+    /// specified virtual address, or `None` if no segment contains it.
+    ///
+    /// The slice points into live memory: it can't outlive the `CodeSegs` (and thus the snapshot
+    /// of the objects) that it came from, but it is still only valid for as long as the object
+    /// containing the segment remains loaded.
+    fn seg(&self, vaddr: usize) -> Option<Segment<'_>> {
+        let mut hits = self.tree.query(vaddr..(vaddr + 1));
+        let x = hits.next()?;
+        // Segments can't overlap.
+        debug_assert_eq!(hits.next(), None);
+
+        let slice = unsafe {
+            slice::from_raw_parts(x.range.start as *const u8, x.range.end - x.range.start)
+        };
+        Some(Segment {
+            vaddrs: x.range.clone(),
+            slice,
+        })
     }
 }
 
 /// The virtual address range of and memory slice of one ELF segment.
 struct Segment<'a> {
     /// The virtual address range of the segment.
-    vaddrs: &'a Range<usize>,
+    vaddrs: Range<usize>,
     /// A memory slice for the segment.
     slice: &'a [u8],
 }
 
+/// The address space of the process that collected a trace: either this process, or one captured
+/// alongside the trace (see [crate::TraceCapture]).
+///
+/// For this process, the objects loaded are snapshotted once, when decoding starts. Any object
+/// that the trace passed through was loaded while the trace was being collected, so it is either
+/// in the snapshot or has since been unloaded (in which case the trace can't be decoded anyway).
+enum AddrSpace<'t> {
+    Live(Arc<CodeSegs>),
+    Captured(&'t Sideband),
+}
+
+impl<'t> AddrSpace<'t> {
+    fn new(trace: &'t dyn Trace) -> Self {
+        match trace.sideband() {
+            Some(sb) => AddrSpace::Captured(sb),
+            None => AddrSpace::Live(CodeSegs::live(&PHDR_OBJECT_CACHE.get())),
+        }
+    }
+
+    /// Obtain the virtual address range and a slice of memory for the code segment containing
+    /// `vaddr`.
+    fn seg(&self, vaddr: usize) -> Result<Segment<'_>, HWTracerError> {
+        match self {
+            AddrSpace::Live(segs) => segs.seg(vaddr).ok_or_else(|| {
+                HWTracerError::DisasmFail(format!("no loaded object contains address 0x{vaddr:x}"))
+            }),
+            AddrSpace::Captured(sb) => match sb.code(vaddr) {
+                Some((vaddrs, slice)) => Ok(Segment {
+                    vaddrs: vaddrs.clone(),
+                    slice,
+                }),
+                None => Err(HWTracerError::DisasmFail(format!(
+                    "no code captured for address 0x{vaddr:x}"
+                ))),
+            },
+        }
+    }
+
+    /// Convert a virtual address to an [ObjLoc].
+    fn vaddr_to_objloc(&self, vaddr: usize) -> Result<ObjLoc<'t>, HWTracerError> {
+        let obj = match self {
+            AddrSpace::Live(segs) => {
+                match ykutil::addr::vaddr_to_obj_and_off_in(&segs.objs, vaddr) {
+                    Some((obj, off)) => LLVM_BLOCK_MAPS.get(&obj)?.map(|blockmap| YkObj {
+                        base: vaddr - usize::try_from(off).unwrap(),
+                        blockmap,
+                    }),
+                    None => {
+                        return Err(HWTracerError::TraceParseError(
+                            "failed to convert a virtual address to an offset".to_owned(),
+                        ))
+                    }
+                }
+            }
+            AddrSpace::Captured(sb) => sb
+                .blockmap_obj(vaddr)?
+                .map(|(base, blockmap)| YkObj { base, blockmap }),
+        };
+        match obj {
+            Some(obj) => Ok(ObjLoc::YkObj(obj, u64::try_from(vaddr - obj.base).unwrap())),
+            None => Ok(ObjLoc::OtherObjOrUnknown(Some(vaddr))),
+        }
+    }
+
+    /// Return the virtual addresses of [LONGJMP_FUNCS] (0 for any that can't be found).
+    fn longjmp_vaddrs(&self) -> [u64; LONGJMP_FUNCS.len()] {
+        match self {
+            AddrSpace::Live(_) => LONGJMP_FUNCS.map(|f| {
+                let func = CString::new(f).unwrap();
+                // `as usize` is a safe cast from raw pointer to pointer-sized integer.
+                u64::try_from(unsafe { libc::dlsym(ptr::null_mut(), func.as_ptr()) } as usize)
+                    .unwrap()
+            }),
+            AddrSpace::Captured(sb) => sb.longjmp_vaddrs(),
+        }
+    }
+}
+
+/// A loaded object built with ykllvm, and thus having a blockmap with which we can do
+/// compiler-assisted decoding.
+#[derive(Clone, Copy)]
+struct YkObj<'t> {
+    /// The address the object was loaded at.
+    base: usize,
+    blockmap: &'t BlockMap,
+}
+
+impl YkObj<'_> {
+    /// Convert an offset in the object to a virtual address.
+    fn vaddr(&self, off: u64) -> usize {
+        self.base + usize::try_from(off).unwrap()
+    }
+}
+
+impl PartialEq for YkObj<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        // No two objects can be loaded at the same address.
+        self.base == other.base
+    }
+}
+
+impl Eq for YkObj<'_> {}
+
 /// Represents a location in the instruction stream of the traced binary.
 #[derive(Eq, PartialEq)]
-enum ObjLoc {
-    /// A known byte offset in the "main binary object" of the program.
-    MainObj(u64),
+enum ObjLoc<'t> {
+    /// A known byte offset in an object built with ykllvm.
+    YkObj(YkObj<'t>, u64),
     /// Anything else, as a virtual address (if known).
     OtherObjOrUnknown(Option<usize>),
 }
 
-impl Debug for ObjLoc {
+impl Debug for ObjLoc<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::MainObj(v) => write!(f, "ObjLoc::MainObj(0x{:x})", v),
+            Self::YkObj(obj, off) => write!(f, "ObjLoc::YkObj(0x{:x}+0x{:x})", obj.base, off),
             Self::OtherObjOrUnknown(e) => {
                 if let Some(e) = e {
                     write!(f, "ObjLoc::OtherObjOrUnknown(0x{:x})", e)
@@ -170,17 +303,17 @@ impl Debug for ObjLoc {
 }
 
 /// The return addresses that can appear on the compressed return stack.
-#[derive(Debug, Clone)]
-enum CompRetAddr {
+#[derive(Clone)]
+enum CompRetAddr<'t> {
     /// A regular return address (as a virtual address).
     VAddr(usize),
-    /// Return to directly after the callsite at the given offset in the main object binary.
+    /// Return to directly after the callsite at the given offset in the given object.
     ///
     /// This exists because when we do compiler-assisted decoding, we don't disassemble the
     /// instruction stream, and thus we don't know how long the call instruction is, and hence nor
     /// the address of the instruction to return to. That's actually OK, because compiler-assisted
     /// decoding needs only to know after which call to continue decoding after.
-    AfterCall(u64),
+    AfterCall(YkObj<'t>, u64),
 }
 
 /// The compressed return stack (required for the compressed returns optimisation implemented by
@@ -194,18 +327,18 @@ enum CompRetAddr {
 /// For more information, consult Section 34.4.2.2 of the Intel 64 and IA-32 Architectures Software
 /// Developer’s Manual, Volume 3 (under the "Indirect Transfer Compression for Returns")
 /// sub-heading.
-struct CompressedReturns {
-    rets: VecDeque<CompRetAddr>,
+struct CompressedReturns<'t> {
+    rets: VecDeque<CompRetAddr<'t>>,
 }
 
-impl CompressedReturns {
+impl<'t> CompressedReturns<'t> {
     fn new() -> Self {
         Self {
             rets: VecDeque::new(),
         }
     }
 
-    fn push(&mut self, ret: CompRetAddr) {
+    fn push(&mut self, ret: CompRetAddr<'t>) {
         debug_assert!(self.rets.len() <= PT_MAX_COMPRETS);
 
         // The stack is fixed-size. When the stack is full and a new entry is pushed, the oldest
@@ -217,11 +350,29 @@ impl CompressedReturns {
         self.rets.push_back(ret);
     }
 
-    fn pop(&mut self) -> Option<CompRetAddr> {
+    fn pop(&mut self) -> Option<CompRetAddr<'t>> {
         self.rets.pop_back()
     }
 }
 
+/// An asynchronous event that interrupted the control flow of the traced code.
+#[derive(Debug)]
+enum Interrupt {
+    /// Execution resumed where it was interrupted. Any code run in the meantime has been skipped.
+    Resumed,
+    /// Execution never returned to the interrupted code, continuing at the given virtual address
+    /// instead.
+    Diverted(usize),
+    /// Trace data was lost at byte offset `off` of the trace. The decoder resynchronised after
+    /// skipping `skipped` bytes of the trace, and resumes at the virtual address `resume` (or, if
+    /// `None`, wherever the next `TIP.PGE` packet says).
+    Lost {
+        off: usize,
+        skipped: usize,
+        resume: Option<usize>,
+    },
+}
+
 /// Iterate over the blocks of an Intel PT trace using the fast Yk PT decoder.
 struct YkPTBlockIterator<'t> {
     /// The next block that the iterator will hand out. We lookahead like this so that we can
@@ -229,30 +380,66 @@ struct YkPTBlockIterator<'t> {
     next: Cell<Result<Block, HWTracerError>>,
     /// The packet iterator used to drive the decoding process.
     parser: PacketParser<'t>,
+    /// The address space of the process that collected the trace.
+    aspace: AddrSpace<'t>,
     /// Keeps track of where we are in the traced binary.
-    cur_loc: ObjLoc,
+    cur_loc: ObjLoc<'t>,
     /// A vector of "taken/not-taken" (TNT) decisions. These arrive in batches and get buffered
     /// here in a FIFO fashion (oldest decision at head poistion).
     tnts: VecDeque<bool>,
     /// The compressed return stack.
-    comprets: CompressedReturns,
+    comprets: CompressedReturns<'t>,
     /// When true, packet generation is enabled (we've seen a `TIP.PGE` packet, but no
     /// corresponding `TIP.PGD` yet).
     pge: bool,
     /// When `true` we have seen one of more `MODE.*` packets that are yet to be bound.
     unbound_modes: bool,
+    /// When `true`, one of the unbound `MODE.*` packets reported a transactional abort.
+    tsx_abort: bool,
+    /// When `true`, the next FUP packet binds to the packet before it, rather than signalling an
+    /// asynchronous event.
+    bound_fup: bool,
+    /// An interruption encountered while decoding the next block, yet to be reported.
+    interrupt: Option<Interrupt>,
+    /// The block to hand out after reporting an interruption or a divergent edge.
+    after_interrupt: Option<Block>,
+    /// Recover from lost trace data by resynchronising at the next PSB packet?
+    recover_overflows: bool,
+    /// Foreign code already disassembled (possibly by other decoders).
+    foreign: Arc<ForeignBlocks>,
+    /// The virtual addresses of [LONGJMP_FUNCS] (0 for any that can't be found).
+    longjmps: [u64; LONGJMP_FUNCS.len()],
+    /// When `true`, entry to the `longjmp()` we are about to disassemble has already been
+    /// reported.
+    longjmp_reported: bool,
 }
 
 impl<'t> YkPTBlockIterator<'t> {
-    fn new(trace: &'t dyn Trace) -> Self {
+    fn new(trace: &'t dyn Trace, recover_overflows: bool) -> Self {
+        let aspace = AddrSpace::new(trace);
+        let foreign = match &aspace {
+            AddrSpace::Live(segs) => ForeignBlocks::live(&segs.objs),
+            // The code of a captured address space may differ from capture to capture.
+            AddrSpace::Captured(_) => Arc::new(ForeignBlocks::new()),
+        };
+        let longjmps = aspace.longjmp_vaddrs();
         let mut this = YkPTBlockIterator {
             next: Cell::new(Ok(Block::new_unknown())),
             parser: PacketParser::new(trace.bytes()),
+            aspace,
             cur_loc: ObjLoc::OtherObjOrUnknown(None),
             tnts: VecDeque::new(),
             comprets: CompressedReturns::new(),
             pge: false,
             unbound_modes: false,
+            tsx_abort: false,
+            bound_fup: false,
+            interrupt: None,
+            after_interrupt: None,
+            recover_overflows,
+            foreign,
+            longjmps,
+            longjmp_reported: false,
         };
 
         // Prime the cached next element.
@@ -261,32 +448,13 @@ impl<'t> YkPTBlockIterator<'t> {
         this
     }
 
-    /// Convert a file offset to a virtual address.
-    fn off_to_vaddr(&self, obj: &Path, off: u64) -> Result<usize, HWTracerError> {
-        match ykutil::addr::off_to_vaddr(obj, off) {
-            Some(vaddr) => Ok(vaddr),
-            None => Err(HWTracerError::TraceParseError(
-                "failed to convert an offset to a virtual address".to_owned(),
-            )),
-        }
-    }
-
-    /// Convert a virtual address to a file offset.
-    fn vaddr_to_off(&self, vaddr: usize) -> Result<(PathBuf, u64), HWTracerError> {
-        match ykutil::addr::vaddr_to_obj_and_off(vaddr) {
-            Some(tup) => Ok(tup),
-            None => Err(HWTracerError::TraceParseError(
-                "failed to convert a virtual address to an offset".to_owned(),
-            )),
-        }
-    }
-
-    /// Looks up the blockmap entry for the given offset in the "main object binary".
+    /// Looks up the blockmap entry for the given offset in the object `obj`.
     fn lookup_blockmap_entry(
         &self,
+        obj: YkObj<'t>,
         off: u64,
-    ) -> Option<&'static intervaltree::Element<u64, BlockMapEntry>> {
-        let mut ents = LLVM_BLOCK_MAP.query(off, off + 1);
+    ) -> Option<&'t intervaltree::Element<u64, BlockMapEntry>> {
+        let mut ents = obj.blockmap.query(off, off + 1);
         if let Some(ent) = ents.next() {
             // A single-address range cannot span multiple blocks.
             debug_assert!(ents.next().is_none());
@@ -296,60 +464,62 @@ impl<'t> YkPTBlockIterator<'t> {
         }
     }
 
-    // Lookup a block from an offset in the "main binary" (i.e. not from a shared object).
-    fn lookup_block_from_main_bin_offset(&mut self, off: u64) -> Result<Block, HWTracerError> {
-        if let Some(ent) = self.lookup_blockmap_entry(off) {
-            Ok(Block::from_vaddr_range(
-                u64::try_from(self.off_to_vaddr(&PHDR_MAIN_OBJ, ent.range.start)?).unwrap(),
-                u64::try_from(self.off_to_vaddr(&PHDR_MAIN_OBJ, ent.range.end)?).unwrap(),
-            ))
+    // Lookup a block from an offset in an object built with ykllvm.
+    fn lookup_block_from_obj_offset(&self, obj: YkObj<'t>, off: u64) -> Block {
+        if let Some(ent) = self.lookup_blockmap_entry(obj, off) {
+            Block::from_vaddr_range(
+                u64::try_from(obj.vaddr(ent.range.start)).unwrap(),
+                u64::try_from(obj.vaddr(ent.range.end)).unwrap(),
+            )
         } else {
-            Ok(Block::new_unknown())
+            Block::new_unknown()
         }
     }
 
-    /// Use the blockmap entry `ent` to follow the next (after the offset `b_off`) call in the
-    /// block (if one exists).
+    /// Use the blockmap entry `ent` (of the object `obj`) to follow the next (after the offset
+    /// `b_off`) call in the block (if one exists).
     ///
     /// Returns `Ok(Some(blk))` if there was a call to follow that lands us in the block `blk`.
     ///
     /// Returns `Ok(None)` if there was no call to follow after `b_off`.
     fn maybe_follow_blockmap_call(
         &mut self,
+        obj: YkObj<'t>,
         b_off: u64,
         ent: &BlockMapEntry,
     ) -> Result<Option<Block>, HWTracerError> {
         if let Some(call_info) = ent.call_offs().iter().find(|c| c.callsite_off() >= b_off) {
             self.comprets
-                .push(CompRetAddr::AfterCall(call_info.callsite_off()));
+                .push(CompRetAddr::AfterCall(obj, call_info.callsite_off()));
 
             let target = call_info.target_off();
             if let Some(target_off) = target {
-                self.cur_loc = ObjLoc::MainObj(target_off);
-                return Ok(Some(self.lookup_block_from_main_bin_offset(target_off)?));
+                self.cur_loc = ObjLoc::YkObj(obj, target_off);
+                return Ok(Some(self.lookup_block_from_obj_offset(obj, target_off)));
             } else {
                 // Call target isn't known statically. Find it from a TIP packet.
                 self.seek_tip()?;
-                return match self.cur_loc {
-                    ObjLoc::MainObj(off) => Ok(Some(self.lookup_block_from_main_bin_offset(off)?)),
-                    ObjLoc::OtherObjOrUnknown(_) => Ok(Some(Block::new_unknown())),
-                };
+                return Ok(Some(self.block_at_cur_loc()));
             }
         }
 
         Ok(None)
     }
 
-    /// Follow the successor of the block described by the blockmap entry `ent`.
-    fn follow_blockmap_successor(&mut self, ent: &BlockMapEntry) -> Result<Block, HWTracerError> {
-        match ent.successor() {
+    /// Follow the successor of the block described by the blockmap entry `ent` (of the object
+    /// `obj`).
+    fn follow_blockmap_successor(
+        &mut self,
+        obj: YkObj<'t>,
+        ent: &intervaltree::Element<u64, BlockMapEntry>,
+    ) -> Result<Block, HWTracerError> {
+        match ent.value.successor() {
             SuccessorKind::Unconditional { target } => {
                 if let Some(target_off) = target {
-                    self.cur_loc = ObjLoc::MainObj(*target_off);
-                    self.lookup_block_from_main_bin_offset(*target_off)
+                    self.cur_loc = ObjLoc::YkObj(obj, *target_off);
+                    Ok(self.lookup_block_from_obj_offset(obj, *target_off))
                 } else {
-                    // Divergent control flow.
-                    todo!();
+                    Ok(self.follow_divergent(obj, ent.range.end))
                 }
             }
             SuccessorKind::Conditional {
@@ -370,29 +540,22 @@ impl<'t> YkPTBlockIterator<'t> {
                 } else if let Some(ntt) = not_taken_target {
                     *ntt
                 } else {
-                    // Divergent control flow.
-                    todo!();
+                    // The not-taken path falls through past the end of the block.
+                    return Ok(self.follow_divergent(obj, ent.range.end));
                 };
-                self.cur_loc = ObjLoc::MainObj(target_off);
-                self.lookup_block_from_main_bin_offset(target_off)
+                self.cur_loc = ObjLoc::YkObj(obj, target_off);
+                Ok(self.lookup_block_from_obj_offset(obj, target_off))
             }
             SuccessorKind::Return => {
                 if self.is_return_compressed()? {
                     // This unwrap cannot fail if the CPU has implemented compressed
                     // returns correctly.
                     self.cur_loc = match self.comprets.pop().unwrap() {
-                        CompRetAddr::AfterCall(off) => ObjLoc::MainObj(off + 1),
-                        CompRetAddr::VAddr(vaddr) => {
-                            let (obj, off) = self.vaddr_to_off(vaddr)?;
-                            if obj == *SELF_BIN_PATH {
-                                ObjLoc::MainObj(off)
-                            } else {
-                                ObjLoc::OtherObjOrUnknown(Some(vaddr))
-                            }
-                        }
+                        CompRetAddr::AfterCall(obj, off) => ObjLoc::YkObj(obj, off + 1),
+                        CompRetAddr::VAddr(vaddr) => self.aspace.vaddr_to_objloc(vaddr)?,
                     };
-                    if let ObjLoc::MainObj(off) = self.cur_loc {
-                        self.lookup_block_from_main_bin_offset(off + 1)
+                    if let ObjLoc::YkObj(obj, off) = self.cur_loc {
+                        Ok(self.lookup_block_from_obj_offset(obj, off + 1))
                     } else {
                         Ok(Block::new_unknown())
                     }
@@ -401,43 +564,101 @@ impl<'t> YkPTBlockIterator<'t> {
                     //
                     // Note that `is_return_compressed()` has already updated
                     // `self.cur_loc()`.
-                    match self.cur_loc {
-                        ObjLoc::MainObj(off) => Ok(self.lookup_block_from_main_bin_offset(off)?),
-                        _ => Ok(Block::new_unknown()),
-                    }
+                    Ok(self.block_at_cur_loc())
                 }
             }
             SuccessorKind::Dynamic => {
                 // We can only know the successor via a TIP update in a packet.
                 self.seek_tip()?;
-                match self.cur_loc {
-                    ObjLoc::MainObj(off) => Ok(self.lookup_block_from_main_bin_offset(off)?),
-                    _ => Ok(Block::new_unknown()),
-                }
+                Ok(self.block_at_cur_loc())
             }
         }
     }
 
+    /// Leave a block of the object `obj` along a divergent edge, i.e. one which the compiler
+    /// assumed would never be taken, and so for which the blockmap has no target.
+    ///
+    /// Since there's no telling what (if anything) the compiler put after such an edge, the
+    /// machine code from the end of the block (offset `end_off`) onwards is disassembled, just as
+    /// the CPU would execute it. This returns a marker reporting the divergence, and the decoding
+    /// of where control went resumes on the next call to `do_next()`.
+    fn follow_divergent(&mut self, obj: YkObj<'t>, end_off: u64) -> Block {
+        self.cur_loc = ObjLoc::OtherObjOrUnknown(Some(obj.vaddr(end_off)));
+        // Disassembly of foreign code expects an unknown block to be pending.
+        self.after_interrupt = Some(Block::new_unknown());
+        Block::Divergent
+    }
+
     fn do_next(&mut self) -> Result<Block, HWTracerError> {
+        if let Some(blk) = self.after_interrupt.take() {
+            return Ok(blk);
+        }
+
+        let res = self.next_block();
+        match self.interrupt.take() {
+            None => res,
+            Some(Interrupt::Resumed) => {
+                // The interruption happened before the end of the block we were decoding when we
+                // encountered it, so report it before the block that follows.
+                self.after_interrupt = Some(res?);
+                Ok(Block::Interrupted { resumed: true })
+            }
+            Some(Interrupt::Diverted(vaddr)) => {
+                // Whatever we were decoding was abandoned. Pending branch decisions belonged to
+                // the interrupted code, so they are discarded.
+                debug_assert!(matches!(res, Err(HWTracerError::TraceInterrupted)));
+                self.tnts.clear();
+                self.cur_loc = self.aspace.vaddr_to_objloc(vaddr)?;
+                self.after_interrupt = Some(self.block_at_cur_loc());
+                Ok(Block::Interrupted { resumed: false })
+            }
+            Some(Interrupt::Lost {
+                off,
+                skipped,
+                resume,
+            }) => {
+                // Whatever we were decoding was abandoned, and none of the decoding state from
+                // before the gap can be trusted.
+                debug_assert!(matches!(res, Err(HWTracerError::TraceInterrupted)));
+                self.tnts.clear();
+                self.comprets.rets.clear();
+                self.cur_loc = match resume {
+                    Some(vaddr) => self.aspace.vaddr_to_objloc(vaddr)?,
+                    None => ObjLoc::OtherObjOrUnknown(None),
+                };
+                self.after_interrupt = Some(self.block_at_cur_loc());
+                Ok(Block::Gap { off, skipped })
+            }
+        }
+    }
+
+    /// Returns the block for `self.cur_loc` after decoding has been redirected there.
+    fn block_at_cur_loc(&self) -> Block {
+        match self.cur_loc {
+            ObjLoc::YkObj(obj, off) => self.lookup_block_from_obj_offset(obj, off),
+            ObjLoc::OtherObjOrUnknown(_) => Block::new_unknown(),
+        }
+    }
+
+    fn next_block(&mut self) -> Result<Block, HWTracerError> {
         // Read as far ahead as we can using static successor info encoded into the blockmap.
         match self.cur_loc {
-            ObjLoc::MainObj(b_off) => {
-                // We know where we are in the main object binary, so there's a chance that there's
-                // a blockmap entry for this location (not all code from the main object binary
+            ObjLoc::YkObj(obj, b_off) => {
+                // We know where we are in an object built with ykllvm, so there's a chance that
+                // there's a blockmap entry for this location (not all code from such an object
                 // necessarily has blockmap info. e.g. PLT resolution routines).
-                if let Some(ent) = self.lookup_blockmap_entry(b_off.to_owned()) {
+                if let Some(ent) = self.lookup_blockmap_entry(obj, b_off) {
                     // If there are calls in the block that come *after* the current position in the
                     // block, then we will need to follow those before we look at the successor info.
-                    if let Some(blk) = self.maybe_follow_blockmap_call(b_off, &ent.value)? {
+                    if let Some(blk) = self.maybe_follow_blockmap_call(obj, b_off, &ent.value)? {
                         Ok(blk)
                     } else {
                         // If we get here, there were no further calls to follow in the block, so we
                         // consult the static successor information.
-                        self.follow_blockmap_successor(&ent.value)
+                        self.follow_blockmap_successor(obj, ent)
                     }
                 } else {
-                    self.cur_loc =
-                        ObjLoc::OtherObjOrUnknown(Some(self.off_to_vaddr(&PHDR_MAIN_OBJ, b_off)?));
+                    self.cur_loc = ObjLoc::OtherObjOrUnknown(Some(obj.vaddr(b_off)));
                     Ok(Block::new_unknown())
                 }
             }
@@ -446,14 +667,15 @@ impl<'t> YkPTBlockIterator<'t> {
     }
 
     /// Returns the target virtual address for a branch instruction.
-    fn branch_target_vaddr(&self, inst: &iced_x86::Instruction) -> u64 {
-        match inst.op0_kind() {
+    fn branch_target_vaddr(&self, inst: &iced_x86::Instruction) -> usize {
+        let target = match inst.op0_kind() {
             iced_x86::OpKind::NearBranch16 => inst.near_branch16().into(),
             iced_x86::OpKind::NearBranch32 => inst.near_branch32().into(),
             iced_x86::OpKind::NearBranch64 => inst.near_branch64(),
             iced_x86::OpKind::FarBranch16 | iced_x86::OpKind::FarBranch32 => panic!(),
             _ => unreachable!(),
-        }
+        };
+        usize::try_from(target).unwrap()
     }
 
     // Determines if a return from a function was compressed in the packet stream.
@@ -493,158 +715,184 @@ impl<'t> YkPTBlockIterator<'t> {
             .unwrap() += by;
     }
 
-    fn disassemble(&mut self, start_vaddr: usize) -> Result<Block, HWTracerError> {
-        let mut seg = CODE_SEGS.seg(start_vaddr);
+    /// If `vaddr` is in code with blockmap info, return its location and the block containing it.
+    fn native_block_at(&self, vaddr: usize) -> Result<Option<(ObjLoc<'t>, Block)>, HWTracerError> {
+        if let ObjLoc::YkObj(obj, off) = self.aspace.vaddr_to_objloc(vaddr)? {
+            let block = self.lookup_block_from_obj_offset(obj, off);
+            if !block.is_unknown() {
+                return Ok(Some((ObjLoc::YkObj(obj, off), block)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Disassemble the foreign block starting at `start_vaddr`.
+    fn decode_foreign_block(&self, start_vaddr: usize) -> Result<ForeignBlock, HWTracerError> {
+        let seg = self.aspace.seg(start_vaddr)?;
         let mut dis =
             iced_x86::Decoder::with_ip(64, seg.slice, u64::try_from(seg.vaddrs.start).unwrap(), 0);
         dis.set_ip(u64::try_from(start_vaddr).unwrap());
         dis.set_position(start_vaddr - seg.vaddrs.start)
             .map_err(|_| HWTracerError::DisasmFail("failed to set position".to_owned()))?;
-        let mut reposition: bool = false;
-
-        // `as usize` below are safe casts from raw pointer to pointer-sized integer.
-        let longjmp_vaddr = {
-            let func = CString::new("longjmp").unwrap();
-            u64::try_from(unsafe { libc::dlsym(ptr::null_mut(), func.as_ptr()) } as usize).unwrap()
-        };
-        let us_longjmp_vaddr = {
-            let func = CString::new("_longjmp").unwrap();
-            u64::try_from(unsafe { libc::dlsym(ptr::null_mut(), func.as_ptr()) } as usize).unwrap()
-        };
-        let siglongjmp_vaddr = {
-            let func = CString::new("siglongjmp").unwrap();
-            u64::try_from(unsafe { libc::dlsym(ptr::null_mut(), func.as_ptr()) } as usize).unwrap()
-        };
 
         loop {
-            let vaddr = usize::try_from(dis.ip()).unwrap();
-            let (obj, off) = self.vaddr_to_off(vaddr)?;
-
-            if obj == *SELF_BIN_PATH {
-                let block = self.lookup_block_from_main_bin_offset(off)?;
-                if !block.is_unknown() {
-                    // We are back to "native code" and can resume compiler-assisted decoding.
-                    self.cur_loc = ObjLoc::MainObj(off);
-                    return Ok(block);
+            let inst = dis.decode();
+            let end = usize::try_from(inst.next_ip()).unwrap();
+            let term = match inst.flow_control() {
+                iced_x86::FlowControl::Next => None,
+                iced_x86::FlowControl::Call if inst.code() == iced_x86::Code::Syscall => {
+                    // Do nothing. We have disabled kernel tracing in hwtracer, so entering/leaving
+                    // a syscall will generate packet generation disable/enable events
+                    // (`TIP.PGD`/`TIP.PGE` packets) which are handled by the decoder elsewhere.
+                    None
+                }
+                iced_x86::FlowControl::Return => {
+                    // We don't expect to see any 16-bit far returns.
+                    debug_assert!(is_ret_near(&inst));
+                    Some(Terminator::Return)
+                }
+                iced_x86::FlowControl::IndirectBranch => Some(Terminator::IndirectBranch),
+                iced_x86::FlowControl::IndirectCall => {
+                    debug_assert!(!inst.is_call_far());
+                    Some(Terminator::IndirectCall)
+                }
+                iced_x86::FlowControl::ConditionalBranch => Some(Terminator::Conditional {
+                    target: self.branch_target_vaddr(&inst),
+                }),
+                iced_x86::FlowControl::UnconditionalBranch => Some(Terminator::Unconditional {
+                    target: self.branch_target_vaddr(&inst),
+                }),
+                iced_x86::FlowControl::Call => {
+                    // We don't expect to see any 16-bit mode far calls in modernity.
+                    debug_assert!(!inst.is_call_far());
+                    Some(Terminator::Call {
+                        target: self.branch_target_vaddr(&inst),
+                    })
                 }
+                _ => {
+                    // We don't (yet) know how to follow control flow through interrupts,
+                    // exceptions, or transactional memory instructions, so the trace can't be
+                    // decoded.
+                    return Err(HWTracerError::DisasmFail(format!(
+                        "unsupported instruction at 0x{:x}: {inst}",
+                        inst.ip()
+                    )));
+                }
+            };
+            if let Some(term) = term {
+                return Ok(ForeignBlock { end, term });
             }
-
-            if !seg.vaddrs.contains(&vaddr) {
-                // The next instruction is outside of the current segment. Switch segment and make
-                // a new decoder for it.
-                seg = CODE_SEGS.seg(vaddr);
-                let seg_start_u64 = u64::try_from(seg.vaddrs.start).unwrap();
-                dis = iced_x86::Decoder::with_ip(64, seg.slice, seg_start_u64, 0);
-                dis.set_ip(u64::try_from(vaddr).unwrap());
-                reposition = true;
+            // Straight-line code may run into code with blockmap info, or into another segment.
+            if !seg.vaddrs.contains(&end) || self.native_block_at(end)?.is_some() {
+                return Ok(ForeignBlock {
+                    end,
+                    term: Terminator::FallThrough,
+                });
             }
+        }
+    }
 
-            if reposition {
-                dis.set_position(vaddr - seg.vaddrs.start)
-                    .map_err(|_| HWTracerError::DisasmFail("failed to reposition".to_owned()))?;
-                reposition = false;
+    /// Is `vaddr` the start of `longjmp()` or one of its variants?
+    fn is_longjmp(&self, vaddr: usize) -> bool {
+        let vaddr = u64::try_from(vaddr).unwrap();
+        vaddr != 0 && self.longjmps.contains(&vaddr)
+    }
+
+    fn disassemble(&mut self, start_vaddr: usize) -> Result<Block, HWTracerError> {
+        let mut vaddr = start_vaddr;
+        loop {
+            if let Some((loc, block)) = self.native_block_at(vaddr)? {
+                // We are back to "native code" and can resume compiler-assisted decoding.
+                self.cur_loc = loc;
+                return Ok(block);
             }
 
-            let inst = dis.decode();
-            match inst.flow_control() {
-                iced_x86::FlowControl::Next => (),
-                iced_x86::FlowControl::Return => {
-                    // We don't expect to see any 16-bit far returns.
-                    debug_assert!(is_ret_near(&inst));
+            if self.is_longjmp(vaddr) && !mem::take(&mut self.longjmp_reported) {
+                // Control won't return from `longjmp()`, so report it before decoding where it
+                // goes. Decoding resumes here (without reporting it again) on the next call to
+                // `do_next()`.
+                self.longjmp_reported = true;
+                self.cur_loc = ObjLoc::OtherObjOrUnknown(Some(vaddr));
+                // Disassembly of foreign code expects an unknown block to be pending.
+                self.after_interrupt = Some(Block::new_unknown());
+                return Ok(Block::Longjmp);
+            }
 
+            let fb = match self.foreign.get(vaddr) {
+                Some(fb) => fb,
+                None => {
+                    let fb = self.decode_foreign_block(vaddr)?;
+                    self.foreign.insert(vaddr, fb);
+                    fb
+                }
+            };
+            vaddr = match fb.term {
+                Terminator::FallThrough => fb.end,
+                Terminator::Return => {
                     let ret_vaddr = if self.is_return_compressed()? {
                         // This unwrap cannot fail if the CPU correctly implements compressed
                         // returns.
                         match self.comprets.pop().unwrap() {
                             CompRetAddr::VAddr(vaddr) => vaddr,
-                            CompRetAddr::AfterCall(off) => {
-                                self.off_to_vaddr(&PHDR_MAIN_OBJ, off + 1)?
-                            }
+                            CompRetAddr::AfterCall(obj, off) => obj.vaddr(off + 1),
                         }
                     } else {
                         match self.cur_loc {
-                            ObjLoc::MainObj(off) => self.off_to_vaddr(&PHDR_MAIN_OBJ, off)?,
+                            ObjLoc::YkObj(obj, off) => obj.vaddr(off),
                             ObjLoc::OtherObjOrUnknown(opt_vaddr) => match opt_vaddr {
                                 Some(vaddr) => vaddr,
                                 None => unreachable!(),
                             },
                         }
                     };
-                    dis.set_ip(u64::try_from(ret_vaddr).unwrap());
-                    reposition = true;
                     self.update_stack_adjust(-1);
+                    ret_vaddr
                 }
-                iced_x86::FlowControl::IndirectBranch | iced_x86::FlowControl::IndirectCall => {
+                Terminator::IndirectBranch | Terminator::IndirectCall => {
                     self.seek_tip()?;
-                    let vaddr = match self.cur_loc {
-                        ObjLoc::MainObj(off) => self.off_to_vaddr(&PHDR_MAIN_OBJ, off)?,
+                    let target_vaddr = match self.cur_loc {
+                        ObjLoc::YkObj(obj, off) => obj.vaddr(off),
                         ObjLoc::OtherObjOrUnknown(opt_vaddr) => match opt_vaddr {
                             Some(vaddr) => vaddr,
                             None => unreachable!(),
                         },
                     };
 
-                    if inst.flow_control() == iced_x86::FlowControl::IndirectCall {
-                        if usize::try_from(inst.next_ip()).unwrap() == vaddr {
-                            todo!("zero length call");
+                    if fb.term == Terminator::IndirectCall {
+                        if fb.end == target_vaddr {
+                            return Err(HWTracerError::DisasmFail(format!(
+                                "zero length call to 0x{target_vaddr:x}"
+                            )));
                         }
-                        debug_assert!(!inst.is_call_far());
-                        self.comprets
-                            .push(CompRetAddr::VAddr(usize::try_from(inst.next_ip()).unwrap()));
+                        self.comprets.push(CompRetAddr::VAddr(fb.end));
                         self.update_stack_adjust(1);
                     }
-
-                    dis.set_ip(u64::try_from(vaddr).unwrap());
-                    reposition = true;
+                    target_vaddr
                 }
-                iced_x86::FlowControl::ConditionalBranch => {
+                Terminator::Conditional { target } => {
                     // Ensure we have TNT decisions buffered.
                     if self.tnts.is_empty() {
                         self.seek_tnt()?;
                     }
                     // unwrap() cannot fail as the above code ensures we have decisions buffered.
                     if self.tnts.pop_front().unwrap() {
-                        dis.set_ip(self.branch_target_vaddr(&inst));
-                        reposition = true;
+                        target
+                    } else {
+                        fb.end
                     }
                 }
-                iced_x86::FlowControl::UnconditionalBranch => {
-                    dis.set_ip(self.branch_target_vaddr(&inst));
-                    reposition = true;
-                }
-                iced_x86::FlowControl::Call => {
-                    if inst.code() == iced_x86::Code::Syscall {
-                        // Do nothing. We have disabled kernel tracing in hwtracer, so
-                        // entering/leaving a syscall will generate packet generation
-                        // disable/enable events (`TIP.PGD`/`TIP.PGE` packets) which are handled by
-                        // the decoder elsewhere.
-                    } else {
-                        let target_vaddr = self.branch_target_vaddr(&inst);
-
-                        // We can't (yet) handle longjmp in unmapped code. Crash if we spot it.
-                        if (longjmp_vaddr != 0 && target_vaddr == longjmp_vaddr)
-                            || (us_longjmp_vaddr != 0 && target_vaddr == us_longjmp_vaddr)
-                            || (siglongjmp_vaddr == 0 && target_vaddr == siglongjmp_vaddr)
-                        {
-                            panic!("encountered call to longjmp in unmapped code");
-                        }
-
-                        // Intel PT doesn't compress a call to the next address in the instruction
-                        // stream because such calls are unlikely to be convergent (i.e. they are
-                        // unlikely to ever return).
-                        if target_vaddr != inst.next_ip() {
-                            self.comprets
-                                .push(CompRetAddr::VAddr(usize::try_from(inst.next_ip()).unwrap()));
-                        }
-                        // We don't expect to see any 16-bit mode far calls in modernity.
-                        debug_assert!(!inst.is_call_far());
-                        dis.set_ip(target_vaddr);
-                        reposition = true;
-                        self.update_stack_adjust(1);
+                Terminator::Unconditional { target } => target,
+                Terminator::Call { target } => {
+                    // Intel PT doesn't compress a call to the next address in the instruction
+                    // stream because such calls are unlikely to be convergent (i.e. they are
+                    // unlikely to ever return).
+                    if target != fb.end {
+                        self.comprets.push(CompRetAddr::VAddr(fb.end));
                     }
+                    self.update_stack_adjust(1);
+                    target
                 }
-                _ => todo!(),
-            }
+            };
         }
     }
 
@@ -661,7 +909,7 @@ impl<'t> YkPTBlockIterator<'t> {
                         // The above `seek_tip()` ensures this can't happen!
                         unreachable!();
                     }
-                    ObjLoc::MainObj(off) => self.off_to_vaddr(&PHDR_MAIN_OBJ, off)?,
+                    ObjLoc::YkObj(obj, off) => obj.vaddr(off),
                 }
             }
         };
@@ -701,59 +949,36 @@ impl<'t> YkPTBlockIterator<'t> {
         }
     }
 
-    /// Skip packets up until and including the next `PSBEND` packet. The first packet after the
-    /// `PSBEND` is returned.
-    fn skip_psb_plus(&mut self) -> Result<Packet, HWTracerError> {
+    /// Skip packets up until and including the next `PSBEND` packet.
+    fn skip_psb_plus(&mut self) -> Result<(), HWTracerError> {
         loop {
             if let Some(pkt_or_err) = self.parser.next() {
                 if pkt_or_err?.kind() == PacketKind::PSBEND {
-                    break;
+                    return Ok(());
                 }
             } else {
                 return Err(HWTracerError::NoMorePackets);
             }
         }
-
-        if let Some(pkt_or_err) = self.parser.next() {
-            pkt_or_err
-        } else {
-            Err(HWTracerError::NoMorePackets)
-        }
     }
 
-    /// Fetch the next packet and update iterator state.
-    fn packet(&mut self) -> Result<Packet, HWTracerError> {
-        if let Some(pkt_or_err) = self.parser.next() {
-            let mut pkt = pkt_or_err?;
+    /// Fetch the next packet from the parser, skipping over PSB+ sequences.
+    fn next_packet(&mut self) -> Result<Packet, HWTracerError> {
+        loop {
+            let off = self.parser.byte_off();
+            let pkt = match self.parser.next() {
+                Some(pkt_or_err) => pkt_or_err?,
+                None => return Err(HWTracerError::NoMorePackets),
+            };
 
             if pkt.kind() == PacketKind::OVF {
-                return Err(HWTracerError::HWBufferOverflow);
-            }
-
-            if pkt.kind() == PacketKind::FUP && self.pge && !self.unbound_modes {
-                // FIXME: https://github.com/ykjit/yk/issues/593
-                //
-                // A FUP packet when there are no outstanding MODE packets indicates that
-                // regular control flow was interrupted by an asynchronous event (e.g. a signal
-                // handler or a context switch). For now we only support the simple case where
-                // execution jumps off to some untraceable foreign code for a while, before
-                // returning and resuming where we left off. This is characterised by a [FUP,
-                // TIP.PGD, TIP.PGE] sequence (with no intermediate TIP or TNT packets). In
-                // this case we can simply ignore the interruption. Later we need to support
-                // FUPs more generally.
-                pkt = self.seek_tnt_or_tip()?;
-                if pkt.kind() != PacketKind::TIPPGD {
-                    return Err(HWTracerError::TraceInterrupted);
-                }
-                pkt = self.seek_tnt_or_tip()?;
-                if pkt.kind() != PacketKind::TIPPGE {
-                    return Err(HWTracerError::TraceInterrupted);
-                }
-                if let Some(pkt_or_err) = self.parser.next() {
-                    pkt = pkt_or_err?;
-                } else {
-                    return Err(HWTracerError::NoMorePackets);
+                if !self.recover_overflows {
+                    return Err(HWTracerError::HWBufferOverflow);
                 }
+                // Abandon the current decoding step. `do_next()` picks up where `resync()` left
+                // off.
+                self.interrupt = Some(self.resync(off)?);
+                return Err(HWTracerError::TraceInterrupted);
             }
 
             // Section 33.3.7 of the Intel Manual says that packets in a PSB+ sequence:
@@ -766,7 +991,7 @@ impl<'t> YkPTBlockIterator<'t> {
             // So we don't let (e.g.) packets carrying a target ip inside a PSB+ update
             // `self.cur_loc`.
             if pkt.kind() == PacketKind::PSB {
-                pkt = self.skip_psb_plus()?;
+                self.skip_psb_plus()?;
 
                 // FIXME: Why does clearing the compressed return stack here (as we should) cause
                 // non-deterministic crashes?
@@ -782,6 +1007,64 @@ impl<'t> YkPTBlockIterator<'t> {
                 //   self.comprets.rets.clear();
                 //
                 // will causes us to to (sometimes) pop from an empty return stack.
+                continue;
+            }
+
+            return Ok(pkt);
+        }
+    }
+
+    /// Recover from trace data lost at byte offset `off` of the trace, by resynchronising with
+    /// the packet stream at the next PSB packet.
+    ///
+    /// Unlike other PSB+ sequences, the one we resynchronise at isn't "status only": its FUP
+    /// packet (if any) tells us where execution is. If there's no FUP packet, then packet
+    /// generation is disabled and the next `TIP.PGE` packet will tell us where execution is.
+    fn resync(&mut self, off: usize) -> Result<Interrupt, HWTracerError> {
+        self.unbound_modes = false;
+        self.tsx_abort = false;
+        self.bound_fup = false;
+
+        let synced = self.parser.sync_to_psb();
+        let skipped = self.parser.byte_off() - off;
+        let mut resume = None;
+        if synced {
+            loop {
+                let pkt = match self.parser.next() {
+                    Some(pkt_or_err) => pkt_or_err?,
+                    None => return Err(HWTracerError::NoMorePackets),
+                };
+                match pkt.kind() {
+                    PacketKind::FUP => resume = pkt.target_ip(),
+                    PacketKind::PSBEND => break,
+                    _ => (),
+                }
+            }
+        }
+        self.pge = resume.is_some();
+
+        Ok(Interrupt::Lost {
+            off,
+            skipped,
+            resume,
+        })
+    }
+
+    /// Fetch the next packet and update iterator state.
+    ///
+    /// FUP packets are dealt with here and never returned.
+    fn packet(&mut self) -> Result<Packet, HWTracerError> {
+        loop {
+            let pkt = self.next_packet()?;
+
+            if pkt.kind() == PacketKind::FUP {
+                self.fup(&pkt)?;
+                continue;
+            }
+
+            // Remember if the next FUP packet binds to this one.
+            if pkt.binds_fup() {
+                self.bound_fup = true;
             }
 
             // Update `self.pge` if necessary.
@@ -796,9 +1079,11 @@ impl<'t> YkPTBlockIterator<'t> {
             // If it's a MODE packet, remember we've seen it. The meaning of TIP and FUP packets
             // vary depending upon if they were preceded by MODE packets.
             if pkt.kind().is_mode() {
-                // This whole codebase assumes 64-bit mode.
-                if let Packet::MODEExec(ref mep) = pkt {
-                    debug_assert_eq!(mep.bitness(), Bitness::Bits64);
+                match pkt {
+                    // This whole codebase assumes 64-bit mode.
+                    Packet::MODEExec(ref mep) => debug_assert_eq!(mep.bitness(), Bitness::Bits64),
+                    Packet::MODETSX(ref mtp) => self.tsx_abort |= mtp.is_abort(),
+                    _ => (),
                 }
                 self.unbound_modes = true;
             }
@@ -806,15 +1091,13 @@ impl<'t> YkPTBlockIterator<'t> {
             // Does this packet bind to prior MODE packets? If so, it "consumes" the packet.
             if pkt.kind().encodes_target_ip() && self.unbound_modes {
                 self.unbound_modes = false;
+                self.tsx_abort = false;
             }
 
             // Update `self.target_ip` if necessary.
             if let Some(vaddr) = pkt.target_ip() {
                 if self.pge {
-                    self.cur_loc = match self.vaddr_to_off(vaddr)? {
-                        (obj, off) if obj == *SELF_BIN_PATH => ObjLoc::MainObj(off),
-                        _ => ObjLoc::OtherObjOrUnknown(Some(vaddr)),
-                    };
+                    self.cur_loc = self.aspace.vaddr_to_objloc(vaddr)?;
                 }
             }
 
@@ -823,11 +1106,144 @@ impl<'t> YkPTBlockIterator<'t> {
                 self.tnts.extend(bits);
             }
 
-            Ok(pkt)
+            return Ok(pkt);
+        }
+    }
+
+    /// Deal with the FUP packet `pkt`.
+    ///
+    /// A FUP packet either binds to an earlier packet (in which case it tells us nothing about
+    /// control flow), or it gives the source IP of an asynchronous event, such as an interrupt or a
+    /// transactional abort. The latter are followed by a packet telling us where execution resumed
+    /// (with a `TIP.PGD` in between if the event was handled by untraced code, e.g. the kernel).
+    ///
+    /// If execution resumed where it was interrupted, the interruption is invisible to the traced
+    /// code and we carry on decoding. If the interruption ran traced code (e.g. a signal handler),
+    /// then we skip the packets for that code and record the interruption in `self.interrupt`.
+    ///
+    /// If execution never returns to the interrupted code, the current decoding step is abandoned
+    /// by returning `Err(HWTracerError::TraceInterrupted)`, and `self.interrupt` records where
+    /// decoding should resume.
+    fn fup(&mut self, pkt: &Packet) -> Result<(), HWTracerError> {
+        if self.bound_fup {
+            // e.g. a FUP giving the IP of a PTWRITE instruction.
+            self.bound_fup = false;
+            return Ok(());
+        }
+
+        if self.unbound_modes {
+            self.unbound_modes = false;
+            if self.tsx_abort {
+                // The transaction was aborted. Execution continues in the abort handler and never
+                // returns to the aborted code.
+                self.tsx_abort = false;
+                let dst = self.async_resume_target()?;
+                self.interrupt = Some(Interrupt::Diverted(dst));
+                return Err(HWTracerError::TraceInterrupted);
+            }
+            // Otherwise the FUP just reports the IP at which a transaction started or committed.
+            return Ok(());
+        }
+
+        if !self.pge {
+            return Ok(());
+        }
+
+        let src = pkt.target_ip().ok_or(HWTracerError::TraceInterrupted)?;
+        let dst = self.async_resume_target()?;
+        if dst == src {
+            // e.g. a context switch. The traced code is none the wiser.
+            return Ok(());
+        }
+
+        if let Some(parser) = self.find_resumption(src) {
+            // e.g. a signal handler which returned.
+            self.parser = parser;
+            self.interrupt.get_or_insert(Interrupt::Resumed);
+            Ok(())
         } else {
-            Err(HWTracerError::NoMorePackets)
+            // e.g. a signal handler which didn't return (perhaps by calling `siglongjmp()`).
+            self.interrupt = Some(Interrupt::Diverted(dst));
+            Err(HWTracerError::TraceInterrupted)
+        }
+    }
+
+    /// After the FUP packet of an asynchronous event, find the virtual address at which execution
+    /// resumed.
+    fn async_resume_target(&mut self) -> Result<usize, HWTracerError> {
+        loop {
+            let pkt = self.next_packet()?;
+            match pkt.kind() {
+                PacketKind::TIPPGD => self.pge = false,
+                PacketKind::TIPPGE | PacketKind::TIP => {
+                    self.pge = true;
+                    self.unbound_modes = false;
+                    self.tsx_abort = false;
+                    return pkt.target_ip().ok_or(HWTracerError::TraceInterrupted);
+                }
+                PacketKind::ShortTNT | PacketKind::LongTNT => {
+                    // Branches can't be taken before execution resumes.
+                    return Err(HWTracerError::TraceInterrupted);
+                }
+                _ => {
+                    if pkt.kind().is_mode() {
+                        self.unbound_modes = true;
+                    }
+                }
+            }
         }
     }
+
+    /// Look ahead in the packet stream for a `TIP.PGE` packet that resumes execution at `src`, the
+    /// source IP of an asynchronous event. Returns a parser positioned just after that packet, or
+    /// `None` if execution never resumes at `src`.
+    ///
+    /// The lookahead doesn't decode the intervening packets, but it does keep track of further
+    /// (nested) asynchronous events, so that their resumptions aren't mistaken for ours.
+    fn find_resumption(&self, src: usize) -> Option<PacketParser<'t>> {
+        let mut parser = self.parser.clone();
+        let mut srcs = vec![src];
+        let mut pge = true;
+        let mut in_psb_plus = false;
+        let mut unbound_modes = false;
+        let mut bound_fup = false;
+        while let Some(Ok(pkt)) = parser.next() {
+            match pkt.kind() {
+                // We can't see past lost trace data.
+                PacketKind::OVF => return None,
+                PacketKind::PSB => in_psb_plus = true,
+                PacketKind::PSBEND => in_psb_plus = false,
+                _ if in_psb_plus => (),
+                PacketKind::FUP => {
+                    if !bound_fup && !unbound_modes && pge {
+                        srcs.push(pkt.target_ip()?);
+                    }
+                    bound_fup = false;
+                    unbound_modes = false;
+                }
+                PacketKind::TIPPGE => {
+                    pge = true;
+                    unbound_modes = false;
+                    if pkt.target_ip() == srcs.last().copied() {
+                        srcs.pop();
+                        if srcs.is_empty() {
+                            return Some(parser);
+                        }
+                    }
+                }
+                PacketKind::TIPPGD => pge = false,
+                kind => {
+                    if kind.is_mode() {
+                        unbound_modes = true;
+                    } else if kind.encodes_target_ip() {
+                        unbound_modes = false;
+                    }
+                    bound_fup |= pkt.binds_fup();
+                }
+            }
+        }
+        None
+    }
 }
 
 impl<'t> Iterator for YkPTBlockIterator<'t> {
@@ -877,10 +1293,16 @@ fn is_ret_near(inst: &iced_x86::Instruction) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use super::YkPTTraceDecoder;
     use crate::{
+        capture::{CAPTURE_MAGIC, CAPTURE_VERSION, LONGJMP_FUNCS},
         collect::default_tracer_for_platform,
-        decode::{test_helpers, TraceDecoderKind},
+        decode::{test_helpers, TraceDecoder, TraceDecoderKind},
+        llvm_blockmap::BLOCKMAP_VERSION,
+        Block, HWTracerError, TraceCapture,
     };
+    use byteorder::{LittleEndian, NativeEndian, WriteBytesExt};
+    use std::io::{Cursor, Write};
 
     #[ignore] // FIXME
     #[test]
@@ -888,4 +1310,449 @@ mod tests {
         let tc = default_tracer_for_platform().unwrap();
         test_helpers::ten_times_as_many_blocks(tc, TraceDecoderKind::YkPT);
     }
+
+    /// Build a capture file, as described in `crate::capture`, for a made-up process. `objs` are
+    /// `(is_main, base, code, blockmap)` tuples, each object having a single (executable) segment
+    /// containing `code` at `base`, and (if `blockmap` isn't empty) having been built with ykllvm.
+    fn mk_capture(objs: &[(bool, u64, &[u8], &[u8])], trace: &[u8]) -> TraceCapture {
+        mk_capture_with_longjmps(objs, [0; LONGJMP_FUNCS.len()], trace)
+    }
+
+    /// Like [mk_capture], but with `longjmp()` and its variants at the addresses `longjmps`.
+    fn mk_capture_with_longjmps(
+        objs: &[(bool, u64, &[u8], &[u8])],
+        longjmps: [u64; LONGJMP_FUNCS.len()],
+        trace: &[u8],
+    ) -> TraceCapture {
+        let mut w = Vec::new();
+        w.write_all(CAPTURE_MAGIC).unwrap();
+        w.write_u32::<LittleEndian>(CAPTURE_VERSION).unwrap();
+        w.write_u32::<LittleEndian>(u32::try_from(objs.len()).unwrap())
+            .unwrap();
+        for (is_main, base, code, blockmap) in objs {
+            w.write_u8(u8::from(*is_main)).unwrap();
+            w.write_u32::<LittleEndian>(0).unwrap();
+            w.write_u64::<LittleEndian>(*base).unwrap();
+            w.write_u32::<LittleEndian>(1).unwrap();
+            w.write_u64::<LittleEndian>(*base).unwrap();
+            w.write_u64::<LittleEndian>(u64::try_from(code.len()).unwrap())
+                .unwrap();
+            w.write_u8(1).unwrap();
+            w.write_all(code).unwrap();
+            w.write_u64::<LittleEndian>(u64::try_from(blockmap.len()).unwrap())
+                .unwrap();
+            w.write_all(blockmap).unwrap();
+        }
+        for lj in longjmps {
+            w.write_u64::<LittleEndian>(lj).unwrap();
+        }
+        w.write_u64::<LittleEndian>(u64::try_from(trace.len()).unwrap())
+            .unwrap();
+        w.write_all(trace).unwrap();
+        TraceCapture::read_from(&mut Cursor::new(w)).unwrap()
+    }
+
+    /// The start of a blockmap record for a function at offset `off` consisting of `n_blks` blocks.
+    /// The blocks themselves must be appended.
+    fn blockmap_record(off: u64, n_blks: u8) -> Vec<u8> {
+        let mut blockmap = vec![BLOCKMAP_VERSION, 0];
+        blockmap.write_u64::<NativeEndian>(off).unwrap();
+        blockmap.push(n_blks);
+        blockmap
+    }
+
+    /// A blockmap for a function at offset `off` consisting of a single 4-byte block which returns.
+    fn ret_fn_blockmap(off: u64) -> Vec<u8> {
+        let mut blockmap = blockmap_record(off, 1);
+        blockmap.extend([0, 4, 0, 0, 0, 2]);
+        blockmap
+    }
+
+    /// A PSB+ sequence followed by a `TIP.PGE` packet to `vaddr`.
+    fn mk_trace(vaddr: u64) -> Vec<u8> {
+        let mut t = Vec::new();
+        for _ in 0..8 {
+            t.extend([0x02, 0x82]); // PSB
+        }
+        t.extend([0x02, 0x23]); // PSBEND
+        t.push(0x71); // TIP.PGE with a 6-byte sign-extended IP.
+        t.extend(&vaddr.to_le_bytes()[..6]);
+        t
+    }
+
+    /// Check that a captured trace is decoded using only the captured address space: the addresses
+    /// involved aren't mapped in this process.
+    #[test]
+    fn decode_captured_trace() {
+        // A function at offset 0x8 in the main object.
+        let blockmap = ret_fn_blockmap(0x8);
+        // A shared object whose code jumps to the function in the main object.
+        let so_base: u64 = 0x10_0000;
+        let rel = i32::try_from(0x1008 - i64::try_from(so_base + 5).unwrap()).unwrap();
+        let mut so_code = vec![0xe9]; // jmp rel32
+        so_code.extend(rel.to_le_bytes());
+
+        let cap = mk_capture(
+            &[
+                (true, 0x1000, &[0x90; 0x10][..], &blockmap[..]),
+                (false, so_base, &so_code[..], &[][..]),
+            ],
+            &mk_trace(so_base),
+        );
+        let dec = YkPTTraceDecoder::new();
+        let blocks = dec
+            .iter_blocks(&cap)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(blocks, vec![Block::from_vaddr_range(0x1008, 0x100c)]);
+    }
+
+    /// Check that code in a shared object built with ykllvm is decoded with compiler assistance.
+    #[test]
+    fn decode_captured_trace_ykllvm_so() {
+        // A shared object with a function at offset 0x8.
+        let so_base: u64 = 0x10_0000;
+        let blockmap = ret_fn_blockmap(0x8);
+        // A main object (not built with ykllvm) whose code jumps to the function.
+        let rel = i32::try_from(i64::try_from(so_base + 0x8).unwrap() - (0x1000 + 5)).unwrap();
+        let mut main_code = vec![0xe9]; // jmp rel32
+        main_code.extend(rel.to_le_bytes());
+
+        let cap = mk_capture(
+            &[
+                (true, 0x1000, &main_code[..], &[][..]),
+                (false, so_base, &[0x90; 0x10][..], &blockmap[..]),
+            ],
+            &mk_trace(0x1000),
+        );
+        let dec = YkPTTraceDecoder::new();
+        let blocks = dec
+            .iter_blocks(&cap)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            vec![Block::from_vaddr_range(so_base + 0x8, so_base + 0xc)]
+        );
+    }
+
+    /// Check that foreign code executed repeatedly (and thus decoded from the cache of disassembled
+    /// code after the first time) is followed correctly.
+    #[test]
+    fn decode_captured_trace_foreign_loop() {
+        // A shared object with a function at offset 0x8.
+        let so_base: u64 = 0x10_0000;
+        let blockmap = ret_fn_blockmap(0x8);
+        // A main object (not built with ykllvm) which loops before jumping to the function.
+        let mut main_code = vec![0x90, 0x75, 0xfd]; // nop; jnz -3
+        let rel = i32::try_from(i64::try_from(so_base + 0x8).unwrap() - (0x1003 + 5)).unwrap();
+        main_code.push(0xe9); // jmp rel32
+        main_code.extend(rel.to_le_bytes());
+
+        let mut trace = mk_trace(0x1000);
+        trace.push(0x1c); // TNT: taken, taken, not taken.
+        let cap = mk_capture(
+            &[
+                (true, 0x1000, &main_code[..], &[][..]),
+                (false, so_base, &[0x90; 0x10][..], &blockmap[..]),
+            ],
+            &trace,
+        );
+        let dec = YkPTTraceDecoder::new();
+        let blocks = dec
+            .iter_blocks(&cap)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            vec![Block::from_vaddr_range(so_base + 0x8, so_base + 0xc)]
+        );
+    }
+
+    /// Check that a call to `longjmp()` is reported, and that decoding continues with wherever it
+    /// jumped to.
+    #[test]
+    fn decode_captured_trace_longjmp() {
+        // A function at offset 0x8 in the main object.
+        let blockmap = ret_fn_blockmap(0x8);
+        // A shared object whose code calls a `longjmp()` (at offset 0x10) which jumps to the
+        // function in the main object.
+        let so_base: u64 = 0x10_0000;
+        let mut so_code = vec![0xe8]; // call rel32
+        so_code.extend(0xbi32.to_le_bytes());
+        so_code.resize(0x10, 0x90);
+        let rel = i32::try_from(0x1008 - i64::try_from(so_base + 0x15).unwrap()).unwrap();
+        so_code.push(0xe9); // jmp rel32
+        so_code.extend(rel.to_le_bytes());
+
+        let cap = mk_capture_with_longjmps(
+            &[
+                (true, 0x1000, &[0x90; 0x10][..], &blockmap[..]),
+                (false, so_base, &so_code[..], &[][..]),
+            ],
+            [0, so_base + 0x10, 0, 0],
+            &mk_trace(so_base),
+        );
+        let dec = YkPTTraceDecoder::new();
+        let blocks = dec
+            .iter_blocks(&cap)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            vec![
+                Block::Longjmp,
+                Block::new_unknown(),
+                Block::from_vaddr_range(0x1008, 0x100c)
+            ]
+        );
+    }
+
+    /// Check that a blockmap which can't be parsed is reported as a decoding error.
+    #[test]
+    fn decode_captured_trace_bad_blockmap() {
+        let blockmap = [BLOCKMAP_VERSION + 1, 0];
+        let cap = mk_capture(
+            &[(true, 0x1000, &[0x90; 0x10][..], &blockmap[..])],
+            &mk_trace(0x1008),
+        );
+        let dec = YkPTTraceDecoder::new();
+        let err = dec.iter_blocks(&cap).find_map(Result::err).unwrap();
+        assert!(err.to_string().contains("version"));
+    }
+
+    #[test]
+    fn decode_captured_trace_outside_capture() {
+        let cap = mk_capture(
+            &[(true, 0x1000, &[0x90; 0x10][..], &[][..])],
+            &mk_trace(0x5000),
+        );
+        let dec = YkPTTraceDecoder::new();
+        assert!(matches!(dec.iter_blocks(&cap).next(), Some(Err(_))));
+    }
+
+    /// Decode a trace of a made-up main object whose block `A` (0x1008..0x100c) conditionally
+    /// branches to `B` (0x1010..0x1014, taken) or `C` (0x1018..0x101c, not taken), both of which
+    /// return. Tracing starts at `A` and continues with the packets `pkts`.
+    fn decode_branchy(pkts: &[u8]) -> Vec<Block> {
+        try_decode_branchy(pkts, false).unwrap()
+    }
+
+    /// Like `decode_branchy()`, but optionally recovering from lost trace data, and returning any
+    /// error.
+    fn try_decode_branchy(
+        pkts: &[u8],
+        recover_overflows: bool,
+    ) -> Result<Vec<Block>, HWTracerError> {
+        let mut blockmap = blockmap_record(0x8, 3);
+        blockmap.extend([0, 4, 0, 0, 0, 1]);
+        blockmap.write_u64::<NativeEndian>(0x10).unwrap();
+        blockmap.write_u64::<NativeEndian>(0x18).unwrap();
+        blockmap.extend([4, 4, 0, 0, 0, 2]);
+        blockmap.extend([4, 4, 0, 0, 0, 2]);
+
+        let mut trace = mk_trace(0x1008);
+        trace.extend(pkts);
+        let cap = mk_capture(&[(true, 0x1000, &[0x90; 0x20][..], &blockmap[..])], &trace);
+        let dec = YkPTTraceDecoder::with_overflow_recovery(recover_overflows);
+        let blocks = dec.iter_blocks(&cap).collect::<Result<Vec<_>, _>>();
+        blocks
+    }
+
+    /// An IP packet (a FUP, TIP or `TIP.PGE` depending on `magic`) with a 6-byte sign-extended IP.
+    fn ip_pkt(magic: u8, vaddr: u64) -> Vec<u8> {
+        let mut pkt = vec![0x60 | magic];
+        pkt.extend(&vaddr.to_le_bytes()[..6]);
+        pkt
+    }
+
+    const FUP: u8 = 0x1d;
+    const TIP: u8 = 0x0d;
+    const TIPPGE: u8 = 0x11;
+    const TIPPGD: u8 = 0x01;
+    const TAKEN: u8 = 0x06;
+    const NOT_TAKEN: u8 = 0x04;
+
+    fn blk_a() -> Block {
+        Block::from_vaddr_range(0x1008, 0x100c)
+    }
+
+    fn blk_b() -> Block {
+        Block::from_vaddr_range(0x1010, 0x1014)
+    }
+
+    fn blk_c() -> Block {
+        Block::from_vaddr_range(0x1018, 0x101c)
+    }
+
+    #[test]
+    fn uninterrupted() {
+        assert_eq!(decode_branchy(&[TAKEN]), vec![blk_a(), blk_b()]);
+        assert_eq!(decode_branchy(&[NOT_TAKEN]), vec![blk_a(), blk_c()]);
+    }
+
+    /// An interruption which doesn't run any traced code (e.g. a context switch) is invisible.
+    #[test]
+    fn interrupt_resumes_in_place() {
+        let mut pkts = ip_pkt(FUP, 0x100a);
+        pkts.push(TIPPGD);
+        pkts.extend(ip_pkt(TIPPGE, 0x100a));
+        pkts.push(TAKEN);
+        assert_eq!(decode_branchy(&pkts), vec![blk_a(), blk_b()]);
+    }
+
+    /// An interruption which runs traced code (e.g. a signal handler), itself interrupted, before
+    /// resuming where it left off.
+    #[test]
+    fn interrupt_runs_handler() {
+        let mut pkts = ip_pkt(FUP, 0x100a);
+        pkts.push(TIPPGD);
+        pkts.extend(ip_pkt(TIPPGE, 0x1018));
+        pkts.push(TAKEN);
+        pkts.extend(ip_pkt(FUP, 0x1019));
+        pkts.push(TIPPGD);
+        pkts.extend(ip_pkt(TIPPGE, 0x1019));
+        pkts.push(TIPPGD);
+        pkts.extend(ip_pkt(TIPPGE, 0x100a));
+        pkts.push(NOT_TAKEN);
+        assert_eq!(
+            decode_branchy(&pkts),
+            vec![blk_a(), Block::Interrupted { resumed: true }, blk_c()]
+        );
+    }
+
+    /// An interruption which runs traced code (e.g. a signal handler) that never returns.
+    #[test]
+    fn interrupt_never_returns() {
+        let mut pkts = ip_pkt(FUP, 0x100a);
+        pkts.push(TIPPGD);
+        pkts.extend(ip_pkt(TIPPGE, 0x1018));
+        assert_eq!(
+            decode_branchy(&pkts),
+            vec![blk_a(), Block::Interrupted { resumed: false }, blk_c()]
+        );
+    }
+
+    /// A transactional abort diverts control flow to the abort handler.
+    #[test]
+    fn interrupt_tsx_abort() {
+        let mut pkts = vec![0x99, 0x22]; // MODE.TSX with TXAbort set.
+        pkts.extend(ip_pkt(FUP, 0x100a));
+        pkts.extend(ip_pkt(TIP, 0x1018));
+        assert_eq!(
+            decode_branchy(&pkts),
+            vec![blk_a(), Block::Interrupted { resumed: false }, blk_c()]
+        );
+    }
+
+    /// A FUP bound to a `PTWRITE` packet doesn't signal an interruption.
+    #[test]
+    fn bound_fup() {
+        let mut pkts = vec![0x02, 0x92, 0xff, 0xff, 0xff, 0xff]; // PTWRITE with IP set.
+        pkts.extend(ip_pkt(FUP, 0x100a));
+        pkts.push(TAKEN);
+        assert_eq!(decode_branchy(&pkts), vec![blk_a(), blk_b()]);
+    }
+
+    /// Without recovery, lost trace data is an error.
+    #[test]
+    fn overflow() {
+        assert!(matches!(
+            try_decode_branchy(&[0x02, 0xf3, TAKEN], false),
+            Err(HWTracerError::HWBufferOverflow)
+        ));
+    }
+
+    /// With recovery, the decoder resynchronises at the next PSB packet, whose FUP packet tells us
+    /// where execution is.
+    #[test]
+    fn overflow_recovery() {
+        let mut pkts = vec![0x02, 0xf3, TAKEN]; // OVF at offset 25, then a packet we can't use.
+        pkts.extend([0x02, 0x82].repeat(8)); // PSB at offset 28.
+        pkts.extend(ip_pkt(FUP, 0x1018));
+        pkts.extend([0x02, 0x23]); // PSBEND
+        assert_eq!(
+            try_decode_branchy(&pkts, true).unwrap(),
+            vec![
+                blk_a(),
+                Block::Gap {
+                    off: 25,
+                    skipped: 3
+                },
+                blk_c()
+            ]
+        );
+    }
+
+    /// With recovery, but nothing to resynchronise with.
+    #[test]
+    fn overflow_recovery_no_psb() {
+        assert_eq!(
+            try_decode_branchy(&[0x02, 0xf3, TAKEN], true).unwrap(),
+            vec![
+                blk_a(),
+                Block::Gap {
+                    off: 25,
+                    skipped: 3
+                },
+                Block::new_unknown()
+            ]
+        );
+    }
+
+    /// Decode a trace of a made-up main object whose block `A` (0x1008..0x100c) is left along a
+    /// divergent edge, either unconditionally or (if `cond`) when its conditional branch to `C`
+    /// (0x1018..0x101c) isn't taken. The bytes after `A` are block `B` (0x100c..0x1010). Both `B`
+    /// and `C` return. Tracing starts at `A` and continues with the packets `pkts`.
+    fn decode_divergent(cond: bool, pkts: &[u8]) -> Vec<Block> {
+        let mut blockmap = blockmap_record(0x8, 3);
+        if cond {
+            blockmap.extend([0, 4, 0, 0, 0, 1]);
+            blockmap.write_u64::<NativeEndian>(0x18).unwrap();
+        } else {
+            blockmap.extend([0, 4, 0, 0, 0, 0]);
+        }
+        blockmap.write_u64::<NativeEndian>(0).unwrap(); // Divergent.
+        blockmap.extend([0, 4, 0, 0, 0, 2]);
+        blockmap.extend([8, 4, 0, 0, 0, 2]);
+
+        let mut trace = mk_trace(0x1008);
+        trace.extend(pkts);
+        let cap = mk_capture(&[(true, 0x1000, &[0x90; 0x20][..], &blockmap[..])], &trace);
+        let dec = YkPTTraceDecoder::new();
+        let blocks = dec
+            .iter_blocks(&cap)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        blocks
+    }
+
+    /// Leaving a block along a divergent edge is reported, and decoding continues with whatever
+    /// code follows the block.
+    #[test]
+    fn divergent_unconditional() {
+        assert_eq!(
+            decode_divergent(false, &[]),
+            vec![
+                blk_a(),
+                Block::Divergent,
+                Block::new_unknown(),
+                Block::from_vaddr_range(0x100c, 0x1010)
+            ]
+        );
+    }
+
+    #[test]
+    fn divergent_not_taken() {
+        assert_eq!(decode_divergent(true, &[TAKEN]), vec![blk_a(), blk_c()]);
+        assert_eq!(
+            decode_divergent(true, &[NOT_TAKEN]),
+            vec![
+                blk_a(),
+                Block::Divergent,
+                Block::new_unknown(),
+                Block::from_vaddr_range(0x100c, 0x1010)
+            ]
+        );
+    }
 }
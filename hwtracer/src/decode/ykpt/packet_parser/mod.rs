@@ -37,22 +37,36 @@ impl PacketParserState {
                 PacketKind::FUP,
                 PacketKind::TIP,
                 PacketKind::CYC,
+                PacketKind::MTC,
                 PacketKind::LongTNT,
                 PacketKind::PSB,
                 PacketKind::MODEExec,
                 PacketKind::MODETSX,
+                PacketKind::TSC,
+                PacketKind::TMA,
                 PacketKind::CBR,
                 PacketKind::TIPPGE,
                 PacketKind::TIPPGD,
+                PacketKind::PTWRITE,
+                PacketKind::PIP,
+                PacketKind::VMCS,
                 PacketKind::EXSTOP,
+                PacketKind::PWRE,
+                PacketKind::PWRX,
+                PacketKind::MNT,
+                PacketKind::TraceStop,
                 PacketKind::OVF,
             ],
             Self::PSBPlus => &[
                 PacketKind::PAD,
                 PacketKind::CBR,
+                PacketKind::TSC,
+                PacketKind::TMA,
                 PacketKind::FUP,
                 PacketKind::MODEExec,
                 PacketKind::MODETSX,
+                PacketKind::PIP,
+                PacketKind::VMCS,
                 PacketKind::PSBEND,
                 PacketKind::OVF,
             ],
@@ -72,9 +86,12 @@ impl PacketParserState {
     }
 }
 
+#[derive(Clone)]
 pub(super) struct PacketParser<'t> {
-    /// The raw bytes of the PT trace we are iterating over. Stored as a `BitSlice` so that we can
-    /// use `deku` to parse bit-granularity fields out of packets.
+    /// The raw bytes of the whole PT trace.
+    bytes: &'t [u8],
+    /// The raw bytes of the PT trace we are yet to iterate over. Stored as a `BitSlice` so that we
+    /// can use `deku` to parse bit-granularity fields out of packets.
     bits: &'t BitSlice<u8, Msb0>,
     /// The parser operates as a state machine. This field keeps track of which state we are in.
     state: PacketParserState,
@@ -108,6 +125,7 @@ macro_rules! read_to_packet_tip {
 impl<'t> PacketParser<'t> {
     pub(super) fn new(bytes: &'t [u8]) -> Self {
         Self {
+            bytes,
             bits: BitSlice::from_slice(bytes),
             state: PacketParserState::Init,
             prev_tip: 0,
@@ -145,6 +163,16 @@ impl<'t> PacketParser<'t> {
             PacketKind::CYC => read_to_packet!(CYCPacket, self.bits, Packet::CYC),
             PacketKind::EXSTOP => read_to_packet!(EXSTOPPacket, self.bits, Packet::EXSTOP),
             PacketKind::OVF => read_to_packet!(OVFPacket, self.bits, Packet::OVF),
+            PacketKind::TSC => read_to_packet!(TSCPacket, self.bits, Packet::TSC),
+            PacketKind::MTC => read_to_packet!(MTCPacket, self.bits, Packet::MTC),
+            PacketKind::TMA => read_to_packet!(TMAPacket, self.bits, Packet::TMA),
+            PacketKind::PIP => read_to_packet!(PIPPacket, self.bits, Packet::PIP),
+            PacketKind::VMCS => read_to_packet!(VMCSPacket, self.bits, Packet::VMCS),
+            PacketKind::PTWRITE => read_to_packet!(PTWRITEPacket, self.bits, Packet::PTWRITE),
+            PacketKind::PWRE => read_to_packet!(PWREPacket, self.bits, Packet::PWRE),
+            PacketKind::PWRX => read_to_packet!(PWRXPacket, self.bits, Packet::PWRX),
+            PacketKind::MNT => read_to_packet!(MNTPacket, self.bits, Packet::MNT),
+            PacketKind::TraceStop => read_to_packet!(TraceStopPacket, self.bits, Packet::TraceStop),
         };
         if let Ok((remain, pkt)) = parse_res {
             self.bits = remain;
@@ -165,12 +193,40 @@ impl<'t> PacketParser<'t> {
             }
         }
         Err(HWTracerError::TraceParseError(format!(
-            "In state {:?}, failed to parse packet from bytes: {}",
+            "In state {:?}, failed to parse packet at byte offset {} from bytes: {}",
             self.state,
+            self.byte_off(),
             self.byte_stream_str(8, ", ")
         )))
     }
 
+    /// Returns the offset (in bytes) of the next packet from the start of the trace.
+    pub(super) fn byte_off(&self) -> usize {
+        self.bytes.len() - self.bits.len() / 8
+    }
+
+    /// Skip forward to the next PSB packet, the point at which a decoder can (re)synchronise with
+    /// the packet stream, and reset the parser's state accordingly. This is used to recover after
+    /// trace data was lost.
+    ///
+    /// Returns `false` (and skips to the end of the trace) if there is no such PSB packet.
+    pub(super) fn sync_to_psb(&mut self) -> bool {
+        // The bytes of a PSB packet (see `PSBPacket`).
+        const PSB: [u8; 16] = [
+            0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+            0x02, 0x82,
+        ];
+        let off = self.byte_off();
+        let sync_off = self.bytes[off..]
+            .windows(PSB.len())
+            .position(|w| w == PSB)
+            .map(|pos| off + pos);
+        self.bits = BitSlice::from_slice(&self.bytes[sync_off.unwrap_or(self.bytes.len())..]);
+        self.state = PacketParserState::Init;
+        self.prev_tip = 0;
+        sync_off.is_some()
+    }
+
     /// Returns a string showing a binary formatted peek at the next `nbytes` bytes of
     /// `self.bytes`. Bytes in the output are separated by `sep`.
     ///
@@ -232,6 +288,7 @@ mod tests {
     use super::{packets::*, PacketParser};
     use crate::{
         collect::{default_tracer_for_platform, test_helpers::trace_closure},
+        errors::HWTracerError,
         work_loop,
     };
 
@@ -324,4 +381,209 @@ mod tests {
             Some(0xffff887766554433)
         );
     }
+
+    /// Test target IP decompression when the `IPBytes = 0b100`.
+    #[test]
+    fn ipbytes_decompress_100() {
+        let ipb = IPBytes::new(0b100);
+        assert_eq!(
+            TargetIP::from_bits(48, 0x0000887766554433).decompress(ipb, Some(0xaaaa999999999999)),
+            Some(0xaaaa887766554433)
+        );
+        // Unlike `0b011`, bit 47 is not sign-extended.
+        assert_eq!(
+            TargetIP::from_bits(48, 0x0000010203040506).decompress(ipb, Some(0xffff800000000000)),
+            Some(0xffff010203040506)
+        );
+    }
+
+    /// Returns the bytes of a minimal PSB+ sequence, which gets the parser into the "normal"
+    /// state.
+    fn psb_plus() -> Vec<u8> {
+        let mut bytes = [0x02, 0x82].repeat(8);
+        bytes.extend([0x02, 0x23]);
+        bytes
+    }
+
+    /// Parse `bytes` to completion, returning the kinds of the packets parsed.
+    fn parse_kinds(bytes: &[u8]) -> Result<Vec<PacketKind>, HWTracerError> {
+        PacketParser::new(bytes)
+            .map(|p| p.map(|p| p.kind()))
+            .collect()
+    }
+
+    /// Parse `bytes`, expecting a parse error. The error's message is returned.
+    fn parse_err(bytes: &[u8]) -> String {
+        match parse_kinds(bytes) {
+            Err(HWTracerError::TraceParseError(s)) => s,
+            r => panic!("expected a parse error, got {r:?}"),
+        }
+    }
+
+    /// Check that every kind of packet can be parsed and that each consumes the right number of
+    /// bytes. Payload bytes are `0xff` so that a mis-sized packet derails the parser.
+    #[test]
+    fn parse_all_packet_kinds() {
+        let mut bytes = [0x02, 0x82].repeat(8);
+        let mut expect = vec![PacketKind::PSB];
+        let mut push = |kind, pkt: &[u8]| {
+            bytes.extend(pkt);
+            expect.push(kind);
+        };
+
+        // Packets which can appear in a PSB+ sequence.
+        push(
+            PacketKind::TSC,
+            &[0x19, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::TMA, &[0x02, 0x73, 0xff, 0xff, 0x00, 0xff, 0x01]);
+        push(
+            PacketKind::PIP,
+            &[0x02, 0x43, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(
+            PacketKind::VMCS,
+            &[0x02, 0xc8, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::CBR, &[0x02, 0x03, 0xff, 0x00]);
+        push(PacketKind::MODEExec, &[0x99, 0x01]);
+        push(PacketKind::MODETSX, &[0x99, 0x20]);
+        push(PacketKind::PSBEND, &[0x02, 0x23]);
+
+        // Packets which can appear outside of a PSB+ sequence.
+        push(
+            PacketKind::TSC,
+            &[0x19, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::MTC, &[0x59, 0xff]);
+        push(PacketKind::TMA, &[0x02, 0x73, 0xff, 0xff, 0x00, 0xff, 0x01]);
+        push(PacketKind::CYC, &[0x03]);
+        push(PacketKind::CYC, &[0xff, 0xff, 0x00]);
+        push(
+            PacketKind::PIP,
+            &[0x02, 0x43, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(
+            PacketKind::VMCS,
+            &[0x02, 0xc8, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::PTWRITE, &[0x02, 0x12, 0xff, 0xff, 0xff, 0xff]);
+        push(
+            PacketKind::PTWRITE,
+            &[0x02, 0xb2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::PWRE, &[0x02, 0x22, 0xff, 0xff]);
+        push(
+            PacketKind::PWRX,
+            &[0x02, 0xa2, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(
+            PacketKind::MNT,
+            &[
+                0x02, 0xc3, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+        );
+        push(PacketKind::EXSTOP, &[0x02, 0x62]);
+        push(PacketKind::EXSTOP, &[0x02, 0xe2]);
+        push(PacketKind::CBR, &[0x02, 0x03, 0xff, 0x00]);
+        push(PacketKind::ShortTNT, &[0xfe]);
+        push(
+            PacketKind::LongTNT,
+            &[0x02, 0xa3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::PAD, &[0x00]);
+        push(
+            PacketKind::TIPPGE,
+            &[0xd1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        push(PacketKind::TIP, &[0x2d, 0xff, 0xff]);
+        push(PacketKind::TIP, &[0x8d, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        push(PacketKind::FUP, &[0x5d, 0xff, 0xff, 0xff, 0xff]);
+        push(PacketKind::TIPPGD, &[0x01]);
+        push(PacketKind::TraceStop, &[0x02, 0x83]);
+        push(PacketKind::OVF, &[0x02, 0xf3]);
+
+        assert_eq!(parse_kinds(&bytes).unwrap(), expect);
+    }
+
+    /// Check the taken/not-taken decisions extracted from long TNT packets.
+    #[test]
+    fn parse_long_tnt() {
+        let tnts = |payload: &[u8]| {
+            let mut bytes = psb_plus();
+            bytes.extend([0x02, 0xa3]);
+            bytes.extend(payload);
+            let pkt = PacketParser::new(&bytes).nth(2).unwrap().unwrap();
+            assert_eq!(pkt.kind(), PacketKind::LongTNT);
+            pkt.tnts().unwrap()
+        };
+
+        assert_eq!(tnts(&[0b101, 0, 0, 0, 0, 0]), vec![false, true]);
+        assert_eq!(tnts(&[0b1, 0, 0, 0, 0, 0]), vec![]);
+        assert_eq!(tnts(&[0, 0, 0, 0, 0, 0x80]), vec![false; 47]);
+        assert_eq!(tnts(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), vec![true; 47]);
+        let mut expect = vec![true];
+        expect.extend([false; 38]);
+        expect.extend([true, false, false, false, false, false, false, true]);
+        assert_eq!(tnts(&[0x81, 0, 0, 0, 0, 0xc0]), expect);
+    }
+
+    /// Check that unknown and reserved encodings are reported as errors carrying the offset of the
+    /// offending packet.
+    #[test]
+    fn parse_reserved_encodings() {
+        let err = |pkt: &[u8]| {
+            let mut bytes = psb_plus();
+            bytes.extend([0x00, 0x00]);
+            bytes.extend(pkt);
+            parse_err(&bytes)
+        };
+        let off = "byte offset 20";
+
+        // IP packets with reserved `IPBytes` encodings.
+        assert!(err(&[0xad, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).contains(off));
+        assert!(err(&[0xf1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).contains(off));
+        assert!(err(&[0xbd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).contains(off));
+        // PTWRITE with a reserved payload size.
+        assert!(err(&[0x02, 0x52, 0xff, 0xff, 0xff, 0xff]).contains(off));
+        // Long TNT without a stop bit.
+        assert!(err(&[0x02, 0xa3, 0, 0, 0, 0, 0, 0]).contains(off));
+        // MODE.Exec with the reserved `CS.D = 1`, `CS.L = 1` combination.
+        assert!(err(&[0x99, 0x03]).contains(off));
+        // Unknown packets.
+        assert!(err(&[0x02, 0xff]).contains(off));
+        assert!(err(&[0x02]).contains(off));
+
+        // Packets which aren't valid inside a PSB+ sequence.
+        let mut bytes = [0x02, 0x82].repeat(8);
+        bytes.extend([0x59, 0xff]);
+        assert!(parse_err(&bytes).contains("byte offset 16"));
+    }
+
+    #[test]
+    fn sync_to_psb() {
+        // A trace which can't be parsed up front, with a PSB+ sequence later on.
+        let mut bytes = vec![0x02, 0xff, 0x02];
+        bytes.extend(psb_plus());
+        bytes.push(0x00);
+        let mut parser = PacketParser::new(&bytes);
+        assert!(parser.next().unwrap().is_err());
+        assert!(parser.sync_to_psb());
+        assert_eq!(parser.byte_off(), 3);
+        let kinds = parser
+            .map(|p| p.map(|p| p.kind()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            kinds,
+            vec![PacketKind::PSB, PacketKind::PSBEND, PacketKind::PAD]
+        );
+
+        // A trace with no PSB to synchronise with.
+        let bytes = [0x02, 0x82, 0x02, 0x82, 0x00];
+        let mut parser = PacketParser::new(&bytes);
+        assert!(!parser.sync_to_psb());
+        assert_eq!(parser.byte_off(), bytes.len());
+        assert!(parser.next().is_none());
+    }
 }
@@ -64,54 +64,40 @@ impl TargetIP {
         ip_bytes: IPBytes,
         prev_tip: Option<usize>,
     ) -> Option<usize> {
-        let res = match ip_bytes.val {
-            0b000 => {
-                debug_assert!(matches!(self, Self::OutOfContext));
-                return None;
-            }
-            0b001 => {
+        let res = match (ip_bytes.val, self) {
+            (0b000, Self::OutOfContext) => return None,
+            (0b001, Self::Ip16(v)) => {
                 // The result is bytes 63..=16 from `prev_tip` and bytes 15..=0 from `ip`.
-                if let Self::Ip16(v) = self {
-                    prev_tip.unwrap() & 0xffffffffffff0000 | usize::try_from(*v).unwrap()
-                } else {
-                    unreachable!();
-                }
+                prev_tip.unwrap() & 0xffffffffffff0000 | usize::from(*v)
             }
-            0b010 => {
+            (0b010, Self::Ip32(v)) => {
                 // The result is bytes 63..=32 from `prev_tip` and bytes 31..=0 from `ip`.
-                if let Self::Ip32(v) = self {
-                    prev_tip.unwrap() & 0xffffffff00000000 | usize::try_from(*v).unwrap()
-                } else {
-                    unreachable!();
-                }
+                prev_tip.unwrap() & 0xffffffff00000000 | usize::try_from(*v).unwrap()
             }
-            0b011 => {
+            (0b011, Self::Ip48(v)) => {
                 // The result is bits 0..=47 from the IP, with the remaining high-order bits
                 // extended with the value of bit 47.
-                if let Self::Ip48(v) = self {
-                    debug_assert!(v >> 48 == 0);
-                    // Extract the value of bit 47.
-                    let b47 = (v & (1 << 47)) >> 47;
-                    // Copy the value of bit 47 across all 64 bits.
-                    let all = u64::wrapping_sub(!b47 & 0x1, 1);
-                    // Restore bits 47..=0 to arrive at the result.
-                    usize::try_from(all & 0xffff000000000000 | v).unwrap()
-                } else {
-                    unreachable!();
-                }
+                debug_assert!(v >> 48 == 0);
+                // Extract the value of bit 47.
+                let b47 = (v & (1 << 47)) >> 47;
+                // Copy the value of bit 47 across all 64 bits.
+                let all = u64::wrapping_sub(!b47 & 0x1, 1);
+                // Restore bits 47..=0 to arrive at the result.
+                usize::try_from(all & 0xffff000000000000 | v).unwrap()
+            }
+            (0b100, Self::Ip48(v)) => {
+                // The result is bits 63..=48 from `prev_tip` and bits 47..=0 from `ip`.
+                debug_assert!(v >> 48 == 0);
+                prev_tip.unwrap() & 0xffff000000000000 | usize::try_from(*v).unwrap()
             }
-            0b100 => todo!(),
-            0b101 => unreachable!(), // reserved by Intel.
-            0b110 => {
+            (0b110, Self::Ip64(v)) => {
                 // Uncompressed IP.
-                if let Self::Ip64(v) = self {
-                    usize::try_from(*v).unwrap()
-                } else {
-                    unreachable!();
-                }
+                usize::try_from(*v).unwrap()
             }
-            0b111 => unreachable!(), // reserved by Intel.
-            _ => todo!("IPBytes: {:03b}", ip_bytes.val),
+            // deku only reads a `TargetIP` whose variant matches `IPBytes`, and there is no
+            // variant for the encodings reserved by Intel (`0b101` and `0b111`). Packets using
+            // those encodings are reported as a parse error by the packet parser instead.
+            (v, tip) => unreachable!("IPBytes: {v:03b}, TargetIP: {tip:?}"),
         };
         Some(res)
     }
@@ -161,7 +147,8 @@ pub(in crate::decode::ykpt) struct MODEExecPacket {
     if_: u8,
     #[deku(bits = "1")]
     csd: u8,
-    #[deku(bits = "1")]
+    /// `CS.D = 1` and `CS.L = 1` is a reserved combination.
+    #[deku(bits = "1", assert = "!(*csd == 1 && *csl_lma == 1)")]
     csl_lma: u8,
 }
 
@@ -171,7 +158,7 @@ impl MODEExecPacket {
             (0, 1) => Bitness::Bits64,
             (1, 0) => Bitness::Bits32,
             (0, 0) => Bitness::Bits16,
-            _ => unreachable!(),
+            _ => unreachable!(), // rejected when parsing.
         }
     }
 }
@@ -183,8 +170,19 @@ impl MODEExecPacket {
 pub(in crate::decode::ykpt) struct MODETSXPacket {
     #[deku(bits = "3", assert = "*magic1 == 0x1", temp)]
     magic1: u8,
-    #[deku(bits = "5", temp)]
-    unused: u8,
+    #[deku(bits = "3", temp)]
+    reserved: u8,
+    #[deku(bits = "1")]
+    tx_abort: bool,
+    #[deku(bits = "1", temp)]
+    in_tx: bool,
+}
+
+impl MODETSXPacket {
+    /// Returns `true` if this packet reports a transactional abort.
+    pub(in crate::decode::ykpt) fn is_abort(&self) -> bool {
+        self.tx_abort
+    }
 }
 
 /// Packet Generation Enable (TIP.PGE) packet.
@@ -252,14 +250,21 @@ impl ShortTNTPacket {
 pub(in crate::decode::ykpt) struct LongTNTPacket {
     /// Bits encoding the branch decisions **and** a stop bit.
     ///
-    /// FIXME: marked `temp` until we actually use the field.
-    #[deku(bits = "48", temp)]
+    /// The stop bit is the most significant set bit. A payload with no stop bit is invalid.
+    #[deku(bits = "48", assert = "*branches != 0")]
     branches: u64,
 }
 
 impl LongTNTPacket {
     pub(in crate::decode::ykpt) fn tnts(&self) -> Vec<bool> {
-        todo!();
+        debug_assert!(self.branches != 0 && self.branches >> 48 == 0);
+        // The oldest branch decision is the bit immediately below the stop bit, the newest is bit
+        // zero.
+        let stop = u64::BITS - 1 - self.branches.leading_zeros();
+        (0..stop)
+            .rev()
+            .map(|i| self.branches >> i & 0x1 == 1)
+            .collect()
     }
 }
 
@@ -347,9 +352,11 @@ pub(in crate::decode::ykpt) struct CYCPacket {
 pub(in crate::decode::ykpt) struct EXSTOPPacket {
     #[deku(bits = "8", assert = "*magic1 == 0x2", temp)]
     magic1: u8,
-    #[deku(bits = "1", temp)]
-    ip: u8,
-    #[deku(bits = "7", assert = "*magic2 == 0x2", temp)]
+    /// When set, the packet is followed by a FUP packet giving the IP of the instruction which
+    /// caused execution to stop.
+    #[deku(bits = "1")]
+    ip: bool,
+    #[deku(bits = "7", assert = "*magic2 == 0x62", temp)]
     magic2: u8,
 }
 
@@ -358,6 +365,113 @@ pub(in crate::decode::ykpt) struct EXSTOPPacket {
 #[deku(magic = b"\x02\xf3")]
 pub(in crate::decode::ykpt) struct OVFPacket {}
 
+/// Timestamp Counter (TSC) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x19")]
+pub(in crate::decode::ykpt) struct TSCPacket {
+    #[deku(bits = "56", temp)]
+    tsc: u64,
+}
+
+/// Mini Time Counter (MTC) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x59")]
+pub(in crate::decode::ykpt) struct MTCPacket {
+    #[deku(temp)]
+    ctc: u8,
+}
+
+/// Time Stamp Counter/Core Crystal Clock Association (TMA) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\x73")]
+pub(in crate::decode::ykpt) struct TMAPacket {
+    #[deku(temp)]
+    ctc: u16,
+    #[deku(temp)]
+    reserved1: u8,
+    #[deku(temp)]
+    fast_counter: u8,
+    #[deku(bits = "7", temp)]
+    reserved2: u8,
+    #[deku(bits = "1", temp)]
+    fast_counter_hi: u8,
+}
+
+/// Paging Information (PIP) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\x43")]
+pub(in crate::decode::ykpt) struct PIPPacket {
+    /// Bits 51..=5 of CR3 and the "non-root" bit.
+    #[deku(bits = "48", temp)]
+    payload: u64,
+}
+
+/// Virtual Machine Control Structure (VMCS) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\xc8")]
+pub(in crate::decode::ykpt) struct VMCSPacket {
+    /// Bits 51..=12 of the VMCS pointer.
+    #[deku(bits = "40", temp)]
+    vmcs_ptr: u64,
+}
+
+/// PTWRITE packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+pub(in crate::decode::ykpt) struct PTWRITEPacket {
+    #[deku(bits = "8", assert = "*magic1 == 0x2", temp)]
+    magic1: u8,
+    /// When set, the packet is followed by a FUP packet giving the IP of the PTWRITE instruction.
+    #[deku(bits = "1")]
+    ip: bool,
+    /// The size of the payload: `0b00` is 4 bytes, `0b01` is 8 bytes. Other values are reserved.
+    #[deku(bits = "2", assert = "*payload_bytes <= 0b01", temp)]
+    payload_bytes: u8,
+    #[deku(bits = "5", assert = "*magic2 == 0b10010", temp)]
+    magic2: u8,
+    #[deku(cond = "*payload_bytes == 0b00", temp)]
+    payload32: Option<u32>,
+    #[deku(cond = "*payload_bytes == 0b01", temp)]
+    payload64: Option<u64>,
+}
+
+/// Power Entry (PWRE) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\x22")]
+pub(in crate::decode::ykpt) struct PWREPacket {
+    #[deku(temp)]
+    unused: u16,
+}
+
+/// Power Exit (PWRX) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\xa2")]
+pub(in crate::decode::ykpt) struct PWRXPacket {
+    #[deku(bits = "40", temp)]
+    unused: u64,
+}
+
+/// Maintenance (MNT) packet.
+#[deku_derive(DekuRead)]
+#[derive(Debug)]
+#[deku(magic = b"\x02\xc3\x88")]
+pub(in crate::decode::ykpt) struct MNTPacket {
+    #[deku(temp)]
+    payload: u64,
+}
+
+/// Trace Stop (TraceStop) packet.
+#[derive(Debug, DekuRead)]
+#[deku(magic = b"\x02\x83")]
+pub(in crate::decode::ykpt) struct TraceStopPacket {}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(in crate::decode::ykpt) enum PacketKind {
     PSB,
@@ -375,6 +489,16 @@ pub(in crate::decode::ykpt) enum PacketKind {
     CYC,
     EXSTOP,
     OVF,
+    TSC,
+    MTC,
+    TMA,
+    PIP,
+    VMCS,
+    PTWRITE,
+    PWRE,
+    PWRX,
+    MNT,
+    TraceStop,
 }
 
 impl PacketKind {
@@ -392,7 +516,17 @@ impl PacketKind {
             | Self::LongTNT
             | Self::CYC
             | Self::EXSTOP
-            | Self::OVF => false,
+            | Self::OVF
+            | Self::TSC
+            | Self::MTC
+            | Self::TMA
+            | Self::PIP
+            | Self::VMCS
+            | Self::PTWRITE
+            | Self::PWRE
+            | Self::PWRX
+            | Self::MNT
+            | Self::TraceStop => false,
         }
     }
 
@@ -412,7 +546,17 @@ impl PacketKind {
             | Self::TIPPGD
             | Self::TIPPGE
             | Self::EXSTOP
-            | Self::OVF => false,
+            | Self::OVF
+            | Self::TSC
+            | Self::MTC
+            | Self::TMA
+            | Self::PIP
+            | Self::VMCS
+            | Self::PTWRITE
+            | Self::PWRE
+            | Self::PWRX
+            | Self::MNT
+            | Self::TraceStop => false,
         }
     }
 }
@@ -438,6 +582,16 @@ pub(in crate::decode::ykpt) enum Packet {
     CYC(CYCPacket),
     EXSTOP(EXSTOPPacket),
     OVF(OVFPacket),
+    TSC(TSCPacket),
+    MTC(MTCPacket),
+    TMA(TMAPacket),
+    PIP(PIPPacket),
+    VMCS(VMCSPacket),
+    PTWRITE(PTWRITEPacket),
+    PWRE(PWREPacket),
+    PWRX(PWRXPacket),
+    MNT(MNTPacket),
+    TraceStop(TraceStopPacket),
 }
 
 impl Packet {
@@ -458,7 +612,17 @@ impl Packet {
             | Self::LongTNT(_)
             | Self::CYC(_)
             | Self::EXSTOP(_)
-            | Self::OVF(_) => None,
+            | Self::OVF(_)
+            | Self::TSC(_)
+            | Self::MTC(_)
+            | Self::TMA(_)
+            | Self::PIP(_)
+            | Self::VMCS(_)
+            | Self::PTWRITE(_)
+            | Self::PWRE(_)
+            | Self::PWRX(_)
+            | Self::MNT(_)
+            | Self::TraceStop(_) => None,
         }
     }
 
@@ -479,6 +643,16 @@ impl Packet {
             Self::CYC(_) => PacketKind::CYC,
             Self::EXSTOP(_) => PacketKind::EXSTOP,
             Self::OVF(_) => PacketKind::OVF,
+            Self::TSC(_) => PacketKind::TSC,
+            Self::MTC(_) => PacketKind::MTC,
+            Self::TMA(_) => PacketKind::TMA,
+            Self::PIP(_) => PacketKind::PIP,
+            Self::VMCS(_) => PacketKind::VMCS,
+            Self::PTWRITE(_) => PacketKind::PTWRITE,
+            Self::PWRE(_) => PacketKind::PWRE,
+            Self::PWRX(_) => PacketKind::PWRX,
+            Self::MNT(_) => PacketKind::MNT,
+            Self::TraceStop(_) => PacketKind::TraceStop,
         }
     }
 
@@ -503,14 +677,56 @@ impl Packet {
             | Self::FUP(_, _)
             | Self::CYC(_)
             | Self::EXSTOP(_)
-            | Self::OVF(_) => None,
+            | Self::OVF(_)
+            | Self::TSC(_)
+            | Self::MTC(_)
+            | Self::TMA(_)
+            | Self::PIP(_)
+            | Self::VMCS(_)
+            | Self::PTWRITE(_)
+            | Self::PWRE(_)
+            | Self::PWRX(_)
+            | Self::MNT(_)
+            | Self::TraceStop(_) => None,
+        }
+    }
+
+    /// Returns `true` if the packet is followed by a FUP packet giving the IP of the instruction
+    /// which generated it. Such a FUP packet doesn't signal an asynchronous event.
+    pub(in crate::decode::ykpt) fn binds_fup(&self) -> bool {
+        match self {
+            Self::EXSTOP(p) => p.ip,
+            Self::PTWRITE(p) => p.ip,
+            Self::PSB(_)
+            | Self::CBR(_)
+            | Self::PSBEND(_)
+            | Self::PAD(_)
+            | Self::MODEExec(_)
+            | Self::MODETSX(_)
+            | Self::TIPPGE(..)
+            | Self::TIPPGD(..)
+            | Self::ShortTNT(_)
+            | Self::LongTNT(_)
+            | Self::TIP(..)
+            | Self::FUP(..)
+            | Self::CYC(_)
+            | Self::OVF(_)
+            | Self::TSC(_)
+            | Self::MTC(_)
+            | Self::TMA(_)
+            | Self::PIP(_)
+            | Self::VMCS(_)
+            | Self::PWRE(_)
+            | Self::PWRX(_)
+            | Self::MNT(_)
+            | Self::TraceStop(_) => false,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ShortTNTPacket;
+    use super::{LongTNTPacket, ShortTNTPacket};
 
     #[test]
     fn short_tnt() {
@@ -525,4 +741,15 @@ mod tests {
             vec![false, false, true, false, false, true]
         );
     }
+
+    #[test]
+    fn long_tnt() {
+        let mk = |branches: u64| LongTNTPacket { branches };
+
+        debug_assert_eq!(mk(0b1).tnts(), vec![]);
+        debug_assert_eq!(mk(0b10).tnts(), vec![false]);
+        debug_assert_eq!(mk(0b1011).tnts(), vec![false, true, true]);
+        debug_assert_eq!(mk(1 << 47).tnts(), vec![false; 47]);
+        debug_assert_eq!(mk(0xffffffffffff).tnts(), vec![true; 47]);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::llvm_blockmap::BlockMapError;
 use libc::{c_int, strerror};
 use std::error::Error;
 use std::ffi::{self, CStr};
@@ -31,6 +32,8 @@ pub enum HWTracerError {
     NoMorePackets,
     /// The trace was interrupted by an asynchronous event.
     TraceInterrupted,
+    /// The trace followed a control flow edge that the compiler assumed would never be taken.
+    DivergentControlFlow,
     /// Any other error.
     Custom(Box<dyn Error>),
 }
@@ -56,6 +59,7 @@ impl Display for HWTracerError {
             HWTracerError::NoMorePackets => write!(f, "End of packet stream"),
             HWTracerError::DisasmFail(ref s) => write!(f, "failed to disassemble: {}", s),
             HWTracerError::TraceInterrupted => write!(f, "trace interrupted"),
+            HWTracerError::DivergentControlFlow => write!(f, "divergent control flow"),
             HWTracerError::Unknown => write!(f, "Unknown error"),
         }
     }
@@ -81,6 +85,7 @@ impl Error for HWTracerError {
             HWTracerError::NoMorePackets => None,
             HWTracerError::DisasmFail(_) => None,
             HWTracerError::TraceInterrupted => None,
+            HWTracerError::DivergentControlFlow => None,
         }
     }
 }
@@ -97,6 +102,12 @@ impl From<ffi::NulError> for HWTracerError {
     }
 }
 
+impl From<BlockMapError> for HWTracerError {
+    fn from(err: BlockMapError) -> Self {
+        HWTracerError::Custom(Box::new(err))
+    }
+}
+
 impl From<ParseIntError> for HWTracerError {
     fn from(err: ParseIntError) -> Self {
         HWTracerError::Custom(Box::new(err))
@@ -7,12 +7,17 @@
 mod block;
 pub use block::Block;
 mod c_errors;
+pub mod capture;
+pub use capture::TraceCapture;
 pub mod collect;
-pub use collect::{default_tracer_for_platform, ThreadTracer, Tracer};
+pub use collect::{
+    default_tracer_for_platform, tracer_for_platform_with_config, ThreadTracer, Tracer,
+};
 pub mod decode;
 pub mod errors;
 pub mod llvm_blockmap;
 
+use capture::Sideband;
 pub use errors::HWTracerError;
 use std::fmt::Debug;
 #[cfg(test)]
@@ -30,6 +35,13 @@ pub trait Trace: Debug + Send {
 
     /// Get the size of the trace in bytes.
     fn len(&self) -> usize;
+
+    /// If this trace was captured with a snapshot of the address space of the process that
+    /// collected it (see [TraceCapture]), return that snapshot. Otherwise the trace can only be
+    /// decoded by the process that collected it.
+    fn sideband(&self) -> Option<&Sideband> {
+        None
+    }
 }
 
 /// A loop that does some work that we can use to build a trace.
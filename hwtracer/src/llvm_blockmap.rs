@@ -2,16 +2,161 @@
 
 use byteorder::{NativeEndian, ReadBytesExt};
 use intervaltree::IntervalTree;
-use libc::{dlsym, RTLD_DEFAULT};
 use std::{
+    collections::HashMap,
     convert::TryFrom,
-    ffi::CString,
-    io::{prelude::*, Cursor, SeekFrom},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
     slice,
-    sync::LazyLock,
+    sync::{LazyLock, Mutex},
 };
+use ykutil::obj::{obj_dlsym, SELF_BIN_PATH};
 
-pub static LLVM_BLOCK_MAP: LazyLock<BlockMap> = LazyLock::new(BlockMap::new);
+/// The version of the blockmap records whose layout we know.
+pub(crate) const BLOCKMAP_VERSION: u8 = 1;
+
+/// The blockmaps of the objects loaded into this process.
+pub static LLVM_BLOCK_MAPS: LazyLock<BlockMaps> = LazyLock::new(BlockMaps::new);
+
+/// The blockmaps of loaded objects, keyed by object path (as returned by
+/// [ykutil::addr::vaddr_to_obj_and_off]).
+pub struct BlockMaps {
+    /// Objects whose blockmap we have already looked for, mapped to the parsed blockmap (or `None`
+    /// if the object wasn't built with ykllvm), or the error encountered parsing it.
+    ///
+    /// Blockmaps are never freed: they describe offsets within an object, so they remain valid if
+    /// an object is unloaded and later loaded again.
+    maps: Mutex<HashMap<PathBuf, Result<Option<&'static BlockMap>, BlockMapError>>>,
+}
+
+impl BlockMaps {
+    fn new() -> Self {
+        Self {
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the blockmap of the loaded object `obj`, or `None` if `obj` wasn't built with
+    /// ykllvm (or isn't loaded).
+    pub fn get(&self, obj: &Path) -> Result<Option<&'static BlockMap>, BlockMapError> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(bm) = maps.get(obj) {
+            return bm.clone();
+        }
+        let bm = match find_blockmap_section(obj) {
+            Some(data) => BlockMap::from_bytes(data).map(|bm| Some(&*Box::leak(Box::new(bm)))),
+            None => Ok(None),
+        };
+        maps.insert(obj.to_owned(), bm.clone());
+        bm
+    }
+}
+
+/// An error encountered while parsing a blockmap section.
+///
+/// Each variant records the byte offset (within the section) at which the problem was found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockMapError {
+    /// A record that we couldn't parse has a version other than [BLOCKMAP_VERSION]. This usually
+    /// means that the object was built with a different version of ykllvm.
+    UnsupportedVersion { off: usize, version: u8 },
+    /// The section ended part way through a record.
+    Truncated { off: usize },
+    /// A variable-length integer is too big to fit in 64 bits.
+    BadInteger { off: usize },
+    /// A block has a successor kind that we don't know about.
+    BadSuccessorKind { off: usize, kind: u8 },
+    /// A block's address range doesn't fit in 64 bits.
+    BadBlockRange { off: usize },
+}
+
+impl Display for BlockMapError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            BlockMapError::UnsupportedVersion { off, version } => write!(
+                f,
+                "can't parse blockmap record at byte offset {off} with version {version}: only \
+                 version {BLOCKMAP_VERSION} is supported (was the object built with a different \
+                 ykllvm?)"
+            ),
+            BlockMapError::Truncated { off } => {
+                write!(f, "blockmap truncated at byte offset {off}")
+            }
+            BlockMapError::BadInteger { off } => {
+                write!(f, "blockmap integer at byte offset {off} is too big")
+            }
+            BlockMapError::BadSuccessorKind { off, kind } => {
+                write!(
+                    f,
+                    "unknown blockmap successor kind {kind} at byte offset {off}"
+                )
+            }
+            BlockMapError::BadBlockRange { off } => {
+                write!(
+                    f,
+                    "the address range of the blockmap block at byte offset {off} is too big"
+                )
+            }
+        }
+    }
+}
+
+impl Error for BlockMapError {}
+
+/// Reads the fields of a blockmap section, reporting malformed data as a [BlockMapError].
+struct BlockMapReader<'a> {
+    crsr: Cursor<&'a [u8]>,
+}
+
+impl<'a> BlockMapReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            crsr: Cursor::new(data),
+        }
+    }
+
+    /// The byte offset of the next field to be read.
+    fn off(&self) -> usize {
+        usize::try_from(self.crsr.position()).unwrap()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.off() >= self.crsr.get_ref().len()
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BlockMapError> {
+        let off = self.off();
+        self.crsr
+            .read_u8()
+            .map_err(|_| BlockMapError::Truncated { off })
+    }
+
+    fn read_u64(&mut self) -> Result<u64, BlockMapError> {
+        let off = self.off();
+        self.crsr
+            .read_u64::<NativeEndian>()
+            .map_err(|_| BlockMapError::Truncated { off })
+    }
+
+    fn read_uleb128(&mut self) -> Result<u64, BlockMapError> {
+        let off = self.off();
+        leb128::read::unsigned(&mut self.crsr).map_err(|e| match e {
+            leb128::read::Error::IoError(_) => BlockMapError::Truncated { off },
+            leb128::read::Error::Overflow => BlockMapError::BadInteger { off },
+        })
+    }
+
+    /// Read a successor (or call target) offset, where 0 means "none".
+    fn read_maybe_target(&mut self) -> Result<Option<u64>, BlockMapError> {
+        Ok(match self.read_u64()? {
+            0 => None,
+            target => Some(target),
+        })
+    }
+}
 
 /// Describes the successors (if any) of an LLVM `MachineBlock`.
 #[derive(Debug)]
@@ -78,10 +223,11 @@ impl BlockMapEntry {
     }
 }
 
-// ykllvm inserts a symbol pair marking the extent of the `.llvm_bb_addr_map` section.
-// This function returns a byte slice of the memory between these two marker symbols.
+// ykllvm inserts a symbol pair marking the extent of the `.llvm_bb_addr_map` section of each
+// object it builds. This function returns a byte slice of the memory between these two marker
+// symbols in the loaded object `obj`, or `None` if `obj` wasn't built with ykllvm.
 //
-// Note that in the "common use case" this lookup could be done statically (without `dlsym`) using:
+// Note that for the main executable this lookup could be done statically (without `dlsym`) using:
 //
 // ```
 // extern "C" {
@@ -94,21 +240,11 @@ impl BlockMapEntry {
 //
 // however, this would force every binary that uses this crate to provide the symbols. This is not
 // desirable, e.g. Rust test binaries.
-fn find_blockmap_section() -> &'static [u8] {
-    let start_sym = CString::new("ykllvm.bbaddrmaps.start").unwrap();
-    let start_addr = unsafe { dlsym(RTLD_DEFAULT, start_sym.as_ptr()) } as *const u8;
-    if start_addr.is_null() {
-        panic!("can't find ykllvm.bbaddrmaps.start");
-    }
-
-    let stop_sym = CString::new("ykllvm.bbaddrmaps.stop").unwrap();
-    let stop_addr = unsafe { dlsym(RTLD_DEFAULT, stop_sym.as_ptr()) } as *const u8;
-    if stop_addr.is_null() {
-        panic!("can't find ykllvm.bbaddrmaps.stop");
-    }
-
+pub(crate) fn find_blockmap_section(obj: &Path) -> Option<&'static [u8]> {
+    let start_addr = obj_dlsym(obj, "ykllvm.bbaddrmaps.start")? as *const u8;
+    let stop_addr = obj_dlsym(obj, "ykllvm.bbaddrmaps.stop")? as *const u8;
     debug_assert!(stop_addr > start_addr);
-    unsafe { slice::from_raw_parts(start_addr, stop_addr.sub_ptr(start_addr)) }
+    Some(unsafe { slice::from_raw_parts(start_addr, stop_addr.sub_ptr(start_addr)) })
 }
 
 /// Maps (unrelocated) block offsets to their corresponding block map entry.
@@ -120,83 +256,108 @@ impl BlockMap {
     /// Parse the LLVM blockmap section of the current executable and return a struct holding the
     /// mappings.
     pub fn new() -> Self {
-        let bbaddrmap_data = find_blockmap_section();
+        match find_blockmap_section(&SELF_BIN_PATH) {
+            Some(data) => match Self::from_bytes(data) {
+                Ok(bm) => bm,
+                Err(e) => panic!("can't parse the ykllvm.bbaddrmaps section: {e}"),
+            },
+            None => panic!("can't find the ykllvm.bbaddrmaps section"),
+        }
+    }
 
+    /// Parse `data`, the contents of an LLVM blockmap section, and return a struct holding the
+    /// mappings.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BlockMapError> {
         // Keep reading blockmap records until we fall outside of the section's bounds.
         let mut elems = Vec::new();
-        let mut crsr = Cursor::new(bbaddrmap_data);
-        while crsr.position() < u64::try_from(bbaddrmap_data.len()).unwrap() {
-            let _version = crsr.read_u8().unwrap();
-            let _feature = crsr.read_u8().unwrap();
-            let mut last_off = crsr.read_u64::<NativeEndian>().unwrap();
-            let n_blks = leb128::read::unsigned(&mut crsr).unwrap();
-            for _ in 0..n_blks {
-                let mut corr_bbs = Vec::new();
-                let b_off = leb128::read::unsigned(&mut crsr).unwrap();
-                // Skip the block size. We still have to parse the field, as it's variable-size.
-                let b_sz = leb128::read::unsigned(&mut crsr).unwrap();
-                // Skip over block meta-data.
-                crsr.seek(SeekFrom::Current(1)).unwrap();
-                // Read the indices of the BBs corresponding with this MBB.
-                let num_corr = leb128::read::unsigned(&mut crsr).unwrap();
-                for _ in 0..num_corr {
-                    corr_bbs.push(leb128::read::unsigned(&mut crsr).unwrap());
+        let mut rdr = BlockMapReader::new(data);
+        while !rdr.is_empty() {
+            let off = rdr.off();
+            let version = rdr.read_u8()?;
+            // The features byte doesn't affect the layout of the records that ykllvm emits, so
+            // it's ignored.
+            rdr.read_u8()?;
+            // Records with other versions have always been accepted as long as they parse, so we
+            // only blame the version if a record can't be parsed.
+            Self::read_record(&mut rdr, &mut elems).map_err(|e| {
+                if version != BLOCKMAP_VERSION {
+                    BlockMapError::UnsupportedVersion { off, version }
+                } else {
+                    e
                 }
+            })?;
+        }
+        Ok(Self {
+            tree: elems.into_iter().collect::<IntervalTree<_, _>>(),
+        })
+    }
 
-                // Read call information.
-                let num_calls = leb128::read::unsigned(&mut crsr).unwrap();
-                let mut call_offs = Vec::new();
-                for _ in 0..num_calls {
-                    let callsite_off = crsr.read_u64::<NativeEndian>().unwrap();
-                    let target = crsr.read_u64::<NativeEndian>().unwrap();
-                    let target_off = if target != 0 { Some(target) } else { None };
-                    call_offs.push(CallInfo {
-                        callsite_off,
-                        target_off,
-                    })
-                }
+    /// Read the blocks of the blockmap record (following its version and features bytes) at the
+    /// current position of `rdr` into `elems`.
+    fn read_record(
+        rdr: &mut BlockMapReader,
+        elems: &mut Vec<(Range<u64>, BlockMapEntry)>,
+    ) -> Result<(), BlockMapError> {
+        let mut last_off = rdr.read_u64()?;
+        let n_blks = rdr.read_uleb128()?;
+        for _ in 0..n_blks {
+            let blk_off = rdr.off();
+            let mut corr_bbs = Vec::new();
+            let b_off = rdr.read_uleb128()?;
+            let b_sz = rdr.read_uleb128()?;
+            // Skip over block meta-data. We still have to parse the field, as it's
+            // variable-size.
+            rdr.read_uleb128()?;
+            // Read the indices of the BBs corresponding with this MBB.
+            let num_corr = rdr.read_uleb128()?;
+            for _ in 0..num_corr {
+                corr_bbs.push(rdr.read_uleb128()?);
+            }
 
-                let read_maybe_divergent_target = |crsr: &mut Cursor<_>| {
-                    let target = crsr.read_u64::<NativeEndian>().unwrap();
-                    if target != 0 {
-                        Some(target)
-                    } else {
-                        None // Divergent.
-                    }
-                };
-
-                // Read successor info.
-                // unconditional, target-address
-                // conditional, taken-address, not-taken-address
-                let succ = match crsr.read_u8().unwrap() {
-                    0 => SuccessorKind::Unconditional {
-                        target: read_maybe_divergent_target(&mut crsr),
-                    },
-                    1 => SuccessorKind::Conditional {
-                        taken_target: crsr.read_u64::<NativeEndian>().unwrap(),
-                        not_taken_target: read_maybe_divergent_target(&mut crsr),
-                    },
-                    2 => SuccessorKind::Return,
-                    3 => SuccessorKind::Dynamic,
-                    _ => unreachable!(),
-                };
-
-                let lo = last_off + b_off;
-                let hi = lo + b_sz;
-                elems.push((
-                    (lo..hi),
-                    BlockMapEntry {
-                        corr_bbs,
-                        call_offs,
-                        succ,
-                    },
-                ));
-                last_off = hi;
+            // Read call information.
+            let num_calls = rdr.read_uleb128()?;
+            let mut call_offs = Vec::new();
+            for _ in 0..num_calls {
+                let callsite_off = rdr.read_u64()?;
+                let target_off = rdr.read_maybe_target()?;
+                call_offs.push(CallInfo {
+                    callsite_off,
+                    target_off,
+                })
             }
+
+            // Read successor info.
+            // unconditional, target-address
+            // conditional, taken-address, not-taken-address
+            let off = rdr.off();
+            let succ = match rdr.read_u8()? {
+                0 => SuccessorKind::Unconditional {
+                    target: rdr.read_maybe_target()?,
+                },
+                1 => SuccessorKind::Conditional {
+                    taken_target: rdr.read_u64()?,
+                    not_taken_target: rdr.read_maybe_target()?,
+                },
+                2 => SuccessorKind::Return,
+                3 => SuccessorKind::Dynamic,
+                kind => return Err(BlockMapError::BadSuccessorKind { off, kind }),
+            };
+
+            let range = last_off
+                .checked_add(b_off)
+                .and_then(|lo| Some(lo..lo.checked_add(b_sz)?))
+                .ok_or(BlockMapError::BadBlockRange { off: blk_off })?;
+            last_off = range.end;
+            elems.push((
+                range,
+                BlockMapEntry {
+                    corr_bbs,
+                    call_offs,
+                    succ,
+                },
+            ));
         }
-        Self {
-            tree: elems.into_iter().collect::<IntervalTree<_, _>>(),
-        }
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -212,3 +373,148 @@ impl BlockMap {
         self.tree.query(start_off..end_off)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockMap, BlockMapError, CallInfo, SuccessorKind, BLOCKMAP_VERSION};
+    use byteorder::{NativeEndian, WriteBytesExt};
+
+    /// A blockmap record for a function at offset `func_off`, consisting of two blocks. The first
+    /// is 4 bytes long, calls the function at offset 0x100, and then conditionally branches to
+    /// either itself (taken) or the second block (not taken). The second is 2 bytes long and
+    /// returns.
+    fn record(func_off: u64) -> Vec<u8> {
+        let mut r = vec![BLOCKMAP_VERSION, 0];
+        r.write_u64::<NativeEndian>(func_off).unwrap();
+        r.push(2); // Number of blocks.
+
+        // Offset, size, metadata, one corresponding BB (0), one call.
+        r.extend([0, 4, 0, 1, 0, 1]);
+        r.write_u64::<NativeEndian>(func_off + 1).unwrap();
+        r.write_u64::<NativeEndian>(0x100).unwrap();
+        r.push(1); // Conditional.
+        r.write_u64::<NativeEndian>(func_off).unwrap();
+        r.write_u64::<NativeEndian>(func_off + 4).unwrap();
+
+        // Offset, size, metadata, two corresponding BBs (1 and 2), no calls, return.
+        r.extend([0, 2, 0, 2, 1, 2, 0, 2]);
+        r
+    }
+
+    #[test]
+    fn from_bytes() {
+        let mut data = record(0x10);
+        data.extend(record(0x40));
+        let bm = BlockMap::from_bytes(&data).unwrap();
+        assert_eq!(bm.len(), 4);
+
+        let ents = bm.query(0x10, 0x11).collect::<Vec<_>>();
+        assert_eq!(ents.len(), 1);
+        assert_eq!(ents[0].range, 0x10..0x14);
+        assert_eq!(ents[0].value.corr_bbs(), &vec![0]);
+        assert_eq!(
+            ents[0].value.call_offs(),
+            &vec![CallInfo {
+                callsite_off: 0x11,
+                target_off: Some(0x100)
+            }]
+        );
+        assert!(matches!(
+            ents[0].value.successor(),
+            SuccessorKind::Conditional {
+                taken_target: 0x10,
+                not_taken_target: Some(0x14)
+            }
+        ));
+
+        let ents = bm.query(0x45, 0x46).collect::<Vec<_>>();
+        assert_eq!(ents.len(), 1);
+        assert_eq!(ents[0].range, 0x44..0x46);
+        assert_eq!(ents[0].value.corr_bbs(), &vec![1, 2]);
+        assert!(ents[0].value.call_offs().is_empty());
+        assert!(matches!(ents[0].value.successor(), SuccessorKind::Return));
+    }
+
+    #[test]
+    fn from_bytes_empty() {
+        assert_eq!(BlockMap::from_bytes(&[]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = record(0x10);
+        let off = data.len();
+        let mut rec = record(0x40);
+        rec[0] = BLOCKMAP_VERSION + 1;
+        rec.pop();
+        data.extend(rec);
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::UnsupportedVersion {
+                off,
+                version: BLOCKMAP_VERSION + 1
+            })
+        );
+    }
+
+    /// Records with another version, or with features, are accepted as long as they can be
+    /// parsed.
+    #[test]
+    fn other_version_and_features() {
+        let mut data = record(0x10);
+        data[0] = BLOCKMAP_VERSION + 1;
+        data[1] = 0x4;
+        assert_eq!(BlockMap::from_bytes(&data).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = record(0x10);
+        data.pop();
+        let off = data.len();
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::Truncated { off })
+        );
+    }
+
+    #[test]
+    fn bad_integer() {
+        let mut data = vec![BLOCKMAP_VERSION, 0];
+        data.write_u64::<NativeEndian>(0x10).unwrap();
+        // A block count too big for 64 bits.
+        data.extend([0xff; 10]);
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::BadInteger { off: 10 })
+        );
+    }
+
+    #[test]
+    fn bad_block_range() {
+        let mut data = record(u64::MAX - 8);
+        // Move the first (4 byte) block so that it ends just beyond the end of the address space.
+        data[11] = 6;
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::BadBlockRange { off: 11 })
+        );
+        // The block's offset overflows too.
+        data[11] = 0x10;
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::BadBlockRange { off: 11 })
+        );
+    }
+
+    #[test]
+    fn bad_successor_kind() {
+        let mut data = record(0x10);
+        let off = data.len() - 1;
+        data[off] = 7;
+        assert_eq!(
+            BlockMap::from_bytes(&data).err(),
+            Some(BlockMapError::BadSuccessorKind { off, kind: 7 })
+        );
+    }
+}
@@ -2,7 +2,7 @@
 
 use criterion::{
     criterion_group, criterion_main, measurement::Measurement, BenchmarkGroup, BenchmarkId,
-    Criterion, SamplingMode,
+    Criterion, SamplingMode, Throughput,
 };
 use hwtracer::decode::TraceDecoderKind;
 use std::{
@@ -98,5 +98,23 @@ fn bench_disasm(c: &mut Criterion) {
     }
 }
 
-criterion_group!(decoder_benchmarks, bench_native, bench_disasm);
+/// Benchmark decoding many traces which do the same calls to foreign code (so that, after the
+/// first trace, ykpt can reuse foreign code it has already disassembled).
+fn bench_disasm_repeat(c: &mut Criterion) {
+    let (_tempdir, runner, mut group) = setup(c, "trace-decode-disasm-repeat");
+
+    for param in [1, 10, 100] {
+        group.throughput(Throughput::Elements(u64::try_from(param).unwrap()));
+        group.bench_function(BenchmarkId::new("YkPT", format!("{}", param)), |b| {
+            b.iter(|| collect_and_decode_trace(&runner, 2, param, TraceDecoderKind::YkPT))
+        });
+    }
+}
+
+criterion_group!(
+    decoder_benchmarks,
+    bench_native,
+    bench_disasm,
+    bench_disasm_repeat
+);
 criterion_main!(decoder_benchmarks);
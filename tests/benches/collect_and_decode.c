@@ -14,6 +14,10 @@
 
 #define BM_NATIVE 0
 #define BM_DISASM 1
+#define BM_DISASM_REPEAT 2
+
+// The number of loop iterations in each trace of the `BM_DISASM_REPEAT` benchmark.
+#define DISASM_REPEAT_ITERS 10
 
 __attribute__((noinline)) uint64_t native(uint64_t iters) {
   uint64_t sum = 0;
@@ -69,5 +73,15 @@ int main(int argc, char **argv) {
   if (argc != 4)
     usage();
 
-  collect_and_decode(atoi(argv[1]), atoi(argv[2]), atoi(argv[3]));
+  int benchmark = atoi(argv[1]);
+  size_t param = atoi(argv[2]);
+  int decoder_kind = atoi(argv[3]);
+  if (benchmark == BM_DISASM_REPEAT) {
+    // Collect and decode `param` traces of the same foreign code, as happens
+    // when a hot loop calling into libc is traced repeatedly.
+    for (size_t i = 0; i < param; i++)
+      collect_and_decode(BM_DISASM, DISASM_REPEAT_ITERS, decoder_kind);
+  } else {
+    collect_and_decode(benchmark, param, decoder_kind);
+  }
 }
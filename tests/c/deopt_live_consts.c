@@ -0,0 +1,50 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     ...
+//     jit-state: start-tracing
+//     4
+//     jit-state: stop-tracing
+//     3
+//     jit-state: enter-jit-code
+//     2
+//     1
+//     jit-state: deoptimise
+//     small=-3 large=123456789abc
+
+// Check that live values which the trace compiler knows to be constant (and
+// which are thus recorded as small and large constants in the JIT stackmap)
+// survive deoptimisation.
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int i = 4;
+  int64_t small = 0;
+  uint64_t large = 0;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(small);
+  NOOPT_VAL(large);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    small = -3;
+    large = 0x123456789abc;
+    fprintf(stderr, "%d\n", i);
+    i--;
+  }
+  fprintf(stderr, "small=%ld large=%lx\n", small, large);
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
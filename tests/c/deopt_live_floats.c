@@ -0,0 +1,48 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     ...
+//     jit-state: start-tracing
+//     4: 1.500000 0.250000
+//     jit-state: stop-tracing
+//     3: 3.000000 0.500000
+//     jit-state: enter-jit-code
+//     2: 4.500000 0.750000
+//     1: 6.000000 1.000000
+//     jit-state: deoptimise
+//     exit: 6.000000 1.000000
+
+// Check that floating point live values (which are kept in SSE registers)
+// survive deoptimisation.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int i = 4;
+  double d = 0;
+  float f = 0;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(d);
+  NOOPT_VAL(f);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    d += 1.5;
+    f += 0.25;
+    fprintf(stderr, "%d: %f %f\n", i, d, f);
+    i--;
+  }
+  fprintf(stderr, "exit: %f %f\n", d, f);
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
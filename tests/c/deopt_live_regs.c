@@ -0,0 +1,62 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     ...
+//     jit-state: start-tracing
+//     4: 10
+//     jit-state: stop-tracing
+//     3: 20
+//     jit-state: enter-jit-code
+//     2: 30
+//     1: 40
+//     jit-state: deoptimise
+//     exit: 4 8 12 16 20 24 28 40
+
+// Check that many values live across a guard (so that some are kept in
+// callee-saved registers, rather than on the stack) survive deoptimisation.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+__attribute__((noinline)) int sum(int a, int b, int c, int d, int e, int f,
+                                  int g) {
+  return (a + b + c + d + e + f + g) / 7;
+}
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int i = 4, a = 0, b = 0, c = 0, d = 0, e = 0, f = 0, g = 0, h = 0;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(a);
+  NOOPT_VAL(b);
+  NOOPT_VAL(c);
+  NOOPT_VAL(d);
+  NOOPT_VAL(e);
+  NOOPT_VAL(f);
+  NOOPT_VAL(g);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    a += 1;
+    b += 2;
+    c += 3;
+    d += 4;
+    e += 5;
+    f += 6;
+    g += 7;
+    h += sum(a, b, c, d, e, f, g) * 10 / (a * 4);
+    fprintf(stderr, "%d: %d\n", i, h);
+    i--;
+  }
+  fprintf(stderr, "exit: %d %d %d %d %d %d %d %d\n", a, b, c, d, e, f, g, h);
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
@@ -0,0 +1,54 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     ...
+//     jit-state: start-tracing
+//     4: 1 300 70000 5000000000
+//     jit-state: stop-tracing
+//     3: 2 600 140000 10000000000
+//     jit-state: enter-jit-code
+//     2: 3 900 210000 15000000000
+//     1: 4 1200 280000 20000000000
+//     jit-state: deoptimise
+//     exit: 4 1200 280000 20000000000
+
+// Check that live values of every integer size survive deoptimisation.
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int i = 4;
+  uint8_t c = 0;
+  uint16_t s = 0;
+  uint32_t n = 0;
+  uint64_t l = 0;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(c);
+  NOOPT_VAL(s);
+  NOOPT_VAL(n);
+  NOOPT_VAL(l);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    c += 1;
+    s += 300;
+    n += 70000;
+    l += 5000000000;
+    fprintf(stderr, "%d: %u %u %u %lu\n", i, c, s, n, l);
+    i--;
+  }
+  fprintf(stderr, "exit: %u %u %u %lu\n", c, s, n, l);
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
@@ -1,11 +1,27 @@
-// ignore: guards for indirect branches not implemented.
 // Run-time:
-//   env-var: YKD_PRINT_IR=jit-pre-opt
+//   env-var: YKD_SERIALISE_COMPILATION=1
 //   env-var: YKD_PRINT_JITSTATE=1
 //   stderr:
-//     ...
+//     jit-state: start-tracing
+//     i=5
+//     label3
+//     jit-state: stop-tracing
+//     i=4
+//     label3
+//     jit-state: enter-jit-code
+//     i=3
+//     label3
+//     i=2
+//     label3
+//     i=1
+//     jit-state: deoptimise
+//     jit-state: exit-jit-code
+//     label2
+//   stdout:
+//     exit
 
-// Check that tracing an `indirectbr` works.
+// Check that tracing an `indirectbr` works, and that a guard fails when it
+// jumps somewhere other than where it did in the trace.
 
 #include <assert.h>
 #include <stdio.h>
@@ -15,24 +31,32 @@
 #include <yk_testing.h>
 
 int main(int argc, char **argv) {
-  int loc = 0;
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
   int i = 5;
-  int idx = 1;
-  void *dispatch[] = {&&label1, &&label3, &&label2};
+  void *dispatch[] = {&&label1, &&label2, &&label3,
+                      &&label3, &&label3, &&label3};
+  NOOPT_VAL(loc);
   NOOPT_VAL(i);
-  NOOPT_VAL(idx);
   while (i > 0) {
-    yk_mt_control_point(loc);
+    yk_mt_control_point(mt, &loc);
     fprintf(stderr, "i=%d\n", i);
-    goto *dispatch[idx];
+    goto *dispatch[i];
   label1:
     abort(); // unreachable.
   label2:
-    abort(); // unreachable.
+    fprintf(stderr, "label2\n");
+    i--;
+    continue;
   label3:
+    fprintf(stderr, "label3\n");
     i--;
   }
-  abort(); // FIXME: unreachable due to aborting guard failure earlier.
+  printf("exit");
 
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
   return (EXIT_SUCCESS);
 }
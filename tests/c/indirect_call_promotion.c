@@ -0,0 +1,58 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     jit-state: start-tracing
+//     i=4, z=1
+//     jit-state: stop-tracing
+//     i=3, z=2
+//     jit-state: enter-jit-code
+//     i=2, z=3
+//     jit-state: deoptimise
+//     jit-state: exit-jit-code
+//     i=1, z=2
+//   stdout:
+//     exit
+
+// Check that an indirect call is inlined, and that a guard fails when a
+// different function is called than in the trace.
+
+#include <assert.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+__attribute__((noinline)) int add1(int a) {
+  NOOPT_VAL(a);
+  return a + 1;
+}
+
+__attribute__((noinline)) int sub1(int a) {
+  NOOPT_VAL(a);
+  return a - 1;
+}
+
+int (*ops[])(int) = {add1, sub1};
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int z = 0, i = 4;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(z);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    z = ops[i == 1](z);
+    fprintf(stderr, "i=%d, z=%d\n", i, z);
+    i--;
+  }
+  printf("exit");
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
@@ -0,0 +1,62 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     jit-state: start-tracing
+//     i=4, z=1, y=2
+//     jit-state: stop-tracing
+//     i=3, z=2, y=4
+//     jit-state: enter-jit-code
+//     i=2, z=3, y=6
+//     jit-state: deoptimise
+//     jit-state: exit-jit-code
+//     i=1, z=13, y=26
+//   stdout:
+//     exit
+
+// Check that the result of a promoted indirect call can be used after the
+// call, including when a guard fails inside the inlined callee.
+
+#include <assert.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+__attribute__((noinline)) int add(int a, int i) {
+  NOOPT_VAL(a);
+  if (i == 1)
+    return a + 10;
+  return a + 1;
+}
+
+__attribute__((noinline)) int sub(int a, int i) {
+  NOOPT_VAL(a);
+  return a - i;
+}
+
+int (*ops[])(int, int) = {add, sub};
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  int y = 0, z = 0, i = 4;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  NOOPT_VAL(y);
+  NOOPT_VAL(z);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    z = ops[i == 0](z, i);
+    y = z * 2;
+    fprintf(stderr, "i=%d, z=%d, y=%d\n", i, z, y);
+    i--;
+  }
+  printf("exit");
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
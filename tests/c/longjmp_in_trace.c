@@ -0,0 +1,63 @@
+// Run-time:
+//   env-var: YKD_PRINT_IR=jit-pre-opt
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     setjmp: 0
+//     jit-state: start-tracing
+//     i=4
+//     setjmp: 1
+//     jit-state: stop-tracing
+//     --- Begin jit-pre-opt ---
+//     ...
+//       %{{rtn}} = call ptr @__ykrt_longjmp(ptr @longjmp, ptr @buf, i32 1)
+//       ret ptr %{{rtn}}
+//     ...
+//     --- End jit-pre-opt ---
+//     i=3
+//     setjmp: 1
+//     jit-state: enter-jit-code
+//     i=2
+//     jit-state: longjmp
+//     jit-state: exit-jit-code
+//     setjmp: 1
+//     jit-state: enter-jit-code
+//     i=1
+//     jit-state: longjmp
+//     jit-state: exit-jit-code
+//     setjmp: 1
+//   stdout:
+//     exit
+
+// Check that a trace can longjmp to a setjmp outside of the trace.
+
+#include <assert.h>
+#include <setjmp.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+jmp_buf buf;
+int i = 4;
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+
+  NOOPT_VAL(loc);
+  int r = setjmp(buf);
+  fprintf(stderr, "setjmp: %d\n", r);
+  while (i > 0) {
+    yk_mt_control_point(mt, &loc);
+    fprintf(stderr, "i=%d\n", i);
+    i--;
+    longjmp(buf, 1);
+  }
+  printf("exit");
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
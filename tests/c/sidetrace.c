@@ -0,0 +1,61 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     jit-state: start-tracing
+//     i=0 even
+//     jit-state: stop-tracing
+//     i=1 odd
+//     jit-state: enter-jit-code
+//     i=2 even
+//     jit-state: start-side-tracing
+//     jit-state: deoptimise
+//     jit-state: exit-jit-code
+//     i=3 odd
+//     jit-state: stop-side-tracing
+//     i=4 even
+//     jit-state: enter-jit-code
+//     jit-state: enter-side-trace
+//     i=5 odd
+//     jit-state: exit-jit-code
+//     i=6 even
+//     jit-state: enter-jit-code
+//     jit-state: enter-side-trace
+//     i=7 odd
+//     jit-state: exit-jit-code
+//   stdout:
+//     exit
+
+// Check that a side-trace is compiled for a guard that fails often enough,
+// and that it is then executed instead of deoptimising.
+
+#include <assert.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  yk_mt_sidetrace_threshold_set(mt, 1);
+  YkLocation loc = yk_location_new();
+
+  int i = 0;
+  NOOPT_VAL(loc);
+  NOOPT_VAL(i);
+  while (i < 8) {
+    yk_mt_control_point(mt, &loc);
+    if (i % 2 == 0) {
+      fprintf(stderr, "i=%d even\n", i);
+    } else {
+      fprintf(stderr, "i=%d odd\n", i);
+    }
+    i++;
+  }
+  printf("exit");
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
@@ -0,0 +1,73 @@
+// Run-time:
+//   env-var: YKD_SERIALISE_COMPILATION=1
+//   env-var: YKD_PRINT_JITSTATE=1
+//   stderr:
+//     jit-state: start-tracing
+//     a: i=4
+//     jit-state: stop-tracing
+//     a: i=3
+//     jit-state: enter-jit-code
+//     a: i=2
+//     a: i=1
+//     jit-state: deoptimise
+//     ...
+//     jit-state: exit-jit-code
+//     jit-state: start-tracing
+//     b: j=4, s=1
+//     jit-state: stop-tracing
+//     b: j=3, s=3
+//     jit-state: enter-jit-code
+//     b: j=2, s=6
+//     b: j=1, s=10
+//     jit-state: deoptimise
+//     ...
+//     jit-state: exit-jit-code
+//   stdout:
+//     exit
+
+// Check that a location reached at more than one control point is traced,
+// and its traces executed, separately at each.
+
+#include <assert.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <yk.h>
+#include <yk_testing.h>
+
+void loop_a(YkMT *mt, YkLocation *loc) {
+  int i = 4;
+  NOOPT_VAL(i);
+  while (i > 0) {
+    yk_mt_control_point(mt, loc);
+    fprintf(stderr, "a: i=%d\n", i);
+    i--;
+  }
+}
+
+void loop_b(YkMT *mt, YkLocation *loc) {
+  long s = 0;
+  int j = 4;
+  NOOPT_VAL(s);
+  NOOPT_VAL(j);
+  while (j > 0) {
+    yk_mt_control_point(mt, loc);
+    s += 5 - j;
+    fprintf(stderr, "b: j=%d, s=%ld\n", j, s);
+    j--;
+  }
+}
+
+int main(int argc, char **argv) {
+  YkMT *mt = yk_mt_new(NULL);
+  yk_mt_hot_threshold_set(mt, 0);
+  YkLocation loc = yk_location_new();
+  NOOPT_VAL(loc);
+
+  loop_a(mt, &loc);
+  loop_b(mt, &loc);
+  printf("exit");
+
+  yk_location_drop(loc);
+  yk_mt_drop(mt);
+  return (EXIT_SUCCESS);
+}
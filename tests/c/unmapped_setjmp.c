@@ -1,5 +1,4 @@
 // Run-time:
-//   status: error
 //   env-var: YKD_SERIALISE_COMPILATION=1
 //   env-var: YKD_PRINT_JITSTATE=1
 //   stderr:
@@ -7,9 +6,11 @@
 //     set jump point
 //     jumped!
 //     jit-state: stop-tracing
+//     jit-state: trace-compilation-aborted
 //     ...
 
-// Check that we bork on a call to setjmp in unmapped code.
+// Check that a trace which calls longjmp in unmapped code is decoded, but
+// isn't compiled.
 
 #include <assert.h>
 #include <stdio.h>
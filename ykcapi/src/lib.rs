@@ -7,17 +7,96 @@
 //! other crates are regular `rlibs`.
 
 #![allow(clippy::missing_safety_doc)]
+#![feature(naked_functions)]
 
 use std::{
+    arch::asm,
+    error::Error,
     ffi::{c_char, c_void, CString},
     ptr,
+    sync::Arc,
+    time::Duration,
 };
-use ykrt::{HotThreshold, Location, MT};
+use ykrt::{
+    trace::{PerfCollectorConfig, TracerKind},
+    ControlPointId, HotThreshold, JitEvent, JitObserver, Location, MTBuilder,
+    TraceFailureThreshold, MT,
+};
+
+/// Values for `YkMTConfig::tracer_kind`. Must be kept in sync with `yk.h`.
+const YK_TRACER_DEFAULT: u32 = 0;
+const YK_TRACER_HW: u32 = 1;
+const YK_TRACER_SW: u32 = 2;
+
+/// The configuration of a new `MT`. A field with the value 0 takes its default value. Must be
+/// kept in sync with `YkMTConfig` in `yk.h`.
+#[repr(C)]
+pub struct YkMTConfig {
+    tracer_kind: u32,
+    hot_threshold: HotThreshold,
+    trace_failure_threshold: TraceFailureThreshold,
+    max_worker_threads: usize,
+    pt_data_bufsize: usize,
+    pt_aux_bufsize: usize,
+    pt_initial_trace_bufsize: usize,
+    max_trace_len: usize,
+    code_cache_budget: usize,
+}
 
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn yk_mt_new(err_msg: *mut *const c_char) -> *mut MT {
-    match MT::new() {
+    mt_or_err_msg(MT::new(), err_msg)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn yk_mt_new_with_config(
+    config: *const YkMTConfig,
+    err_msg: *mut *const c_char,
+) -> *mut MT {
+    let config = unsafe { &*config };
+    let mut mtb = MTBuilder::new();
+    match config.tracer_kind {
+        YK_TRACER_DEFAULT => (),
+        YK_TRACER_HW => mtb = mtb.tracer_kind(TracerKind::Hardware),
+        YK_TRACER_SW => mtb = mtb.tracer_kind(TracerKind::Software),
+        x => {
+            return mt_or_err_msg(Err(format!("Unknown tracer kind {x}").into()), err_msg);
+        }
+    }
+    if config.hot_threshold != 0 {
+        mtb = mtb.hot_threshold(config.hot_threshold);
+    }
+    if config.trace_failure_threshold != 0 {
+        mtb = mtb.trace_failure_threshold(config.trace_failure_threshold);
+    }
+    if config.max_worker_threads != 0 {
+        mtb = mtb.max_worker_threads(config.max_worker_threads);
+    }
+    if config.max_trace_len != 0 {
+        mtb = mtb.max_trace_len(config.max_trace_len);
+    }
+    if config.code_cache_budget != 0 {
+        mtb = mtb.code_cache_budget(config.code_cache_budget);
+    }
+    let mut perf_config = PerfCollectorConfig::default();
+    if config.pt_data_bufsize != 0 {
+        perf_config.data_bufsize = config.pt_data_bufsize;
+    }
+    if config.pt_aux_bufsize != 0 {
+        perf_config.aux_bufsize = config.pt_aux_bufsize;
+    }
+    if config.pt_initial_trace_bufsize != 0 {
+        perf_config.initial_trace_bufsize = config.pt_initial_trace_bufsize;
+    }
+    mt_or_err_msg(mtb.perf_collector_config(perf_config).build(), err_msg)
+}
+
+/// If `mt` is `Ok`, return a raw pointer to it. Otherwise, if `err_msg` is null, panic, or if it
+/// isn't, put a malloc()d error string in `*err_msg` and return null.
+fn mt_or_err_msg(mt: Result<MT, Box<dyn Error>>, err_msg: *mut *const c_char) -> *mut MT {
+    match mt {
         Ok(mt) => Box::into_raw(Box::new(mt)),
         Err(e) => {
             if err_msg.is_null() {
@@ -41,6 +120,15 @@ pub extern "C" fn yk_mt_drop(mt: *mut MT) {
     unsafe { Box::from_raw(mt) };
 }
 
+#[no_mangle]
+pub extern "C" fn yk_mt_shutdown(mt: &MT, timeout_ms: u64) -> bool {
+    let timeout = match timeout_ms {
+        u64::MAX => None,
+        x => Some(Duration::from_millis(x)),
+    };
+    mt.shutdown(timeout)
+}
+
 // The "dummy control point" that is replaced in an LLVM pass.
 #[no_mangle]
 pub extern "C" fn yk_mt_control_point(_mt: *mut MT, _loc: *mut Location) {
@@ -49,20 +137,44 @@ pub extern "C" fn yk_mt_control_point(_mt: *mut MT, _loc: *mut Location) {
 
 // The "real" control point, that is called once the interpreter has been patched by ykllvm.
 // Returns the address of a reconstructed stack or null if there wasn't a guard failure.
+//
+// An interpreter may call the control point from more than one place, so the address that this
+// call will return to is passed on to identify the call-site.
+#[cfg(target_arch = "x86_64")]
+#[naked]
 #[no_mangle]
-#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn __ykrt_control_point(
     mt: *mut MT,
     loc: *mut Location,
     ctrlp_vars: *mut c_void,
     // Frame address of caller.
     frameaddr: *mut c_void,
+) -> *const c_void {
+    unsafe {
+        asm!(
+            // The return address becomes the fifth argument. Jumping (rather than calling) leaves
+            // the stack as our caller left it.
+            "mov r8, [rsp]",
+            "jmp {}",
+            sym control_point,
+            options(noreturn)
+        )
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+extern "C" fn control_point(
+    mt: *mut MT,
+    loc: *mut Location,
+    ctrlp_vars: *mut c_void,
+    frameaddr: *mut c_void,
+    retaddr: usize,
 ) -> *const c_void {
     debug_assert!(!ctrlp_vars.is_null());
     if !loc.is_null() {
         let mt = unsafe { &*mt };
         let loc = unsafe { &*loc };
-        return mt.control_point(loc, ctrlp_vars, frameaddr);
+        return mt.control_point(loc, ControlPointId::new(retaddr), ctrlp_vars, frameaddr);
     }
     std::ptr::null()
 }
@@ -72,6 +184,193 @@ pub extern "C" fn yk_mt_hot_threshold_set(mt: &MT, hot_threshold: HotThreshold)
     mt.set_hot_threshold(hot_threshold);
 }
 
+#[no_mangle]
+pub extern "C" fn yk_mt_sidetrace_threshold_set(mt: &MT, sidetrace_threshold: HotThreshold) {
+    mt.set_sidetrace_threshold(sidetrace_threshold);
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_max_trace_len_set(mt: &MT, max_trace_len: usize) {
+    mt.set_max_trace_len(max_trace_len);
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_code_cache_budget_set(mt: &MT, code_cache_budget: usize) {
+    mt.set_code_cache_budget(code_cache_budget);
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_max_worker_threads_set(mt: &MT, max_worker_threads: usize) {
+    mt.set_max_worker_threads(max_worker_threads);
+}
+
+/// A C mirror of [ykrt::MTStats]. Must be kept in sync with `YkMTStats` in `yk.h`.
+#[repr(C)]
+pub struct YkMTStats {
+    locations_hot: u64,
+    traces_started: u64,
+    traces_aborted: u64,
+    traces_compiled: u64,
+    trace_compilations_aborted: u64,
+    sidetraces_compiled: u64,
+    sidetraces_aborted: u64,
+    trace_executions: u64,
+    deopts: u64,
+    compile_time_ns: u64,
+    job_queue_len: usize,
+    traces_evicted: u64,
+    code_cache_size: usize,
+    traces_invalidated: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_stats(mt: &MT, stats: &mut YkMTStats) {
+    let st = mt.stats();
+    *stats = YkMTStats {
+        locations_hot: st.locations_hot,
+        traces_started: st.traces_started,
+        traces_aborted: st.traces_aborted,
+        traces_compiled: st.traces_compiled,
+        trace_compilations_aborted: st.trace_compilations_aborted,
+        sidetraces_compiled: st.sidetraces_compiled,
+        sidetraces_aborted: st.sidetraces_aborted,
+        trace_executions: st.trace_executions,
+        deopts: st.deopts,
+        compile_time_ns: u64::try_from(st.compile_time.as_nanos()).unwrap_or(u64::MAX),
+        job_queue_len: st.job_queue_len,
+        traces_evicted: st.traces_evicted,
+        code_cache_size: st.code_cache_size,
+        traces_invalidated: st.traces_invalidated,
+    };
+}
+
+/// Values for `YkEvent::kind`. Must be kept in sync with `yk.h`.
+const YK_EVENT_START_TRACING: u32 = 0;
+const YK_EVENT_STOP_TRACING: u32 = 1;
+const YK_EVENT_TRACING_ABORTED: u32 = 2;
+const YK_EVENT_TRACE_COMPILED: u32 = 3;
+const YK_EVENT_TRACE_COMPILATION_ABORTED: u32 = 4;
+const YK_EVENT_ENTER_JIT_CODE: u32 = 5;
+const YK_EVENT_EXIT_JIT_CODE: u32 = 6;
+const YK_EVENT_START_SIDE_TRACING: u32 = 7;
+const YK_EVENT_STOP_SIDE_TRACING: u32 = 8;
+const YK_EVENT_SIDE_TRACE_COMPILED: u32 = 9;
+const YK_EVENT_SIDE_TRACE_ABORTED: u32 = 10;
+const YK_EVENT_ENTER_SIDE_TRACE: u32 = 11;
+const YK_EVENT_DEOPTIMISE: u32 = 12;
+const YK_EVENT_TRACE_EVICTED: u32 = 13;
+const YK_EVENT_TRACE_INVALIDATED: u32 = 14;
+
+/// A C mirror of [JitEvent]. Fields which aren't relevant to an event's kind are 0 (or null).
+/// Must be kept in sync with `YkEvent` in `yk.h`.
+#[repr(C)]
+pub struct YkEvent {
+    kind: u32,
+    location: usize,
+    guard_id: usize,
+    duration_ns: u64,
+    reason: *const c_char,
+}
+
+type YkEventCallback = extern "C" fn(event: *const YkEvent, data: *mut c_void);
+
+/// Forwards [JitEvent]s to a C callback.
+struct CallbackObserver {
+    callback: YkEventCallback,
+    data: *mut c_void,
+}
+
+// It is the C caller's responsibility to ensure that `data` can be used from any thread.
+unsafe impl Send for CallbackObserver {}
+unsafe impl Sync for CallbackObserver {}
+
+impl JitObserver for CallbackObserver {
+    fn event(&self, event: &JitEvent) {
+        let mut ev = YkEvent {
+            kind: 0,
+            location: 0,
+            guard_id: 0,
+            duration_ns: 0,
+            reason: ptr::null(),
+        };
+        // Must outlive the call to `callback`, as `ev.reason` may point into it.
+        let reason;
+        match event {
+            JitEvent::StartTracing(loc) => {
+                ev.kind = YK_EVENT_START_TRACING;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::StopTracing(loc) => {
+                ev.kind = YK_EVENT_STOP_TRACING;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::TracingAborted { loc, reason: r } => {
+                reason = CString::new(r.to_string()).unwrap();
+                ev.kind = YK_EVENT_TRACING_ABORTED;
+                ev.location = loc.as_usize();
+                ev.reason = reason.as_ptr();
+            }
+            JitEvent::TraceCompiled { loc, duration } => {
+                ev.kind = YK_EVENT_TRACE_COMPILED;
+                ev.location = loc.as_usize();
+                ev.duration_ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            }
+            JitEvent::TraceCompilationAborted { loc, duration } => {
+                ev.kind = YK_EVENT_TRACE_COMPILATION_ABORTED;
+                ev.location = loc.as_usize();
+                ev.duration_ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            }
+            JitEvent::TraceEvicted(loc) => {
+                ev.kind = YK_EVENT_TRACE_EVICTED;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::TraceInvalidated(loc) => {
+                ev.kind = YK_EVENT_TRACE_INVALIDATED;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::EnterJitCode(loc) => {
+                ev.kind = YK_EVENT_ENTER_JIT_CODE;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::ExitJitCode(loc) => {
+                ev.kind = YK_EVENT_EXIT_JIT_CODE;
+                ev.location = loc.as_usize();
+            }
+            JitEvent::StartSideTracing { guardid } => {
+                ev.kind = YK_EVENT_START_SIDE_TRACING;
+                ev.guard_id = *guardid;
+            }
+            JitEvent::StopSideTracing { guardid } => {
+                ev.kind = YK_EVENT_STOP_SIDE_TRACING;
+                ev.guard_id = *guardid;
+            }
+            JitEvent::SideTraceCompiled { guardid, duration } => {
+                ev.kind = YK_EVENT_SIDE_TRACE_COMPILED;
+                ev.guard_id = *guardid;
+                ev.duration_ns = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            }
+            JitEvent::SideTraceAborted { guardid } => {
+                ev.kind = YK_EVENT_SIDE_TRACE_ABORTED;
+                ev.guard_id = *guardid;
+            }
+            JitEvent::EnterSideTrace { guardid } => {
+                ev.kind = YK_EVENT_ENTER_SIDE_TRACE;
+                ev.guard_id = *guardid;
+            }
+            JitEvent::Deoptimise { guardid } => {
+                ev.kind = YK_EVENT_DEOPTIMISE;
+                ev.guard_id = *guardid;
+            }
+        }
+        (self.callback)(&ev, self.data);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_event_callback_add(mt: &MT, callback: YkEventCallback, data: *mut c_void) {
+    mt.add_observer(Arc::new(CallbackObserver { callback, data }));
+}
+
 #[no_mangle]
 pub extern "C" fn yk_location_new() -> Location {
     Location::new()
@@ -81,3 +380,13 @@ pub extern "C" fn yk_location_new() -> Location {
 pub extern "C" fn yk_location_drop(loc: Location) {
     drop(loc)
 }
+
+#[no_mangle]
+pub extern "C" fn yk_location_invalidate(mt: &MT, loc: &Location) {
+    mt.invalidate(loc);
+}
+
+#[no_mangle]
+pub extern "C" fn yk_mt_invalidate_all(mt: &MT) {
+    mt.invalidate_all();
+}
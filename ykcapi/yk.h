@@ -1,6 +1,8 @@
 #ifndef YK_H
 #define YK_H
 
+#include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 // A `Location` stores state that the meta-tracer needs to identify hot loops
@@ -28,6 +30,45 @@ typedef uint32_t YkHotThreshold;
 
 typedef struct YkMT YkMT;
 
+// Values for `YkMTConfig.tracer_kind`.
+#define YK_TRACER_DEFAULT 0 // Hardware tracing if supported, else software.
+#define YK_TRACER_HW 1      // Hardware tracing (Intel PT).
+#define YK_TRACER_SW 2      // Software tracing.
+
+// The configuration of a new `YkMT` instance, for use with
+// `yk_mt_new_with_config`. A field with the value 0 takes its default value,
+// so a zero-initialised `YkMTConfig` gives the same configuration as
+// `yk_mt_new`.
+//
+// If the `YKD_TRACER` environment variable is set, it overrides `tracer_kind`.
+typedef struct {
+  // Which tracer to use (one of the `YK_TRACER_*` values above).
+  uint32_t tracer_kind;
+  // The threshold at which `YkLocation`'s are considered hot. Use
+  // `yk_mt_hot_threshold_set` to set a hot threshold of 0.
+  YkHotThreshold hot_threshold;
+  // The number of times tracing a `YkLocation` can fail before it is no
+  // longer traced.
+  uint16_t trace_failure_threshold;
+  // The maximum number of compilation worker threads.
+  size_t max_worker_threads;
+  // The size of the hardware tracer's data buffer, in pages. Must be a power
+  // of 2.
+  size_t pt_data_bufsize;
+  // The size of the hardware tracer's AUX buffer, in pages. Must be a power of
+  // 2.
+  size_t pt_aux_bufsize;
+  // The initial size (in bytes) of the hardware tracer's trace storage buffer.
+  size_t pt_initial_trace_bufsize;
+  // The length beyond which a trace is abandoned: in blocks for the software
+  // tracer (default 100000) and bytes of trace data for the hardware tracer
+  // (default 1MiB).
+  size_t max_trace_len;
+  // The number of bytes that compiled traces can use before some of them are
+  // evicted.
+  size_t code_cache_budget;
+} YkMTConfig;
+
 // Create a new `YkMT` instance. If this fails then:
 //   * If `err_msg` is `NULL`, this function will abort.
 //   * If `err_msg` is not `NULL`:
@@ -37,20 +78,141 @@ typedef struct YkMT YkMT;
 //       2. `yk_mt_new` will return `NULL`.
 YkMT *yk_mt_new(char **err_msg);
 
+// Create a new `YkMT` instance configured by `config`. Errors are handled as
+// for `yk_mt_new`.
+YkMT *yk_mt_new_with_config(const YkMTConfig *config, char **err_msg);
+
 // Drop a `YkMT` instance. This must be called at most once per `YkMT`
 // instance: calling this function more than once on a `YkMT` instance leads to
 // undefined behaviour.
 void yk_mt_drop(YkMT *);
 
+// Stop compiling traces and shut down the compilation worker threads of a
+// `YkMT` instance. Queued compilation jobs are discarded and in-progress ones
+// are allowed to finish. Waits for up to `timeout_ms` milliseconds (or
+// indefinitely if `timeout_ms` is `YK_SHUTDOWN_WAIT_FOREVER`) for the worker
+// threads to exit, returning true if they all did. A `timeout_ms` of 0 doesn't
+// wait at all. Previously compiled traces can still be executed.
+//
+// `yk_mt_drop` implicitly calls this function, waiting indefinitely.
+bool yk_mt_shutdown(YkMT *, uint64_t timeout_ms);
+
+// The `timeout_ms` that makes `yk_mt_shutdown` wait indefinitely.
+#define YK_SHUTDOWN_WAIT_FOREVER UINT64_MAX
+
 // Notify yk that an iteration of an interpreter loop is about to start. The
 // argument passed uniquely identifies the current location in the user's
 // program. A call to this function may cause yk to start/stop tracing, or to
 // execute JITted code.
+//
+// A program may call this function from more than one place (e.g. once in
+// each of several interpreter loops). A `YkLocation` passed to more than one
+// of those call sites is traced, and its traces executed, separately at each.
 void yk_mt_control_point(YkMT *, YkLocation *);
 
 // Set the threshold at which `YkLocation`'s are considered hot.
 void yk_mt_hot_threshold_set(YkMT *, YkHotThreshold);
 
+// Set the number of times a guard in a compiled trace must fail before a
+// side-trace is traced from it.
+void yk_mt_sidetrace_threshold_set(YkMT *, YkHotThreshold);
+
+// Set the length beyond which a trace is abandoned and counted as a failed
+// attempt at tracing its `YkLocation`. This is measured in blocks for the
+// software tracer and bytes of trace data for the hardware tracer. Each tracer
+// has its own default (see `YkMTConfig.max_trace_len`).
+void yk_mt_max_trace_len_set(YkMT *, size_t);
+
+// Set the number of bytes that compiled traces can use before some of them
+// are evicted. Evicted traces' memory is freed once no thread is executing
+// them, and their `YkLocation`s must become hot again before being retraced.
+void yk_mt_code_cache_budget_set(YkMT *, size_t);
+
+// Set the maximum number of compilation worker threads. Must be >= 1.
+void yk_mt_max_worker_threads_set(YkMT *, size_t);
+
+// A snapshot of a `YkMT` instance's statistics, as filled in by
+// `yk_mt_stats`.
+typedef struct {
+  // How many `YkLocation`s have become hot.
+  uint64_t locations_hot;
+  // How many traces (not including side-traces) have started to be collected.
+  uint64_t traces_started;
+  // How many traces were abandoned before they could be compiled.
+  uint64_t traces_aborted;
+  // How many traces have been successfully compiled.
+  uint64_t traces_compiled;
+  // How many traces failed to compile.
+  uint64_t trace_compilations_aborted;
+  // How many side-traces have been successfully compiled.
+  uint64_t sidetraces_compiled;
+  // How many side-traces were abandoned, at any stage.
+  uint64_t sidetraces_aborted;
+  // How many times compiled traces (including side-traces) have been entered.
+  uint64_t trace_executions;
+  // How many times a guard failure has deoptimised back to the interpreter.
+  uint64_t deopts;
+  // The total time spent by worker threads mapping and compiling traces, in
+  // nanoseconds.
+  uint64_t compile_time_ns;
+  // How many jobs were waiting for a worker thread.
+  size_t job_queue_len;
+  // How many compiled traces have been evicted from the code cache.
+  uint64_t traces_evicted;
+  // How many bytes of code and data are used by the compiled traces in the
+  // code cache.
+  size_t code_cache_size;
+  // How many compiled traces have been invalidated.
+  uint64_t traces_invalidated;
+} YkMTStats;
+
+// Fill `stats` in with a snapshot of the statistics of a `YkMT` instance.
+void yk_mt_stats(YkMT *, YkMTStats *stats);
+
+// Values for `YkEvent.kind`.
+#define YK_EVENT_START_TRACING 0
+#define YK_EVENT_STOP_TRACING 1
+#define YK_EVENT_TRACING_ABORTED 2
+#define YK_EVENT_TRACE_COMPILED 3
+#define YK_EVENT_TRACE_COMPILATION_ABORTED 4
+#define YK_EVENT_ENTER_JIT_CODE 5
+#define YK_EVENT_EXIT_JIT_CODE 6
+#define YK_EVENT_START_SIDE_TRACING 7
+#define YK_EVENT_STOP_SIDE_TRACING 8
+#define YK_EVENT_SIDE_TRACE_COMPILED 9
+#define YK_EVENT_SIDE_TRACE_ABORTED 10
+#define YK_EVENT_ENTER_SIDE_TRACE 11
+#define YK_EVENT_DEOPTIMISE 12
+#define YK_EVENT_TRACE_EVICTED 13
+#define YK_EVENT_TRACE_INVALIDATED 14
+
+// A JIT event, as passed to a `YkEventCallback`. Fields which aren't relevant
+// to an event's kind are 0 (or `NULL`).
+typedef struct {
+  // What happened (one of the `YK_EVENT_*` values above).
+  uint32_t kind;
+  // An identifier for the `YkLocation` concerned. This is stable for as long
+  // as the `YkLocation` is hot, even if the `YkLocation` is moved, but may be
+  // reused once it has been dropped. A `YkLocation` passed to more than one
+  // call site of `yk_mt_control_point` has a different identifier at each.
+  uintptr_t location;
+  // The ID of the guard concerned.
+  size_t guard_id;
+  // How long compilation took, in nanoseconds.
+  uint64_t duration_ns;
+  // Why tracing was aborted. Only valid for the duration of the callback.
+  const char *reason;
+} YkEvent;
+
+typedef void (*YkEventCallback)(const YkEvent *event, void *data);
+
+// Register `callback` to be called, with `data` as its second argument, for
+// every subsequent JIT event. Callbacks are called synchronously on whichever
+// thread caused the event (which may be a compilation worker thread), so they
+// must be thread-safe and should return quickly. A callback may itself call
+// this function: the new callback is called from the next event onwards.
+void yk_mt_event_callback_add(YkMT *, YkEventCallback callback, void *data);
+
 // Create a new `Location`.
 //
 // Note that a `Location` created by this call must not simply be discarded:
@@ -63,4 +225,15 @@ YkLocation yk_location_new(void);
 // will occur.
 void yk_location_drop(YkLocation);
 
+// Invalidate the compiled traces of a `Location` (e.g. because code they
+// depend on has changed), so that the `Location` is traced afresh once it has
+// become hot again. Threads already executing a trace finish doing so. If the
+// `Location` is being traced, or a trace of it compiled, that trace is
+// discarded.
+void yk_location_invalidate(YkMT *, YkLocation *);
+
+// Invalidate every compiled trace of a `YkMT` instance, and discard all traces
+// currently being collected or compiled.
+void yk_mt_invalidate_all(YkMT *);
+
 #endif
@@ -0,0 +1,253 @@
+//! A bounded cache of compiled traces.
+
+use crate::{
+    location::{HotLocation, HotLocationKind, LocationId},
+    trace::CompiledTrace,
+};
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Weak,
+};
+
+/// Keeps track of the compiled traces of an `MT` and, when they (and their side-traces) use more
+/// than a budgeted number of bytes, evicts some of them. An evicted trace's [HotLocation] starts
+/// counting again, and the trace's memory is freed once no thread is executing it.
+pub(crate) struct CodeCache {
+    /// The number of bytes that compiled traces may use before some are evicted.
+    budget: AtomicUsize,
+    entries: Mutex<Vec<Entry>>,
+}
+
+/// A compiled trace in the cache. Neither the trace nor its location are kept alive by the cache.
+struct Entry {
+    hl: Weak<Mutex<HotLocation>>,
+    ctr: Weak<CompiledTrace>,
+}
+
+impl CodeCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget: AtomicUsize::new(budget),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, Ordering::Relaxed);
+    }
+
+    /// Return the number of bytes used by the traces in the cache.
+    pub(crate) fn size(&self) -> usize {
+        self.entries
+            .lock()
+            .iter()
+            .filter_map(|e| e.ctr.upgrade())
+            .map(|ctr| ctr.size())
+            .sum()
+    }
+
+    /// Add `ctr`, which has just been compiled for `hl`, to the cache. If the cache is then over
+    /// budget, evict other traces and return their locations. `ctr` itself is never evicted by
+    /// this call.
+    pub(crate) fn insert(
+        &self,
+        hl: &Arc<Mutex<HotLocation>>,
+        ctr: &Arc<CompiledTrace>,
+    ) -> Vec<LocationId> {
+        let mut entries = self.entries.lock();
+        entries.push(Entry {
+            hl: Arc::downgrade(hl),
+            ctr: Arc::downgrade(ctr),
+        });
+        self.evict(&mut entries, Some(ctr))
+    }
+
+    /// If the cache is over budget (e.g. because a side-trace has been added to a trace in the
+    /// cache), evict traces and return their locations.
+    pub(crate) fn shrink(&self) -> Vec<LocationId> {
+        let mut entries = self.entries.lock();
+        self.evict(&mut entries, None)
+    }
+
+    /// Evict traces, other than `keep`, until the cache is within budget. Traces which deoptimise
+    /// on most of their executions are evicted first, then those which have been executed least
+    /// often since the last eviction.
+    fn evict(
+        &self,
+        entries: &mut Vec<Entry>,
+        keep: Option<&Arc<CompiledTrace>>,
+    ) -> Vec<LocationId> {
+        // Forget about traces that have already been freed.
+        entries.retain(|e| e.ctr.strong_count() > 0);
+        let budget = self.budget();
+        let mut size = entries
+            .iter()
+            .filter_map(|e| e.ctr.upgrade())
+            .map(|ctr| ctr.size())
+            .sum::<usize>();
+        if size <= budget {
+            return Vec::new();
+        }
+
+        let mut cands = Vec::new();
+        for (i, e) in entries.iter().enumerate() {
+            if let Some(ctr) = e.ctr.upgrade() {
+                if keep.is_some_and(|k| Arc::ptr_eq(k, &ctr)) {
+                    continue;
+                }
+                let (execs, deopts) = ctr.take_counts();
+                let deopt_heavy = deopts > 0 && deopts.saturating_mul(2) > execs;
+                cands.push((!deopt_heavy, execs, i, ctr));
+            }
+        }
+        cands.sort_by_key(|(not_deopt_heavy, execs, i, _)| (*not_deopt_heavy, *execs, *i));
+
+        let mut evicted = Vec::new();
+        let mut evicted_idxs = Vec::new();
+        for (_, _, i, ctr) in cands {
+            if size <= budget {
+                break;
+            }
+            size -= ctr.size();
+            evicted_idxs.push(i);
+            if let Some(hl) = entries[i].hl.upgrade() {
+                let mut lk = hl.lock();
+                // The location may since have been (say) invalidated and re-traced, in which case
+                // the trace we're evicting is no longer its trace.
+                if let HotLocationKind::Compiled(ref cur) = lk.kind {
+                    if Arc::ptr_eq(cur, &ctr) {
+                        lk.reprofile();
+                        evicted.push(LocationId::new(&hl));
+                    }
+                }
+            }
+        }
+        let mut i = 0;
+        entries.retain(|_| {
+            i += 1;
+            !evicted_idxs.contains(&(i - 1))
+        });
+        evicted
+    }
+
+    /// Reprofile the location of every trace in the cache, empty the cache, and return the
+    /// locations that were reprofiled. Each trace's memory is freed once no thread is executing it.
+    pub(crate) fn invalidate_all(&self) -> Vec<LocationId> {
+        let mut invalidated = Vec::new();
+        for e in self.entries.lock().drain(..) {
+            if let (Some(hl), Some(ctr)) = (e.hl.upgrade(), e.ctr.upgrade()) {
+                let mut lk = hl.lock();
+                if let HotLocationKind::Compiled(ref cur) = lk.kind {
+                    if Arc::ptr_eq(cur, &ctr) {
+                        lk.reprofile();
+                        invalidated.push(LocationId::new(&hl));
+                    }
+                }
+            }
+        }
+        invalidated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::location::ControlPointId;
+
+    fn compiled(size: usize) -> (Arc<Mutex<HotLocation>>, Arc<CompiledTrace>) {
+        let ctr = Arc::new(unsafe { CompiledTrace::new_null_sized(size) });
+        let hl = Arc::new(Mutex::new(HotLocation {
+            kind: HotLocationKind::Compiled(Arc::clone(&ctr)),
+            trace_failure: 0,
+            ctrlp: ControlPointId::new(0),
+            generation: 0,
+            others: Vec::new(),
+        }));
+        (hl, ctr)
+    }
+
+    fn is_compiled(hl: &Mutex<HotLocation>) -> bool {
+        matches!(hl.lock().kind, HotLocationKind::Compiled(_))
+    }
+
+    #[test]
+    fn within_budget() {
+        let cc = CodeCache::new(200);
+        let (hl1, ctr1) = compiled(100);
+        let (hl2, ctr2) = compiled(100);
+        assert!(cc.insert(&hl1, &ctr1).is_empty());
+        assert!(cc.insert(&hl2, &ctr2).is_empty());
+        assert_eq!(cc.size(), 200);
+        assert!(is_compiled(&hl1) && is_compiled(&hl2));
+    }
+
+    #[test]
+    fn cold_traces_are_evicted_first() {
+        let cc = CodeCache::new(250);
+        let (hl1, ctr1) = compiled(100);
+        let (hl2, ctr2) = compiled(100);
+        let (hl3, ctr3) = compiled(100);
+        cc.insert(&hl1, &ctr1);
+        cc.insert(&hl2, &ctr2);
+        for _ in 0..10 {
+            ctr1.inc_execs();
+        }
+        ctr2.inc_execs();
+        assert_eq!(cc.insert(&hl3, &ctr3), vec![LocationId::new(&hl2)]);
+        assert!(matches!(hl2.lock().kind, HotLocationKind::Counting(0)));
+        assert!(is_compiled(&hl1) && is_compiled(&hl3));
+        assert_eq!(cc.size(), 200);
+    }
+
+    #[test]
+    fn deoptimising_traces_are_evicted_first() {
+        let cc = CodeCache::new(250);
+        let (hl1, ctr1) = compiled(100);
+        let (hl2, ctr2) = compiled(100);
+        let (hl3, ctr3) = compiled(100);
+        cc.insert(&hl1, &ctr1);
+        cc.insert(&hl2, &ctr2);
+        for _ in 0..10 {
+            ctr1.inc_execs();
+            ctr1.inc_deopts();
+        }
+        ctr2.inc_execs();
+        assert_eq!(cc.insert(&hl3, &ctr3), vec![LocationId::new(&hl1)]);
+        assert!(is_compiled(&hl2) && is_compiled(&hl3));
+    }
+
+    #[test]
+    fn shrink_after_budget_change() {
+        let cc = CodeCache::new(200);
+        let (hl1, ctr1) = compiled(100);
+        let (hl2, ctr2) = compiled(100);
+        cc.insert(&hl1, &ctr1);
+        cc.insert(&hl2, &ctr2);
+        cc.set_budget(0);
+        assert_eq!(cc.shrink().len(), 2);
+        assert!(!is_compiled(&hl1) && !is_compiled(&hl2));
+    }
+
+    #[test]
+    fn invalidate_all() {
+        let cc = CodeCache::new(200);
+        let (hl1, ctr1) = compiled(100);
+        let (hl2, ctr2) = compiled(100);
+        cc.insert(&hl1, &ctr1);
+        cc.insert(&hl2, &ctr2);
+        assert_eq!(
+            cc.invalidate_all(),
+            vec![LocationId::new(&hl1), LocationId::new(&hl2)]
+        );
+        assert!(matches!(hl1.lock().kind, HotLocationKind::Counting(0)));
+        assert!(matches!(hl2.lock().kind, HotLocationKind::Counting(0)));
+        assert_eq!(cc.size(), 0);
+        // The traces themselves are still alive, as they are referenced from this thread.
+        assert_eq!(ctr1.size(), 100);
+    }
+}
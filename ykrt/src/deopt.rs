@@ -1,15 +1,27 @@
 //! Run-time deoptimisation support: when a guard fails, this module restores the state necessary
 //! to resume interpreter execution.
 
-use crate::frame::{FrameInfo, FrameReconstructor};
 #[cfg(feature = "yk_jitstate_debug")]
 use crate::print_jit_state;
-use std::{arch::asm, ffi::c_void, ptr, slice};
-use yksmp::{Location as SMLocation, StackMapParser};
+use crate::{
+    frame::{location_size, FrameInfo, NUM_XMM_REGS, XMM0_DWARF_NUM},
+    mt::{guard_failed, running_trace},
+};
+use std::{
+    arch::asm,
+    ffi::{c_int, c_void},
+    ptr, slice,
+};
+use yksmp::Location as SMLocation;
+
+/// The number of general purpose registers saved by [__llvm_deoptimize].
+#[cfg(target_arch = "x86_64")]
+const NUM_SAVED_GPRS: u16 = 16;
 
 /// Reads out registers spilled to the stack of the previous frame during the deoptimisation
-/// routine. The order of the registers are in accordance to the DWARF register number mapping
-/// referenced in the SystemV ABI manual (https://uclibc.org/docs/psABI-x86_64.pdf).
+/// routine. The general purpose registers are spilled in the order of the DWARF register number
+/// mapping referenced in the SystemV ABI manual (https://uclibc.org/docs/psABI-x86_64.pdf), lowest
+/// number first, followed by the SSE registers XMM0 to XMM15.
 struct Registers {
     addr: *const usize,
 }
@@ -33,19 +45,61 @@ impl Registers {
     /// register's value.
     #[cfg(target_arch = "x86_64")]
     unsafe fn get(&self, id: u16) -> usize {
-        if id > 7 {
+        if id >= NUM_SAVED_GPRS {
             unreachable!(
                 "Register #{} currently not saved during deoptimisation.",
                 id
             )
         }
-        let val = self.read_from_stack(id.try_into().unwrap());
-        // Due to the return address being pushed to the stack before we store RSP, its value is
-        // off by 8 bytes.
-        if id == 7 {
-            todo!(); // Check this is still true now that llvm_deoptimize is a naked function.
+        self.read_from_stack(id.try_into().unwrap())
+    }
+
+    /// Retrieve the previous frame's value of the SSE register with DWARF register number `id`.
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn get_sse(&self, id: u16) -> u128 {
+        if !(XMM0_DWARF_NUM..XMM0_DWARF_NUM + NUM_XMM_REGS).contains(&id) {
+            unreachable!("Register #{} is not an SSE register.", id)
+        }
+        // Each SSE register takes up two words.
+        let off = isize::from(NUM_SAVED_GPRS) + 2 * isize::from(id - XMM0_DWARF_NUM);
+        ptr::read_unaligned(self.addr.offset(off) as *const u128)
+    }
+}
+
+/// Read the `size`-byte value at `addr`, zero-extending it to 128 bits.
+#[cfg(target_arch = "x86_64")]
+unsafe fn read_sized(addr: *const u8, size: u16) -> u128 {
+    let size = usize::from(size);
+    assert!(size <= 16, "can't deoptimise a {size}-byte value");
+    let mut buf = [0; 16];
+    ptr::copy_nonoverlapping(addr, buf.as_mut_ptr(), size);
+    u128::from_le_bytes(buf)
+}
+
+/// Read the value of the stackmap location `loc`.
+#[cfg(target_arch = "x86_64")]
+unsafe fn read_location(registers: &Registers, loc: &SMLocation) -> u128 {
+    match loc {
+        SMLocation::Register(reg, _size, _off, _extra) => {
+            if *reg >= XMM0_DWARF_NUM {
+                registers.get_sse(*reg)
+            } else {
+                registers.get(*reg) as u128
+            }
+        }
+        SMLocation::Direct(reg, off, _size) => {
+            // The value is the address itself (e.g. of an alloca).
+            let addr = registers.get(*reg) as *mut u8;
+            addr.offset(isize::try_from(*off).unwrap()) as u128
+        }
+        SMLocation::Indirect(reg, off, size) => {
+            let addr = registers.get(*reg) as *mut u8;
+            read_sized(addr.offset(isize::try_from(*off).unwrap()), *size)
         }
-        val
+        // Small constants are stored sign-extended to 32 bits, so we sign-extend them the rest of
+        // the way.
+        SMLocation::Constant(v) => *v as i32 as i64 as u128,
+        SMLocation::LargeConstant(v) => u128::from(*v),
     }
 }
 
@@ -53,11 +107,11 @@ impl Registers {
 /// variable in the AOT module. Mirrors the LLVM struct defined in yktracec/jitmodbuilder.cc.
 #[derive(Debug)]
 #[repr(C)]
-struct AOTVar {
-    bbidx: usize,
-    instridx: usize,
-    fname: *const i8,
-    sfidx: usize,
+pub(crate) struct AOTVar {
+    pub(crate) bbidx: usize,
+    pub(crate) instridx: usize,
+    pub(crate) fname: *const i8,
+    pub(crate) sfidx: usize,
 }
 
 /// Address and length of a vector. Mirrors the struct defined in
@@ -103,10 +157,39 @@ extern "C" fn __llvm_deoptimize(
     // stackmap).
     unsafe {
         asm!(
-            // Save registers that may be referenced by the stackmap to the stack before they get
-            // overwritten, so that we can read their values later during deoptimisation.
-            // FIXME: Add other registers that may be referenced by the stackmap.
+            // Save the SSE registers, which may hold floating point live values.
+            "sub rsp, 256",
+            "movdqu [rsp], xmm0",
+            "movdqu [rsp+16], xmm1",
+            "movdqu [rsp+32], xmm2",
+            "movdqu [rsp+48], xmm3",
+            "movdqu [rsp+64], xmm4",
+            "movdqu [rsp+80], xmm5",
+            "movdqu [rsp+96], xmm6",
+            "movdqu [rsp+112], xmm7",
+            "movdqu [rsp+128], xmm8",
+            "movdqu [rsp+144], xmm9",
+            "movdqu [rsp+160], xmm10",
+            "movdqu [rsp+176], xmm11",
+            "movdqu [rsp+192], xmm12",
+            "movdqu [rsp+208], xmm13",
+            "movdqu [rsp+224], xmm14",
+            "movdqu [rsp+240], xmm15",
+            // Save all of the general purpose registers, since any of them may be referenced by
+            // the stackmap, in reverse DWARF register number order. The register numbered `n`
+            // then ends up `n` words above RSP, with the SSE registers above them.
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            // Save the previous frame's RSP. `push rsp` stores RSP as it was before the push, so
+            // account for the registers saved so far and for our return address.
             "push rsp",
+            "add qword ptr [rsp], 328",
             "push rbp",
             "push rdi",
             "push rsi",
@@ -114,6 +197,8 @@ extern "C" fn __llvm_deoptimize(
             "push rcx",
             "push rdx",
             "push rax",
+            // Point R9 (the 6th argument of __ykrt_deopt) at the saved registers.
+            "mov r9, rsp",
             "push r8", // Push guardcount as 7th argument onto stack.
             // Now we need to call __ykrt_deopt. The arguments need to be in RDI, RSI, RDX,
             // RCX, R8, and R9. The first four arguments (stackmap,
@@ -122,11 +207,9 @@ extern "C" fn __llvm_deoptimize(
             // arguments. The remaining arguments (return address and current stack
             // pointer) need to be in R8 and R9. The return address was at [RSP] before
             // the above pushes, so to find it we need to offset 8 bytes per push.
-            "mov r8, [rsp+72]",
-            "mov r9, rsp",
-            //"sub rsp, 8", // Alignment
+            "mov r8, [rsp+392]",
             "call __ykrt_deopt",
-            "add rsp, 80",
+            "add rsp, 392",
             // FIXME: Don't rely on RBP being pushed. Use frame size retrieved from
             // stackmap instead.
             "mov rsp, rbp",
@@ -137,13 +220,14 @@ extern "C" fn __llvm_deoptimize(
     }
 }
 
-/// Called when a guard failure occurs. Handles the reading of stackmaps, matching of JIT to AOT
-/// variables, etc., in order to reconstruct the stack.
+/// Called when a guard failure occurs. If the failing guard has a side-trace, executes it.
+/// Otherwise handles the reading of stackmaps, matching of JIT to AOT variables, etc., in order to
+/// reconstruct the stack.
 #[cfg(target_arch = "x86_64")]
 #[no_mangle]
 unsafe extern "C" fn __ykrt_deopt(
-    // Address and size of the JIT stackmap.
-    stackmap: &CVec,
+    // Address and size of the JIT stackmap. This was already parsed when the trace was created.
+    _stackmap: &CVec,
     // Struct describing the location of the AOT live variables.
     aotvals: &LiveAOTVals,
     // Address and size of vector holding active AOT frame information needed to recreate them.
@@ -157,12 +241,6 @@ unsafe extern "C" fn __ykrt_deopt(
     // ID of the failing guard.
     guardid: usize,
 ) -> *const c_void {
-    #[cfg(feature = "yk_jitstate_debug")]
-    print_jit_state("deoptimise");
-
-    // FIXME: Check here if we have a side trace and execute it. Otherwise just increment the guard
-    // failure counter.
-
     // Parse the live AOT values.
     let aotvalsptr =
         unsafe { (aotvals.addr as *const u8).offset(isize::try_from(aotvals.offset).unwrap()) };
@@ -177,85 +255,88 @@ unsafe extern "C" fn __ykrt_deopt(
     // Restore saved registers from the stack.
     let registers = Registers::from_ptr(rsp);
 
-    let mut framerec = unsafe { FrameReconstructor::new(activeframes) };
-
-    // Parse the stackmap of the JIT module.
-    // OPT: Parsing the stackmap and initialising `framerec` is slow and could be heavily reduced
-    // by caching the result.
-    let slice = unsafe { slice::from_raw_parts(stackmap.addr as *mut u8, stackmap.length) };
-    let map = StackMapParser::parse(slice).unwrap();
-    let live_vars = map.get(&retaddr.try_into().unwrap()).unwrap();
+    // Find the live variables of the JIT stackmap record for this guard.
+    let ctr = running_trace();
+    let live_vars = ctr.live_vars(retaddr);
 
     // Extract live values from the stackmap.
     // Skip first live variable that contains 3 unrelated locations (CC, Flags, Num Deopts).
-    for (i, locs) in live_vars.iter().skip(1).enumerate() {
-        // We currently assume that live variables have at most one location. We currently encode
-        // extra locations inside a single location. But we are running out of space so we might
-        // need to extend stackmaps later to allow for multiple locations per variable.
-        assert!(locs.len() == 1);
-        let l = locs.get(0).unwrap();
-        match l {
-            SMLocation::Register(reg, _size, _off, _extra) => {
-                let _val = unsafe { registers.get(*reg) };
-                todo!();
-            }
-            SMLocation::Direct(reg, off, _size) => {
-                // When using `llvm.experimental.deoptimize` then direct locations should always be
-                // in relation to RBP.
-                assert_eq!(*reg, 6);
-                let addr = unsafe { registers.get(*reg) as *mut u8 };
-                let addr = unsafe { addr.offset(isize::try_from(*off).unwrap()) };
-                let aot = &aotvals[i];
-                unsafe {
-                    framerec.var_init(
-                        aot.bbidx,
-                        aot.instridx,
-                        std::ffi::CStr::from_ptr(aot.fname),
-                        aot.sfidx,
-                        addr as u64,
-                    );
-                }
-            }
-            SMLocation::Indirect(reg, off, size) => {
-                let addr = unsafe { registers.get(*reg) as *mut u8 };
-                let addr = unsafe { addr.offset(isize::try_from(*off).unwrap()) };
-                let v = match *size {
-                    1 => unsafe { ptr::read::<u8>(addr as *mut u8) as u64 },
-                    2 => unsafe { ptr::read::<u16>(addr as *mut u16) as u64 },
-                    4 => unsafe { ptr::read::<u32>(addr as *mut u32) as u64 },
-                    8 => unsafe { ptr::read::<u64>(addr as *mut u64) },
-                    _ => unreachable!(),
-                };
-                let aot = &aotvals[i];
-                unsafe {
-                    framerec.var_init(
-                        aot.bbidx,
-                        aot.instridx,
-                        std::ffi::CStr::from_ptr(aot.fname),
-                        aot.sfidx,
-                        v,
-                    );
-                }
-            }
-            SMLocation::Constant(v) => {
-                let aot = &aotvals[i];
-                unsafe {
-                    framerec.var_init(
-                        aot.bbidx,
-                        aot.instridx,
-                        std::ffi::CStr::from_ptr(aot.fname),
-                        aot.sfidx,
-                        *v as u64,
-                    );
-                }
-            }
-            SMLocation::LargeConstant(_v) => {
-                todo!();
+    let mut vals = Vec::with_capacity(aotvals.len());
+    for locs in live_vars.iter().skip(1) {
+        // A live variable may be split across several locations, each holding the next most
+        // significant part of its value.
+        let mut val = 0;
+        let mut bits = 0;
+        for l in locs.iter() {
+            let mut v = unsafe { read_location(&registers, l) };
+            let size = u32::from(location_size(l)) * 8;
+            if locs.len() > 1 && size < 128 {
+                v &= (1 << size) - 1;
             }
+            assert!(bits < 128, "can't deoptimise a value wider than 128 bits");
+            val |= v << bits;
+            bits += size;
         }
+        vals.push(val);
     }
 
-    unsafe { framerec.reconstruct_frames(frameaddr) }
+    // If this guard has a side-trace, run it instead of deoptimising.
+    if let Some(ret) = guard_failed(guardid, activeframes, aotvals, &vals, frameaddr) {
+        return ret;
+    }
+
+    #[cfg(feature = "yk_jitstate_debug")]
+    print_jit_state("deoptimise");
+
+    let info = ctr.guard(guardid).deopt_info();
+    unsafe { info.reconstruct_frames(&info.values(&vals), frameaddr) }
+}
+
+/// Called by a trace which reaches a call to `func` (`longjmp()` or one of its variants) with the
+/// arguments `env` and `val`. Since the trace can't jump out of itself, it returns what this
+/// function returns, and then the call is made from the control point's frame (where the stack is
+/// as the AOT code left it) by [__ykrt_reconstruct_frames].
+#[cfg(target_arch = "x86_64")]
+#[no_mangle]
+unsafe extern "C" fn __ykrt_longjmp(
+    func: *const c_void,
+    env: *const c_void,
+    val: c_int,
+) -> *const c_void {
+    #[cfg(feature = "yk_jitstate_debug")]
+    print_jit_state("longjmp");
+
+    // The layout is that expected by `__ykrt_reconstruct_frames`: the size of the memory, the SSE
+    // registers, the general purpose registers (in the order they are popped), and the address to
+    // jump to. No frames are reconstructed, but a dummy return address for `func` keeps the stack
+    // aligned as it would be on entry to a function.
+    let mut words = vec![0; 1 + usize::from(NUM_XMM_REGS) * 2];
+    // r15, r14, r13, r12, r11, r10, r9, r8, rbp.
+    words.extend([0; 9]);
+    // rdi and rsi hold `func`'s arguments.
+    words.extend([env as u64, u64::from(val as u32)]);
+    // rbx, rcx, rdx, rax.
+    words.extend([0; 4]);
+    words.extend([func as u64, 0]);
+    let memsize = words.len() * 8;
+    words[0] = u64::try_from(memsize).unwrap();
+
+    let mmap = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            memsize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(mmap, libc::MAP_FAILED);
+    unsafe {
+        ptr::copy_nonoverlapping(words.as_ptr(), mmap as *mut u64, words.len());
+        assert_eq!(libc::mprotect(mmap, memsize, libc::PROT_READ), 0);
+    }
+    mmap
 }
 
 /// After a guard failure, reconstructs the stack frames and registers and then jumps back to the
@@ -292,7 +373,23 @@ extern "C" fn __ykrt_reconstruct_frames(newframesptr: *const c_void) {
             "add rsi, 8",
             "call munmap",
             // Restore registers.
-            // FIXME: Add other registers that may need restoring (e.g. floating point).
+            "movdqu xmm15, [rsp]",
+            "movdqu xmm14, [rsp+16]",
+            "movdqu xmm13, [rsp+32]",
+            "movdqu xmm12, [rsp+48]",
+            "movdqu xmm11, [rsp+64]",
+            "movdqu xmm10, [rsp+80]",
+            "movdqu xmm9, [rsp+96]",
+            "movdqu xmm8, [rsp+112]",
+            "movdqu xmm7, [rsp+128]",
+            "movdqu xmm6, [rsp+144]",
+            "movdqu xmm5, [rsp+160]",
+            "movdqu xmm4, [rsp+176]",
+            "movdqu xmm3, [rsp+192]",
+            "movdqu xmm2, [rsp+208]",
+            "movdqu xmm1, [rsp+224]",
+            "movdqu xmm0, [rsp+240]",
+            "add rsp, 256",
             "pop r15",
             "pop r14",
             "pop r13",
@@ -0,0 +1,157 @@
+//! Typed notifications of JIT state transitions, for profilers, loggers, and the like.
+
+use crate::{location::LocationId, trace::InvalidTraceError};
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Something of interest that the JIT has done.
+#[derive(Debug)]
+pub enum JitEvent<'a> {
+    /// This thread has started tracing a `Location`.
+    StartTracing(LocationId),
+    /// This thread has finished tracing a `Location` and queued the trace for compilation.
+    StopTracing(LocationId),
+    /// Tracing a `Location` (or mapping its trace) failed and the trace has been discarded.
+    TracingAborted {
+        loc: LocationId,
+        reason: &'a InvalidTraceError,
+    },
+    /// A trace for a `Location` was compiled in `duration` (including mapping the trace).
+    TraceCompiled { loc: LocationId, duration: Duration },
+    /// Compiling a trace for a `Location` failed after `duration`.
+    TraceCompilationAborted { loc: LocationId, duration: Duration },
+    /// The compiled trace of a `Location` has been evicted from the code cache. The `Location`
+    /// will be traced again if it becomes hot again.
+    TraceEvicted(LocationId),
+    /// The compiled trace of a `Location` has been invalidated by the embedder. As with an evicted
+    /// trace, the `Location` will be traced again if it becomes hot again.
+    TraceInvalidated(LocationId),
+    /// This thread is about to execute the compiled trace of a `Location`.
+    EnterJitCode(LocationId),
+    /// This thread has returned from executing the compiled trace of a `Location`.
+    ExitJitCode(LocationId),
+    /// This thread has started tracing a side-trace from the guard `guardid`.
+    StartSideTracing { guardid: usize },
+    /// This thread has finished tracing the side-trace of the guard `guardid` and queued it for
+    /// compilation.
+    StopSideTracing { guardid: usize },
+    /// A side-trace for the guard `guardid` was compiled in `duration`.
+    SideTraceCompiled { guardid: usize, duration: Duration },
+    /// Tracing or compiling a side-trace for the guard `guardid` failed. It won't be retried.
+    SideTraceAborted { guardid: usize },
+    /// The guard `guardid` failed and this thread is executing its side-trace.
+    EnterSideTrace { guardid: usize },
+    /// The guard `guardid` failed and this thread is deoptimising back to the interpreter.
+    Deoptimise { guardid: usize },
+}
+
+/// An observer of [JitEvent]s. Observers are called synchronously on whichever thread caused the
+/// event (which may be a compilation worker thread), so they should return quickly. An observer
+/// may register further observers: they are notified of events from the next one onwards.
+pub trait JitObserver: Send + Sync {
+    fn event(&self, event: &JitEvent);
+}
+
+/// The observers registered with an `MT`. This can be cheaply cloned to pass to worker threads.
+#[derive(Clone)]
+pub(crate) struct Observers(Arc<ObserversInner>);
+
+struct ObserversInner {
+    /// The observer that is always registered (the `MT`'s statistics). It is notified without
+    /// taking any locks.
+    builtin: Arc<dyn JitObserver>,
+    /// How many observers are in `extra`. Events are notified on hot paths (e.g. every entry into
+    /// JIT code), so this lets `notify` avoid taking the lock when, as is normal, no observer has
+    /// been registered.
+    num_extra: AtomicUsize,
+    /// The observers registered by the user. The list itself is never mutated: registering an
+    /// observer replaces it with an extended copy, so `notify` can take a snapshot and call the
+    /// observers without holding the lock.
+    extra: Mutex<Arc<Vec<Arc<dyn JitObserver>>>>,
+}
+
+impl Observers {
+    pub(crate) fn new(builtin: Arc<dyn JitObserver>) -> Self {
+        Observers(Arc::new(ObserversInner {
+            builtin,
+            num_extra: AtomicUsize::new(0),
+            extra: Mutex::new(Arc::new(Vec::new())),
+        }))
+    }
+
+    pub(crate) fn add(&self, observer: Arc<dyn JitObserver>) {
+        let mut extra = self.0.extra.lock();
+        let mut new = Vec::clone(&extra);
+        new.push(observer);
+        *extra = Arc::new(new);
+        self.0.num_extra.fetch_add(1, Ordering::Release);
+    }
+
+    /// Pass `event` to each registered observer in the order they were registered.
+    pub(crate) fn notify(&self, event: JitEvent) {
+        self.0.builtin.event(&event);
+        if self.0.num_extra.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let extra = Arc::clone(&self.0.extra.lock());
+        for o in extra.iter() {
+            o.event(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the events it sees and, on the first one, registers another `Counter`.
+    struct Counter {
+        observers: Observers,
+        seen: AtomicUsize,
+        registered: Mutex<Option<Arc<Counter>>>,
+    }
+
+    impl JitObserver for Counter {
+        fn event(&self, _event: &JitEvent) {
+            self.seen.fetch_add(1, Ordering::Relaxed);
+            let mut registered = self.registered.lock();
+            if registered.is_none() {
+                let c = Arc::new(Counter {
+                    observers: self.observers.clone(),
+                    seen: AtomicUsize::new(0),
+                    registered: Mutex::new(None),
+                });
+                self.observers.add(Arc::clone(&c) as Arc<dyn JitObserver>);
+                *registered = Some(c);
+            }
+        }
+    }
+
+    struct Ignore;
+    impl JitObserver for Ignore {
+        fn event(&self, _event: &JitEvent) {}
+    }
+
+    #[test]
+    fn observers_can_register_observers() {
+        let observers = Observers::new(Arc::new(Ignore));
+        let c = Arc::new(Counter {
+            observers: observers.clone(),
+            seen: AtomicUsize::new(0),
+            registered: Mutex::new(None),
+        });
+        observers.add(Arc::clone(&c) as Arc<dyn JitObserver>);
+        observers.notify(JitEvent::Deoptimise { guardid: 0 });
+        observers.notify(JitEvent::Deoptimise { guardid: 0 });
+        assert_eq!(c.seen.load(Ordering::Relaxed), 2);
+        // The observer registered during the first event only sees the second.
+        let c2 = c.registered.lock().clone().unwrap();
+        assert_eq!(c2.seen.load(Ordering::Relaxed), 1);
+    }
+}
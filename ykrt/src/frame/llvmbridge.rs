@@ -1,9 +1,11 @@
-use super::SGValue;
+use super::{SGVal, SGValue};
 use llvm_sys::core::*;
 use llvm_sys::prelude::{LLVMBasicBlockRef, LLVMModuleRef, LLVMTypeRef, LLVMValueRef};
 use llvm_sys::target::{LLVMGetModuleDataLayout, LLVMTargetDataRef};
 use llvm_sys::{LLVMTypeKind, LLVMValueKind};
-use std::ffi::CStr;
+use std::error::Error;
+use std::ffi::{c_void, CStr};
+use std::path::Path;
 
 pub struct Module(LLVMModuleRef);
 
@@ -15,14 +17,42 @@ pub struct BitcodeSection {
 }
 
 extern "C" {
-    pub fn LLVMGetThreadSafeModule(bs: BitcodeSection) -> LLVMModuleRef;
+    pub fn LLVMWithThreadSafeModule(
+        bs: *const BitcodeSection,
+        f: extern "C" fn(LLVMModuleRef, *mut c_void),
+        data: *mut c_void,
+    );
 }
 
 impl Module {
-    pub unsafe fn from_bc() -> Self {
-        let (data, len) = ykutil::obj::llvmbc_section();
-        let module = LLVMGetThreadSafeModule(BitcodeSection { data, len });
-        Self(module)
+    /// Call `f` with this thread's copy of the AOT IR embedded in the loaded object `obj`, or of the
+    /// IR linked into the running program if `obj` is `None`. The module's context is locked while
+    /// `f` runs, so the module must not be used once `f` has returned. Fails if `obj` no longer has
+    /// any embedded IR (e.g. because it has been unloaded).
+    pub unsafe fn with_bc<R>(
+        obj: Option<&Path>,
+        f: impl FnOnce(&Module) -> R,
+    ) -> Result<R, Box<dyn Error>> {
+        extern "C" fn trampoline(module: LLVMModuleRef, data: *mut c_void) {
+            let call = unsafe { &mut *(data as *mut &mut dyn FnMut(&Module)) };
+            call(&Module(module));
+        }
+
+        let (data, len) = match obj {
+            Some(obj) => ykutil::obj::llvmbc_section_of(obj)
+                .ok_or_else(|| format!("no IR embedded in {}", obj.display()))?,
+            None => ykutil::obj::llvmbc_section(),
+        };
+        let mut f = Some(f);
+        let mut ret = None;
+        let mut call = |module: &Module| ret = Some(f.take().unwrap()(module));
+        let mut call: &mut dyn FnMut(&Module) = &mut call;
+        LLVMWithThreadSafeModule(
+            &BitcodeSection { data, len },
+            trampoline,
+            &mut call as *mut _ as *mut c_void,
+        );
+        Ok(ret.unwrap())
     }
 
     pub fn function(&self, name: *const i8) -> Function {
@@ -155,12 +185,22 @@ pub fn llvm_const_to_sgvalue(c: Value) -> SGValue {
         LLVMTypeKind::LLVMIntegerTypeKind => {
             // FIXME: Add tests to check there's no silent sign extension going on.
             let val = unsafe { LLVMConstIntGetZExtValue(c.get()) };
-            SGValue::new(val, ty)
+            SGValue::new(SGVal::Int(val), ty)
         }
         LLVMTypeKind::LLVMPointerTypeKind => match c.kind() {
-            LLVMValueKind::LLVMConstantPointerNullValueKind => SGValue::new(0, ty),
+            LLVMValueKind::LLVMConstantPointerNullValueKind => SGValue::new(SGVal::Int(0), ty),
             _ => todo!(),
         },
+        LLVMTypeKind::LLVMFloatTypeKind => {
+            let mut loses_info = 0;
+            let val = unsafe { LLVMConstRealGetDouble(c.get(), &mut loses_info) };
+            SGValue::new(SGVal::Float(val as f32), ty)
+        }
+        LLVMTypeKind::LLVMDoubleTypeKind => {
+            let mut loses_info = 0;
+            let val = unsafe { LLVMConstRealGetDouble(c.get(), &mut loses_info) };
+            SGValue::new(SGVal::Double(val), ty)
+        }
         _ => todo!("{:?}", c.as_str()),
     }
 }
@@ -184,12 +224,14 @@ pub unsafe fn get_aot_original(instr: &Value) -> Option<Value> {
             // If this isn't a struct it can't be YKCtrlPointVars.
             return None;
         }
-        let name = CStr::from_ptr(LLVMGetStructName(ty.0));
-        if name.to_str().unwrap() != "YkCtrlPointVars" {
-            // This isn't the YKCtrlPointVars struct.
+        // Each control point call-site has its own struct, whose name LLVM makes unique by adding
+        // a suffix to all but the first (e.g. `YkCtrlPointVars.1`).
+        let name = CStr::from_ptr(LLVMGetStructName(ty.0)).to_str().unwrap();
+        if name != "YkCtrlPointVars" && !name.starts_with("YkCtrlPointVars.") {
+            // This isn't a YKCtrlPointVars struct.
             return None;
         }
-        // We found the YKCtrlPointVars struct. Now iterate over all it's uses to find the
+        // We found a YKCtrlPointVars struct. Now iterate over all it's uses to find the
         // corresponding store instruction from which we can extract the original AOT variable.
         let tgtoff = llvm_const_to_sgvalue(gep.get_operand(2));
 
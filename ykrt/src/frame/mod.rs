@@ -1,43 +1,117 @@
 #![allow(clippy::comparison_chain)]
 #![allow(clippy::missing_safety_doc)]
 
-use llvm_sys::{core::*, target::LLVMABISizeOfType};
+use llvm_sys::{core::*, target::LLVMABISizeOfType, LLVMTypeKind};
 use object::{Object, ObjectSection};
 use std::{
     collections::HashMap,
     convert::TryFrom,
-    env,
+    error::Error,
     ffi::{c_void, CStr},
-    fs, ptr, slice,
-    sync::LazyLock,
+    fmt, fs, mem,
+    path::{Path, PathBuf},
+    ptr, slice,
+    sync::{Arc, LazyLock, Mutex},
 };
-use yksmp::{Location as SMLocation, SMEntry, StackMapParser};
+use yksmp::{Location as SMLocation, PrologueInfo, Record, SMEntry, StackMapParser};
+use ykutil::obj::{PHDR_OBJECT_CACHE, SELF_BIN_PATH};
 
 mod llvmbridge;
 use llvmbridge::{get_aot_original, Module, Type, Value};
 
-pub static AOT_STACKMAPS: LazyLock<Vec<SMEntry>> = LazyLock::new(|| {
-    // Load the stackmap from the binary to parse in the stackmaps.
-    // FIXME: Don't use current_exe.
-    let pathb = env::current_exe().unwrap();
-    let file = fs::File::open(pathb.as_path()).unwrap();
-    let exemmap = unsafe { memmap2::Mmap::map(&file).unwrap() };
-    let object = object::File::parse(&*exemmap).unwrap();
-    let sec = object.section_by_name(".llvm_stackmaps").unwrap();
-
-    // Parse the stackmap.
-    let slice = unsafe {
-        slice::from_raw_parts(
-            sec.address() as *mut u8,
-            usize::try_from(sec.size()).unwrap(),
-        )
-    };
-    StackMapParser::get_entries(slice)
-});
+use crate::deopt::AOTVar;
+
+/// The stackmap records of the AOT code of each loaded object built with ykllvm, keyed by the
+/// object's path and the address it was loaded at. Each object's records are parsed, by a compile
+/// worker, the first time that a trace with guards is compiled from its IR.
+static AOT_STACKMAPS: LazyLock<Mutex<HashMap<(PathBuf, u64), Arc<AOTStackmaps>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The AOT stackmap records, indexed by stackmap ID.
+pub struct AOTStackmaps {
+    /// The prologue info of each function with stackmap records.
+    pinfos: Vec<PrologueInfo>,
+    /// Maps a stackmap ID to its record and the index in `pinfos` of its function's prologue info.
+    records: HashMap<u64, (Record, usize)>,
+}
+
+impl AOTStackmaps {
+    /// Return the stackmap records of the loaded object `obj`, or of the running program if `obj`
+    /// is `None`.
+    fn of(obj: Option<&Path>) -> Result<Arc<Self>, Box<dyn Error>> {
+        let obj = obj.unwrap_or(SELF_BIN_PATH.as_path());
+        // `obj` was loaded when we started compiling a trace from its IR, but it may since have
+        // been unloaded.
+        let base = PHDR_OBJECT_CACHE
+            .get()
+            .find(obj)
+            .ok_or_else(|| format!("{} is no longer loaded", obj.display()))?
+            .addr();
+        let mut maps = AOT_STACKMAPS.lock().unwrap();
+        let key = (obj.to_owned(), base);
+        if let Some(sms) = maps.get(&key) {
+            return Ok(Arc::clone(sms));
+        }
+        let sms = Arc::new(Self::load(obj, base)?);
+        maps.insert(key, Arc::clone(&sms));
+        Ok(sms)
+    }
+
+    /// Parse the stackmap records of the object `obj`, loaded at the address `base`.
+    fn load(obj: &Path, base: u64) -> Result<Self, Box<dyn Error>> {
+        let file = fs::File::open(obj)?;
+        let objmmap = unsafe { memmap2::Mmap::map(&file)? };
+        let object = object::File::parse(&*objmmap)?;
+        let sec = object
+            .section_by_name(".llvm_stackmaps")
+            .ok_or_else(|| format!("no stackmaps in {}", obj.display()))?;
+
+        // Parse the stackmap. We parse the loaded section, rather than the one in the file, since
+        // the function addresses in the former have been relocated.
+        let slice = unsafe {
+            slice::from_raw_parts(
+                (base + sec.address()) as *const u8,
+                usize::try_from(sec.size()).unwrap(),
+            )
+        };
+        Ok(AOTStackmaps::new(StackMapParser::get_entries(slice)))
+    }
+
+    fn new(entries: Vec<SMEntry>) -> Self {
+        let mut pinfos = Vec::with_capacity(entries.len());
+        let mut records = HashMap::new();
+        for entry in entries {
+            for r in entry.records {
+                records.insert(r.id, (r, pinfos.len()));
+            }
+            pinfos.push(entry.pinfo);
+        }
+        AOTStackmaps { pinfos, records }
+    }
+
+    /// Return the record with stackmap ID `id` and the prologue info of the function containing
+    /// it.
+    fn get(&self, id: u64) -> (&Record, &PrologueInfo) {
+        let (rec, pidx) = &self.records[&id];
+        (rec, &self.pinfos[*pidx])
+    }
+}
+
+impl fmt::Debug for AOTStackmaps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} AOT stackmap records>", self.records.len())
+    }
+}
 
 static USIZEOF_POINTER: usize = std::mem::size_of::<*const ()>();
 static ISIZEOF_POINTER: isize = std::mem::size_of::<*const ()>() as isize;
 static RBP_DWARF_NUM: u16 = 6;
+/// The DWARF register number of XMM0. XMM1 to XMM15 follow it.
+pub(crate) static XMM0_DWARF_NUM: u16 = 17;
+/// The number of SSE registers.
+pub(crate) static NUM_XMM_REGS: u16 = 16;
+/// The size, in bytes, of an SSE register.
+static XMM_REG_SIZE: usize = 16;
 
 /// Active frames (basic block index, instruction index, function name) in the AOTModule where the
 /// guard failure occured. Mirrors the struct defined in yktracec/jitmodbuilder.cc.
@@ -49,44 +123,104 @@ pub struct FrameInfo {
     pub fname: *const i8,
 }
 
-/// Stopgap interpreter values.
-#[derive(Clone, Copy, PartialEq)]
-pub struct SGValue {
-    pub val: u64,
-    pub ty: Type,
+/// The payload of a [SGValue].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SGVal {
+    /// An integer (of at most 64 bits) or a pointer.
+    Int(u64),
+    Float(f32),
+    Double(f64),
+    /// A vector of at most 128 bits (the size of an SSE register), as little-endian bytes.
+    Vector([u8; 16]),
 }
 
-impl SGValue {
-    pub fn new(val: u64, ty: Type) -> Self {
-        SGValue { val, ty }
+/// The type of a [SGValue], as far as deoptimisation needs to know it. Unlike an LLVM type, this
+/// doesn't refer to the (per-thread) AOT IR module, so it can be computed once and shared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SGType {
+    /// An integer of the given bit width.
+    Int(u32),
+    Float,
+    Double,
+    Vector,
+    /// Pointers, and anything else that fits in a general purpose register.
+    Other,
+}
+
+impl SGType {
+    fn of(ty: Type) -> Self {
+        match ty.kind() {
+            LLVMTypeKind::LLVMIntegerTypeKind => {
+                let iw = ty.get_int_width();
+                assert!(iw <= 64, "can't deoptimise a {iw}-bit integer");
+                SGType::Int(iw)
+            }
+            LLVMTypeKind::LLVMFloatTypeKind => SGType::Float,
+            LLVMTypeKind::LLVMDoubleTypeKind => SGType::Double,
+            LLVMTypeKind::LLVMVectorTypeKind => SGType::Vector,
+            _ => SGType::Other,
+        }
     }
 }
 
-/// A frame holding live variables.
-struct Frame {
-    vars: HashMap<Value, SGValue>,
-    pc: Value,
+/// Stopgap interpreter values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SGValue {
+    pub val: SGVal,
+    pub ty: SGType,
 }
 
-impl Frame {
-    fn new(pc: Value) -> Frame {
-        Frame {
-            vars: HashMap::new(),
-            pc,
+impl SGValue {
+    pub fn new(val: SGVal, ty: Type) -> Self {
+        SGValue {
+            val,
+            ty: SGType::of(ty),
         }
     }
 
-    /// Get the value of the variable `key` in this frame.
-    fn get(&self, key: &Value) -> Option<&SGValue> {
-        self.vars.get(key)
+    /// Create a value of type `ty` from the bits `raw`, as they would be held in a register (or
+    /// stored in memory).
+    pub fn from_raw(raw: u128, ty: SGType) -> Self {
+        let val = match ty {
+            // Stackmap "small constants" get their value sign-extended, so if the type of the value
+            // is smaller than that, then we have to discard the unwanted high-order bits.
+            SGType::Int(iw) => SGVal::Int(raw as u64 & (u64::MAX >> (64 - iw))),
+            SGType::Float => SGVal::Float(f32::from_bits(raw as u32)),
+            SGType::Double => SGVal::Double(f64::from_bits(raw as u64)),
+            SGType::Vector => SGVal::Vector(raw.to_le_bytes()),
+            SGType::Other => SGVal::Int(raw as u64),
+        };
+        SGValue { val, ty }
+    }
+
+    /// The bits of the value, as they would be held in a register (or stored in memory).
+    pub fn raw(&self) -> u128 {
+        match self.val {
+            SGVal::Int(v) => u128::from(v),
+            SGVal::Float(v) => u128::from(v.to_bits()),
+            SGVal::Double(v) => u128::from(v.to_bits()),
+            SGVal::Vector(v) => u128::from_le_bytes(v),
+        }
     }
+}
 
-    /// Add new variable `key` with value `val`.
-    fn add(&mut self, key: Value, val: SGValue) {
-        self.vars.insert(key, val);
+/// The size, in bytes, of the part of a live variable stored in the stackmap location `loc`.
+pub(crate) fn location_size(loc: &SMLocation) -> u16 {
+    match loc {
+        SMLocation::Register(_, size, _, _) => *size,
+        SMLocation::Direct(..) => USIZEOF_POINTER.try_into().unwrap(),
+        SMLocation::Indirect(_, _, size) => *size,
+        SMLocation::Constant(_) | SMLocation::LargeConstant(_) => 8,
     }
 }
 
+/// Write the low `size` bytes of `val` to `addr`.
+unsafe fn write_sized(addr: *mut c_void, val: u128, size: u16) {
+    let size = usize::from(size);
+    assert!(size <= 16, "can't deoptimise a {size}-byte value");
+    ptr::copy_nonoverlapping(val.to_le_bytes().as_ptr(), addr as *mut u8, size);
+}
+
 fn get_stackmap_call(pc: Value) -> Value {
     debug_assert!(pc.is_instruction());
     // Stackmap instructions are inserted after calls, but before branch instructions. So we need
@@ -109,67 +243,156 @@ fn get_stackmap_call(pc: Value) -> Value {
     sm
 }
 
-/// The struct responsible for reconstructing the new frames after a guard failure.
-pub struct FrameReconstructor {
-    /// AOT IR module.
-    module: Module,
-    /// Current frames.
-    frames: Vec<Frame>,
+/// Where the value of one live variable of an AOT stackmap record comes from.
+#[derive(Debug)]
+struct VarSrc {
+    /// The index of the live value (in the order of the guard's [AOTVar]s) holding the value.
+    val: usize,
+    /// If the live variable is an alloca, the size of the allocated type. The sizes reported by the
+    /// stackmap aren't always correct, so we get this from the IR.
+    alloca_size: Option<usize>,
 }
 
-impl FrameReconstructor {
-    /// Create a new instance and initialise the frames we need to reconstruct.
-    pub unsafe fn new(activeframes: &[FrameInfo]) -> FrameReconstructor {
-        // Get AOT module IR and parse it.
-        let module = Module::from_bc();
-
-        // Initialise frames.
-        let mut frames = Vec::with_capacity(activeframes.len());
-        for frame in activeframes {
-            let funcname = std::ffi::CStr::from_ptr(frame.fname);
-            let func = module.function(funcname.as_ptr());
-            let bb = func.bb(frame.bbidx);
-            let instr = bb.instruction(frame.instridx);
-            frames.push(Frame::new(instr));
+/// A frame to be reconstructed.
+#[derive(Debug)]
+struct FrameDeopt {
+    /// The ID of the AOT stackmap record describing the frame.
+    smid: u64,
+    /// For each live variable in the stackmap record, where its value comes from.
+    vars: Vec<VarSrc>,
+}
+
+/// Everything needed to deoptimise at a guard. This is computed from the AOT IR when the trace is
+/// compiled, so that deoptimising doesn't need to load or walk the IR.
+#[derive(Debug)]
+pub(crate) struct DeoptInfo {
+    /// The types of the live values, in the order of the guard's [AOTVar]s.
+    tys: Vec<SGType>,
+    /// The frames to reconstruct, starting with the frame containing the control point.
+    frames: Vec<FrameDeopt>,
+    /// The stackmap records of the AOT code that the frames belong to.
+    stackmaps: Arc<AOTStackmaps>,
+}
+
+impl DeoptInfo {
+    /// Work out how to deoptimise at each of a trace's guards, described by the frames active at
+    /// the guard and the live values at the guard, for a trace compiled from the IR embedded in the
+    /// object `obj` (or linked into the running program if `obj` is `None`).
+    pub(crate) unsafe fn for_guards(
+        obj: Option<&Path>,
+        guards: &[(&[FrameInfo], &[AOTVar])],
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        if guards.is_empty() {
+            return Ok(Vec::new());
+        }
+        let stackmaps = AOTStackmaps::of(obj)?;
+        Module::with_bc(obj, |module| {
+            guards
+                .iter()
+                .map(|(frames, aotvals)| Self::new(module, &stackmaps, frames, aotvals))
+                .collect()
+        })
+    }
+
+    /// Work out how to deoptimise into the frames `activeframes` from the live values described by
+    /// `aotvals`, using the AOT IR `module` and its stackmap records `stackmaps`.
+    unsafe fn new(
+        module: &Module,
+        stackmaps: &Arc<AOTStackmaps>,
+        activeframes: &[FrameInfo],
+        aotvals: &[AOTVar],
+    ) -> Self {
+        // For each frame, map AOT IR values to the index of the live value holding them.
+        let mut srcs = vec![HashMap::new(); activeframes.len()];
+        let mut tys = Vec::with_capacity(aotvals.len());
+        for (i, aot) in aotvals.iter().enumerate() {
+            let instr = module
+                .function(aot.fname)
+                .bb(aot.bbidx)
+                .instruction(aot.instridx);
+            tys.push(SGType::of(instr.get_type()));
+            srcs[aot.sfidx].insert(instr, i);
+            // Only the root stackframe contains the control point call, so for the other frames
+            // there's no need to match live variables to their corresponding variables passed into
+            // the control point. See `get_aot_original` for more details.
+            if aot.sfidx == 0 {
+                if let Some(v) = get_aot_original(&instr) {
+                    srcs[0].insert(v, i);
+                }
+            }
+        }
+
+        // Get the modules layout which we'll need to extract type sizes of LLVM IR.
+        let layout = module.datalayout();
+        let frames = activeframes
+            .iter()
+            .zip(srcs)
+            .map(|(frame, srcs)| {
+                let pc = module
+                    .function(frame.fname)
+                    .bb(frame.bbidx)
+                    .instruction(frame.instridx);
+                let smcall = get_stackmap_call(pc);
+                let smid = LLVMConstIntGetZExtValue(smcall.get_operand(0).get());
+                let (rec, _) = stackmaps.get(smid);
+                // Live variables are listed in the order of the AOT stackmap call's operands.
+                let vars = (0..rec.live_vars.len())
+                    .map(|j| {
+                        // Adjust the operand index by 2 to skip stackmap ID and shadow bytes.
+                        let op = smcall.get_operand(u32::try_from(j + 2).unwrap());
+                        let alloca_size = if op.is_alloca() {
+                            let eltype = LLVMGetAllocatedType(op.get());
+                            Some(usize::try_from(LLVMABISizeOfType(layout, eltype)).unwrap())
+                        } else {
+                            None
+                        };
+                        VarSrc {
+                            val: srcs[&op],
+                            alloca_size,
+                        }
+                    })
+                    .collect();
+                FrameDeopt { smid, vars }
+            })
+            .collect();
+        DeoptInfo {
+            tys,
+            frames,
+            stackmaps: Arc::clone(stackmaps),
         }
-        FrameReconstructor { module, frames }
+    }
+
+    /// Type the raw live values `vals` read from the JIT stackmap.
+    pub(crate) fn values(&self, vals: &[u128]) -> Vec<SGValue> {
+        vals.iter()
+            .zip(&self.tys)
+            .map(|(v, ty)| SGValue::from_raw(*v, *ty))
+            .collect()
     }
 
     /// Generate frames from stackmap information after a guard failure. The new frames are stored
     /// inside some allocated memory whose pointer this function returns. The frames are then later
     /// `memcpy`ed to the actual stack by [ykcapi::__ykrt_reconstruct_frames].
     #[cfg(target_arch = "x86_64")]
-    pub unsafe fn reconstruct_frames(&self, btmframeaddr: *mut c_void) -> *const c_void {
-        // Vec holding currently active register values.
-        let mut registers = vec![0; 16];
+    pub(crate) unsafe fn reconstruct_frames(
+        &self,
+        vals: &[SGValue],
+        btmframeaddr: *mut c_void,
+    ) -> *const c_void {
+        // Vec holding currently active register values, indexed by DWARF register number.
+        let mut registers = vec![0u128; usize::from(XMM0_DWARF_NUM + NUM_XMM_REGS)];
 
         // The final size of the memory we need to allocate. Inititialised with space to store
         // registers for register recovery.
-        let mut memsize: usize = 15 * USIZEOF_POINTER;
+        let mut memsize: usize = 15 * USIZEOF_POINTER + usize::from(NUM_XMM_REGS) * XMM_REG_SIZE;
         // Vec to collect stackmaps for each frame.
         let mut smaps = Vec::new();
 
         // Collect stackmaps for each frame and calculate the final size of memory required to
         // store the reconstructed stack.
         for (i, frame) in self.frames.iter().enumerate() {
-            // Get stackmap ID for the current frame's pc.
-            let smcall = get_stackmap_call(frame.pc);
-            let smid = unsafe { LLVMConstIntGetZExtValue(smcall.get_operand(0).get()) };
             // Find prologue info and stackmap record for this frame.
-            let mut pinfo = None;
-            let mut rec = None;
-            // Iterate over function entries to find the correct record and relevant prologue info.
-            for entry in AOT_STACKMAPS.iter() {
-                for r in &entry.records {
-                    if r.id == smid {
-                        pinfo = Some(&entry.pinfo);
-                        rec = Some(r);
-                        break;
-                    }
-                }
-            }
-            let rec = rec.unwrap();
-            let pinfo = pinfo.unwrap();
+            let (rec, pinfo) = self.stackmaps.get(frame.smid);
             // We don't need to allocate memory for the bottom-most frame, i.e. the frame
             // containing the control point, since this frame already exists and doesn't need to be
             // reconstructed.
@@ -195,9 +418,7 @@ impl FrameReconstructor {
                 0,
             )
         };
-
-        // Get the modules layout which we'll need to extract type sizes of LLVM IR.
-        let layout = self.module.datalayout();
+        assert_ne!(mmap, libc::MAP_FAILED);
 
         // Generate and write frames to the mmap. Since the stack grows downwards and we need to
         // keep track of spilled register values we write to the mmap from back to front. To make
@@ -232,7 +453,7 @@ impl FrameReconstructor {
 
             // Update RBP to represent this frame's address.
             if pinfo.hasfp {
-                registers[usize::from(RBP_DWARF_NUM)] = currframe as u64;
+                registers[usize::from(RBP_DWARF_NUM)] = currframe as u128;
             }
 
             // Calculate the next frame's address by substracting its size (plus return address)
@@ -256,7 +477,8 @@ impl FrameReconstructor {
                     if pinfo.hasfp {
                         tmp = unsafe { tmp.offset(ISIZEOF_POINTER) };
                     }
-                    let val = registers[usize::from(*reg)];
+                    // Callee-saved registers are always general purpose registers.
+                    let val = registers[usize::from(*reg)] as u64;
                     unsafe { ptr::write(tmp as *mut u64, val) };
                 }
             }
@@ -264,99 +486,99 @@ impl FrameReconstructor {
             // WRITE STACKMAP LOCATIONS.
             // Now write all live variables to the new stack in the order they are listed in the
             // AOT stackmap call.
-            let smcall = get_stackmap_call(frame.pc);
-            for (j, lv) in rec.live_vars.iter().enumerate() {
-                // Adjust the operand index by 2 to skip stackmap ID and shadow bytes.
-                let op = smcall.get_operand(u32::try_from(j + 2).unwrap());
-                let val = frame.get(&op).unwrap().val;
-                let l = if lv.len() == 1 {
-                    lv.get(0).unwrap()
-                } else {
-                    todo!("Deal with multi register locations");
-                };
-
-                // Iterate over all locations. Register locations just update the current value in
-                // the registers vector. Direct locations make up part of the stack so need to
-                // written to the allocated memory. Other locations we haven't encountered yet, so
-                // will deal with them as they appear.
-                match l {
-                    SMLocation::Register(reg, _size, off, extra) => {
-                        registers[usize::from(*reg)] = val;
-                        if *extra != 0 {
-                            // The stackmap has recorded an additional register we need to write
-                            // this value to.
-                            registers[usize::try_from(*extra - 1).unwrap()] = val;
+            for (lv, src) in rec.live_vars.iter().zip(&frame.vars) {
+                let mut remaining = vals[src.val].raw();
+
+                // Iterate over all locations. A live variable split across several locations has
+                // the next most significant part of its value in each. Register locations just
+                // update the current value in the registers vector. Direct and indirect locations
+                // make up part of the stack so need to written to the allocated memory.
+                for l in lv.iter() {
+                    let val = if lv.len() == 1 {
+                        remaining
+                    } else {
+                        let bits = u32::from(location_size(l)) * 8;
+                        if bits >= 128 {
+                            mem::take(&mut remaining)
+                        } else {
+                            let part = remaining & ((1 << bits) - 1);
+                            remaining >>= bits;
+                            part
                         }
-                        if i == 0 {
-                            // skip first frame
-                            continue;
+                    };
+                    match l {
+                        SMLocation::Register(reg, size, off, extra) => {
+                            registers[usize::from(*reg)] = val;
+                            if *extra != 0 {
+                                // The stackmap has recorded an additional register we need to
+                                // write this value to.
+                                registers[usize::try_from(*extra - 1).unwrap()] = val;
+                            }
+                            if i == 0 {
+                                // skip first frame
+                                continue;
+                            }
+                            // Check if there's an additional spill location for this value.
+                            // Negative values indicate stack offsets, positive values are
+                            // registers. Lastly, 0 indicates that there's no additional location.
+                            // Note, that this means that in order to encode register locations
+                            // (where RAX = 0), all register values have been offset by 1.
+                            if *off < 0 {
+                                let temp = unsafe { rbp.offset(isize::try_from(*off).unwrap()) };
+                                debug_assert!(*off < i32::try_from(rec.size).unwrap());
+                                unsafe { write_sized(temp, val, *size) };
+                            } else if *off > 0 {
+                                registers[usize::try_from(*off - 1).unwrap()] = val;
+                            }
                         }
-                        // Check if there's an additional spill location for this value. Negative
-                        // values indicate stack offsets, positive values are registers. Lastly, 0
-                        // indicates that there's no additional location. Note, that this means
-                        // that in order to encode register locations (where RAX = 0), all register
-                        // values have been offset by 1.
-                        if *off < 0 {
+                        SMLocation::Direct(reg, off, _) => {
+                            if i == 0 {
+                                // Direct locations are pointers into the stack (e.g. alloca or
+                                // GEP). Normally, AOT and JIT have different stacks so copying
+                                // them over would be incorrect. However, since we are using a
+                                // shadow stack which is shared between AOT and JIT these values
+                                // should be identical and thus don't need copying. Interestingly,
+                                // if we do copy them, this leads to segfaults. FIXME: Investigate
+                                // more.
+                                continue;
+                            }
+                            // Direct locations in other frames are always allocas.
+                            let size = src.alloca_size.unwrap();
+                            // Direct locations are always be in regards to RBP.
+                            debug_assert_eq!(*reg, RBP_DWARF_NUM);
                             let temp = unsafe { rbp.offset(isize::try_from(*off).unwrap()) };
                             debug_assert!(*off < i32::try_from(rec.size).unwrap());
-                            unsafe { ptr::write::<u64>(temp as *mut u64, val) };
-                        } else if *off > 0 {
-                            registers[usize::try_from(*off - 1).unwrap()] = val;
+                            unsafe {
+                                libc::memcpy(
+                                    temp,
+                                    usize::try_from(val).unwrap() as *const c_void,
+                                    size,
+                                )
+                            };
                         }
-                    }
-                    SMLocation::Direct(reg, off, _) => {
-                        if i == 0 {
-                            // Direct locations are pointers into the stack (e.g. alloca or GEP).
-                            // Normally, AOT and JIT have different stacks so copying them over
-                            // would be incorrect. However, since we are using a shadow stack which
-                            // is shared between AOT and JIT these values should be identical and
-                            // thus don't need copying. Interestingly, if we do copy them, this
-                            // leads to segfaults. FIXME: Investigate more.
-                            continue;
+                        SMLocation::Indirect(reg, off, size) => {
+                            debug_assert_eq!(*reg, RBP_DWARF_NUM);
+                            let temp = if i == 0 {
+                                // While the bottom frame is already on the stack and doesn't need
+                                // to be recreated, we still need to copy over new values from the
+                                // JIT. Luckily, we know the address of the bottom frame, so we can
+                                // write any changes directly to it from here.
+                                unsafe { btmframeaddr.offset(isize::try_from(*off).unwrap()) }
+                            } else {
+                                unsafe { rbp.offset(isize::try_from(*off).unwrap()) }
+                            };
+                            debug_assert!(*off < i32::try_from(rec.size).unwrap());
+                            // FIXME: The minimum size reported by the stackmap is 1 which
+                            // represents 1 byte. LLVM IR allows for smaller sizes, e.g. `i1`
+                            // representing a single bit. It is currently unclear how that affects
+                            // this code, so I'm leaving this comment here so we don't forget.
+                            unsafe { write_sized(temp, val, *size) };
                         }
-                        debug_assert!(op.is_alloca());
-
-                        // The sizes reported by the stackmap aren't always correct. But we can get
-                        // the correct size from the IR.
-                        let eltype = unsafe { LLVMGetAllocatedType(op.get()) };
-                        let size = unsafe { LLVMABISizeOfType(layout, eltype) };
-                        // Direct locations are always be in regards to RBP.
-                        debug_assert_eq!(*reg, RBP_DWARF_NUM);
-                        let temp = unsafe { rbp.offset(isize::try_from(*off).unwrap()) };
-                        debug_assert!(*off < i32::try_from(rec.size).unwrap());
-                        unsafe {
-                            libc::memcpy(temp, val as *const c_void, usize::try_from(size).unwrap())
-                        };
-                    }
-                    SMLocation::Indirect(reg, off, size) => {
-                        debug_assert_eq!(*reg, RBP_DWARF_NUM);
-                        let temp = if i == 0 {
-                            // While the bottom frame is already on the stack and doesn't need to
-                            // be recreated, we still need to copy over new values from the JIT.
-                            // Luckily, we know the address of the bottom frame, so we can write
-                            // any changes directly to it from here.
-                            unsafe { btmframeaddr.offset(isize::try_from(*off).unwrap()) }
-                        } else {
-                            unsafe { rbp.offset(isize::try_from(*off).unwrap()) }
-                        };
-                        debug_assert!(*off < i32::try_from(rec.size).unwrap());
-                        // FIXME: The minimum size reported by the stackmap is 1 which represents 1
-                        // byte. LLVM IR allows for smaller sizes, e.g. `i1` representing a single
-                        // bit. It is currently unclear how that affects this code, so I'm leaving
-                        // this comment here so we don't forget.
-                        match size {
-                            1 => unsafe { ptr::write::<u8>(temp as *mut u8, val as u8) },
-                            4 => unsafe { ptr::write::<u32>(temp as *mut u32, val as u32) },
-                            8 => unsafe { ptr::write::<u64>(temp as *mut u64, val) },
-                            _ => todo!(),
+                        SMLocation::Constant(_) | SMLocation::LargeConstant(_) => {
+                            // The AOT code materialises constants itself, so there's nothing to
+                            // write.
                         }
                     }
-                    SMLocation::Constant(_v) => {
-                        todo!()
-                    }
-                    SMLocation::LargeConstant(_v) => {
-                        todo!();
-                    }
                 }
             }
             if i > 0 {
@@ -391,7 +613,14 @@ impl FrameReconstructor {
         for reg in [0, 1, 2, 3, 4, 5, 6, 8, 9, 10, 11, 12, 13, 14, 15] {
             rsp = unsafe { rsp.sub(USIZEOF_POINTER) };
             unsafe {
-                ptr::write(rsp as *mut u64, registers[reg]);
+                ptr::write(rsp as *mut u64, registers[reg] as u64);
+            }
+        }
+        // The SSE registers may hold floating point values, so they are restored too.
+        for reg in XMM0_DWARF_NUM..XMM0_DWARF_NUM + NUM_XMM_REGS {
+            rsp = unsafe { rsp.sub(XMM_REG_SIZE) };
+            unsafe {
+                ptr::write_unaligned(rsp as *mut u128, registers[usize::from(reg)]);
             }
         }
 
@@ -404,44 +633,7 @@ impl FrameReconstructor {
         }
 
         // Mark the memory read-only and return its pointer.
-        unsafe { libc::mprotect(mmap, memsize, libc::PROT_READ) };
+        assert_eq!(unsafe { libc::mprotect(mmap, memsize, libc::PROT_READ) }, 0);
         mmap
     }
-
-    /// Add a live variable and its value to the current frame.
-    pub fn var_init(
-        &mut self,
-        bbidx: usize,
-        instridx: usize,
-        fname: &CStr,
-        sfidx: usize,
-        mut val: u64,
-    ) {
-        let func = self.module.function(fname.as_ptr());
-        let bb = func.bb(bbidx);
-        let instr = bb.instruction(instridx);
-
-        if instr.get_type().is_integer() {
-            // Stackmap "small constants" get their value sign-extended to fill the reserved 32-bit
-            // space in the stackmap record. If the type of the constant is actually smaller than
-            // 32 bits, then we have to discard the unwanted high-order bits.
-            let iw = instr.get_type().get_int_width();
-            val &= u64::MAX >> (64 - iw);
-        }
-
-        let orgaot = if sfidx == 0 {
-            unsafe { get_aot_original(&instr) }
-        } else {
-            // Only the root stackframe contains the control point call, so for the other frames
-            // there's no need to match live variables to their corresponding variables passed into
-            // the control point. See `get_aot_original` for more details.
-            None
-        };
-        let ty = instr.get_type();
-        let value = SGValue::new(val, ty);
-        self.frames.get_mut(sfidx).unwrap().add(instr, value);
-        if let Some(v) = orgaot {
-            self.frames.get_mut(sfidx).unwrap().add(v, value);
-        }
-    }
 }
@@ -6,14 +6,17 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::new_without_default)]
 
+mod codecache;
 mod deopt;
+mod events;
 mod frame;
 mod location;
 pub(crate) mod mt;
 pub mod trace;
 
-pub use self::location::Location;
-pub use self::mt::{HotThreshold, MT};
+pub use self::events::{JitEvent, JitObserver};
+pub use self::location::{ControlPointId, Location, LocationId};
+pub use self::mt::{HotThreshold, MTBuilder, MTStats, TraceFailureThreshold, MT};
 
 #[cfg(feature = "yk_jitstate_debug")]
 use std::{env, sync::LazyLock};
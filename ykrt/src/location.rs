@@ -68,6 +68,11 @@ pub struct Location {
     //  └───────────│   Compiled   │
     //              └──────────────┘
     //
+    // When a compiled trace is evicted from the code cache, or the embedder invalidates a Location
+    // that is being traced, compiled, or has been compiled, the Location is "reprofiled". Since the
+    // memory for its HotLocation can't be deallocated (see below), reprofiling happens in the
+    // HotLocation's own `Counting` state rather than by returning to the initial state.
+    //
     // We hope that a Location soon reaches the `Compiled` state (aka "the happy state") and stays
     // there. However, many Locations will not be used frequently enough to reach such a state, so
     // we don't want to waste resources on them.
@@ -192,15 +197,82 @@ impl Drop for Location {
     }
 }
 
+/// An identifier for a hot [Location], as passed to [crate::JitObserver]s. Unlike the address of
+/// a `Location` (which can be freely moved), this is stable for as long as the `Location` is hot.
+/// Once the `Location` has been dropped, its identifier may be reused. A `Location` reached at
+/// more than one control point has a different identifier at each.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LocationId(usize);
+
+impl LocationId {
+    pub(crate) fn new(hl: &Mutex<HotLocation>) -> Self {
+        LocationId(hl as *const _ as usize)
+    }
+
+    /// Return this identifier as an integer.
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+/// An identifier for a call-site of the control point. An interpreter may have more than one
+/// control point (e.g. one per interpreter loop), each with its own live variables, so a trace can
+/// only be executed at the control point it was traced from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ControlPointId(usize);
+
+impl ControlPointId {
+    /// Create the identifier for the call-site of the control point which returns to `retaddr`.
+    pub const fn new(retaddr: usize) -> Self {
+        ControlPointId(retaddr)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct HotLocation {
     pub(crate) kind: HotLocationKind,
     pub(crate) trace_failure: TraceFailureThreshold,
+    /// The control point this HotLocation belongs to. Its traces start (and end) at that control
+    /// point, and are only executed there.
+    pub(crate) ctrlp: ControlPointId,
+    /// If this is the HotLocation a `Location` points to (i.e. the one for the control point at
+    /// which the `Location` became hot), the HotLocations of the same `Location` at the other
+    /// control points it has since been reached at. These are otherwise empty.
+    pub(crate) others: Vec<(ControlPointId, Arc<Mutex<HotLocation>>)>,
+    /// Incremented each time this HotLocation is invalidated while it is being traced or while its
+    /// trace is being compiled. A trace whose HotLocation's generation has changed since tracing
+    /// started is discarded.
+    pub(crate) generation: u64,
+}
+
+impl HotLocation {
+    /// Record that tracing this HotLocation failed. If it has already failed `threshold` times, it
+    /// won't be traced again; otherwise the next thread to encounter it will try tracing it again.
+    pub(crate) fn trace_failed(&mut self, threshold: TraceFailureThreshold) {
+        if self.trace_failure < threshold {
+            self.trace_failure += 1;
+            self.kind = HotLocationKind::TracingFailed;
+        } else {
+            self.kind = HotLocationKind::DontTrace;
+        }
+    }
+
+    /// Forget this HotLocation's compiled trace (if any) and past trace failures, and start
+    /// counting executions again so that it is retraced once it is hot.
+    pub(crate) fn reprofile(&mut self) {
+        self.kind = HotLocationKind::Counting(0);
+        self.trace_failure = 0;
+    }
 }
 
-/// A `Location`'s non-counting states.
+/// The states of a `Location` once it has become hot.
 #[derive(Debug)]
 pub(crate) enum HotLocationKind {
+    /// This HotLocation's compiled trace was evicted from the code cache or invalidated, or it has
+    /// only just been created for a control point other than the one its `Location` became hot
+    /// at. It counts executions (in the same way that a `Location` does before it becomes hot)
+    /// until it is hot enough to be traced.
+    Counting(HotThreshold),
     /// Points to executable machine code that can be executed instead of the interpreter for this
     /// HotLocation.
     Compiled(Arc<CompiledTrace>),
@@ -212,4 +284,7 @@ pub(crate) enum HotLocationKind {
     DontTrace,
     /// This HotLocation started a trace which is ongoing.
     Tracing,
+    /// Tracing (or mapping the trace of) this HotLocation failed, but it may be traced again: the
+    /// next thread to encounter it will start tracing.
+    TracingFailed,
 }
@@ -1,19 +1,23 @@
 //! The main end-user interface to the meta-tracing system.
 
-#[cfg(feature = "yk_testing")]
-use std::env;
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     cmp,
     collections::VecDeque,
+    env,
     error::Error,
     ffi::c_void,
+    fmt::Write,
+    fs,
     marker::PhantomData,
+    path::Path,
+    ptr,
     sync::{
-        atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering},
+        atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use parking_lot::{Condvar, Mutex, MutexGuard};
@@ -24,8 +28,15 @@ use std::sync::LazyLock;
 #[cfg(feature = "yk_jitstate_debug")]
 use crate::print_jit_state;
 use crate::{
-    location::{HotLocation, HotLocationKind, Location},
-    trace::{default_tracer_for_platform, CompiledTrace, ThreadTracer, Tracer, UnmappedTrace},
+    codecache::CodeCache,
+    deopt::AOTVar,
+    events::{JitEvent, JitObserver, Observers},
+    frame::FrameInfo,
+    location::{ControlPointId, HotLocation, HotLocationKind, Location, LocationId},
+    trace::{
+        default_tracer_for_platform, tracer_for_kind, CompiledTrace, InvalidTraceError,
+        PerfCollectorConfig, SideTraceInfo, ThreadTracer, Tracer, TracerKind, UnmappedTrace,
+    },
 };
 
 // The HotThreshold must be less than a machine word wide for [`Location::Location`] to do its
@@ -42,6 +53,8 @@ pub type AtomicTraceFailureThreshold = AtomicU16;
 
 const DEFAULT_HOT_THRESHOLD: HotThreshold = 50;
 const DEFAULT_TRACE_FAILURE_THRESHOLD: TraceFailureThreshold = 5;
+const DEFAULT_SIDETRACE_THRESHOLD: HotThreshold = 5;
+const DEFAULT_CODE_CACHE_BUDGET: usize = 64 * 1024 * 1024;
 
 thread_local! {static THREAD_MTTHREAD: MTThread = MTThread::new();}
 
@@ -55,30 +68,37 @@ static SERIALISE_COMPILATION: LazyLock<bool> = LazyLock::new(|| {
 pub struct MT {
     hot_threshold: AtomicHotThreshold,
     trace_failure_threshold: AtomicTraceFailureThreshold,
+    sidetrace_threshold: AtomicHotThreshold,
+    /// The length beyond which a trace is abandoned. See [MT::max_trace_len].
+    max_trace_len: AtomicUsize,
     /// The ordered queue of compilation worker functions.
-    job_queue: Arc<(Condvar, Mutex<VecDeque<Box<dyn FnOnce() + Send>>>)>,
+    job_queue: Arc<(Condvar, Mutex<JobQueue>)>,
     /// The hard cap on the number of worker threads.
     max_worker_threads: AtomicUsize,
     /// How many worker threads are currently running. Note that this may temporarily be `>`
     /// [`max_worker_threads`].
     active_worker_threads: AtomicUsize,
+    /// The worker threads that have not yet been joined.
+    worker_threads: Mutex<Vec<JoinHandle<()>>>,
     tracer: Arc<dyn Tracer>,
+    observers: Observers,
+    code_cache: Arc<CodeCache>,
+    /// Incremented whenever all traces that are being collected or compiled must be discarded,
+    /// because they may have recorded code that has since been invalidated. See
+    /// [MT::invalidate_all].
+    invalidation_epoch: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+    /// Where to write this `MT` instance's statistics, as JSON, when it is dropped: `-` for stderr,
+    /// otherwise a file path.
+    stats_file: Option<String>,
 }
 
 impl MT {
-    // Create a new meta-tracer instance. Arbitrarily many of these can be created, though there
-    // are no guarantees as to whether they will share resources effectively or fairly.
+    // Create a new meta-tracer instance with the default configuration. Arbitrarily many of these
+    // can be created, though there are no guarantees as to whether they will share resources
+    // effectively or fairly.
     pub fn new() -> Result<Self, Box<dyn Error>> {
-        Ok(Self {
-            hot_threshold: AtomicHotThreshold::new(DEFAULT_HOT_THRESHOLD),
-            trace_failure_threshold: AtomicTraceFailureThreshold::new(
-                DEFAULT_TRACE_FAILURE_THRESHOLD,
-            ),
-            job_queue: Arc::new((Condvar::new(), Mutex::new(VecDeque::new()))),
-            max_worker_threads: AtomicUsize::new(cmp::max(1, num_cpus::get() - 1)),
-            active_worker_threads: AtomicUsize::new(0),
-            tracer: default_tracer_for_platform()?,
-        })
+        MTBuilder::new().build()
     }
 
     /// Return this `MT` instance's current hot threshold. Notice that this value can be changed by
@@ -108,130 +128,516 @@ impl MT {
             .store(trace_failure_threshold, Ordering::Relaxed);
     }
 
+    /// Return this `MT` instance's current side-trace threshold. Notice that this value can be
+    /// changed by other threads and is thus potentially stale as soon as it is read.
+    pub fn sidetrace_threshold(&self) -> HotThreshold {
+        self.sidetrace_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Set the number of times a guard must fail before a side-trace is traced from it.
+    pub fn set_sidetrace_threshold(&self, sidetrace_threshold: HotThreshold) {
+        self.sidetrace_threshold
+            .store(sidetrace_threshold, Ordering::Relaxed);
+    }
+
+    /// Return this `MT` instance's maximum trace length. This is measured in the tracer's own unit:
+    /// blocks for the software tracer and bytes of trace data for the hardware tracer. Notice that
+    /// this value can be changed by other threads and is thus potentially stale as soon as it is
+    /// read.
+    pub fn max_trace_len(&self) -> usize {
+        self.max_trace_len.load(Ordering::Relaxed)
+    }
+
+    /// Set the length beyond which a trace is abandoned and counted as a trace failure of the
+    /// `Location` being traced.
+    pub fn set_max_trace_len(&self, max_trace_len: usize) {
+        self.max_trace_len.store(max_trace_len, Ordering::Relaxed);
+    }
+
+    /// Return this `MT` instance's code cache budget in bytes. Notice that this value can be changed
+    /// by other threads and is thus potentially stale as soon as it is read.
+    pub fn code_cache_budget(&self) -> usize {
+        self.code_cache.budget()
+    }
+
+    /// Set the number of bytes that compiled traces can use before some of them are evicted. If
+    /// compiled traces currently use more than `code_cache_budget` bytes, traces are evicted
+    /// immediately.
+    pub fn set_code_cache_budget(&self, code_cache_budget: usize) {
+        self.code_cache.set_budget(code_cache_budget);
+        for loc in self.code_cache.shrink() {
+            self.observers.notify(JitEvent::TraceEvicted(loc));
+        }
+    }
+
+    /// Invalidate `loc`'s compiled traces (one for each control point at which it has been traced),
+    /// so that `loc` is traced afresh once it has become hot again. Threads that are already
+    /// executing a trace finish doing so, and the trace is freed once no thread is executing it. If
+    /// `loc` is being traced, or a trace of it is being compiled, that trace is discarded.
+    pub fn invalidate(&self, loc: &Location) {
+        let hl = match loc.hot_location() {
+            Some(hl) => hl,
+            None => return,
+        };
+        let lk = hl.lock();
+        let others = lk
+            .others
+            .iter()
+            .map(|(_, other)| Arc::clone(other))
+            .collect::<Vec<_>>();
+        self.invalidate_hot_location(hl, lk);
+        for other in others {
+            self.invalidate_hot_location(&other, other.lock());
+        }
+    }
+
+    /// Invalidate the HotLocation `hl`, whose lock `lk` is held, as per [MT::invalidate].
+    fn invalidate_hot_location(
+        &self,
+        hl: &Mutex<HotLocation>,
+        mut lk: MutexGuard<'_, HotLocation>,
+    ) {
+        match lk.kind {
+            HotLocationKind::Compiled(_) => {
+                lk.reprofile();
+                drop(lk);
+                self.observers
+                    .notify(JitEvent::TraceInvalidated(LocationId::new(hl)));
+            }
+            HotLocationKind::Tracing | HotLocationKind::Compiling => {
+                // The thread tracing `hl`, or the worker thread compiling its trace, will notice
+                // that the generation has changed and reprofile `hl`.
+                lk.generation = lk.generation.wrapping_add(1);
+            }
+            HotLocationKind::Counting(_)
+            | HotLocationKind::DontTrace
+            | HotLocationKind::TracingFailed => (),
+        }
+    }
+
+    /// Invalidate every compiled trace, as [MT::invalidate] does, and discard all traces currently
+    /// being collected or compiled.
+    pub fn invalidate_all(&self) {
+        self.invalidation_epoch.fetch_add(1, Ordering::Relaxed);
+        for loc in self.code_cache.invalidate_all() {
+            self.observers.notify(JitEvent::TraceInvalidated(loc));
+        }
+    }
+
     /// Return this meta-tracer's maximum number of worker threads. Notice that this value can be
     /// changed by other threads and is thus potentially stale as soon as it is read.
     pub fn max_worker_threads(&self) -> usize {
         self.max_worker_threads.load(Ordering::Relaxed)
     }
 
+    /// Set the maximum number of worker threads. Lowering this value does not stop worker threads
+    /// that are already running.
+    pub fn set_max_worker_threads(&self, max_worker_threads: usize) {
+        if max_worker_threads < 1 {
+            panic!("Maximum number of worker threads must be >= 1.");
+        }
+        self.max_worker_threads
+            .store(max_worker_threads, Ordering::Relaxed);
+    }
+
+    /// Register `observer` to be notified of every subsequent [JitEvent] on this `MT` instance.
+    pub fn add_observer(&self, observer: Arc<dyn JitObserver>) {
+        self.observers.add(observer);
+    }
+
+    /// Return a snapshot of this `MT` instance's statistics. Since other threads may be updating
+    /// the statistics while the snapshot is taken, its fields may be very slightly inconsistent
+    /// with one another.
+    pub fn stats(&self) -> MTStats {
+        let st = &self.stats;
+        MTStats {
+            locations_hot: st.locations_hot.load(Ordering::Relaxed),
+            traces_started: st.traces_started.load(Ordering::Relaxed),
+            traces_aborted: st.traces_aborted.load(Ordering::Relaxed),
+            traces_compiled: st.traces_compiled.load(Ordering::Relaxed),
+            trace_compilations_aborted: st.trace_compilations_aborted.load(Ordering::Relaxed),
+            sidetraces_compiled: st.sidetraces_compiled.load(Ordering::Relaxed),
+            sidetraces_aborted: st.sidetraces_aborted.load(Ordering::Relaxed),
+            trace_executions: st.trace_executions.load(Ordering::Relaxed),
+            deopts: st.deopts.load(Ordering::Relaxed),
+            traces_evicted: st.traces_evicted.load(Ordering::Relaxed),
+            traces_invalidated: st.traces_invalidated.load(Ordering::Relaxed),
+            code_cache_size: self.code_cache.size(),
+            compile_time: Duration::from_nanos(st.compile_time_ns.load(Ordering::Relaxed)),
+            job_queue_len: self.job_queue.1.lock().jobs.len(),
+        }
+    }
+
     /// Queue `job` to be run on a worker thread.
     fn queue_job(&self, job: Box<dyn FnOnce() + Send>) {
         // We have a very simple model of worker threads. Each time a job is queued, we spin up a
         // new worker thread iff we aren't already running the maximum number of worker threads.
-        // Once started, a worker thread never dies, waiting endlessly for work.
+        // Once started, a worker thread waits endlessly for work until the `MT` is shut down.
 
         let (cv, mtx) = &*self.job_queue;
-        mtx.lock().push_back(job);
+        // We hold the lock until we have decided whether to spawn a worker thread (and, if so,
+        // recorded it in `worker_threads`). Since `shutdown` sets `lock.shutdown` with the same
+        // lock held, it either stops us queueing the job, or sees every worker thread we spawn.
+        let mut lock = mtx.lock();
+        if lock.shutdown {
+            // Nothing will ever run this job.
+            return;
+        }
+        lock.jobs.push_back(job);
         cv.notify_one();
 
+        // Since we hold the lock, no other thread can be deciding whether to spawn a worker
+        // thread at the same time as us.
         let max_jobs = self.max_worker_threads.load(Ordering::Relaxed);
         if self.active_worker_threads.load(Ordering::Relaxed) < max_jobs {
-            // At the point of the `load` on the previous line, we weren't running the maximum
-            // number of worker threads. There is now a possible race condition where multiple
-            // threads calling `queue_job` could try creating multiple worker threads and push us
-            // over the maximum worker thread limit.
-            if self.active_worker_threads.fetch_add(1, Ordering::Relaxed) > max_jobs {
-                // Another thread(s) is also spinning up another worker thread and they won the
-                // race.
-                self.active_worker_threads.fetch_sub(1, Ordering::Relaxed);
-                return;
-            }
-
+            self.active_worker_threads.fetch_add(1, Ordering::Relaxed);
             let jq = Arc::clone(&self.job_queue);
-            thread::spawn(move || {
+            let hdl = thread::spawn(move || {
                 let (cv, mtx) = &*jq;
                 let mut lock = mtx.lock();
                 loop {
-                    match lock.pop_front() {
+                    match lock.jobs.pop_front() {
                         Some(x) => MutexGuard::unlocked(&mut lock, x),
+                        None if lock.shutdown => break,
                         None => cv.wait(&mut lock),
                     }
                 }
             });
+            self.worker_threads.lock().push(hdl);
         }
+        drop(lock);
     }
 
+    /// Shut down this `MT` instance's worker threads: compilation jobs which are still queued are
+    /// discarded, and those that are in progress are allowed to finish. Waits for up to `timeout`
+    /// (or indefinitely if `None`) for the worker threads to exit, returning `true` if they all
+    /// did. Worker threads which haven't exited by then are left to exit in their own time.
+    ///
+    /// After this has been called, traces are no longer compiled, though previously compiled
+    /// traces can still be executed.
+    pub fn shutdown(&self, timeout: Option<Duration>) -> bool {
+        let (cv, mtx) = &*self.job_queue;
+        let jobs = {
+            let mut lock = mtx.lock();
+            lock.shutdown = true;
+            std::mem::take(&mut lock.jobs)
+        };
+        // Dropping the jobs may run arbitrary destructors, so we do it without holding the lock.
+        drop(jobs);
+        cv.notify_all();
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut hdls = self.worker_threads.lock();
+        loop {
+            let mut i = 0;
+            while i < hdls.len() {
+                if hdls[i].is_finished() || deadline.is_none() {
+                    // A worker thread only panics if a compilation job does, which has already
+                    // been reported on stderr: there's nothing more useful we can do about it.
+                    let _ = hdls.swap_remove(i).join();
+                } else {
+                    i += 1;
+                }
+            }
+            match deadline {
+                _ if hdls.is_empty() => return true,
+                Some(d) if Instant::now() >= d => return false,
+                _ => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    /// Called by the control point `ctrlp`, whose live variables are in `ctrlp_vars`, when the
+    /// interpreter is at `loc`. Returns the address of a reconstructed stack, or null if there
+    /// wasn't a guard failure.
     pub fn control_point(
         &self,
         loc: &Location,
+        ctrlp: ControlPointId,
         ctrlp_vars: *mut c_void,
         frameaddr: *mut c_void,
     ) -> *const c_void {
-        match self.transition_location(loc) {
+        match self.transition_location(loc, ctrlp) {
             TransitionLocation::NoAction => (),
-            TransitionLocation::Execute(ctr) => {
+            TransitionLocation::Execute(ctr, id) => {
                 #[cfg(feature = "yk_jitstate_debug")]
                 print_jit_state("enter-jit-code");
-                let ptr = ctr.exec(ctrlp_vars, frameaddr);
+                self.observers.notify(JitEvent::EnterJitCode(id));
+                let ptr = self.exec_trace(ctr, ctrlp, ctrlp_vars, frameaddr, ptr::null());
                 #[cfg(feature = "yk_jitstate_debug")]
                 print_jit_state("exit-jit-code");
+                self.observers.notify(JitEvent::ExitJitCode(id));
                 return ptr;
             }
             TransitionLocation::StartTracing => {
                 #[cfg(feature = "yk_jitstate_debug")]
                 print_jit_state("start-tracing");
                 let tracer = Arc::clone(&self.tracer);
+                let epoch = self.invalidation_epoch.load(Ordering::Relaxed);
                 match Arc::clone(&tracer).start_collector() {
-                    Ok(tt) => THREAD_MTTHREAD.with(|mtt| {
-                        *mtt.thread_tracer.borrow_mut() = Some((tracer, tt));
-                    }),
-                    Err(e) => todo!("{e:?}"),
+                    Ok(tt) => {
+                        // `transition_location` will have put the HotLocation we're tracing in
+                        // `tracing`.
+                        let id = THREAD_MTTHREAD.with(|mtt| {
+                            *mtt.thread_tracer.borrow_mut() = Some((tracer, tt));
+                            mtt.tracing_epoch.set(epoch);
+                            LocationId::new(mtt.tracing.borrow().as_ref().unwrap())
+                        });
+                        self.observers.notify(JitEvent::StartTracing(id));
+                    }
+                    Err(e) => {
+                        // `transition_location` will have put a `Some` in `tracing`.
+                        let hl = THREAD_MTTHREAD.with(|mtt| mtt.tracing.take().unwrap());
+                        trace_failed(
+                            &self.observers,
+                            &hl,
+                            self.trace_failure_threshold(),
+                            &InvalidTraceError::CollectorFailed(e.to_string()),
+                        );
+                    }
                 }
             }
             TransitionLocation::StopTracing(hl_arc) => {
                 // Assuming no bugs elsewhere, the `unwrap` cannot fail, because `StartTracing`
                 // will have put a `Some` in the `Rc`.
-                let (trcr, thrdtrcr) =
-                    THREAD_MTTHREAD.with(|mtt| mtt.thread_tracer.take().unwrap());
+                let ((trcr, thrdtrcr), epoch, generation) = THREAD_MTTHREAD.with(|mtt| {
+                    (
+                        mtt.thread_tracer.take().unwrap(),
+                        mtt.tracing_epoch.get(),
+                        mtt.tracing_generation.get(),
+                    )
+                });
                 match thrdtrcr.stop_collector() {
                     Ok(utrace) => {
                         #[cfg(feature = "yk_jitstate_debug")]
                         print_jit_state("stop-tracing");
-                        self.queue_compile_job(utrace, hl_arc, trcr);
+                        self.observers
+                            .notify(JitEvent::StopTracing(LocationId::new(&hl_arc)));
+                        self.queue_compile_job(utrace, hl_arc, trcr, epoch, generation);
+                    }
+                    Err(e) => {
+                        trace_failed(&self.observers, &hl_arc, self.trace_failure_threshold(), &e)
                     }
-                    Err(_) => todo!(),
                 }
             }
+            TransitionLocation::AbortTracing(e) => {
+                // Assuming no bugs elsewhere, the `unwrap`s cannot fail, because `StartTracing`
+                // will have put a `Some` in both.
+                let (hl, (_, thrdtrcr)) = THREAD_MTTHREAD.with(|mtt| {
+                    (
+                        mtt.tracing.take().unwrap(),
+                        mtt.thread_tracer.take().unwrap(),
+                    )
+                });
+                // The trace is discarded whether or not the collector stops cleanly.
+                let _ = thrdtrcr.stop_collector();
+                trace_failed(&self.observers, &hl, self.trace_failure_threshold(), &e);
+            }
+            TransitionLocation::StopSideTracing => {
+                // Assuming no bugs elsewhere, the `unwrap`s cannot fail, because
+                // `start_sidetracing` will have put a `Some` in both.
+                let (sidetracing, (trcr, thrdtrcr)) = THREAD_MTTHREAD.with(|mtt| {
+                    (
+                        mtt.sidetracing.take().unwrap(),
+                        mtt.thread_tracer.take().unwrap(),
+                    )
+                });
+                match thrdtrcr.stop_collector() {
+                    Ok(utrace) => {
+                        #[cfg(feature = "yk_jitstate_debug")]
+                        print_jit_state("stop-side-tracing");
+                        self.observers.notify(JitEvent::StopSideTracing {
+                            guardid: sidetracing.guardid,
+                        });
+                        self.queue_sidetrace_compile_job(utrace, sidetracing, trcr);
+                    }
+                    Err(_) => {
+                        sidetracing
+                            .parent
+                            .guard(sidetracing.guardid)
+                            .dont_sidetrace();
+                        self.observers.notify(JitEvent::SideTraceAborted {
+                            guardid: sidetracing.guardid,
+                        });
+                    }
+                }
+            }
+            TransitionLocation::AbortSideTracing => {
+                // Assuming no bugs elsewhere, the `unwrap`s cannot fail, because
+                // `start_sidetracing` will have put a `Some` in both.
+                let (sidetracing, (_, thrdtrcr)) = THREAD_MTTHREAD.with(|mtt| {
+                    (
+                        mtt.sidetracing.take().unwrap(),
+                        mtt.thread_tracer.take().unwrap(),
+                    )
+                });
+                // The trace is discarded whether or not the collector stops cleanly.
+                let _ = thrdtrcr.stop_collector();
+                sidetracing
+                    .parent
+                    .guard(sidetracing.guardid)
+                    .dont_sidetrace();
+                self.observers.notify(JitEvent::SideTraceAborted {
+                    guardid: sidetracing.guardid,
+                });
+            }
         }
         std::ptr::null()
     }
 
+    /// Execute `ctr` (a trace for the control point `ctrlp`), recording it as the trace this thread
+    /// is running so that guard failures can find it. `livevals` is as for [CompiledTrace::exec].
+    fn exec_trace(
+        &self,
+        ctr: Arc<CompiledTrace>,
+        ctrlp: ControlPointId,
+        ctrlp_vars: *mut c_void,
+        frameaddr: *mut c_void,
+        livevals: *const u64,
+    ) -> *const c_void {
+        let prev = THREAD_MTTHREAD.with(|mtt| {
+            mtt.running_trace.replace(Some(RunningTrace {
+                mt: self,
+                ctr: Arc::clone(&ctr),
+                ctrlp,
+                ctrlp_vars,
+            }))
+        });
+        ctr.inc_execs();
+        let ptr = ctr.exec(ctrlp_vars, frameaddr, livevals);
+        THREAD_MTTHREAD.with(|mtt| *mtt.running_trace.borrow_mut() = prev);
+        ptr
+    }
+
+    /// Start tracing a side-trace for guard `guardid` of `parent` (a trace for the control point
+    /// `ctrlp`), which has just failed with the AOT frames `frames` active and the AOT values
+    /// `aotvals` live.
+    fn start_sidetracing(
+        &self,
+        parent: Arc<CompiledTrace>,
+        ctrlp: ControlPointId,
+        guardid: usize,
+        frames: &[FrameInfo],
+        aotvals: &[AOTVar],
+    ) {
+        let tracer = Arc::clone(&self.tracer);
+        match Arc::clone(&tracer).start_collector() {
+            Ok(tt) => {
+                #[cfg(feature = "yk_jitstate_debug")]
+                print_jit_state("start-side-tracing");
+                self.observers
+                    .notify(JitEvent::StartSideTracing { guardid });
+                THREAD_MTTHREAD.with(|mtt| {
+                    *mtt.thread_tracer.borrow_mut() = Some((tracer, tt));
+                    *mtt.sidetracing.borrow_mut() = Some(SideTracing {
+                        parent,
+                        ctrlp,
+                        guardid,
+                        sti: SideTraceInfo::new(frames, aotvals),
+                    });
+                })
+            }
+            Err(_) => {
+                parent.guard(guardid).dont_sidetrace();
+                self.observers
+                    .notify(JitEvent::SideTraceAborted { guardid });
+            }
+        }
+    }
+
+    /// Lock `hl`. If this thread is tracing something (`am_tracing`), we *must* grab the lock,
+    /// because we need to know for sure if `hl` is the point at which we should stop tracing. If
+    /// this thread is not tracing anything, however, it's not worth contending too much with other
+    /// threads: we try moderately hard to grab the lock, but we don't want to park this thread,
+    /// and return `None` if we give up.
+    fn lock_hot_location(
+        hl: &Mutex<HotLocation>,
+        am_tracing: bool,
+    ) -> Option<MutexGuard<'_, HotLocation>> {
+        if !am_tracing {
+            // This thread isn't tracing anything, so we try for a little while to grab the lock,
+            // before giving up and falling back to the interpreter. In general, we expect that
+            // we'll grab the lock rather quickly. However, there is one nasty use-case, which is
+            // when an army of threads all start executing the same piece of tiny code and end up
+            // thrashing away at a single Location, particularly when it's in a non-Compiled
+            // state: we can end up contending horribly for a single lock, and not making much
+            // progress. In that case, it's probably better to let some threads fall back to the
+            // interpreter for another iteration, and hopefully allow them to get sufficiently
+            // out-of-sync that they no longer contend on this one lock as much.
+            let mut sw = SpinWait::new();
+            loop {
+                if let Some(lk) = hl.try_lock() {
+                    return Some(lk);
+                }
+                if !sw.spin() {
+                    return None;
+                }
+            }
+        } else {
+            // This thread is tracing something, so we must grab the lock.
+            Some(hl.lock())
+        }
+    }
+
     /// Perform the next step to `loc` in the `Location` state-machine. If `loc` moves to the
     /// Compiled state, return a pointer to a [CompiledTrace] object.
-    fn transition_location(&self, loc: &Location) -> TransitionLocation {
+    fn transition_location(&self, loc: &Location, ctrlp: ControlPointId) -> TransitionLocation {
         THREAD_MTTHREAD.with(|mtt| {
+            if let Some(ref st) = *mtt.sidetracing.borrow() {
+                // A side-trace ends at the first control point it reaches, whichever `Location` is
+                // passed to it. It must be the control point its parent was traced from, as the
+                // side-trace can only return to that control point's interpreter loop.
+                if st.ctrlp == ctrlp {
+                    return TransitionLocation::StopSideTracing;
+                }
+                return TransitionLocation::AbortSideTracing;
+            }
+            if let Some((_, ref tt)) = *mtt.thread_tracer.borrow() {
+                if tt.trace_len() > self.max_trace_len() {
+                    // Either the interpreter has left the loop being traced and may never come
+                    // back to it, or the loop is too long to be worth compiling. Either way, we
+                    // stop tracing so that this thread can trace something else.
+                    return TransitionLocation::AbortTracing(InvalidTraceError::TraceTooLong);
+                }
+                if mtt.tracing_epoch.get() != self.invalidation_epoch.load(Ordering::Relaxed) {
+                    // Some of the code we've traced may have been invalidated.
+                    return TransitionLocation::AbortTracing(InvalidTraceError::Invalidated);
+                }
+            }
             let am_tracing = mtt.tracing.borrow().is_some();
-            match loc.hot_location() {
-                Some(hl) => {
-                    // If this thread is tracing something, we *must* grab the [HotLocation] lock,
-                    // because we need to know for sure if `loc` is the point at which we should stop
-                    // tracing. If this thread is not tracing anything, however, it's not worth
-                    // contending too much with other threads: we try moderately hard to grab the lock,
-                    // but we don't want to park this thread.
-                    let mut lk = if !am_tracing {
-                        // This thread isn't tracing anything, so we try for a little while to grab the
-                        // lock, before giving up and falling back to the interpreter. In general, we
-                        // expect that we'll grab the lock rather quickly. However, there is one nasty
-                        // use-case, which is when an army of threads all start executing the same
-                        // piece of tiny code and end up thrashing away at a single Location,
-                        // particularly when it's in a non-Compiled state: we can end up contending
-                        // horribly for a single lock, and not making much progress. In that case, it's
-                        // probably better to let some threads fall back to the interpreter for another
-                        // iteration, and hopefully allow them to get sufficiently out-of-sync that
-                        // they no longer contend on this one lock as much.
-                        let mut sw = SpinWait::new();
-                        loop {
-                            if let Some(lk) = hl.try_lock() {
-                                break lk;
-                            }
-                            if !sw.spin() {
-                                return TransitionLocation::NoAction;
-                            }
-                        }
-                    } else {
-                        // This thread is tracing something, so we must grab the lock.
-                        hl.lock()
+            match loc.hot_location_arc_clone() {
+                Some(mut hl_arc) => {
+                    let mut lk = match Self::lock_hot_location(&hl_arc, am_tracing) {
+                        Some(lk) => lk,
+                        None => return TransitionLocation::NoAction,
                     };
+                    if lk.ctrlp != ctrlp {
+                        // `loc` became hot at another control point. Each control point has its
+                        // own live variables, so `loc` is traced, and its traces executed,
+                        // separately at each control point it is reached at: it has a HotLocation
+                        // for each.
+                        let other = match lk.others.iter().find(|(c, _)| *c == ctrlp) {
+                            Some((_, other)) => Arc::clone(other),
+                            None if am_tracing => return TransitionLocation::NoAction,
+                            None => {
+                                let other = Arc::new(Mutex::new(HotLocation {
+                                    kind: HotLocationKind::Counting(0),
+                                    trace_failure: 0,
+                                    ctrlp,
+                                    generation: 0,
+                                    others: Vec::new(),
+                                }));
+                                lk.others.push((ctrlp, Arc::clone(&other)));
+                                other
+                            }
+                        };
+                        drop(lk);
+                        hl_arc = other;
+                        lk = match Self::lock_hot_location(&hl_arc, am_tracing) {
+                            Some(lk) => lk,
+                            None => return TransitionLocation::NoAction,
+                        };
+                    }
 
                     match lk.kind {
                         HotLocationKind::Compiled(ref ctr) => {
@@ -239,35 +645,43 @@ impl MT {
                                 // This thread is tracing something, so bail out as quickly as possible
                                 TransitionLocation::NoAction
                             } else {
-                                TransitionLocation::Execute(Arc::clone(ctr))
+                                TransitionLocation::Execute(
+                                    Arc::clone(ctr),
+                                    LocationId::new(&hl_arc),
+                                )
                             }
                         }
                         HotLocationKind::Compiling => TransitionLocation::NoAction,
                         HotLocationKind::Tracing => {
-                            let hl = loc.hot_location_arc_clone().unwrap();
                             let mut thread_hl_out = mtt.tracing.borrow_mut();
                             if let Some(ref thread_hl_in) = *thread_hl_out {
                                 // This thread is tracing something...
-                                if !Arc::ptr_eq(thread_hl_in, &hl) {
+                                if !Arc::ptr_eq(thread_hl_in, &hl_arc) {
                                     // ...but not this Location.
                                     TransitionLocation::NoAction
+                                } else if lk.generation != mtt.tracing_generation.get() {
+                                    // ...and it's this location, but it was invalidated while we
+                                    // were tracing it.
+                                    TransitionLocation::AbortTracing(InvalidTraceError::Invalidated)
                                 } else {
                                     // ...and it's this location: we have therefore finished tracing the loop.
                                     *thread_hl_out = None;
                                     lk.kind = HotLocationKind::Compiling;
-                                    TransitionLocation::StopTracing(hl)
+                                    TransitionLocation::StopTracing(Arc::clone(&hl_arc))
                                 }
                             } else {
-                                // This thread isn't tracing anything. Note that because we called
-                                // `hot_location_arc_clone` above, the strong count of an `Arc`
-                                // that's no longer being used by that thread will be 2.
-                                if Arc::strong_count(&hl) == 2 {
+                                // This thread isn't tracing anything. Note that because we hold
+                                // `hl_arc`, the strong count of an `Arc` that's no longer being
+                                // used by that thread will be 2 (the other reference belonging to
+                                // `loc`).
+                                if Arc::strong_count(&hl_arc) == 2 {
                                     // Another thread was tracing this location but it's terminated.
                                     if lk.trace_failure < self.trace_failure_threshold() {
                                         // Let's try tracing the location again in this thread.
                                         lk.trace_failure += 1;
                                         lk.kind = HotLocationKind::Tracing;
-                                        *thread_hl_out = Some(hl);
+                                        mtt.tracing_generation.set(lk.generation);
+                                        *thread_hl_out = Some(Arc::clone(&hl_arc));
                                         TransitionLocation::StartTracing
                                     } else {
                                         // This location has failed too many times: don't try tracing it
@@ -281,6 +695,36 @@ impl MT {
                                 }
                             }
                         }
+                        HotLocationKind::TracingFailed => {
+                            if am_tracing {
+                                // This thread is tracing something, so bail out as quickly as possible
+                                TransitionLocation::NoAction
+                            } else {
+                                // A previous attempt at tracing this location failed: try again.
+                                lk.kind = HotLocationKind::Tracing;
+                                mtt.tracing_generation.set(lk.generation);
+                                *mtt.tracing.borrow_mut() = Some(Arc::clone(&hl_arc));
+                                TransitionLocation::StartTracing
+                            }
+                        }
+                        HotLocationKind::Counting(x) => {
+                            if am_tracing {
+                                // This thread is tracing something, so bail out as quickly as possible
+                                TransitionLocation::NoAction
+                            } else if x < self.hot_threshold() {
+                                lk.kind = HotLocationKind::Counting(x + 1);
+                                TransitionLocation::NoAction
+                            } else {
+                                // This location's previous trace was evicted (or it has only
+                                // been counting at this control point), but it's become hot:
+                                // start afresh.
+                                lk.kind = HotLocationKind::Tracing;
+                                lk.trace_failure = 0;
+                                mtt.tracing_generation.set(lk.generation);
+                                *mtt.tracing.borrow_mut() = Some(Arc::clone(&hl_arc));
+                                TransitionLocation::StartTracing
+                            }
+                        }
                         HotLocationKind::DontTrace => TransitionLocation::NoAction,
                     }
                 }
@@ -298,10 +742,15 @@ impl MT {
                                 let hl = HotLocation {
                                     kind: HotLocationKind::Tracing,
                                     trace_failure: 0,
+                                    ctrlp,
+                                    generation: 0,
+                                    others: Vec::new(),
                                 };
                                 if let Some(hl) = loc.count_to_hot_location(x, hl) {
+                                    self.stats.locations_hot.fetch_add(1, Ordering::Relaxed);
                                     debug_assert!(mtt.tracing.borrow().is_none());
                                     *mtt.tracing.borrow_mut() = Some(hl);
+                                    mtt.tracing_generation.set(0);
                                     TransitionLocation::StartTracing
                                 } else {
                                     // We raced with another thread which has started tracing this
@@ -322,25 +771,82 @@ impl MT {
         })
     }
 
-    /// Add a compilation job for `utrace` to the global work queue.
+    /// Add a compilation job for `utrace` to the global work queue. `epoch` is the invalidation
+    /// epoch, and `generation` the generation of `hl_arc`, at the point that `utrace` started being
+    /// collected: if either has changed by the time the trace is compiled, the trace is discarded.
     fn queue_compile_job(
         &self,
         utrace: Box<dyn UnmappedTrace>,
         hl_arc: Arc<Mutex<HotLocation>>,
         tracer: Arc<dyn Tracer>,
+        epoch: u64,
+        generation: u64,
     ) {
+        let trace_failure_threshold = self.trace_failure_threshold();
+        let observers = self.observers.clone();
+        let code_cache = Arc::clone(&self.code_cache);
+        let invalidation_epoch = Arc::clone(&self.invalidation_epoch);
         let do_compile = move || {
-            // FIXME: if mapping or tracing fails we don't want to abort, but in order to do that,
-            // we'll need to move the location into something other than the Compiling state.
+            let start = Instant::now();
+            let epoch_changed = || invalidation_epoch.load(Ordering::Relaxed) != epoch;
+            let invalidated = |lk: &HotLocation| epoch_changed() || lk.generation != generation;
+            if invalidated(&hl_arc.lock()) {
+                trace_failed(
+                    &observers,
+                    &hl_arc,
+                    trace_failure_threshold,
+                    &InvalidTraceError::Invalidated,
+                );
+                return;
+            }
             let irtrace = match utrace.map(tracer) {
                 Ok(x) => x,
-                Err(e) => todo!("{e:?}"),
+                Err(e) => {
+                    trace_failed(&observers, &hl_arc, trace_failure_threshold, &e);
+                    return;
+                }
             };
-            match irtrace.compile() {
-                Ok((codeptr, di_tmpfile)) => {
-                    hl_arc.lock().kind = HotLocationKind::Compiled(Arc::new(CompiledTrace::new(
-                        codeptr, di_tmpfile,
-                    )));
+            let loc = LocationId::new(&hl_arc);
+            match irtrace.compile(None).and_then(|(codeptr, di_tmpfile)| {
+                CompiledTrace::new(codeptr, di_tmpfile, irtrace.obj().map(Path::to_owned))
+            }) {
+                Ok(ctr) => {
+                    let ctr = Arc::new(ctr);
+                    {
+                        let mut lk = hl_arc.lock();
+                        if invalidated(&lk) {
+                            drop(lk);
+                            trace_failed(
+                                &observers,
+                                &hl_arc,
+                                trace_failure_threshold,
+                                &InvalidTraceError::Invalidated,
+                            );
+                            return;
+                        }
+                        lk.kind = HotLocationKind::Compiled(Arc::clone(&ctr));
+                    }
+                    observers.notify(JitEvent::TraceCompiled {
+                        loc,
+                        duration: start.elapsed(),
+                    });
+                    for evicted in code_cache.insert(&hl_arc, &ctr) {
+                        observers.notify(JitEvent::TraceEvicted(evicted));
+                    }
+                    if epoch_changed() {
+                        // `MT::invalidate_all` may have emptied the code cache after we installed
+                        // the trace but before we inserted it into the cache.
+                        let mut lk = hl_arc.lock();
+                        let installed = match lk.kind {
+                            HotLocationKind::Compiled(ref cur) => Arc::ptr_eq(cur, &ctr),
+                            _ => false,
+                        };
+                        if installed {
+                            lk.reprofile();
+                            drop(lk);
+                            observers.notify(JitEvent::TraceInvalidated(loc));
+                        }
+                    }
                 }
                 Err(_e) => {
                     // FIXME: Properly handle failed trace compilation, e.g. depending on the
@@ -352,6 +858,10 @@ impl MT {
                     //
                     #[cfg(feature = "yk_jitstate_debug")]
                     print_jit_state("trace-compilation-aborted");
+                    observers.notify(JitEvent::TraceCompilationAborted {
+                        loc,
+                        duration: start.elapsed(),
+                    });
                 }
             };
         };
@@ -364,6 +874,423 @@ impl MT {
 
         self.queue_job(Box::new(do_compile));
     }
+
+    /// Add a compilation job for the side-trace `utrace` to the global work queue.
+    fn queue_sidetrace_compile_job(
+        &self,
+        utrace: Box<dyn UnmappedTrace>,
+        sidetracing: SideTracing,
+        tracer: Arc<dyn Tracer>,
+    ) {
+        let observers = self.observers.clone();
+        let code_cache = Arc::clone(&self.code_cache);
+        let do_compile = move || {
+            let start = Instant::now();
+            let guardid = sidetracing.guardid;
+            let guard = sidetracing.parent.guard(guardid);
+            let mut irtrace = match utrace.map(tracer) {
+                Ok(x) => x,
+                Err(_) => {
+                    guard.dont_sidetrace();
+                    observers.notify(JitEvent::SideTraceAborted { guardid });
+                    return;
+                }
+            };
+            // The side-trace's guard failures deoptimise into the same AOT code as its parent's,
+            // so it must be compiled from the same object's IR.
+            let (func_name, bb) = sidetracing.sti.guard_block();
+            if irtrace.obj() != sidetracing.parent.obj()
+                || !irtrace.trim_to_sidetrace_start(func_name, bb)
+            {
+                guard.dont_sidetrace();
+                observers.notify(JitEvent::SideTraceAborted { guardid });
+                return;
+            }
+            match irtrace
+                .compile(Some(&sidetracing.sti))
+                .and_then(|(codeptr, di_tmpfile)| {
+                    CompiledTrace::new(codeptr, di_tmpfile, irtrace.obj().map(Path::to_owned))
+                }) {
+                Ok(ctr) => {
+                    guard.set_sidetrace(Arc::new(ctr));
+                    observers.notify(JitEvent::SideTraceCompiled {
+                        guardid,
+                        duration: start.elapsed(),
+                    });
+                    // The side-trace's memory counts towards its parent's.
+                    for evicted in code_cache.shrink() {
+                        observers.notify(JitEvent::TraceEvicted(evicted));
+                    }
+                }
+                Err(_e) => {
+                    guard.dont_sidetrace();
+                    #[cfg(feature = "yk_jitstate_debug")]
+                    print_jit_state("trace-compilation-aborted");
+                    observers.notify(JitEvent::SideTraceAborted { guardid });
+                }
+            }
+        };
+
+        #[cfg(feature = "yk_testing")]
+        if *SERIALISE_COMPILATION {
+            do_compile();
+            return;
+        }
+
+        self.queue_job(Box::new(do_compile));
+    }
+}
+
+/// Record that tracing `hl` failed because of `reason`. If `hl` has failed more than `threshold`
+/// times it won't be traced again, otherwise it will be retried.
+fn trace_failed(
+    observers: &Observers,
+    hl: &Mutex<HotLocation>,
+    threshold: TraceFailureThreshold,
+    reason: &InvalidTraceError,
+) {
+    #[cfg(feature = "yk_jitstate_debug")]
+    print_jit_state(&format!("tracing-aborted: {reason}"));
+    observers.notify(JitEvent::TracingAborted {
+        loc: LocationId::new(hl),
+        reason,
+    });
+    let mut lk = hl.lock();
+    if let InvalidTraceError::Invalidated = reason {
+        // Invalidation isn't the location's fault, so it doesn't count as a trace failure.
+        if matches!(
+            lk.kind,
+            HotLocationKind::Tracing | HotLocationKind::Compiling
+        ) {
+            lk.reprofile();
+        }
+    } else {
+        lk.trace_failed(threshold);
+    }
+}
+
+/// Return the trace this thread is running. Must only be called while a trace is running.
+pub(crate) fn running_trace() -> Arc<CompiledTrace> {
+    THREAD_MTTHREAD.with(|mtt| Arc::clone(&mtt.running_trace.borrow().as_ref().unwrap().ctr))
+}
+
+/// Called when guard `guardid` of the trace this thread is running fails, with the AOT frames
+/// `frames` active and the AOT values `aotvals` live (whose run-time values are `vals`). If the
+/// guard has a side-trace, it is executed and its return value returned. Otherwise, the failure is
+/// counted (which may cause the guard to be side-traced) and `None` is returned, in which case the
+/// caller must deoptimise.
+pub(crate) fn guard_failed(
+    guardid: usize,
+    frames: &[FrameInfo],
+    aotvals: &[AOTVar],
+    vals: &[u128],
+    frameaddr: *mut c_void,
+) -> Option<*const c_void> {
+    let (mt, ctr, ctrlp, ctrlp_vars) = THREAD_MTTHREAD.with(|mtt| {
+        let rt = mtt.running_trace.borrow();
+        // Guards can only fail while a trace is running.
+        let rt = rt.as_ref().unwrap();
+        (rt.mt, Arc::clone(&rt.ctr), rt.ctrlp, rt.ctrlp_vars)
+    });
+    // The `MT` outlives the execution of the trace.
+    let mt = unsafe { &*mt };
+    let guard = ctr.guard(guardid);
+    if let Some(st) = guard.sidetrace() {
+        #[cfg(feature = "yk_jitstate_debug")]
+        print_jit_state("enter-side-trace");
+        mt.observers.notify(JitEvent::EnterSideTrace { guardid });
+        // Side-traces take each live value as a word: they can't be compiled with floating point
+        // or vector live values, so no bits are lost.
+        let vals = vals.iter().map(|v| *v as u64).collect::<Vec<_>>();
+        return Some(mt.exec_trace(st, ctrlp, ctrlp_vars, frameaddr, vals.as_ptr()));
+    }
+    if guard.inc_failed() >= mt.sidetrace_threshold()
+        && THREAD_MTTHREAD.with(|mtt| mtt.thread_tracer.borrow().is_none())
+        && guard.start_sidetracing()
+    {
+        mt.start_sidetracing(Arc::clone(&ctr), ctrlp, guardid, frames, aotvals);
+    }
+    ctr.inc_deopts();
+    mt.observers.notify(JitEvent::Deoptimise { guardid });
+    None
+}
+
+impl Drop for MT {
+    fn drop(&mut self) {
+        self.shutdown(None);
+        if let Some(path) = &self.stats_file {
+            let json = self.stats().to_json();
+            if path == "-" {
+                eprintln!("{json}");
+            } else if let Err(e) = fs::write(path, json) {
+                eprintln!("Couldn't write statistics to {path}: {e}");
+            }
+        }
+    }
+}
+
+/// The compilation jobs waiting for a worker thread.
+struct JobQueue {
+    jobs: VecDeque<Box<dyn FnOnce() + Send>>,
+    /// Set when the `MT` is shut down: worker threads exit as soon as they have no job to run.
+    shutdown: bool,
+}
+
+/// The counters and timers behind [MT::stats]. These are updated with relaxed atomic operations,
+/// so keeping them is cheap enough to be always on. Most of them are updated by observing
+/// [JitEvent]s.
+#[derive(Default)]
+struct Stats {
+    locations_hot: AtomicU64,
+    traces_started: AtomicU64,
+    traces_aborted: AtomicU64,
+    traces_compiled: AtomicU64,
+    trace_compilations_aborted: AtomicU64,
+    sidetraces_compiled: AtomicU64,
+    sidetraces_aborted: AtomicU64,
+    trace_executions: AtomicU64,
+    deopts: AtomicU64,
+    traces_evicted: AtomicU64,
+    traces_invalidated: AtomicU64,
+    /// The total time, in nanoseconds, spent mapping and compiling traces.
+    compile_time_ns: AtomicU64,
+}
+
+impl JitObserver for Stats {
+    fn event(&self, event: &JitEvent) {
+        let ctr = match event {
+            JitEvent::StartTracing(_) => &self.traces_started,
+            JitEvent::TracingAborted { .. } => &self.traces_aborted,
+            JitEvent::TraceCompiled { duration, .. } => {
+                self.add_compile_time(*duration);
+                &self.traces_compiled
+            }
+            JitEvent::TraceCompilationAborted { duration, .. } => {
+                self.add_compile_time(*duration);
+                &self.trace_compilations_aborted
+            }
+            JitEvent::SideTraceCompiled { duration, .. } => {
+                self.add_compile_time(*duration);
+                &self.sidetraces_compiled
+            }
+            JitEvent::SideTraceAborted { .. } => &self.sidetraces_aborted,
+            JitEvent::EnterJitCode(_) | JitEvent::EnterSideTrace { .. } => &self.trace_executions,
+            JitEvent::Deoptimise { .. } => &self.deopts,
+            JitEvent::TraceEvicted(_) => &self.traces_evicted,
+            JitEvent::TraceInvalidated(_) => &self.traces_invalidated,
+            JitEvent::StopTracing(_)
+            | JitEvent::ExitJitCode(_)
+            | JitEvent::StartSideTracing { .. }
+            | JitEvent::StopSideTracing { .. } => return,
+        };
+        ctr.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    fn add_compile_time(&self, duration: Duration) {
+        self.compile_time_ns.fetch_add(
+            u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// A snapshot of an [MT] instance's statistics, as returned by [MT::stats].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MTStats {
+    /// How many `Location`s have become hot.
+    pub locations_hot: u64,
+    /// How many traces (not including side-traces) have started to be collected.
+    pub traces_started: u64,
+    /// How many traces were abandoned before they could be compiled (e.g. because they were too
+    /// long or couldn't be mapped).
+    pub traces_aborted: u64,
+    /// How many traces have been successfully compiled.
+    pub traces_compiled: u64,
+    /// How many traces failed to compile.
+    pub trace_compilations_aborted: u64,
+    /// How many side-traces have been successfully compiled.
+    pub sidetraces_compiled: u64,
+    /// How many side-traces were abandoned, at any stage.
+    pub sidetraces_aborted: u64,
+    /// How many times compiled traces (including side-traces) have been entered.
+    pub trace_executions: u64,
+    /// How many times a guard failure has deoptimised back to the interpreter.
+    pub deopts: u64,
+    /// How many compiled traces have been evicted from the code cache.
+    pub traces_evicted: u64,
+    /// How many compiled traces have been invalidated by [MT::invalidate] or [MT::invalidate_all].
+    pub traces_invalidated: u64,
+    /// How many bytes of code and data are used by the compiled traces in the code cache.
+    pub code_cache_size: usize,
+    /// The total time spent by worker threads mapping and compiling traces.
+    pub compile_time: Duration,
+    /// How many jobs were waiting for a worker thread when the snapshot was taken.
+    pub job_queue_len: usize,
+}
+
+impl MTStats {
+    /// Return these statistics as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut s = String::from("{\n");
+        for (k, v) in [
+            ("locations_hot", self.locations_hot),
+            ("traces_started", self.traces_started),
+            ("traces_aborted", self.traces_aborted),
+            ("traces_compiled", self.traces_compiled),
+            (
+                "trace_compilations_aborted",
+                self.trace_compilations_aborted,
+            ),
+            ("sidetraces_compiled", self.sidetraces_compiled),
+            ("sidetraces_aborted", self.sidetraces_aborted),
+            ("trace_executions", self.trace_executions),
+            ("deopts", self.deopts),
+            ("traces_evicted", self.traces_evicted),
+            ("traces_invalidated", self.traces_invalidated),
+            ("code_cache_size", self.code_cache_size as u64),
+        ] {
+            writeln!(s, "  \"{k}\": {v},").unwrap();
+        }
+        writeln!(
+            s,
+            "  \"compile_time_secs\": {},",
+            self.compile_time.as_secs_f64()
+        )
+        .unwrap();
+        writeln!(s, "  \"job_queue_len\": {}", self.job_queue_len).unwrap();
+        s.push('}');
+        s
+    }
+}
+
+/// Configures and creates a [MT] instance. Any setting that isn't explicitly set takes its
+/// default value.
+pub struct MTBuilder {
+    hot_threshold: HotThreshold,
+    trace_failure_threshold: TraceFailureThreshold,
+    sidetrace_threshold: HotThreshold,
+    max_trace_len: Option<usize>,
+    code_cache_budget: usize,
+    max_worker_threads: usize,
+    tracer_kind: Option<TracerKind>,
+    perf_config: PerfCollectorConfig,
+}
+
+impl MTBuilder {
+    pub fn new() -> Self {
+        Self {
+            hot_threshold: DEFAULT_HOT_THRESHOLD,
+            trace_failure_threshold: DEFAULT_TRACE_FAILURE_THRESHOLD,
+            sidetrace_threshold: DEFAULT_SIDETRACE_THRESHOLD,
+            max_trace_len: None,
+            code_cache_budget: DEFAULT_CODE_CACHE_BUDGET,
+            max_worker_threads: cmp::max(1, num_cpus::get() - 1),
+            tracer_kind: None,
+            perf_config: PerfCollectorConfig::default(),
+        }
+    }
+
+    /// Set the threshold at which `Location`'s are considered hot.
+    pub fn hot_threshold(mut self, hot_threshold: HotThreshold) -> Self {
+        self.hot_threshold = hot_threshold;
+        self
+    }
+
+    /// Set the threshold at which a `Location` from which tracing has failed multiple times is
+    /// marked as "do not try tracing again". Must be >= 1.
+    pub fn trace_failure_threshold(
+        mut self,
+        trace_failure_threshold: TraceFailureThreshold,
+    ) -> Self {
+        self.trace_failure_threshold = trace_failure_threshold;
+        self
+    }
+
+    /// Set the number of times a guard must fail before a side-trace is traced from it.
+    pub fn sidetrace_threshold(mut self, sidetrace_threshold: HotThreshold) -> Self {
+        self.sidetrace_threshold = sidetrace_threshold;
+        self
+    }
+
+    /// Set the length beyond which a trace is abandoned. See [MT::max_trace_len]. If this is not
+    /// called, the tracer's own default is used.
+    pub fn max_trace_len(mut self, max_trace_len: usize) -> Self {
+        self.max_trace_len = Some(max_trace_len);
+        self
+    }
+
+    /// Set the number of bytes that compiled traces can use before some of them are evicted.
+    pub fn code_cache_budget(mut self, code_cache_budget: usize) -> Self {
+        self.code_cache_budget = code_cache_budget;
+        self
+    }
+
+    /// Set the maximum number of worker threads. Must be >= 1.
+    pub fn max_worker_threads(mut self, max_worker_threads: usize) -> Self {
+        self.max_worker_threads = max_worker_threads;
+        self
+    }
+
+    /// Use a tracer of kind `kind`. If this is not called, the preferred tracer for the platform is
+    /// used.
+    pub fn tracer_kind(mut self, kind: TracerKind) -> Self {
+        self.tracer_kind = Some(kind);
+        self
+    }
+
+    /// Set the configuration used if a hardware tracer is created.
+    pub fn perf_collector_config(mut self, config: PerfCollectorConfig) -> Self {
+        self.perf_config = config;
+        self
+    }
+
+    /// Create a new meta-tracer instance with this configuration. If the `YKD_TRACER` environment
+    /// variable is set, it overrides the tracer kind.
+    pub fn build(self) -> Result<MT, Box<dyn Error>> {
+        if self.trace_failure_threshold < 1 {
+            return Err("Trace failure threshold must be >= 1.".into());
+        }
+        if self.max_worker_threads < 1 {
+            return Err("Maximum number of worker threads must be >= 1.".into());
+        }
+        let tracer_kind = match env::var("YKD_TRACER") {
+            Ok(s) => Some(s.parse::<TracerKind>()?),
+            Err(_) => self.tracer_kind,
+        };
+        let tracer = match tracer_kind {
+            Some(kind) => tracer_for_kind(kind, self.perf_config)?,
+            None => default_tracer_for_platform(self.perf_config)?,
+        };
+        let max_trace_len = self
+            .max_trace_len
+            .unwrap_or_else(|| tracer.default_max_trace_len());
+        let stats = Arc::new(Stats::default());
+        let observers = Observers::new(Arc::clone(&stats) as Arc<dyn JitObserver>);
+        Ok(MT {
+            hot_threshold: AtomicHotThreshold::new(self.hot_threshold),
+            trace_failure_threshold: AtomicTraceFailureThreshold::new(self.trace_failure_threshold),
+            sidetrace_threshold: AtomicHotThreshold::new(self.sidetrace_threshold),
+            max_trace_len: AtomicUsize::new(max_trace_len),
+            job_queue: Arc::new((
+                Condvar::new(),
+                Mutex::new(JobQueue {
+                    jobs: VecDeque::new(),
+                    shutdown: false,
+                }),
+            )),
+            max_worker_threads: AtomicUsize::new(self.max_worker_threads),
+            active_worker_threads: AtomicUsize::new(0),
+            worker_threads: Mutex::new(Vec::new()),
+            tracer,
+            observers,
+            code_cache: Arc::new(CodeCache::new(self.code_cache_budget)),
+            invalidation_epoch: Arc::new(AtomicU64::new(0)),
+            stats,
+            stats_file: env::var("YKD_STATS").ok(),
+        })
+    }
 }
 
 /// Meta-tracer per-thread state. Note that this struct is neither `Send` nor `Sync`: it can only
@@ -383,6 +1310,14 @@ pub struct MTThread {
     /// `RefCell<None>`. We need to keep track of the [Tracer] used to start the [ThreadTracer], as
     /// trace mapping requires a reference to the [Tracer].
     thread_tracer: RefCell<Option<(Arc<dyn Tracer>, Box<dyn ThreadTracer>)>>,
+    /// If this thread is tracing a side-trace, this is the guard failure it started from.
+    sidetracing: RefCell<Option<SideTracing>>,
+    /// The compiled trace that this thread is currently executing, if any.
+    running_trace: RefCell<Option<RunningTrace>>,
+    /// The value of [MT::invalidation_epoch] when this thread started its current trace.
+    tracing_epoch: Cell<u64>,
+    /// The generation of the [HotLocation] being traced when this thread started tracing it.
+    tracing_generation: Cell<u64>,
     // Raw pointers are neither send nor sync.
     _dont_send_or_sync_me: PhantomData<*mut ()>,
 }
@@ -392,18 +1327,51 @@ impl MTThread {
         MTThread {
             tracing: RefCell::new(None),
             thread_tracer: RefCell::new(None),
+            sidetracing: RefCell::new(None),
+            running_trace: RefCell::new(None),
+            tracing_epoch: Cell::new(0),
+            tracing_generation: Cell::new(0),
             _dont_send_or_sync_me: PhantomData,
         }
     }
 }
 
+/// A guard failure from which a side-trace is being traced.
+struct SideTracing {
+    /// The trace containing the failed guard.
+    parent: Arc<CompiledTrace>,
+    /// The control point that `parent` was traced from.
+    ctrlp: ControlPointId,
+    guardid: usize,
+    sti: SideTraceInfo,
+}
+
+/// A compiled trace being executed by a thread, along with the arguments it was entered with.
+struct RunningTrace {
+    /// The `MT` executing the trace. A raw pointer is sound because a `RunningTrace` only exists
+    /// for the duration of [MT::exec_trace], which borrows the `MT`: the `MT` thus outlives every
+    /// use of the pointer (by guard failures during the trace's execution).
+    mt: *const MT,
+    ctr: Arc<CompiledTrace>,
+    ctrlp: ControlPointId,
+    ctrlp_vars: *mut c_void,
+}
+
 /// What action should a caller of `MT::transition_location` take?
 #[derive(Debug)]
 enum TransitionLocation {
     NoAction,
-    Execute(Arc<CompiledTrace>),
+    /// Execute the trace, which belongs to the hot location identified by the [LocationId].
+    Execute(Arc<CompiledTrace>, LocationId),
     StartTracing,
     StopTracing(Arc<Mutex<HotLocation>>),
+    /// This thread's trace must be abandoned (e.g. because it has grown too long).
+    AbortTracing(InvalidTraceError),
+    /// This thread has reached the end of the side-trace it is tracing.
+    StopSideTracing,
+    /// This thread's side-trace reached a control point other than its parent's, so it must be
+    /// abandoned.
+    AbortSideTracing,
 }
 
 #[cfg(test)]
@@ -412,10 +1380,11 @@ impl PartialEq for TransitionLocation {
         // We only implement enough of the equality function for the tests we have.
         match (self, other) {
             (TransitionLocation::NoAction, TransitionLocation::NoAction) => true,
-            (TransitionLocation::Execute(p1), TransitionLocation::Execute(p2)) => {
+            (TransitionLocation::Execute(p1, _), TransitionLocation::Execute(p2, _)) => {
                 std::ptr::eq(p1, p2)
             }
             (TransitionLocation::StartTracing, TransitionLocation::StartTracing) => true,
+            (TransitionLocation::AbortTracing(_), TransitionLocation::AbortTracing(_)) => true,
             (x, y) => todo!("{:?} {:?}", x, y),
         }
     }
@@ -429,6 +1398,9 @@ mod tests {
     use std::{convert::TryFrom, hint::black_box, sync::atomic::AtomicU64, thread};
     use test::bench::Bencher;
 
+    /// The control point used by tests which don't care which control point they're at.
+    const CTRLP: ControlPointId = ControlPointId::new(0);
+
     #[test]
     fn basic_transitions() {
         let hot_thrsh = 5;
@@ -436,18 +1408,21 @@ mod tests {
         mt.set_hot_threshold(hot_thrsh);
         let loc = Location::new();
         for i in 0..mt.hot_threshold() {
-            assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
             assert_eq!(loc.count(), Some(i + 1));
         }
         assert_eq!(
-            mt.transition_location(&loc),
+            mt.transition_location(&loc, CTRLP),
             TransitionLocation::StartTracing
         );
         assert!(matches!(
             loc.hot_location().unwrap().lock().kind,
             HotLocationKind::Tracing
         ));
-        match mt.transition_location(&loc) {
+        match mt.transition_location(&loc, CTRLP) {
             TransitionLocation::StopTracing(_) => {
                 assert!(matches!(
                     loc.hot_location().unwrap().lock().kind,
@@ -459,8 +1434,8 @@ mod tests {
             _ => unreachable!(),
         }
         assert!(matches!(
-            mt.transition_location(&loc),
-            TransitionLocation::Execute(_)
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::Execute(..)
         ));
     }
 
@@ -482,16 +1457,28 @@ mod tests {
                 // need to make sure that this loop cannot tip the Location over the threshold,
                 // otherwise tracing will start, and the assertions will fail.
                 for _ in 0..hot_thrsh / (num_threads * 4) {
-                    assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+                    assert_eq!(
+                        mt.transition_location(&loc, CTRLP),
+                        TransitionLocation::NoAction
+                    );
                     let c1 = loc.count();
                     assert!(c1.is_some());
-                    assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+                    assert_eq!(
+                        mt.transition_location(&loc, CTRLP),
+                        TransitionLocation::NoAction
+                    );
                     let c2 = loc.count();
                     assert!(c2.is_some());
-                    assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+                    assert_eq!(
+                        mt.transition_location(&loc, CTRLP),
+                        TransitionLocation::NoAction
+                    );
                     let c3 = loc.count();
                     assert!(c3.is_some());
-                    assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+                    assert_eq!(
+                        mt.transition_location(&loc, CTRLP),
+                        TransitionLocation::NoAction
+                    );
                     let c4 = loc.count();
                     assert!(c4.is_some());
                     assert!(c4.unwrap() >= c3.unwrap());
@@ -509,14 +1496,14 @@ mod tests {
         // at or below the threshold: it could even be (although it's rather unlikely) 0!
         assert!(loc.count().is_some());
         loop {
-            match mt.transition_location(&loc) {
+            match mt.transition_location(&loc, CTRLP) {
                 TransitionLocation::NoAction => (),
                 TransitionLocation::StartTracing => break,
                 _ => unreachable!(),
             }
         }
         assert!(matches!(
-            mt.transition_location(&loc),
+            mt.transition_location(&loc, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
         // At this point, we have nothing to meaningfully test over the `basic_transitions` test.
@@ -534,7 +1521,10 @@ mod tests {
 
         // Get the location to the point of being hot.
         for _ in 0..THRESHOLD {
-            assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
         }
 
         // Start tracing in a thread and purposefully let the thread terminate before tracing is
@@ -545,7 +1535,7 @@ mod tests {
                 let loc = Arc::clone(&loc);
                 thread::spawn(move || {
                     assert!(matches!(
-                        mt.transition_location(&loc),
+                        mt.transition_location(&loc, CTRLP),
                         TransitionLocation::StartTracing
                     ));
                 })
@@ -563,7 +1553,10 @@ mod tests {
             loc.hot_location().unwrap().lock().kind,
             HotLocationKind::Tracing
         ));
-        assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::NoAction
+        );
         assert!(matches!(
             loc.hot_location().unwrap().lock().kind,
             HotLocationKind::DontTrace
@@ -581,7 +1574,10 @@ mod tests {
 
         // Get the location to the point of being hot.
         for _ in 0..THRESHOLD {
-            assert_eq!(mt.transition_location(&loc), TransitionLocation::NoAction);
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
         }
 
         // Start tracing in a thread and purposefully let the thread terminate before tracing is
@@ -592,7 +1588,7 @@ mod tests {
                 let loc = Arc::clone(&loc);
                 thread::spawn(move || {
                     assert!(matches!(
-                        mt.transition_location(&loc),
+                        mt.transition_location(&loc, CTRLP),
                         TransitionLocation::StartTracing
                     ));
                 })
@@ -612,7 +1608,7 @@ mod tests {
         ));
         // Start tracing again...
         assert!(matches!(
-            mt.transition_location(&loc),
+            mt.transition_location(&loc, CTRLP),
             TransitionLocation::StartTracing
         ));
         assert!(matches!(
@@ -621,7 +1617,7 @@ mod tests {
         ));
         // ...and this time let tracing succeed.
         assert!(matches!(
-            mt.transition_location(&loc),
+            mt.transition_location(&loc, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
         // If tracing succeeded, we'll now be in the Compiling state.
@@ -644,21 +1640,30 @@ mod tests {
         let loc2 = Location::new();
 
         for _ in 0..THRESHOLD {
-            assert_eq!(mt.transition_location(&loc1), TransitionLocation::NoAction);
-            assert_eq!(mt.transition_location(&loc2), TransitionLocation::NoAction);
+            assert_eq!(
+                mt.transition_location(&loc1, CTRLP),
+                TransitionLocation::NoAction
+            );
+            assert_eq!(
+                mt.transition_location(&loc2, CTRLP),
+                TransitionLocation::NoAction
+            );
         }
         assert!(matches!(
-            mt.transition_location(&loc1),
+            mt.transition_location(&loc1, CTRLP),
             TransitionLocation::StartTracing
         ));
-        assert_eq!(mt.transition_location(&loc2), TransitionLocation::NoAction);
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
         assert!(matches!(
             loc1.hot_location().unwrap().lock().kind,
             HotLocationKind::Tracing
         ));
         assert_eq!(loc2.count(), Some(THRESHOLD));
         assert!(matches!(
-            mt.transition_location(&loc1),
+            mt.transition_location(&loc1, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
         assert!(matches!(
@@ -666,11 +1671,11 @@ mod tests {
             HotLocationKind::Compiling
         ));
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StartTracing
         ));
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
     }
@@ -695,9 +1700,9 @@ mod tests {
             let num_starts = Arc::clone(&num_starts);
             thrs.push(thread::spawn(move || {
                 for _ in 0..THRESHOLD {
-                    match mt.transition_location(&loc) {
+                    match mt.transition_location(&loc, CTRLP) {
                         TransitionLocation::NoAction => (),
-                        TransitionLocation::Execute(_) => (),
+                        TransitionLocation::Execute(..) => (),
                         TransitionLocation::StartTracing => {
                             num_starts.fetch_add(1, Ordering::Relaxed);
                             assert!(matches!(
@@ -705,14 +1710,14 @@ mod tests {
                                 HotLocationKind::Tracing
                             ));
 
-                            match mt.transition_location(&loc) {
+                            match mt.transition_location(&loc, CTRLP) {
                                 TransitionLocation::StopTracing(_) => {
                                     assert!(matches!(
                                         loc.hot_location().unwrap().lock().kind,
                                         HotLocationKind::Compiling
                                     ));
                                     assert_eq!(
-                                        mt.transition_location(&loc),
+                                        mt.transition_location(&loc, CTRLP),
                                         TransitionLocation::NoAction
                                     );
                                     assert!(matches!(
@@ -727,7 +1732,8 @@ mod tests {
                                 x => unreachable!("Reached incorrect state {:?}", x),
                             }
                             loop {
-                                if let TransitionLocation::Execute(_) = mt.transition_location(&loc)
+                                if let TransitionLocation::Execute(..) =
+                                    mt.transition_location(&loc, CTRLP)
                                 {
                                     break;
                                 }
@@ -760,8 +1766,14 @@ mod tests {
         let loc2 = Location::new();
 
         for _ in 0..THRESHOLD {
-            assert_eq!(mt.transition_location(&loc1), TransitionLocation::NoAction);
-            assert_eq!(mt.transition_location(&loc2), TransitionLocation::NoAction);
+            assert_eq!(
+                mt.transition_location(&loc1, CTRLP),
+                TransitionLocation::NoAction
+            );
+            assert_eq!(
+                mt.transition_location(&loc2, CTRLP),
+                TransitionLocation::NoAction
+            );
         }
 
         {
@@ -769,7 +1781,7 @@ mod tests {
             let loc1 = Arc::clone(&loc1);
             thread::spawn(move || {
                 assert!(matches!(
-                    mt.transition_location(&loc1),
+                    mt.transition_location(&loc1, CTRLP),
                     TransitionLocation::StartTracing
                 ));
             })
@@ -778,12 +1790,15 @@ mod tests {
         }
 
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StartTracing
         ));
-        assert_eq!(mt.transition_location(&loc1), TransitionLocation::NoAction);
+        assert_eq!(
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::NoAction
+        );
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
     }
@@ -794,7 +1809,7 @@ mod tests {
         let loc = Location::new();
         b.iter(|| {
             for _ in 0..100000 {
-                black_box(mt.transition_location(&loc));
+                black_box(mt.transition_location(&loc, CTRLP));
             }
         });
     }
@@ -810,7 +1825,7 @@ mod tests {
                 let mt = Arc::clone(&mt);
                 thrs.push(thread::spawn(move || {
                     for _ in 0..100 {
-                        black_box(mt.transition_location(&loc));
+                        black_box(mt.transition_location(&loc, CTRLP));
                     }
                 }));
             }
@@ -820,6 +1835,529 @@ mod tests {
         });
     }
 
+    #[test]
+    fn builder_sets_thresholds() {
+        let mt = MTBuilder::new()
+            .hot_threshold(7)
+            .trace_failure_threshold(3)
+            .sidetrace_threshold(9)
+            .max_worker_threads(2)
+            .build()
+            .unwrap();
+        assert_eq!(mt.hot_threshold(), 7);
+        assert_eq!(mt.trace_failure_threshold(), 3);
+        assert_eq!(mt.sidetrace_threshold(), 9);
+        assert_eq!(mt.max_worker_threads(), 2);
+        mt.set_max_worker_threads(4);
+        assert_eq!(mt.max_worker_threads(), 4);
+    }
+
+    #[test]
+    fn failed_traces_are_retried() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(THRESHOLD);
+        let loc = Location::new();
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+
+        // Each failed attempt at tracing leaves the location in a state where it can be retried...
+        for i in 0..mt.trace_failure_threshold() {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::StartTracing
+            );
+            let hl = THREAD_MTTHREAD.with(|mtt| mtt.tracing.take().unwrap());
+            trace_failed(
+                &mt.observers,
+                &hl,
+                mt.trace_failure_threshold(),
+                &InvalidTraceError::BufferOverflow,
+            );
+            assert!(matches!(hl.lock().kind, HotLocationKind::TracingFailed));
+            assert_eq!(hl.lock().trace_failure, i + 1);
+        }
+
+        // ...until it has failed too often.
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        let hl = THREAD_MTTHREAD.with(|mtt| mtt.tracing.take().unwrap());
+        trace_failed(
+            &mt.observers,
+            &hl,
+            mt.trace_failure_threshold(),
+            &InvalidTraceError::EmptyTrace,
+        );
+        assert!(matches!(hl.lock().kind, HotLocationKind::DontTrace));
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::NoAction
+        );
+    }
+
+    /// A thread tracer whose trace is always `self.0` long, but which never produces a trace.
+    struct DummyThreadTracer(usize);
+    impl ThreadTracer for DummyThreadTracer {
+        fn stop_collector(self: Box<Self>) -> Result<Box<dyn UnmappedTrace>, InvalidTraceError> {
+            Err(InvalidTraceError::EmptyTrace)
+        }
+        fn trace_len(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn overlong_traces_are_aborted() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(THRESHOLD);
+        mt.set_max_trace_len(10);
+        let loc1 = Location::new();
+        let loc2 = Location::new();
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc1, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+        assert_eq!(
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::StartTracing
+        );
+
+        // While the trace is short enough, other locations don't affect it.
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.thread_tracer.borrow_mut() =
+                Some((Arc::clone(&mt.tracer), Box::new(DummyThreadTracer(10))));
+        });
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
+
+        // Once it's too long, the next control point aborts it, whichever location it's at.
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.thread_tracer.borrow_mut() =
+                Some((Arc::clone(&mt.tracer), Box::new(DummyThreadTracer(11))));
+        });
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::AbortTracing(InvalidTraceError::TraceTooLong)
+        );
+        mt.control_point(&loc2, CTRLP, ptr::null_mut(), ptr::null_mut());
+        THREAD_MTTHREAD.with(|mtt| {
+            assert!(mtt.tracing.borrow().is_none());
+            assert!(mtt.thread_tracer.borrow().is_none());
+        });
+        assert!(matches!(
+            loc1.hot_location().unwrap().lock().kind,
+            HotLocationKind::TracingFailed
+        ));
+        assert_eq!(loc1.hot_location().unwrap().lock().trace_failure, 1);
+
+        // This thread is now free to trace other locations.
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
+        assert_eq!(loc2.count(), Some(1));
+    }
+
+    #[test]
+    fn observers_see_events() {
+        struct RecordingObserver(Mutex<Vec<String>>);
+        impl JitObserver for RecordingObserver {
+            fn event(&self, event: &JitEvent) {
+                self.0.lock().push(format!("{event:?}"));
+            }
+        }
+
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MTBuilder::new()
+            .hot_threshold(THRESHOLD)
+            .tracer_kind(TracerKind::Software)
+            .build()
+            .unwrap();
+        let obs = Arc::new(RecordingObserver(Mutex::new(Vec::new())));
+        mt.add_observer(Arc::clone(&obs) as Arc<dyn JitObserver>);
+        let loc = Location::new();
+        for _ in 0..THRESHOLD {
+            mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        }
+        assert!(obs.0.lock().is_empty());
+
+        // Nothing is recorded by the software tracer here, so tracing fails with an empty trace.
+        mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        let id = LocationId::new(loc.hot_location().unwrap());
+        assert_eq!(
+            *obs.0.lock(),
+            vec![
+                format!("{:?}", JitEvent::StartTracing(id)),
+                format!(
+                    "{:?}",
+                    JitEvent::TracingAborted {
+                        loc: id,
+                        reason: &InvalidTraceError::EmptyTrace
+                    }
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_are_counted() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MTBuilder::new()
+            .hot_threshold(THRESHOLD)
+            .tracer_kind(TracerKind::Software)
+            .build()
+            .unwrap();
+        let loc = Location::new();
+        for _ in 0..THRESHOLD {
+            mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        }
+        assert_eq!(mt.stats(), MTStats::default());
+
+        // Nothing is recorded by the software tracer here, so tracing fails with an empty trace.
+        mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        mt.control_point(&loc, CTRLP, ptr::null_mut(), ptr::null_mut());
+        assert_eq!(
+            mt.stats(),
+            MTStats {
+                locations_hot: 1,
+                traces_started: 1,
+                traces_aborted: 1,
+                ..Default::default()
+            }
+        );
+        assert!(mt.stats().to_json().contains("\"traces_aborted\": 1,"));
+    }
+
+    #[test]
+    fn shutdown_joins_worker_threads() {
+        let mt = MT::new().unwrap();
+        mt.set_max_worker_threads(2);
+        let ran = Arc::new(AtomicU64::new(0));
+        for _ in 0..2 {
+            let ran = Arc::clone(&ran);
+            mt.queue_job(Box::new(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        assert!(mt.shutdown(Some(Duration::from_secs(60))));
+        assert!(mt.worker_threads.lock().is_empty());
+        let ran_before = ran.load(Ordering::Relaxed);
+
+        // Jobs queued after shutdown are never run.
+        {
+            let ran = Arc::clone(&ran);
+            mt.queue_job(Box::new(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        assert_eq!(mt.job_queue.1.lock().jobs.len(), 0);
+        assert!(mt.worker_threads.lock().is_empty());
+        assert_eq!(ran.load(Ordering::Relaxed), ran_before);
+    }
+
+    #[test]
+    fn evicted_locations_are_retraced() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(THRESHOLD);
+        let loc = Location::new();
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        THREAD_MTTHREAD.with(|mtt| mtt.tracing.take());
+        {
+            let mut lk = loc.hot_location().unwrap().lock();
+            lk.trace_failure = 1;
+            lk.kind = HotLocationKind::Counting(0);
+        }
+
+        // Once evicted, a location must become hot again before it is retraced.
+        for i in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
+            assert!(matches!(
+                loc.hot_location().unwrap().lock().kind,
+                HotLocationKind::Counting(x) if x == i + 1
+            ));
+        }
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        assert!(matches!(
+            loc.hot_location().unwrap().lock().kind,
+            HotLocationKind::Tracing
+        ));
+        assert_eq!(loc.hot_location().unwrap().lock().trace_failure, 0);
+        THREAD_MTTHREAD.with(|mtt| mtt.tracing.take());
+    }
+
+    #[test]
+    fn invalidated_locations_are_retraced() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(THRESHOLD);
+        let loc = Location::new();
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        THREAD_MTTHREAD.with(|mtt| mtt.tracing.take());
+        let ctr = Arc::new(unsafe { CompiledTrace::new_null() });
+        loc.hot_location().unwrap().lock().kind = HotLocationKind::Compiled(Arc::clone(&ctr));
+        assert_eq!(Arc::strong_count(&ctr), 2);
+
+        // A thread executing the trace keeps it alive, but the location itself no longer refers to
+        // it.
+        mt.invalidate(&loc);
+        assert!(matches!(
+            loc.hot_location().unwrap().lock().kind,
+            HotLocationKind::Counting(0)
+        ));
+        assert_eq!(Arc::strong_count(&ctr), 1);
+        assert_eq!(mt.stats().traces_invalidated, 1);
+
+        // Invalidating a location without a compiled trace does nothing.
+        mt.invalidate(&loc);
+        assert_eq!(mt.stats().traces_invalidated, 1);
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        THREAD_MTTHREAD.with(|mtt| mtt.tracing.take());
+    }
+
+    #[test]
+    fn invalidation_aborts_tracing() {
+        const THRESHOLD: HotThreshold = 5;
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(THRESHOLD);
+        let loc1 = Location::new();
+        let loc2 = Location::new();
+        for _ in 0..THRESHOLD {
+            assert_eq!(
+                mt.transition_location(&loc1, CTRLP),
+                TransitionLocation::NoAction
+            );
+        }
+        assert_eq!(
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.thread_tracer.borrow_mut() =
+                Some((Arc::clone(&mt.tracer), Box::new(DummyThreadTracer(0))));
+            mtt.tracing_epoch
+                .set(mt.invalidation_epoch.load(Ordering::Relaxed));
+        });
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
+
+        // The trace is abandoned at the next control point, without counting as a trace failure.
+        mt.invalidate_all();
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::AbortTracing(InvalidTraceError::Invalidated)
+        );
+        mt.control_point(&loc2, CTRLP, ptr::null_mut(), ptr::null_mut());
+        THREAD_MTTHREAD.with(|mtt| {
+            assert!(mtt.tracing.borrow().is_none());
+            assert!(mtt.thread_tracer.borrow().is_none());
+        });
+        let hl = loc1.hot_location().unwrap().lock();
+        assert!(matches!(hl.kind, HotLocationKind::Counting(0)));
+        assert_eq!(hl.trace_failure, 0);
+    }
+
+    #[test]
+    fn invalidating_a_location_only_aborts_its_trace() {
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(0);
+        let loc1 = Location::new();
+        let loc2 = Location::new();
+        assert_eq!(
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.thread_tracer.borrow_mut() =
+                Some((Arc::clone(&mt.tracer), Box::new(DummyThreadTracer(0))));
+            mtt.tracing_epoch
+                .set(mt.invalidation_epoch.load(Ordering::Relaxed));
+        });
+
+        // Invalidating another location doesn't affect the trace.
+        mt.invalidate(&loc2);
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
+
+        // Invalidating the location being traced means that its trace is abandoned when tracing
+        // reaches it again, without counting as a trace failure.
+        mt.invalidate(&loc1);
+        assert_eq!(
+            mt.transition_location(&loc2, CTRLP),
+            TransitionLocation::NoAction
+        );
+        assert_eq!(
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::AbortTracing(InvalidTraceError::Invalidated)
+        );
+        mt.control_point(&loc1, CTRLP, ptr::null_mut(), ptr::null_mut());
+        THREAD_MTTHREAD.with(|mtt| {
+            assert!(mtt.tracing.borrow().is_none());
+            assert!(mtt.thread_tracer.borrow().is_none());
+        });
+        let hl = loc1.hot_location().unwrap().lock();
+        assert!(matches!(hl.kind, HotLocationKind::Counting(0)));
+        assert_eq!(hl.trace_failure, 0);
+    }
+
+    #[test]
+    fn sidetracing_stops_at_any_location() {
+        let mt = MT::new().unwrap();
+        let loc = Location::new();
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.sidetracing.borrow_mut() = Some(SideTracing {
+                parent: Arc::new(unsafe { CompiledTrace::new_null() }),
+                ctrlp: CTRLP,
+                guardid: 0,
+                sti: SideTraceInfo::new(&[], &[]),
+            });
+        });
+        assert!(matches!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StopSideTracing
+        ));
+        THREAD_MTTHREAD.with(|mtt| mtt.sidetracing.take());
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::NoAction
+        );
+        assert_eq!(loc.count(), Some(1));
+    }
+
+    #[test]
+    fn sidetracing_aborts_at_other_control_point() {
+        let mt = MT::new().unwrap();
+        let loc = Location::new();
+        THREAD_MTTHREAD.with(|mtt| {
+            *mtt.sidetracing.borrow_mut() = Some(SideTracing {
+                parent: Arc::new(unsafe { CompiledTrace::new_null() }),
+                ctrlp: CTRLP,
+                guardid: 0,
+                sti: SideTraceInfo::new(&[], &[]),
+            });
+        });
+        assert!(matches!(
+            mt.transition_location(&loc, ControlPointId::new(1)),
+            TransitionLocation::AbortSideTracing
+        ));
+        THREAD_MTTHREAD.with(|mtt| mtt.sidetracing.take());
+    }
+
+    #[test]
+    fn locations_have_per_control_point_state() {
+        let mt = MT::new().unwrap();
+        mt.set_hot_threshold(0);
+        let ctrlp2 = ControlPointId::new(1);
+        let loc = Location::new();
+        assert_eq!(
+            mt.transition_location(&loc, CTRLP),
+            TransitionLocation::StartTracing
+        );
+        // Reaching `loc` at another control point doesn't end the trace...
+        assert_eq!(
+            mt.transition_location(&loc, ctrlp2),
+            TransitionLocation::NoAction
+        );
+        match mt.transition_location(&loc, CTRLP) {
+            TransitionLocation::StopTracing(_) => {
+                loc.hot_location().unwrap().lock().kind =
+                    HotLocationKind::Compiled(Arc::new(unsafe { CompiledTrace::new_null() }));
+            }
+            _ => unreachable!(),
+        }
+        // ...nor is the resulting trace executed there: instead, `loc` is traced separately at the
+        // other control point.
+        assert_eq!(
+            mt.transition_location(&loc, ctrlp2),
+            TransitionLocation::StartTracing
+        );
+        let other = match mt.transition_location(&loc, ctrlp2) {
+            TransitionLocation::StopTracing(hl) => {
+                hl.lock().kind =
+                    HotLocationKind::Compiled(Arc::new(unsafe { CompiledTrace::new_null() }));
+                hl
+            }
+            _ => unreachable!(),
+        };
+        let ctr1 = match mt.transition_location(&loc, CTRLP) {
+            TransitionLocation::Execute(ctr, id) => {
+                assert_eq!(id, LocationId::new(loc.hot_location().unwrap()));
+                ctr
+            }
+            _ => unreachable!(),
+        };
+        match mt.transition_location(&loc, ctrlp2) {
+            TransitionLocation::Execute(ctr, id) => {
+                assert!(!Arc::ptr_eq(&ctr, &ctr1));
+                assert_eq!(id, LocationId::new(&other));
+            }
+            _ => unreachable!(),
+        }
+
+        // Invalidating `loc` invalidates its traces at both control points.
+        mt.invalidate(&loc);
+        assert!(matches!(
+            loc.hot_location().unwrap().lock().kind,
+            HotLocationKind::Counting(0)
+        ));
+        assert!(matches!(other.lock().kind, HotLocationKind::Counting(0)));
+    }
+
+    #[test]
+    fn builder_rejects_bad_config() {
+        assert!(MTBuilder::new().trace_failure_threshold(0).build().is_err());
+        assert!(MTBuilder::new().max_worker_threads(0).build().is_err());
+    }
+
     #[test]
     fn dont_trace_execution_of_a_trace() {
         let mt = Arc::new(MT::new().unwrap());
@@ -829,10 +2367,10 @@ mod tests {
 
         // Get `loc1` to the point where there's a compiled trace for it.
         assert!(matches!(
-            mt.transition_location(&loc1),
+            mt.transition_location(&loc1, CTRLP),
             TransitionLocation::StartTracing
         ));
-        if let TransitionLocation::StopTracing(_) = mt.transition_location(&loc1) {
+        if let TransitionLocation::StopTracing(_) = mt.transition_location(&loc1, CTRLP) {
             loc1.hot_location().unwrap().lock().kind =
                 HotLocationKind::Compiled(Arc::new(unsafe { CompiledTrace::new_null() }));
         } else {
@@ -846,22 +2384,22 @@ mod tests {
         // FIXME: this behaviour will need to change in the future:
         // https://github.com/ykjit/yk/issues/519
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StartTracing
         ));
         assert!(matches!(
-            mt.transition_location(&loc1),
+            mt.transition_location(&loc1, CTRLP),
             TransitionLocation::NoAction
         ));
 
         // But once we stop tracing for `loc2`, we should be able to execute the trace for `loc1`.
         assert!(matches!(
-            mt.transition_location(&loc2),
+            mt.transition_location(&loc2, CTRLP),
             TransitionLocation::StopTracing(_)
         ));
         assert!(matches!(
-            mt.transition_location(&loc1),
-            TransitionLocation::Execute(_)
+            mt.transition_location(&loc1, CTRLP),
+            TransitionLocation::Execute(..)
         ));
     }
 }
@@ -1,12 +1,26 @@
 //! Errors that can occur during tracing.
 
-use std::fmt::{self, Display, Formatter};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
 
 #[derive(Debug)]
 /// Reasons that a trace can be invalidated.
 pub enum InvalidTraceError {
     /// An empty trace was recorded.
     EmptyTrace,
+    /// The hardware trace buffer overflowed, so the trace is incomplete.
+    BufferOverflow,
+    /// The trace collector failed to start or stop.
+    CollectorFailed(String),
+    /// The recorded trace couldn't be decoded.
+    DecodeFailed(String),
+    /// The trace exceeded the maximum trace length, e.g. because the interpreter never returned
+    /// to the location being traced.
+    TraceTooLong,
+    /// The trace was invalidated (e.g. by [crate::MT::invalidate]) before it could be compiled.
+    Invalidated,
     /// Something went wrong in the compiler's tracing code.
     InternalError,
 }
@@ -15,7 +29,14 @@ impl Display for InvalidTraceError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             InvalidTraceError::EmptyTrace => write!(f, "Empty trace"),
+            InvalidTraceError::BufferOverflow => write!(f, "Trace buffer overflow"),
+            InvalidTraceError::CollectorFailed(s) => write!(f, "Trace collector failed: {s}"),
+            InvalidTraceError::DecodeFailed(s) => write!(f, "Trace decoding failed: {s}"),
+            InvalidTraceError::TraceTooLong => write!(f, "Trace too long"),
+            InvalidTraceError::Invalidated => write!(f, "Trace invalidated"),
             InvalidTraceError::InternalError => write!(f, "Internal tracing error"),
         }
     }
 }
+
+impl Error for InvalidTraceError {}
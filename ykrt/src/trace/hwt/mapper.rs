@@ -1,24 +1,38 @@
 //! The mapper translates a PT trace into an IR trace.
 
 use crate::trace::IRBlock;
-use hwtracer::llvm_blockmap::LLVM_BLOCK_MAP;
+use hwtracer::llvm_blockmap::LLVM_BLOCK_MAPS;
 use hwtracer::{Block, HWTracerError};
 use libc::c_void;
-use std::{collections::HashMap, convert::TryFrom, ffi::CString};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::CString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use ykutil::{
-    addr::{vaddr_to_obj_and_off, vaddr_to_sym_and_obj},
-    obj::SELF_BIN_PATH,
+    addr::{vaddr_to_obj_and_off_in, vaddr_to_sym_and_obj},
+    obj::{ObjectMap, PHDR_OBJECT_CACHE},
 };
 
 /// Maps each entry of a hardware trace back to the IR block from which it was compiled.
 pub struct HWTMapper {
     faddrs: HashMap<CString, *const c_void>,
+    /// The object whose embedded IR the trace maps to: the first object built with ykllvm that
+    /// the trace passed through.
+    obj: Option<PathBuf>,
+    /// The objects loaded when mapping started. The trace has already been decoded, so every
+    /// object it passed through is either in this snapshot or has since been unloaded.
+    objs: Arc<ObjectMap>,
 }
 
 impl<'a> HWTMapper {
     pub fn new() -> Self {
         Self {
             faddrs: HashMap::new(),
+            obj: None,
+            objs: PHDR_OBJECT_CACHE.get(),
         }
     }
 
@@ -26,6 +40,12 @@ impl<'a> HWTMapper {
         self.faddrs
     }
 
+    /// The object whose embedded IR the mapped blocks belong to, or `None` if no blocks have been
+    /// mapped.
+    pub fn obj(&self) -> Option<&Path> {
+        self.obj.as_deref()
+    }
+
     /// Maps one PT block to one or many LLVM IR blocks.
     ///
     /// Mapping a PT block to IRBlocks occurs in two phases. First the mapper tries to find machine
@@ -71,22 +91,31 @@ impl<'a> HWTMapper {
         }
         let (block_vaddr, block_last_instr) = b_rng.unwrap();
 
-        let (obj_name, block_off) = vaddr_to_obj_and_off(block_vaddr as usize).unwrap();
+        let (obj_name, block_off) =
+            vaddr_to_obj_and_off_in(&self.objs, block_vaddr as usize).unwrap();
 
-        // Currently we only read in a block map and IR for the currently running binary (and not
-        // for dynamically linked shared objects). Thus, if we see code from another object, we
-        // can't map it.
+        // Only objects built with ykllvm have a blockmap and IR embedded, so if we see code from
+        // any other object, we can't map it. A blockmap that fails to parse will already have been
+        // reported by the decoder, which consults the blockmaps before us.
+        let blockmap = match LLVM_BLOCK_MAPS.get(&obj_name) {
+            Ok(Some(bm)) => bm,
+            Ok(None) | Err(_) => return Vec::new(),
+        };
+
+        // A trace is compiled from the IR of a single object, so code from objects other than the
+        // first one we mapped code from is also treated as unmappable.
         //
-        // FIXME: https://github.com/ykjit/yk/issues/413
-        // In the future we could inline code from shared objects if they were built for use with
-        // yk (i.e. they have a blockmap and IR embedded).
-        if obj_name != *SELF_BIN_PATH {
-            return Vec::new();
+        // FIXME: Link together the IR of all of the objects a trace passes through, so that code
+        // from any of them can be inlined.
+        match &self.obj {
+            Some(obj) if *obj != obj_name => return Vec::new(),
+            Some(_) => (),
+            None => self.obj = Some(obj_name.clone()),
         }
 
         let block_len = block_last_instr - block_vaddr;
         let mut ret = Vec::new();
-        let mut ents = LLVM_BLOCK_MAP
+        let mut ents = blockmap
             .query(block_off, block_off + block_len)
             .collect::<Vec<_>>();
 
@@ -115,11 +144,19 @@ impl<'a> HWTMapper {
                         self.faddrs
                             .insert(sym_name.to_owned(), sio.dli_saddr() as *const c_void);
                     }
-                    for bb in ent.value.corr_bbs() {
-                        ret.push(Some(IRBlock::new_mapped(
-                            sym_name.to_owned(),
-                            usize::try_from(*bb).unwrap(),
-                        )));
+                    // Only the first of the IR blocks starts where the machine block does.
+                    let mblk_vaddr = block_vaddr - block_off + ent.range.start;
+                    for (i, bb) in ent.value.corr_bbs().iter().enumerate() {
+                        let bb = usize::try_from(*bb).unwrap();
+                        ret.push(Some(if i == 0 {
+                            IRBlock::new_mapped_at(
+                                sym_name.to_owned(),
+                                bb,
+                                usize::try_from(mblk_vaddr).unwrap(),
+                            )
+                        } else {
+                            IRBlock::new_mapped(sym_name.to_owned(), bb)
+                        }));
                     }
                 } else {
                     ret.push(None);
@@ -141,6 +178,13 @@ impl<'a> HWTMapper {
     ///
     /// The returned trace will always start with a mapped block (the unmappable prefix of the
     /// foreign "turn on tracing" routine is omitted).
+    ///
+    /// Interruptions after which execution resumed where it left off are omitted from the returned
+    /// trace. If control flow was diverted by an interruption, `HWTracerError::TraceInterrupted`
+    /// is returned. If control flow left a block along an edge that the compiler assumed would never
+    /// be taken (e.g. on the way to an `abort()`), `HWTracerError::DivergentControlFlow` is
+    /// returned. If control entered `longjmp()`, the returned trace ends with an
+    /// `IRBlock::Longjmp`.
     pub fn map_trace(
         &mut self,
         mut trace_iter: &'a mut dyn Iterator<Item = Result<Block, HWTracerError>>,
@@ -149,6 +193,38 @@ impl<'a> HWTMapper {
 
         for block in &mut trace_iter {
             let block = block?;
+            if let Block::Gap { .. } = block {
+                // Part of the trace was lost, so it doesn't describe what the interpreter did.
+                return Err(HWTracerError::HWBufferOverflow);
+            }
+            if let Block::Divergent = block {
+                // The compiler didn't expect control to go this way, so the AOT IR has no edge
+                // which the trace compiler could follow.
+                return Err(HWTracerError::DivergentControlFlow);
+            }
+            if let Block::Longjmp = block {
+                if ret.is_empty() {
+                    // Part of the leading unmappable code, which we never report.
+                    continue;
+                }
+                // Control won't come back to the code which called `longjmp()`. The trace compiler
+                // decides what (if anything) it can make of this, and it has no use for what
+                // follows.
+                ret.push(IRBlock::Longjmp);
+                break;
+            }
+            if let Block::Interrupted { resumed } = block {
+                if resumed {
+                    // An asynchronous event (e.g. a signal) interrupted the traced code, but
+                    // execution later resumed where it left off. Whatever ran in the meantime
+                    // (e.g. a signal handler) isn't part of the trace, so there's nothing to map.
+                    continue;
+                }
+                // Control flow was diverted (e.g. by a transactional abort) and never returned to
+                // the interrupted code. The blocks mapped so far don't describe a path that the
+                // trace compiler could follow.
+                return Err(HWTracerError::TraceInterrupted);
+            }
             let irblocks = self.map_block(&block);
             if irblocks.is_empty() {
                 // The block is unmappable. Insert a IRBlock that indicates this, but only if the
@@ -168,7 +244,7 @@ impl<'a> HWTMapper {
                 for irblock in irblocks.into_iter() {
                     if let Some(irb) = irblock {
                         match ret.last() {
-                            Some(last) if &irb != last => ret.push(irb),
+                            Some(last) if !irb.is_same_block(last) => ret.push(irb),
                             Some(_) => {
                                 // The `BlockDisambiguate` pass in ykllvm ensures that no
                                 // high-level LLVM IR block ever branches straight back to itself,
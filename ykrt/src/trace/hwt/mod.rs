@@ -1,12 +1,36 @@
 //! Hardware tracing via ykrustc.
 
 use super::{errors::InvalidTraceError, IRTrace, ThreadTracer, Tracer, UnmappedTrace};
-use hwtracer::decode::TraceDecoderBuilder;
-use std::{error::Error, sync::Arc};
+use hwtracer::{decode::TraceDecoderBuilder, HWTracerError, TraceCapture};
+use std::{
+    env,
+    error::Error,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+};
+
+pub use hwtracer::collect::PerfCollectorConfig;
 
 pub mod mapper;
 pub use mapper::HWTMapper;
 
+/// The directory, if any, that every PT trace is saved to (as a [TraceCapture]) before it is
+/// mapped.
+static PT_CAPTURE_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| env::var_os("YKD_PT_CAPTURE_DIR").map(PathBuf::from));
+
+/// The number of PT traces saved to [PT_CAPTURE_DIR] so far, used to name the capture files.
+static PT_CAPTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// The default maximum trace length, in bytes of PT trace data. PT packets are compact (a taken
+/// or not-taken conditional branch costs a single bit) so this corresponds to many more blocks
+/// than the software tracer's default.
+const DEFAULT_MAX_TRACE_LEN: usize = 1024 * 1024;
+
 pub struct HWTracer {
     backend: Arc<dyn hwtracer::Tracer>,
 }
@@ -17,12 +41,16 @@ impl super::Tracer for HWTracer {
             thread_tracer: Arc::clone(&self.backend).start_collector()?,
         }))
     }
+
+    fn default_max_trace_len(&self) -> usize {
+        DEFAULT_MAX_TRACE_LEN
+    }
 }
 
 impl HWTracer {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new(config: PerfCollectorConfig) -> Result<Self, HWTracerError> {
         Ok(HWTracer {
-            backend: hwtracer::default_tracer_for_platform()?,
+            backend: hwtracer::tracer_for_platform_with_config(config)?,
         })
     }
 }
@@ -36,26 +64,42 @@ impl ThreadTracer for HWTThreadTracer {
     fn stop_collector(self: Box<Self>) -> Result<Box<dyn UnmappedTrace>, InvalidTraceError> {
         match self.thread_tracer.stop_collector() {
             Ok(t) => Ok(Box::new(PTTrace(t))),
-            Err(e) => todo!("{e:?}"),
+            Err(HWTracerError::HWBufferOverflow) => Err(InvalidTraceError::BufferOverflow),
+            Err(e) => Err(InvalidTraceError::CollectorFailed(e.to_string())),
         }
     }
+
+    fn trace_len(&self) -> usize {
+        self.thread_tracer.trace_len()
+    }
 }
 
 struct PTTrace(Box<dyn hwtracer::Trace>);
 
 impl UnmappedTrace for PTTrace {
     fn map(self: Box<Self>, _tracer: Arc<dyn Tracer>) -> Result<IRTrace, InvalidTraceError> {
+        if let Some(dir) = &*PT_CAPTURE_DIR {
+            let n = PT_CAPTURES.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{n}.ykpt", process::id()));
+            if let Err(e) = TraceCapture::capture(self.0.as_ref()).save(&path) {
+                eprintln!("Couldn't save trace capture to {}: {e}", path.display());
+            }
+        }
+
         let tdec = TraceDecoderBuilder::new().build().unwrap();
         let mut itr = tdec.iter_blocks(self.0.as_ref());
         let mut mt = HWTMapper::new();
 
-        let mapped = mt
-            .map_trace(&mut *itr)
-            .map_err(|_| InvalidTraceError::InternalError)?;
+        let mapped = mt.map_trace(&mut *itr).map_err(|e| match e {
+            HWTracerError::HWBufferOverflow => InvalidTraceError::BufferOverflow,
+            e => InvalidTraceError::DecodeFailed(e.to_string()),
+        })?;
         if mapped.is_empty() {
             return Err(InvalidTraceError::EmptyTrace);
         }
 
-        Ok(IRTrace::new(mapped, mt.faddrs()))
+        // The unwrap can't fail: the trace contains mapped blocks.
+        let obj = mt.obj().unwrap().to_owned();
+        Ok(IRTrace::new_in_obj(mapped, mt.faddrs(), obj))
     }
 }
@@ -5,7 +5,13 @@
 #![allow(clippy::missing_safety_doc)]
 
 mod errors;
+use crate::{
+    deopt::AOTVar,
+    frame::{DeoptInfo, FrameInfo},
+};
+use hwtracer::HWTracerError;
 use libc::c_void;
+use parking_lot::Mutex;
 #[cfg(unix)]
 use std::os::unix::io::AsRawFd;
 use std::{
@@ -13,15 +19,23 @@ use std::{
     env,
     error::Error,
     ffi::{c_char, c_int, CStr, CString},
+    path::{Path, PathBuf},
     ptr,
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
 };
 pub mod hwt;
+pub mod swt;
 use std::arch::asm;
 use tempfile::NamedTempFile;
-use ykutil::obj::llvmbc_section;
+use yksmp::{LiveVar, StackMapParser};
+use ykutil::obj::{llvmbc_section, llvmbc_section_of};
 
 pub use errors::InvalidTraceError;
+pub use hwt::PerfCollectorConfig;
 
 /// A globally unique block ID for an LLVM IR block.
 #[derive(Debug, Eq, PartialEq)]
@@ -36,6 +50,9 @@ pub enum IRBlock {
         ///
         /// The special value `usize::MAX` indicates unmappable code.
         bb: usize,
+        /// The virtual address of the machine code at which the block was entered, if known. The
+        /// trace compiler needs this to guard on the target of an indirect branch.
+        vaddr: Option<usize>,
     },
     /// One or more machine blocks that could not be mapped.
     ///
@@ -44,11 +61,32 @@ pub enum IRBlock {
         /// The change to the stack depth as a result of executing the unmappable region.
         stack_adjust: isize,
     },
+    /// Control entered `longjmp()` (or one of its variants). Nothing after this point in the trace
+    /// is recorded.
+    Longjmp,
 }
 
+/// The block index used (alongside a null function name) to encode an [IRBlock::Longjmp] for the
+/// trace compiler. No unmappable region can adjust the stack by this much, so it can't be confused
+/// with the encoding of an [IRBlock::Unmappable].
+const LONGJMP_BLOCK: usize = isize::MIN as usize;
+
 impl IRBlock {
     pub fn new_mapped(func_name: CString, bb: usize) -> Self {
-        Self::Mapped { func_name, bb }
+        Self::Mapped {
+            func_name,
+            bb,
+            vaddr: None,
+        }
+    }
+
+    /// Create a mapped block which was entered at the virtual address `vaddr`.
+    pub fn new_mapped_at(func_name: CString, bb: usize, vaddr: usize) -> Self {
+        Self::Mapped {
+            func_name,
+            bb,
+            vaddr: Some(vaddr),
+        }
     }
 
     pub fn new_unmappable(stack_adjust: isize) -> Self {
@@ -73,6 +111,35 @@ impl IRBlock {
         }
     }
 
+    /// Determines whether `self` and `other` are the same mapped block (regardless of the address
+    /// at which each was entered).
+    pub fn is_same_block(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Mapped {
+                    func_name: f1,
+                    bb: b1,
+                    ..
+                },
+                Self::Mapped {
+                    func_name: f2,
+                    bb: b2,
+                    ..
+                },
+            ) => f1 == f2 && b1 == b2,
+            _ => false,
+        }
+    }
+
+    /// If `self` is a mapped block entered at a known address, return that address.
+    pub fn vaddr(&self) -> Option<usize> {
+        if let Self::Mapped { vaddr, .. } = self {
+            *vaddr
+        } else {
+            None
+        }
+    }
+
     /// Determines whether `self` represents unmappable code.
     pub fn is_unmappable(&self) -> bool {
         matches!(self, Self::Unmappable { .. })
@@ -102,6 +169,9 @@ pub struct IRTrace {
     blocks: Vec<IRBlock>,
     /// Function addresses discovered dynamically via the trace. symbol-name -> address.
     faddrs: HashMap<CString, *const c_void>,
+    /// The object whose embedded IR the blocks belong to, or `None` for the IR linked into the
+    /// running program (see [llvmbc_section]).
+    obj: Option<PathBuf>,
 }
 
 unsafe impl Send for IRTrace {}
@@ -110,19 +180,87 @@ unsafe impl Sync for IRTrace {}
 impl IRTrace {
     pub fn new(blocks: Vec<IRBlock>, faddrs: HashMap<CString, *const c_void>) -> Self {
         debug_assert!(blocks.len() < usize::MAX);
-        Self { blocks, faddrs }
+        Self {
+            blocks,
+            faddrs,
+            obj: None,
+        }
+    }
+
+    /// Create a trace whose blocks belong to the IR embedded in the loaded object `obj`.
+    pub fn new_in_obj(
+        blocks: Vec<IRBlock>,
+        faddrs: HashMap<CString, *const c_void>,
+        obj: PathBuf,
+    ) -> Self {
+        debug_assert!(blocks.len() < usize::MAX);
+        Self {
+            blocks,
+            faddrs,
+            obj: Some(obj),
+        }
     }
 
     pub fn len(&self) -> usize {
         self.blocks.len()
     }
 
-    fn encode_trace(&self) -> (Vec<*const i8>, Vec<usize>, usize) {
+    /// The object whose embedded IR the blocks belong to, or `None` for the IR linked into the
+    /// running program.
+    pub(crate) fn obj(&self) -> Option<&Path> {
+        self.obj.as_deref()
+    }
+
+    /// Remove the blocks preceding the start of a side-trace. Side tracing starts during
+    /// deoptimisation, so before execution resumes in the block `bb` of `func_name` (the block
+    /// containing the failed guard), the trace records the (unmappable) deoptimisation code. Returns
+    /// `false` if the start of the side-trace can't be found.
+    ///
+    /// A trace which starts in mappable code didn't see the deoptimisation code: the software
+    /// tracer only records blocks as they are entered, so it misses both the deoptimisation code
+    /// and execution resuming part-way through the guard's block. The guard's block is added to
+    /// the start of such a trace.
+    pub(crate) fn trim_to_sidetrace_start(&mut self, func_name: &CStr, bb: usize) -> bool {
+        match self.blocks.first() {
+            Some(blk) if !blk.is_unmappable() => {
+                self.blocks
+                    .insert(0, IRBlock::new_mapped(func_name.to_owned(), bb));
+                return true;
+            }
+            _ => (),
+        }
+        let start = (0..self.blocks.len()).find(|&i| {
+            let resumes = match &self.blocks[i] {
+                IRBlock::Mapped {
+                    func_name: f,
+                    bb: b,
+                    ..
+                } => f.as_c_str() == func_name && *b == bb,
+                IRBlock::Unmappable { .. } | IRBlock::Longjmp => false,
+            };
+            resumes && (i == 0 || self.blocks[i - 1].is_unmappable())
+        });
+        match start {
+            Some(i) => {
+                self.blocks.drain(..i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn encode_trace(&self) -> (Vec<*const i8>, Vec<usize>, Vec<*const c_void>, usize) {
         let trace_len = self.len();
         let mut func_names = Vec::with_capacity(trace_len);
         let mut bbs = Vec::with_capacity(trace_len);
+        let mut vaddrs = Vec::with_capacity(trace_len);
         for blk in &self.blocks {
-            if blk.is_unmappable() {
+            // A null address means that the address at which the block was entered isn't known.
+            vaddrs.push(blk.vaddr().map_or(ptr::null(), |v| v as *const c_void));
+            if let IRBlock::Longjmp = blk {
+                func_names.push(ptr::null());
+                bbs.push(LONGJMP_BLOCK);
+            } else if blk.is_unmappable() {
                 // The block was unmappable. Indicate this with a null function name and the block
                 // index encodes the stack adjustment value.
                 func_names.push(ptr::null());
@@ -135,7 +273,7 @@ impl IRTrace {
                 bbs.push(blk.bb());
             }
         }
-        (func_names, bbs, trace_len)
+        (func_names, bbs, vaddrs, trace_len)
     }
 
     // If necessary, create a temporary file for us to write the trace's debugging "source code"
@@ -163,8 +301,13 @@ impl IRTrace {
         (di_tmp, di_fd, di_tmpname_c)
     }
 
-    pub fn compile(&self) -> Result<(*const c_void, Option<NamedTempFile>), Box<dyn Error>> {
-        let (func_names, bbs, trace_len) = self.encode_trace();
+    /// Compile this trace. If `sti` is `Some`, this trace is compiled as a side-trace starting at the
+    /// guard failure it describes.
+    pub(crate) fn compile(
+        &self,
+        sti: Option<&SideTraceInfo>,
+    ) -> Result<(*const c_void, Option<NamedTempFile>), Box<dyn Error>> {
+        let (func_names, bbs, vaddrs, trace_len) = self.encode_trace();
 
         let mut faddr_keys = Vec::new();
         let mut faddr_vals = Vec::new();
@@ -173,13 +316,24 @@ impl IRTrace {
             faddr_vals.push(*k.1);
         }
 
-        let (llvmbc_data, llvmbc_len) = llvmbc_section();
+        let (llvmbc_data, llvmbc_len) = match &self.obj {
+            Some(obj) => llvmbc_section_of(obj)
+                .ok_or_else(|| format!("no IR embedded in {}", obj.display()))?,
+            None => llvmbc_section(),
+        };
         let (di_tmp, di_fd, di_tmpname_c) = Self::create_debuginfo_temp_file();
 
+        let sti_c = sti.map(|x| x.as_c());
+        let sti_ptr = match &sti_c {
+            Some(x) => x as *const SideTraceInfoC as *const c_void,
+            None => ptr::null(),
+        };
+
         let ret = unsafe {
             yktracec::__yktracec_irtrace_compile(
                 func_names.as_ptr(),
                 bbs.as_ptr(),
+                vaddrs.as_ptr(),
                 trace_len,
                 faddr_keys.as_ptr(),
                 faddr_vals.as_ptr(),
@@ -188,6 +342,7 @@ impl IRTrace {
                 llvmbc_len,
                 di_fd,
                 di_tmpname_c,
+                sti_ptr,
             )
         };
         if ret.is_null() {
@@ -199,7 +354,7 @@ impl IRTrace {
 
     #[cfg(feature = "yk_testing")]
     pub unsafe fn compile_for_tc_tests(&self, llvmbc_data: *const u8, llvmbc_len: u64) {
-        let (func_names, bbs, trace_len) = self.encode_trace();
+        let (func_names, bbs, vaddrs, trace_len) = self.encode_trace();
         let (_di_tmp, di_fd, di_tmpname_c) = Self::create_debuginfo_temp_file();
 
         // These would only need to be populated if we were to load the resulting compiled code
@@ -210,6 +365,7 @@ impl IRTrace {
         let ret = yktracec::__yktracec_irtrace_compile_for_tc_tests(
             func_names.as_ptr(),
             bbs.as_ptr(),
+            vaddrs.as_ptr(),
             trace_len,
             faddr_keys.as_ptr(),
             faddr_vals.as_ptr(),
@@ -223,10 +379,160 @@ impl IRTrace {
     }
 }
 
+/// The state of the program at a guard failure from which a side-trace is being traced: the AOT
+/// frames that were active and the AOT values that were live.
+pub(crate) struct SideTraceInfo {
+    frames: Vec<FrameInfo>,
+    aotvals: Vec<AOTVar>,
+    /// The function names pointed to by `frames` and `aotvals`. The originals may not live as long
+    /// as we need them to, so we keep our own copies.
+    _fnames: Vec<CString>,
+}
+
+// The raw pointers in a `SideTraceInfo` point to strings owned by the same `SideTraceInfo`.
+unsafe impl Send for SideTraceInfo {}
+
+impl SideTraceInfo {
+    pub(crate) fn new(frames: &[FrameInfo], aotvals: &[AOTVar]) -> Self {
+        let mut fnames = Vec::with_capacity(frames.len() + aotvals.len());
+        let mut copy_fname = |fname: *const i8| {
+            let fname = CString::from(unsafe { CStr::from_ptr(fname) });
+            let ptr = fname.as_ptr();
+            fnames.push(fname);
+            ptr
+        };
+        let frames = frames
+            .iter()
+            .map(|f| FrameInfo {
+                bbidx: f.bbidx,
+                instridx: f.instridx,
+                fname: copy_fname(f.fname),
+            })
+            .collect();
+        let aotvals = aotvals
+            .iter()
+            .map(|v| AOTVar {
+                bbidx: v.bbidx,
+                instridx: v.instridx,
+                fname: copy_fname(v.fname),
+                sfidx: v.sfidx,
+            })
+            .collect();
+        Self {
+            frames,
+            aotvals,
+            _fnames: fnames,
+        }
+    }
+
+    /// The function name and block index of the block containing the failed guard.
+    pub(crate) fn guard_block(&self) -> (&CStr, usize) {
+        let f = self.frames.last().unwrap();
+        (unsafe { CStr::from_ptr(f.fname) }, f.bbidx)
+    }
+
+    fn as_c(&self) -> SideTraceInfoC {
+        SideTraceInfoC {
+            frames: self.frames.as_ptr(),
+            frames_len: self.frames.len(),
+            aotvals: self.aotvals.as_ptr(),
+            aotvals_len: self.aotvals.len(),
+        }
+    }
+}
+
+/// Mirrors the `SideTraceInfo` struct defined in yktracec/jitmodbuilder.h.
+#[repr(C)]
+struct SideTraceInfoC {
+    frames: *const FrameInfo,
+    frames_len: usize,
+    aotvals: *const AOTVar,
+    aotvals_len: usize,
+}
+
+/// The side-tracing state of a guard.
+#[derive(Debug)]
+enum SideTraceState {
+    /// The guard hasn't failed often enough to be side-traced.
+    Counting,
+    /// A side-trace for this guard is being traced or compiled.
+    Tracing,
+    /// A side-trace for this guard has been compiled.
+    Compiled(Arc<CompiledTrace>),
+    /// Side-tracing this guard failed: don't try again.
+    DontTrace,
+}
+
+/// Describes how to deoptimise at a guard. Mirrors the `GuardInfo` struct defined in
+/// yktracec/jitmodbuilder.h.
+#[repr(C)]
+struct GuardInfoC {
+    /// The frames active at the guard, allocated with malloc().
+    frames: *mut FrameInfo,
+    frames_len: usize,
+    /// The index of the guard's first live AOT value in the trace's live AOT values.
+    aotvals_idx: usize,
+    aotvals_len: usize,
+}
+
+/// A guard in a compiled trace.
 #[derive(Debug)]
-struct Guard {
-    failed: u32,
-    code: Option<*const c_void>,
+pub(crate) struct Guard {
+    /// How many times has this guard failed?
+    failed: AtomicU32,
+    sidetrace: Mutex<SideTraceState>,
+    /// How to deoptimise at this guard, computed when the trace is compiled.
+    deopt: DeoptInfo,
+}
+
+impl Guard {
+    fn new(deopt: DeoptInfo) -> Self {
+        Self {
+            failed: AtomicU32::new(0),
+            sidetrace: Mutex::new(SideTraceState::Counting),
+            deopt,
+        }
+    }
+
+    /// Record a failure of this guard, returning how many times it has now failed.
+    pub(crate) fn inc_failed(&self) -> u32 {
+        self.failed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// If a side-trace has been compiled for this guard, return it.
+    pub(crate) fn sidetrace(&self) -> Option<Arc<CompiledTrace>> {
+        match &*self.sidetrace.lock() {
+            SideTraceState::Compiled(ctr) => Some(Arc::clone(ctr)),
+            _ => None,
+        }
+    }
+
+    /// If nothing has yet tried to side-trace this guard, move it into the tracing state and return
+    /// `true`. Otherwise return `false`.
+    pub(crate) fn start_sidetracing(&self) -> bool {
+        let mut lk = self.sidetrace.lock();
+        if let SideTraceState::Counting = *lk {
+            *lk = SideTraceState::Tracing;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set the compiled side-trace for this guard.
+    pub(crate) fn set_sidetrace(&self, ctr: Arc<CompiledTrace>) {
+        *self.sidetrace.lock() = SideTraceState::Compiled(ctr);
+    }
+
+    /// Mark this guard as one that can't be side-traced.
+    pub(crate) fn dont_sidetrace(&self) {
+        *self.sidetrace.lock() = SideTraceState::DontTrace;
+    }
+
+    /// Return how to deoptimise at this guard.
+    pub(crate) fn deopt_info(&self) -> &DeoptInfo {
+        &self.deopt
+    }
 }
 
 /// A trace compiled into machine code. Note that these are passed around as raw pointers and
@@ -240,14 +546,25 @@ pub struct CompiledTrace {
     /// control point. The exact definition of this struct is not known to Rust: the struct is
     /// generated at interpreter compile-time by ykllvm.
     entry: *const c_void,
-    /// Pointer to the stackmap, required to parse the stackmap during a guard failure.
-    smptr: *const c_void,
-    /// The stackmaps size.
-    smsize: usize,
+    /// The trace's stackmap, parsed when the trace is created so that guard failures don't have
+    /// to: maps the return address of each deoptimise call to the locations of the live variables.
+    stackmap: HashMap<u64, Vec<LiveVar>>,
     /// Pointer to heap allocated live AOT values.
     aotvals: *const c_void,
-    /// List of guards containing hotness counts or compiled side traces.
-    guards: Vec<Option<Guard>>,
+    /// The guards in this trace, indexed by guard ID.
+    guards: Vec<Guard>,
+    /// Pointer to the yktracec handle which owns the machine code, the stackmap, and the LLVM
+    /// objects they were compiled from.
+    handle: *const c_void,
+    /// How many bytes of code and data `handle` owns.
+    memsize: usize,
+    /// The object whose embedded IR the trace was compiled from, or `None` for the IR linked into
+    /// the running program. Side-traces must be compiled from the same object's IR.
+    obj: Option<PathBuf>,
+    /// How many times has this trace been executed since the code cache last looked at it?
+    execs: AtomicU64,
+    /// How many times has this trace deoptimised since the code cache last looked at it?
+    deopts: AtomicU64,
     /// If requested, a temporary file containing the "source code" for the trace, to be shown in
     /// debuggers when stepping over the JITted code.
     ///
@@ -261,26 +578,124 @@ use std::mem;
 use std::slice;
 impl CompiledTrace {
     /// Create a `CompiledTrace` from a pointer to an array containing: the pointer to the compiled
-    /// trace, the pointer to the stackmap and the size of the stackmap, and the pointer to the
-    /// live AOT values.
-    pub fn new(data: *const c_void, di_tmpfile: Option<NamedTempFile>) -> Self {
-        let slice = unsafe { slice::from_raw_parts(data as *const usize, 5) };
+    /// trace, the pointer to the stackmap and the size of the stackmap, the pointer to the live
+    /// AOT values, the number of guards in the trace, the pointer to the trace's yktracec handle,
+    /// the number of bytes of code and data allocated for the trace, and the pointer to the
+    /// guards' deoptimisation info. `obj` is as per [IRTrace::obj] for the trace that was compiled.
+    ///
+    /// This works out how to deoptimise at every guard in the trace, so that a guard failure
+    /// doesn't have to. If that isn't possible (e.g. because `obj` has since been unloaded), the
+    /// compiled trace is freed and an error returned.
+    pub fn new(
+        data: *const c_void,
+        di_tmpfile: Option<NamedTempFile>,
+        obj: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let slice = unsafe { slice::from_raw_parts(data as *const usize, 8) };
         let funcptr = slice[0] as *const c_void;
         let smptr = slice[1] as *const c_void;
         let smsize = slice[2];
         let aotvals = slice[3] as *mut c_void;
         let guardcount = slice[4] as usize;
+        let handle = slice[5] as *const c_void;
+        let memsize = slice[6];
+        let guardinfos = slice[7] as *mut GuardInfoC;
         // We heap allocated this array in yktracec to pass the data here. Now that we've
         // extracted it we no longer need to keep the array around.
         unsafe { libc::free(data as *mut c_void) };
-        Self {
+
+        let deopts = if guardcount == 0 {
+            Ok(Vec::new())
+        } else {
+            let guardinfos = unsafe { slice::from_raw_parts(guardinfos, guardcount) };
+            let guards = guardinfos
+                .iter()
+                .map(|gi| unsafe {
+                    (
+                        slice::from_raw_parts(gi.frames, gi.frames_len) as &[FrameInfo],
+                        slice::from_raw_parts(
+                            (aotvals as *const AOTVar).add(gi.aotvals_idx),
+                            gi.aotvals_len,
+                        ),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let deopts = unsafe { DeoptInfo::for_guards(obj.as_deref(), &guards) };
+            for gi in guardinfos {
+                unsafe { libc::free(gi.frames as *mut c_void) };
+            }
+            deopts
+        };
+        // Like `data`, the guards' info was heap allocated by yktracec.
+        unsafe { libc::free(guardinfos as *mut c_void) };
+        let stackmap = if smptr.is_null() {
+            HashMap::new()
+        } else {
+            let slice = unsafe { slice::from_raw_parts(smptr as *const u8, smsize) };
+            StackMapParser::parse(slice).unwrap()
+        };
+        // Create the instance before checking for errors, so that dropping it frees the trace.
+        let mut ctr = Self {
             entry: funcptr,
-            smptr,
-            smsize,
+            stackmap,
             aotvals,
             di_tmpfile,
-            guards: Vec::with_capacity(guardcount),
-        }
+            guards: Vec::new(),
+            handle,
+            memsize,
+            obj,
+            execs: AtomicU64::new(0),
+            deopts: AtomicU64::new(0),
+        };
+        ctr.guards = deopts?.into_iter().map(Guard::new).collect();
+        Ok(ctr)
+    }
+
+    /// Return the guard with ID `id`.
+    pub(crate) fn guard(&self, id: usize) -> &Guard {
+        &self.guards[id]
+    }
+
+    /// The object whose embedded IR the trace was compiled from, or `None` for the IR linked into
+    /// the running program.
+    pub(crate) fn obj(&self) -> Option<&Path> {
+        self.obj.as_deref()
+    }
+
+    /// Return the live variables recorded in the stackmap for the deoptimise call returning to
+    /// `retaddr`.
+    pub(crate) fn live_vars(&self, retaddr: usize) -> &[LiveVar] {
+        &self.stackmap[&u64::try_from(retaddr).unwrap()]
+    }
+
+    /// Return the number of bytes of code and data used by this trace and its side-traces.
+    pub(crate) fn size(&self) -> usize {
+        self.memsize
+            + self
+                .guards
+                .iter()
+                .filter_map(|g| g.sidetrace())
+                .map(|st| st.size())
+                .sum::<usize>()
+    }
+
+    /// Record that this trace is about to be executed.
+    pub(crate) fn inc_execs(&self) {
+        self.execs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a guard in this trace failed and deoptimised.
+    pub(crate) fn inc_deopts(&self) {
+        self.deopts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return how many times this trace has been executed and has deoptimised since this function
+    /// was last called, resetting both counts to 0.
+    pub(crate) fn take_counts(&self) -> (u64, u64) {
+        (
+            self.execs.swap(0, Ordering::Relaxed),
+            self.deopts.swap(0, Ordering::Relaxed),
+        )
     }
 
     #[cfg(any(test, feature = "yk_testing"))]
@@ -289,13 +704,24 @@ impl CompiledTrace {
     /// purposes where a `CompiledTrace` instance is required, but cannot sensibly be constructed
     /// without overwhelming the test. The resulting instance must not be inspected or executed.
     pub unsafe fn new_null() -> Self {
+        Self::new_null_sized(0)
+    }
+
+    #[cfg(any(test, feature = "yk_testing"))]
+    #[doc(hidden)]
+    /// Like [Self::new_null], but the resulting instance claims to use `memsize` bytes of memory.
+    pub unsafe fn new_null_sized(memsize: usize) -> Self {
         Self {
             entry: std::ptr::null(),
-            smptr: std::ptr::null() as *const _,
-            smsize: 0,
+            stackmap: HashMap::new(),
             aotvals: std::ptr::null() as *const _,
             di_tmpfile: None,
             guards: Vec::new(),
+            handle: std::ptr::null(),
+            memsize,
+            obj: None,
+            execs: AtomicU64::new(0),
+            deopts: AtomicU64::new(0),
         }
     }
 
@@ -307,10 +733,14 @@ impl CompiledTrace {
     /// Until we've figured out how to restore only the used registers we take the sledge hammer
     /// approach and save and restore all CSRs here.
     /// OPT: Find a way to only restore needed registers (ideally right within the deopt code).
+    ///
+    /// `livevals` is a pointer to the values live at the guard failure that a side-trace starts
+    /// from, or null if this is a root trace.
     pub extern "C" fn exec(
         &self,
         ctrlp_vars: *mut c_void,
         frameaddr: *mut c_void,
+        livevals: *const u64,
     ) -> *const c_void {
         unsafe {
             asm!(
@@ -340,6 +770,7 @@ impl CompiledTrace {
         &self,
         ctrlp_vars: *mut c_void,
         frameaddr: *mut c_void,
+        livevals: *const u64,
     ) -> *const c_void {
         #[cfg(feature = "yk_testing")]
         assert_ne!(self.entry as *const (), std::ptr::null());
@@ -352,9 +783,19 @@ impl CompiledTrace {
                     usize,
                     *mut c_void,
                     *const c_void,
+                    *const u64,
                 ) -> *const c_void,
             >(self.entry);
-            f(ctrlp_vars, self.smptr, self.smsize, frameaddr, self.aotvals)
+            // Guard failures look up the stackmap parsed by [CompiledTrace::new], so the
+            // stackmap arguments are unused.
+            f(
+                ctrlp_vars,
+                ptr::null(),
+                0,
+                frameaddr,
+                self.aotvals,
+                livevals,
+            )
         }
     }
 }
@@ -363,8 +804,12 @@ impl Drop for CompiledTrace {
     fn drop(&mut self) {
         // The memory holding the AOT live values needs to live as long as the trace. Now that we
         // no longer need the trace, this can be freed too.
-        // FIXME: Free the memory for the stackmap which was allocated in yktracec/memman.cc.
         unsafe { libc::free(self.aotvals as *mut c_void) };
+        // Threads executing this trace hold a reference to it, so since we're being dropped, no
+        // thread can be executing the machine code.
+        if !self.handle.is_null() {
+            unsafe { yktracec::__yktracec_free_compiled_trace(self.handle) };
+        }
     }
 }
 
@@ -376,18 +821,122 @@ unsafe impl Sync for CompiledTrace {}
 pub trait Tracer: Send + Sync {
     /// Start collecting a trace of the current thread.
     fn start_collector(self: Arc<Self>) -> Result<Box<dyn ThreadTracer>, Box<dyn Error>>;
+    /// Return the length, in this tracer's own unit (see [ThreadTracer::trace_len]), beyond which
+    /// a trace is abandoned unless the user chooses a different length.
+    fn default_max_trace_len(&self) -> usize;
 }
 
 /// Represents a thread which is currently tracing.
 pub trait ThreadTracer {
     /// Stop collecting a trace of the current thread.
     fn stop_collector(self: Box<Self>) -> Result<Box<dyn UnmappedTrace>, InvalidTraceError>;
+    /// Return how much has been traced so far, in the tracer's own unit: blocks for the software
+    /// tracer and bytes of trace data for the hardware tracer.
+    fn trace_len(&self) -> usize;
 }
 
-pub fn default_tracer_for_platform() -> Result<Arc<dyn Tracer>, Box<dyn Error>> {
-    Ok(Arc::new(hwt::HWTracer::new()?))
+/// The kinds of tracer that can be chosen at run-time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TracerKind {
+    /// Hardware tracing (Intel PT via Linux perf).
+    Hardware,
+    /// Software tracing via ykllvm instrumentation.
+    Software,
+}
+
+impl FromStr for TracerKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hw" => Ok(TracerKind::Hardware),
+            "sw" => Ok(TracerKind::Software),
+            _ => Err(format!("Unknown tracer kind '{s}': expected 'hw' or 'sw'").into()),
+        }
+    }
+}
+
+/// Return the preferred tracer for this platform: the hardware tracer if the CPU supports it,
+/// otherwise the software tracer. If a hardware tracer is returned, it is configured with
+/// `perf_config`.
+///
+/// Only a lack of hardware support causes the software tracer to be returned: any other problem
+/// with the hardware tracer (e.g. an invalid `perf_config`, or not being allowed to use perf) is
+/// returned as an error.
+pub fn default_tracer_for_platform(
+    perf_config: PerfCollectorConfig,
+) -> Result<Arc<dyn Tracer>, Box<dyn Error>> {
+    match hwt::HWTracer::new(perf_config) {
+        Ok(t) => Ok(Arc::new(t)),
+        Err(HWTracerError::NoHWSupport(_)) => Ok(Arc::new(swt::SWTracer::new())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Return a tracer of kind `kind`, or an error if that kind of tracer isn't supported on this
+/// platform. If a hardware tracer is returned, it is configured with `perf_config`.
+pub fn tracer_for_kind(
+    kind: TracerKind,
+    perf_config: PerfCollectorConfig,
+) -> Result<Arc<dyn Tracer>, Box<dyn Error>> {
+    match kind {
+        TracerKind::Hardware => Ok(Arc::new(hwt::HWTracer::new(perf_config)?)),
+        TracerKind::Software => Ok(Arc::new(swt::SWTracer::new())),
+    }
 }
 
 pub trait UnmappedTrace: Send {
     fn map(self: Box<Self>, tracer: Arc<dyn Tracer>) -> Result<IRTrace, InvalidTraceError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped(func_name: &str, bb: usize) -> IRBlock {
+        IRBlock::new_mapped(CString::new(func_name).unwrap(), bb)
+    }
+
+    #[test]
+    fn trim_to_sidetrace_start_after_deopt_code() {
+        let mut trace = IRTrace::new(
+            vec![
+                IRBlock::new_unmappable(1),
+                mapped("f", 2),
+                IRBlock::new_unmappable(-1),
+                mapped("f", 2),
+                mapped("f", 3),
+                IRBlock::new_unmappable(0),
+            ],
+            HashMap::new(),
+        );
+        assert!(trace.trim_to_sidetrace_start(&CString::new("f").unwrap(), 2));
+        assert_eq!(trace.len(), 5);
+        assert!(trace.blocks[0].is_same_block(&mapped("f", 2)));
+    }
+
+    #[test]
+    fn trim_to_sidetrace_start_without_deopt_code() {
+        let mut trace = IRTrace::new(
+            vec![mapped("f", 3), IRBlock::new_unmappable(0)],
+            HashMap::new(),
+        );
+        assert!(trace.trim_to_sidetrace_start(&CString::new("f").unwrap(), 2));
+        assert_eq!(trace.len(), 3);
+        assert!(trace.blocks[0].is_same_block(&mapped("f", 2)));
+        assert!(trace.blocks[1].is_same_block(&mapped("f", 3)));
+    }
+
+    #[test]
+    fn trim_to_sidetrace_start_not_found() {
+        let mut trace = IRTrace::new(
+            vec![
+                IRBlock::new_unmappable(1),
+                mapped("f", 3),
+                IRBlock::new_unmappable(0),
+            ],
+            HashMap::new(),
+        );
+        assert!(!trace.trim_to_sidetrace_start(&CString::new("f").unwrap(), 2));
+    }
+}
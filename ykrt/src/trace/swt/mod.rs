@@ -0,0 +1,207 @@
+//! Software tracing via ykllvm instrumentation.
+//!
+//! When an interpreter is compiled with software tracing enabled, ykllvm inserts a call to
+//! [yk_trace_basicblock] at the start of every LLVM IR basic block, and again after every call
+//! instruction that returns into the middle of a block. Each call passes a pointer to the
+//! (null-terminated) name of the parent function and the index of the block within that function.
+//! While a thread is tracing, these calls append to a per-thread buffer; while it isn't, they are
+//! a cheap no-op.
+//!
+//! Since only code compiled by ykllvm is instrumented, foreign code never appears in the buffer.
+//! The only evidence that foreign code ran is that the same block is recorded twice in a row (once
+//! on entry and once when the foreign call returns), which we translate into an unmappable region
+//! so that the resulting [IRTrace] has the same shape as one produced by the hardware tracer. A
+//! function whose entry block directly calls the same function leads to the same pair of records,
+//! so alongside each block we also record (roughly) the stack pointer it was executed at: a foreign
+//! call returns to the frame it was made from, whereas a recursive call runs in a deeper one.
+//!
+//! Note that this means the software tracer can't see foreign code calling back into mappable code
+//! (e.g. a `qsort` comparison function). Nor can it tell a foreign call apart from recursion if the
+//! block calling foreign code also grows its frame (e.g. with a variable-sized `alloca`) before
+//! the call.
+
+use super::{errors::InvalidTraceError, IRBlock, IRTrace, ThreadTracer, Tracer, UnmappedTrace};
+use libc::{c_void, dlsym, RTLD_DEFAULT};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    ffi::{c_char, CStr, CString},
+    hint::black_box,
+    sync::Arc,
+};
+
+/// A basic block recorded by the instrumentation, prior to mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SWTBlock {
+    /// A pointer to the name of the block's parent function. This points to a static string in
+    /// the interpreter's binary and is only dereferenced when the trace is mapped.
+    func_name: *const c_char,
+    /// The index of the block within its parent function.
+    bb: usize,
+    /// An address identifying the stack frame the block was executed in.
+    frame: usize,
+}
+
+/// The default maximum trace length, in blocks.
+const DEFAULT_MAX_TRACE_LEN: usize = 100_000;
+
+thread_local! {
+    /// The blocks recorded so far by the current thread. This is `Some` only while the thread is
+    /// tracing.
+    static SWT_BLOCKS: RefCell<Option<Vec<SWTBlock>>> = RefCell::new(None);
+}
+
+/// Record that the current thread has executed the block `bb` of the function `func_name`. Calls
+/// to this function are inserted by ykllvm and it should not be called by hand.
+#[no_mangle]
+pub extern "C" fn yk_trace_basicblock(func_name: *const c_char, bb: usize) {
+    // This function's own frame is always the same size, so the address of a local within it is a
+    // fixed distance from the caller's stack pointer.
+    let marker = 0u8;
+    let frame = black_box(&marker) as *const u8 as usize;
+    SWT_BLOCKS.with(|blocks| {
+        if let Some(blocks) = blocks.borrow_mut().as_mut() {
+            blocks.push(SWTBlock {
+                func_name,
+                bb,
+                frame,
+            });
+        }
+    });
+}
+
+pub struct SWTracer {}
+
+impl super::Tracer for SWTracer {
+    fn start_collector(self: Arc<Self>) -> Result<Box<dyn ThreadTracer>, Box<dyn Error>> {
+        SWT_BLOCKS.with(|blocks| {
+            let mut blocks = blocks.borrow_mut();
+            if blocks.is_some() {
+                return Err("Software tracing already active on this thread.".into());
+            }
+            *blocks = Some(Vec::new());
+            Ok(Box::new(SWTThreadTracer {}) as Box<dyn ThreadTracer>)
+        })
+    }
+
+    fn default_max_trace_len(&self) -> usize {
+        DEFAULT_MAX_TRACE_LEN
+    }
+}
+
+impl SWTracer {
+    pub fn new() -> Self {
+        SWTracer {}
+    }
+}
+
+/// Software thread tracer.
+struct SWTThreadTracer {}
+
+impl ThreadTracer for SWTThreadTracer {
+    fn stop_collector(self: Box<Self>) -> Result<Box<dyn UnmappedTrace>, InvalidTraceError> {
+        match SWT_BLOCKS.with(|blocks| blocks.borrow_mut().take()) {
+            Some(blocks) if blocks.is_empty() => Err(InvalidTraceError::EmptyTrace),
+            Some(blocks) => Ok(Box::new(SWTTrace(blocks))),
+            None => Err(InvalidTraceError::InternalError),
+        }
+    }
+
+    fn trace_len(&self) -> usize {
+        SWT_BLOCKS.with(|blocks| blocks.borrow().as_ref().map_or(0, |b| b.len()))
+    }
+}
+
+struct SWTTrace(Vec<SWTBlock>);
+
+// The raw pointers in a `SWTTrace` point to immutable static strings and are thus safe to send to
+// another thread.
+unsafe impl Send for SWTTrace {}
+
+impl UnmappedTrace for SWTTrace {
+    fn map(self: Box<Self>, _tracer: Arc<dyn Tracer>) -> Result<IRTrace, InvalidTraceError> {
+        let mut mapped = Vec::with_capacity(self.0.len() + 1);
+        let mut faddrs = HashMap::new();
+        let mut prev: Option<SWTBlock> = None;
+        for blk in self.0 {
+            if prev == Some(blk) {
+                // The `BlockDisambiguate` pass in ykllvm ensures that no block ever branches
+                // straight back to itself, so seeing the same block twice in a row, in the same
+                // frame, means that the block called (and has now returned from) foreign code. The
+                // trace compiler needs to see the block again afterwards so that it can resume
+                // after the call. If the frames differ, the block called its own function, and
+                // the trace compiler will see that call in the IR.
+                mapped.push(IRBlock::new_unmappable(-1));
+            }
+            let func_name = CString::from(unsafe { CStr::from_ptr(blk.func_name) });
+            if !faddrs.contains_key(&func_name) {
+                // The trace compiler only needs the addresses of functions that it outlines calls
+                // to. Functions that it only inlines needn't be exported (e.g. they may be
+                // `static`), so a name we can't resolve doesn't invalidate the trace.
+                let vaddr = unsafe { dlsym(RTLD_DEFAULT, func_name.as_ptr()) };
+                if !vaddr.is_null() {
+                    faddrs.insert(func_name.clone(), vaddr as *const c_void);
+                }
+            }
+            mapped.push(IRBlock::new_mapped(func_name, blk.bb));
+            prev = Some(blk);
+        }
+
+        // Tracing stops inside the (foreign) control point, which the hardware tracer sees as a
+        // trailing unmappable region. The trace compiler relies on that, so we mimic it.
+        mapped.push(IRBlock::new_unmappable(0));
+        Ok(IRTrace::new(mapped, faddrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(func_name: &'static [u8], bb: usize, frame: usize) -> SWTBlock {
+        SWTBlock {
+            func_name: func_name.as_ptr() as *const c_char,
+            bb,
+            frame,
+        }
+    }
+
+    fn map(blocks: Vec<SWTBlock>) -> IRTrace {
+        Box::new(SWTTrace(blocks))
+            .map(Arc::new(SWTracer::new()))
+            .unwrap()
+    }
+
+    #[test]
+    fn foreign_call() {
+        let trace = map(vec![block(b"f\0", 0, 0x100), block(b"f\0", 0, 0x100)]);
+        assert_eq!(trace.len(), 4);
+        assert!(!trace.blocks[0].is_unmappable());
+        assert!(trace.blocks[1].is_unmappable());
+        assert!(!trace.blocks[2].is_unmappable());
+        assert!(trace.blocks[3].is_unmappable());
+    }
+
+    #[test]
+    fn recursive_entry_block() {
+        let trace = map(vec![block(b"f\0", 0, 0x100), block(b"f\0", 0, 0xc0)]);
+        assert_eq!(trace.len(), 3);
+        assert!(!trace.blocks[0].is_unmappable());
+        assert!(!trace.blocks[1].is_unmappable());
+        assert!(trace.blocks[2].is_unmappable());
+    }
+
+    #[test]
+    fn unresolvable_function() {
+        let trace = map(vec![
+            block(b"malloc\0", 0, 0x100),
+            block(b"__yk_no_such_function\0", 0, 0xc0),
+        ]);
+        assert_eq!(trace.len(), 3);
+        assert!(trace.faddrs.contains_key(&CString::new("malloc").unwrap()));
+        assert!(!trace
+            .faddrs
+            .contains_key(&CString::new("__yk_no_such_function").unwrap()));
+    }
+}
@@ -47,6 +47,10 @@ impl LiveVar {
     pub fn get(&self, idx: usize) -> Option<&Location> {
         self.locs.get(idx)
     }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Location> {
+        self.locs.iter()
+    }
 }
 
 pub struct PrologueInfo {
@@ -38,9 +38,23 @@ uint64_t getNewTraceIdx() {
 #define JITFUNC_ARG_STACKMAP_LEN_IDX 2
 #define JITFUNC_ARG_FRAMEADDR_IDX 3
 #define JITFUNC_ARG_LIVEAOTVALS_PTR_IDX 4
+#define JITFUNC_ARG_LIVEVALS_PTR_IDX 5
 
 #define YK_OUTLINE_FNATTR "yk_outline"
 
+// The function that ykllvm inserts calls to when building an interpreter for
+// software tracing.
+#define YK_TRACE_FUNCTION "yk_trace_basicblock"
+
+// The function that a trace calls (and whose result it returns) in place of a
+// call to `longjmp()`.
+#define YK_LONGJMP_FUNCTION "__ykrt_longjmp"
+
+// The block index which, alongside a null function name, marks the point in
+// an input trace where control entered `longjmp()`. Mirrors `LONGJMP_BLOCK` in
+// ykrt/src/trace/mod.rs.
+#define YK_LONGJMP_BLOCK static_cast<size_t>(PTRDIFF_MIN)
+
 // The first two arguments of a stackmap call are it's id and shadow bytes and
 // need to be skipped when scanning the operands for live values.
 #define YK_STACKMAP_SKIP_ARGS 2
@@ -301,6 +315,9 @@ struct IRBlock {
   char *FuncName;
   // The index of the block in the parent LLVM function.
   size_t BBIdx;
+  // The virtual address of the machine code at which the block was entered,
+  // or null if it isn't known.
+  void *VAddr;
 };
 
 // An unmappable region of code spanning one or more machine blocks.
@@ -317,11 +334,15 @@ struct UnmappableRegion {
   ssize_t StackAdjust;
 };
 
+// The point at which control entered `longjmp()` (or one of its variants).
+// Nothing after this is recorded, so this is always the end of a trace.
+struct Longjmp {};
+
 class TraceLoc {
-  std::variant<IRBlock, UnmappableRegion> Loc;
+  std::variant<IRBlock, UnmappableRegion, Longjmp> Loc;
 
 public:
-  TraceLoc(std::variant<IRBlock, UnmappableRegion> Loc) : Loc(Loc) {}
+  TraceLoc(std::variant<IRBlock, UnmappableRegion, Longjmp> Loc) : Loc(Loc) {}
 
   UnmappableRegion *getUnmappableRegion() {
     return std::get_if<UnmappableRegion>(&Loc);
@@ -329,10 +350,14 @@ public:
 
   IRBlock *getMappedBlock() { return std::get_if<IRBlock>(&Loc); }
 
+  bool isLongjmp() { return std::holds_alternative<Longjmp>(Loc); }
+
   void dump() {
     if (IRBlock *IRB = std::get_if<IRBlock>(&Loc)) {
       errs() << "IRBlock(Func=" << IRB->FuncName << ", BBIdx=" << IRB->BBIdx
              << ")\n";
+    } else if (isLongjmp()) {
+      errs() << "Longjmp\n";
     } else {
       UnmappableRegion *U = std::get_if<UnmappableRegion>(&Loc);
       assert(U);
@@ -352,12 +377,16 @@ private:
   // an element (at the same index) in the above `FuncNames` array to make a
   // (function, block) pair that identifies an LLVM BasicBlock.
   size_t *BBs;
-  // The length of the `FuncNames` and `BBs` arrays.
+  // An ordered array of the virtual addresses at which each block was entered
+  // (null where that isn't known). If the array itself is null, then none of
+  // the addresses are known.
+  void **VAddrs;
+  // The length of the `FuncNames`, `BBs`, and `VAddrs` arrays.
   size_t Len;
 
 public:
-  InputTrace(char **FuncNames, size_t *BBs, size_t Len)
-      : FuncNames(FuncNames), BBs(BBs), Len(Len) {}
+  InputTrace(char **FuncNames, size_t *BBs, void **VAddrs, size_t Len)
+      : FuncNames(FuncNames), BBs(BBs), VAddrs(VAddrs), Len(Len) {}
   size_t Length() { return Len; }
 
   // Returns the optional IRBlock at index `Idx` in the trace. No value is
@@ -366,16 +395,19 @@ public:
   TraceLoc operator[](size_t Idx) {
     assert(Idx < Len);
     char *FuncName = FuncNames[Idx];
-    if (FuncName == nullptr) {
+    if (FuncName == nullptr && BBs[Idx] == YK_LONGJMP_BLOCK) {
+      return TraceLoc(variant<IRBlock, UnmappableRegion, Longjmp>{Longjmp{}});
+    } else if (FuncName == nullptr) {
       // Subtle bitcast from `size_t` to `ssize_t`. When the trace was encoded
       // into an FFI friendly format, unmappable blocks use the `Idx` field
       // (a `size_t`) to encode the stack adjustment value (a `ssize_t`). The
       // cast below reverses that.
-      return TraceLoc(variant<IRBlock, UnmappableRegion>{
+      return TraceLoc(variant<IRBlock, UnmappableRegion, Longjmp>{
           UnmappableRegion{bit_cast<ssize_t, size_t>(BBs[Idx])}});
     } else {
-      return TraceLoc(
-          variant<IRBlock, UnmappableRegion>{IRBlock{FuncName, BBs[Idx]}});
+      void *VAddr = VAddrs != nullptr ? VAddrs[Idx] : nullptr;
+      return TraceLoc(variant<IRBlock, UnmappableRegion, Longjmp>{
+          IRBlock{FuncName, BBs[Idx], VAddr}});
     }
   }
 };
@@ -402,14 +434,6 @@ public:
   }
 };
 
-// Struct to store a live AOT value.
-struct AOTInfo {
-  size_t BBIdx;
-  size_t InstrIdx;
-  const char *FName;
-  size_t FrameIdx;
-};
-
 class JITModBuilder {
   // Global variables/functions that were copied over and need to be
   // initialised.
@@ -422,12 +446,6 @@ class JITModBuilder {
   bool Outlining = false;
   // The JITMod's builder.
   llvm::IRBuilder<> Builder;
-  // Dead values to recursively delete upon finalisation of the JITMod. This is
-  // required because it's not safe to recursively delete values in the middle
-  // of creating the JIT module. We don't know if any of those values might be
-  // required later in the trace.
-  vector<Value *> DeleteDeadOnFinalise;
-
   // Information about the trace we are compiling.
   InputTrace InpTrace;
   // Function virtual addresses discovered from the input trace.
@@ -452,6 +470,9 @@ class JITModBuilder {
   // The entry block for trace looping.
   BasicBlock *LoopEntryBB = nullptr;
 
+  // True if the trace being compiled is a side-trace.
+  bool IsSideTrace = false;
+
   // The function inside which we build the IR for the trace.
   Function *JITFunc;
 
@@ -540,12 +561,28 @@ class JITModBuilder {
     return Struct;
   }
 
+  // Should a call to `CF` (a function defined in AOTMod) be outlined, rather
+  // than inlined?
+  bool shouldOutline(Function *CF) {
+    return CF->hasFnAttribute(YK_OUTLINE_FNATTR) || CF->isVarArg() ||
+           CallStack.hasFrameForFunction(CF);
+  }
+
   void handleCallInst(CallInst *CI, Function *CF, size_t &CurBBIdx,
                       size_t &CurInstrIdx) {
-    // Update the most-recent frame's progress so that we return to the right
-    // place when we return from this call.
     MappableFrame *CurFrame = CallStack.curFrame().getMappableFrame();
     assert(CurFrame);
+
+    // An indirect call to a function that we are about to inline must first
+    // be promoted to a direct call. This has to happen before we set the
+    // resume point below, since emitting a guard clears it.
+    if (CI->getCalledFunction() == nullptr && CF != nullptr &&
+        !CF->isDeclaration() && !Outlining && !shouldOutline(CF)) {
+      promoteIndirectCall(CI, CF, CurBBIdx, CurInstrIdx);
+    }
+
+    // Update the most-recent frame's progress so that we return to the right
+    // place when we return from this call.
     CurFrame->setResume(CurBBIdx, CI, CurInstrIdx);
 
     if (CF == nullptr || CF->isDeclaration()) {
@@ -564,8 +601,7 @@ class JITModBuilder {
       // Calling to a non-foreign function.
       if (!Outlining) {
         // We are not outlining, but should this call start us outlining?
-        if (CF->hasFnAttribute(YK_OUTLINE_FNATTR) || CF->isVarArg() ||
-            CallStack.hasFrameForFunction(CF)) {
+        if (shouldOutline(CF)) {
           // We will outline this call.
           //
           // If this is a recursive call that has been inlined, or if the callee
@@ -597,6 +633,31 @@ class JITModBuilder {
     }
   }
 
+  // Leave the trace in place of the `longjmp()` (or variant) call `CI` to
+  // `CF`.
+  //
+  // Jumping out of the trace directly would skip the runtime's bookkeeping for
+  // leaving a trace, so instead the trace returns (as it does after a guard
+  // failure) and the jump is made on its behalf once execution is back in the
+  // AOT code.
+  void handleLongjmp(CallInst *CI, Function *CF) {
+    if (VMap.find(CF) == VMap.end()) {
+      declareFunction(CF);
+    }
+    std::vector<Value *> Args = {VMap[CF]};
+    for (Value *Arg : CI->args()) {
+      handleOperand(Arg);
+      Args.push_back(getMappedValue(Arg));
+    }
+    assert(Args.size() == 3);
+
+    LLVMContext &Context = JITMod->getContext();
+    PointerType *PtrTy = PointerType::get(Context, 0);
+    FunctionCallee LongjmpFunc = JITMod->getOrInsertFunction(
+        YK_LONGJMP_FUNCTION, PtrTy, PtrTy, PtrTy, IntTy);
+    Builder.CreateRet(Builder.CreateCall(LongjmpFunc, Args));
+  }
+
   // Emits a guard for a LLVM `br` instruction, returning a pointer to the
   // guard success block, or null if no guard was required.
   BasicBlock *handleBranchInst(Function *JITFunc, BasicBlock *NextBlock,
@@ -680,6 +741,127 @@ class JITModBuilder {
     return SuccBB;
   }
 
+  // If the constant `C` is, or contains (nested inside arrays or structs), the
+  // constant `Needle`, appends the GEP indices of `Needle` within `C` to `Idxs`
+  // and returns true.
+  static bool findConstant(Constant *C, Constant *Needle,
+                           std::vector<Constant *> &Idxs) {
+    if (C == Needle)
+      return true;
+    if (!isa<ConstantArray>(C) && !isa<ConstantStruct>(C))
+      return false;
+    for (unsigned I = 0; I < C->getNumOperands(); I++) {
+      Idxs.push_back(ConstantInt::get(Type::getInt32Ty(C->getContext()), I));
+      if (findConstant(cast<Constant>(C->getOperand(I)), Needle, Idxs))
+        return true;
+      Idxs.pop_back();
+    }
+    return false;
+  }
+
+  // Returns a pointer (in AOTMod) to where a constant global, such as the jump
+  // table of a computed goto, stores the address of the AOT block `BB`, or null
+  // if no constant global does.
+  Constant *findBlockAddressSlot(BasicBlock *BB) {
+    BlockAddress *BA = BlockAddress::lookup(BB);
+    if (BA == nullptr)
+      return nullptr;
+    for (GlobalVariable &G : AOTMod->globals()) {
+      if (!G.isConstant() || !G.hasInitializer())
+        continue;
+      std::vector<Constant *> Idxs{
+          ConstantInt::get(Type::getInt32Ty(AOTMod->getContext()), 0)};
+      if (findConstant(G.getInitializer(), BA, Idxs))
+        return ConstantExpr::getInBoundsGetElementPtr(G.getValueType(), &G,
+                                                      Idxs);
+    }
+    return nullptr;
+  }
+
+  // Emits a guard for a LLVM `indirectbr` instruction, checking that it jumps
+  // to the AOT block `NextBB` (the block the trace observed it jumping to).
+  // `NextVAddr` is the address of the machine code at which the trace entered
+  // `NextBB`. If that isn't known (e.g. because the trace was recorded by the
+  // software tracer), the guard instead loads `NextBB`'s address from a
+  // constant global which contains it (see `findBlockAddressSlot`).
+  //
+  // Returns false if there's no way to guard on `NextBB`. Otherwise, sets
+  // `SuccBB` to the guard success block, or null if no guard was required.
+  bool handleIndirectBrInst(Function *JITFunc, BasicBlock *NextBB,
+                            void *NextVAddr, Instruction *I, size_t CurBBIdx,
+                            size_t CurInstrIdx, BasicBlock *&SuccBB) {
+    assert(isa<IndirectBrInst>(I));
+    IndirectBrInst *IBI = cast<IndirectBrInst>(I);
+    LLVMContext &Context = JITMod->getContext();
+
+    // A constant target can't differ from the one we saw.
+    SuccBB = nullptr;
+    if (isa<BlockAddress>(IBI->getAddress()))
+      return true;
+
+    Value *Addr = getMappedValue(IBI->getAddress());
+    Value *Expected;
+    if (NextVAddr != nullptr) {
+      Expected = ConstantExpr::getIntToPtr(
+          ConstantInt::get(PointerSizedIntTy,
+                           reinterpret_cast<uintptr_t>(NextVAddr)),
+          Addr->getType());
+    } else {
+      Constant *Slot = findBlockAddressSlot(NextBB);
+      if (Slot == nullptr)
+        return false;
+      handleOperand(Slot);
+      Expected = Builder.CreateLoad(Addr->getType(), getMappedValue(Slot));
+    }
+
+    // The indirect branch itself isn't copied into `JITMod`, as the successor
+    // block is known from the trace.
+    BasicBlock *FailBB =
+        getGuardFailureBlock(IBI->getParent(), CurBBIdx, I, CurInstrIdx);
+    SuccBB = BasicBlock::Create(Context, GUARD_SUCCESS_BLOCK_NAME, JITFunc);
+    Builder.CreateCondBr(Builder.CreateICmpEQ(Addr, Expected), SuccBB, FailBB);
+
+    return true;
+  }
+
+  // Guard that the indirect call `CI` calls `CF` (the callee observed in the
+  // trace), so that `CF` can be inlined.
+  void promoteIndirectCall(CallInst *CI, Function *CF, size_t CurBBIdx,
+                           size_t CurInstrIdx) {
+    assert(CI->getCalledFunction() == nullptr);
+    LLVMContext &Context = JITMod->getContext();
+    if (VMap.find(CF) == VMap.end()) {
+      declareFunction(CF);
+      addGlobalMappingForFunction(CF);
+    }
+
+    handleOperand(CI->getCalledOperand());
+    Value *Callee = getMappedValue(CI->getCalledOperand());
+    BasicBlock *CallBB = BasicBlock::Create(Context, "indirectcall", JITFunc);
+    BasicBlock *SuccBB =
+        BasicBlock::Create(Context, GUARD_SUCCESS_BLOCK_NAME, JITFunc);
+    Builder.CreateCondBr(Builder.CreateICmpEQ(Callee, VMap[CF]), SuccBB,
+                         CallBB);
+
+    // The AOT code can only be resumed after a call, not before one. So if a
+    // different function is called, the call is made here, and then we
+    // deoptimise as if the AOT code had made it.
+    Builder.SetInsertPoint(CallBB);
+    copyInstruction(&Builder, CI, CurBBIdx, CurInstrIdx);
+    MappableFrame *CurFrame = CallStack.curMappableFrame();
+    assert(CurFrame);
+    CurFrame->LastSMCall = cast<CallInst>(CI->getNextNonDebugInstruction());
+    Builder.CreateBr(
+        getGuardFailureBlock(CI->getParent(), CurBBIdx, CI, CurInstrIdx));
+
+    // The copied call only exists on the guard failure path. On the success
+    // path, the call's result is that of the inlined callee (see
+    // `handleReturnInst`), so until the callee returns the call has no value
+    // in `JITMod`, just as for a direct call that we inline.
+    VMap.erase(CI);
+    Builder.SetInsertPoint(SuccBB);
+  }
+
   void handleReturnInst(Instruction *I, size_t CurBBIdx, size_t CurInstrIdx) {
     CallStack.popFrame();
 
@@ -751,6 +933,10 @@ class JITModBuilder {
     // Add argument for memory block holding live AOT values.
     InputTypes.push_back(PointerSizedIntTy->getPointerTo());
 
+    // Add argument for the values passed to a side-trace by its parent trace
+    // (null for root traces).
+    InputTypes.push_back(PointerSizedIntTy->getPointerTo());
+
     llvm::FunctionType *FType = llvm::FunctionType::get(
         PointerType::get(JITMod->getContext(), 0), InputTypes, false);
     llvm::Function *JITFunc = llvm::Function::Create(
@@ -760,46 +946,6 @@ class JITModBuilder {
     return JITFunc;
   }
 
-  // Delete the dead value `V` from its parent, also deleting any dependencies
-  // of `V` (i.e. operands) which then become dead.
-  void deleteDeadTransitive(Value *V) {
-    assert(V->user_empty()); // The value must be dead.
-    vector<Value *> Work;
-    Work.push_back(V);
-    while (!Work.empty()) {
-      Value *V = Work.back();
-      Work.pop_back();
-      // Remove `V` (an instruction or a global variable) from its parent
-      // container. If any of the operands of `V` have a sole use, then they
-      // will become dead and can also be deleted too.
-      if (isa<Instruction>(V)) {
-        Instruction *I = cast<Instruction>(V);
-        for (auto &Op : I->operands()) {
-          if (Op->hasOneUser()) {
-            Work.push_back(&*Op);
-          }
-        }
-        I->eraseFromParent();
-      } else if (isa<GlobalVariable>(V)) {
-        GlobalVariable *G = cast<GlobalVariable>(V);
-        for (auto &Op : G->operands()) {
-          if (Op->hasOneUser()) {
-            Work.push_back(&*Op);
-          }
-        }
-        // Be sure to remove this global variable from `cloned_globals` too, so
-        // that we don't try to add an initialiser later in `finalise()`.
-        erase_if(cloned_globals, [G, this](GlobalVariable *CG) {
-          assert(VMap.find(CG) != VMap.end());
-          return G == VMap[CG];
-        });
-        G->eraseFromParent();
-      } else {
-        dumpValueAndExit("Unexpected Value", V);
-      }
-    }
-  }
-
   // Given an `IRBlock`, find and return the LLVM data structures for the basic
   // block and its parent function.
   std::pair<Function *, BasicBlock *> getLLVMAOTFuncAndBlock(IRBlock *IB) {
@@ -814,6 +960,16 @@ class JITModBuilder {
     return {F, BB};
   }
 
+  // Return the instruction at index `InstrIdx` of the block at index `BBIdx` in
+  // the AOT function `F`.
+  Instruction *getAOTInstr(Function *F, size_t BBIdx, size_t InstrIdx) {
+    auto BBIt = F->begin();
+    std::advance(BBIt, BBIdx);
+    auto It = BBIt->begin();
+    std::advance(It, InstrIdx);
+    return &*It;
+  }
+
   // Returns a pointer to the guard failure block, creating it if necessary.
   BasicBlock *getGuardFailureBlock(BasicBlock *CurBB, size_t CurBBIdx,
                                    Instruction *Instr, size_t CurInstrIdx) {
@@ -822,10 +978,10 @@ class JITModBuilder {
     // unconditionally and immediately fails a guard.
     assert(JITFunc->size() != 0);
 
-    // Keep a count of he current number of guards. This number is used as an
-    // index into `CompiledTrace.guards` to handle side-traces inside guard
-    // failures.
-    GuardCount += 1;
+    // Give each guard a unique ID, starting from zero. This is used as an
+    // index into `CompiledTrace.guards` to count guard failures and find
+    // side-traces.
+    size_t GuardId = GuardCount++;
 
     LLVMContext &Context = JITFunc->getContext();
 
@@ -863,6 +1019,12 @@ class JITModBuilder {
     assert(CurFrame);
     CurFrame->setResume(CurBBIdx, Instr, CurInstrIdx);
 
+    // The same frames, for the runtime to work out how to deoptimise at this
+    // guard before it ever fails.
+    FrameInfo *Frames =
+        static_cast<FrameInfo *>(calloc(CallStack.size(), sizeof(FrameInfo)));
+    assert(Frames != NULL);
+
     std::vector<Value *> LiveValues;
     for (size_t I = 0; I < CallStack.size(); I++) {
       StackFrame &SF = CallStack.getFrame(I);
@@ -911,6 +1073,8 @@ class JITModBuilder {
                                   {ConstantInt::get(PointerSizedIntTy, I),
                                    ConstantInt::get(Int32Ty, 2)});
       FailBuilder.CreateStore(CurFunc, GEP);
+      Frames[I] = {RP.ResumeBBIdx, RP.ResumeAfterInstrIdx,
+                   MF->Func->getName().data()};
     }
 
     // Store the active frames vector and its length in a separate struct to
@@ -940,6 +1104,11 @@ class JITModBuilder {
       CurrentRegion[I] = {BBIdx, InstrIdx, FName, StackFrameIdx};
     }
 
+    Guards = static_cast<GuardInfo *>(
+        reallocarray(Guards, GuardCount, sizeof(GuardInfo)));
+    assert(Guards != NULL);
+    Guards[GuardId] = {Frames, CallStack.size(), CurPos, LiveValues.size()};
+
     // Store the live variable vector and its length in a separate struct to
     // save arguments.
     AllocaInst *AOTLocs = createAndFillStruct(
@@ -966,7 +1135,7 @@ class JITModBuilder {
         CallInst::Create(DeoptInt,
                          {StackMapStruct, AOTLocs, ActiveFramesStruct,
                           JITFunc->getArg(JITFUNC_ARG_FRAMEADDR_IDX),
-                          ConstantInt::get(PointerSizedIntTy, GuardCount)},
+                          ConstantInt::get(PointerSizedIntTy, GuardId)},
                          {ob}, "", GuardFailBB);
 
     // We always need to return after the deoptimisation call.
@@ -993,7 +1162,9 @@ class JITModBuilder {
     return {0, Idx, cast<Instruction>(TraceInputs), 0};
   }
 
-  void handleBranchingControlFlow(Instruction *I, size_t TraceIdx,
+  // Emits a guard (if required) for the branching instruction `I`. Returns
+  // false if the trace can't be compiled.
+  bool handleBranchingControlFlow(Instruction *I, size_t TraceIdx,
                                   Function *JITFunc, size_t CurBBIdx,
                                   size_t CurInstrIdx) {
     // First, peek ahead in the trace and retrieve the next block. We need this
@@ -1017,20 +1188,9 @@ class JITModBuilder {
       SuccBB = handleSwitchInst(JITFunc, NextBB, &*I, CurBBIdx, CurInstrIdx);
     } else {
       assert(isa<IndirectBrInst>(I));
-      // It isn't necessary to copy the indirect branch into the `JITMod`
-      // as the successor block is known from the trace. However, naively
-      // not copying the branch would lead to dangling references in the
-      // IR because the `address` operand typically (indirectly)
-      // references AOT block addresses not present in the `JITMod`.
-      // Therefore we also remove the IR instruction which defines the
-      // `address` operand and anything which also becomes dead as a
-      // result (recursively).
-      Value *FirstOp = I->getOperand(0);
-      assert(VMap.find(FirstOp) != VMap.end());
-      DeleteDeadOnFinalise.push_back(VMap[FirstOp]);
-      // FIXME: guards for indirect branches are not yet implemented.
-      // https://github.com/ykjit/yk/issues/438
-      abort();
+      if (!handleIndirectBrInst(JITFunc, NextBB, NextIB->VAddr, &*I, CurBBIdx,
+                                CurInstrIdx, SuccBB))
+        return false;
     }
 
     // If a guard was emitted, then the block we had been building the trace
@@ -1038,6 +1198,7 @@ class JITModBuilder {
     // should resume building the trace into the new guard success block.
     if (SuccBB != nullptr)
       Builder.SetInsertPoint(SuccBB);
+    return true;
   }
 
   void handleOperand(Value *Op) {
@@ -1087,6 +1248,19 @@ class JITModBuilder {
     }
   }
 
+  // Does the constant `C` contain (possibly nested inside aggregates or
+  // expressions) the address of a basic block?
+  static bool containsBlockAddress(Constant *C) {
+    if (isa<BlockAddress>(C))
+      return true;
+    for (Value *Op : C->operands()) {
+      if (isa<Constant>(Op) && !isa<GlobalValue>(Op) &&
+          containsBlockAddress(cast<Constant>(Op)))
+        return true;
+    }
+    return false;
+  }
+
   GlobalVariable *cloneGlobalVariable(Value *V) {
     GlobalVariable *OldGV = cast<GlobalVariable>(V);
     // We don't need to check if this global already exists, since
@@ -1098,7 +1272,10 @@ class JITModBuilder {
         (GlobalVariable *)nullptr, OldGV->getThreadLocalMode(),
         OldGV->getType()->getAddressSpace());
     VMap[OldGV] = GV;
-    if (OldGV->isConstant()) {
+    // Block addresses in AOTMod mean nothing in JITMod, so a constant which
+    // contains them (e.g. the jump table of a computed goto) is referenced in
+    // the interpreter's address space, just like a non-constant global.
+    if (OldGV->isConstant() && !containsBlockAddress(OldGV->getInitializer())) {
       GV->copyAttributesFrom(&*OldGV);
       cloned_globals.push_back(OldGV);
       // If this is a vector containing other constants, we need to clone
@@ -1155,12 +1332,6 @@ class JITModBuilder {
   // Finalise the JITModule by adding a return instruction and initialising
   // global variables.
   void finalise(Module *AOTMod, IRBuilder<> *Builder) {
-    // Now that we've seen all possible uses of values in the JITMod, we can
-    // delete the values we've marked dead (and possibly their dependencies if
-    // they too turn out to be dead).
-    for (auto &V : DeleteDeadOnFinalise)
-      deleteDeadTransitive(V);
-
     // Fix initialisers/referrers for copied global variables.
     // FIXME Do we also need to copy Linkage, MetaData, Comdat?
     for (GlobalVariable *G : cloned_globals) {
@@ -1330,47 +1501,14 @@ class JITModBuilder {
     }
   }
 
-  // Find the call site to the (patched) control point, the index of that call
-  // site in the parent block, and the type of the struct used to pass in the
-  // live LLVM variables.
-  //
-  // FIXME: this assumes that there is a single call-site of the control point.
-  // https://github.com/ykjit/yk/issues/479
-  static tuple<CallInst *, size_t, Value *>
-  GetControlPointInfo(Module *AOTMod) {
-    Function *F = AOTMod->getFunction(YK_NEW_CONTROL_POINT);
-    assert(F->arg_size() == YK_CONTROL_POINT_NUM_ARGS);
-
-    User *CallSite = F->user_back();
-    CallInst *CPCI = cast<CallInst>(CallSite);
-    assert(CPCI->arg_size() == YK_CONTROL_POINT_NUM_ARGS);
-
-    // Get the instruction index of CPCI in its parent block.
-    size_t CPCIIdx = 0;
-    for (Instruction &I : *CPCI->getParent()) {
-      if (&I == cast<Instruction>(CPCI)) {
-        break;
-      }
-      CPCIIdx++;
-    }
-
-    Value *Inputs = CPCI->getArgOperand(YK_CONTROL_POINT_ARG_VARS_IDX);
-#ifndef NDEBUG
-    Type *InputsTy = Inputs->getType();
-    assert(InputsTy->isPointerTy());
-#endif
-
-    return {CPCI, CPCIIdx, Inputs};
-  }
-
   // OPT: https://github.com/ykjit/yk/issues/419
   JITModBuilder(Module *AOTMod, char *FuncNames[], size_t BBs[],
-                size_t TraceLen, char *FAddrKeys[], void *FAddrVals[],
-                size_t FAddrLen, CallInst *CPCI,
+                void *BlockVAddrs[], size_t TraceLen, char *FAddrKeys[],
+                void *FAddrVals[], size_t FAddrLen, CallInst *CPCI,
                 std::optional<std::tuple<size_t, CallInst *>> InitialResume,
                 Value *TraceInputs)
       : AOTMod(AOTMod), Builder(AOTMod->getContext()),
-        InpTrace(FuncNames, BBs, TraceLen),
+        InpTrace(FuncNames, BBs, BlockVAddrs, TraceLen),
         FAddrs(FAddrKeys, FAddrVals, FAddrLen), TraceInputs(TraceInputs),
         ControlPointCallInst(CPCI) {
     LLVMContext &Context = AOTMod->getContext();
@@ -1415,9 +1553,12 @@ class JITModBuilder {
     // the maper).
     assert(InpTrace[0].getMappedBlock());
     // There should never be two unmappable blocks in a row in the trace
-    // (because the mapper collapses them to save memory).
+    // (because the mapper collapses them to save memory), and a longjmp only
+    // ever ends the trace.
     for (size_t I = 0; I < IL - 1; I++) {
-      assert(InpTrace[I].getMappedBlock() || InpTrace[I + 1].getMappedBlock());
+      assert(!InpTrace[I].isLongjmp());
+      assert(InpTrace[I].getMappedBlock() || InpTrace[I + 1].getMappedBlock() ||
+             InpTrace[I + 1].isLongjmp());
     }
 #endif
   }
@@ -1434,25 +1575,174 @@ public:
   AOTInfo *LiveAOTArray = nullptr;
   size_t LiveAOTNum = 0;
   size_t GuardCount = 0;
+  // Heap allocated memory describing how to deoptimise at each guard, indexed
+  // by guard ID.
+  GuardInfo *Guards = nullptr;
 
   JITModBuilder(JITModBuilder &&);
 
-  static JITModBuilder Create(Module *AOTMod, char *FuncNames[], size_t BBs[],
-                              size_t TraceLen, char *FAddrKeys[],
-                              void *FAddrVals[], size_t FAddrLen) {
-    CallInst *CPCI;
-    Value *TI;
-    size_t CPCIIdx;
-    std::tie(CPCI, CPCIIdx, TI) = GetControlPointInfo(AOTMod);
-    return JITModBuilder(AOTMod, FuncNames, BBs, TraceLen, FAddrKeys, FAddrVals,
-                         FAddrLen, CPCI, make_tuple(CPCIIdx, CPCI), TI);
+  // Find the call site to the (patched) control point at which the trace
+  // was collected, the index of that call site in the parent block, and the
+  // struct used to pass in the live LLVM variables. Returns `std::nullopt` if
+  // there's no such call site.
+  //
+  // An interpreter may call the control point from more than one place, each
+  // call site having its own live variables struct. A root trace starts
+  // immediately after the control point it was collected at, so the call site
+  // is in the first block of the trace. A side-trace instead ends at the
+  // control point that its parent was collected at (the runtime makes sure of
+  // that), so the call site is in the last mappable block of the trace.
+  static std::optional<tuple<CallInst *, size_t, Value *>>
+  GetControlPointInfo(Module *AOTMod, InputTrace &InpTrace, bool IsSideTrace) {
+    Function *F = AOTMod->getFunction(YK_NEW_CONTROL_POINT);
+    if (F == nullptr)
+      return std::nullopt;
+    assert(F->arg_size() == YK_CONTROL_POINT_NUM_ARGS);
+
+    size_t Idx = 0;
+    if (IsSideTrace) {
+      Idx = InpTrace.Length() - 1;
+      while ((Idx > 0) && !InpTrace[Idx].getMappedBlock())
+        Idx--;
+    }
+    TraceLoc Loc = InpTrace[Idx];
+    IRBlock *IB = Loc.getMappedBlock();
+    if (IB == nullptr)
+      return std::nullopt;
+    Function *BF = AOTMod->getFunction(IB->FuncName);
+    if ((BF == nullptr) || (IB->BBIdx >= BF->size()))
+      return std::nullopt;
+    BasicBlock *BB = &*std::next(BF->begin(), IB->BBIdx);
+
+    // Get the call site, and its instruction index in `BB`.
+    size_t CPCIIdx = 0;
+    for (Instruction &I : *BB) {
+      CallInst *CPCI = dyn_cast<CallInst>(&I);
+      if ((CPCI != nullptr) && (CPCI->getCalledFunction() == F)) {
+        assert(CPCI->arg_size() == YK_CONTROL_POINT_NUM_ARGS);
+        Value *Inputs = CPCI->getArgOperand(YK_CONTROL_POINT_ARG_VARS_IDX);
+        assert(Inputs->getType()->isPointerTy());
+        return make_tuple(CPCI, CPCIIdx, Inputs);
+      }
+      CPCIIdx++;
+    }
+    return std::nullopt;
+  }
+
+  static JITModBuilder
+  Create(Module *AOTMod, char *FuncNames[], size_t BBs[], void *BlockVAddrs[],
+         size_t TraceLen, char *FAddrKeys[], void *FAddrVals[],
+         size_t FAddrLen, SideTraceInfo *STI,
+         tuple<CallInst *, size_t, Value *> ControlPointInfo) {
+    auto [CPCI, CPCIIdx, TI] = ControlPointInfo;
+    // A root trace starts immediately after the control point. A side-trace
+    // starts wherever its parent's guard failed: its initial frames are set up
+    // by `initSideTrace()`.
+    std::optional<std::tuple<size_t, CallInst *>> InitialResume;
+    if (STI == nullptr)
+      InitialResume = make_tuple(CPCIIdx, CPCI);
+    return JITModBuilder(AOTMod, FuncNames, BBs, BlockVAddrs, TraceLen,
+                         FAddrKeys, FAddrVals, FAddrLen, CPCI, InitialResume,
+                         TI);
+  }
+
+  // Prepare to compile a side-trace starting at the guard failure described
+  // by `STI`: recreate the AOT call stack as it was when the guard failed, and
+  // map the values that were live at that point to those passed in by the
+  // parent trace.
+  //
+  // Returns false if the side-trace can't be compiled.
+  bool initSideTrace(SideTraceInfo *STI) {
+    assert(STI->FramesLen > 0);
+    IsSideTrace = true;
+
+    // Replace the initial frame pushed by the constructor.
+    CallStack.popFrame();
+    for (size_t I = 0; I < STI->FramesLen; I++) {
+      FrameInfo &FI = STI->Frames[I];
+      Function *F = AOTMod->getFunction(FI.FName);
+      if (F == nullptr)
+        return false;
+      Instruction *Instr = getAOTInstr(F, FI.BBIdx, FI.InstrIdx);
+      CallInst *SMC;
+      BlockResumePoint RP;
+      if (I == STI->FramesLen - 1) {
+        // The most-recent frame is stopped at the branch whose guard failed.
+        // We resume *at* (not after) the branch, so that the side-trace starts
+        // with a guard for the direction that was actually taken. The branch
+        // is always preceded by the stackmap call describing its live values.
+        if (FI.InstrIdx == 0)
+          return false;
+        SMC = dyn_cast<CallInst>(Instr->getPrevNonDebugInstruction());
+        RP = {FI.BBIdx, Instr->getPrevNode(), FI.InstrIdx - 1};
+      } else {
+        // Older frames are stopped at a call and resume after it.
+        SMC = dyn_cast<CallInst>(Instr->getNextNonDebugInstruction());
+        RP = {FI.BBIdx, Instr, FI.InstrIdx};
+      }
+      if ((SMC == nullptr) ||
+          (SMC->getIntrinsicID() != Intrinsic::experimental_stackmap))
+        return false;
+      CallStack.pushFrame(StackFrame::CreateMappableFrame(F, SMC, RP));
+    }
+
+    // Load the live values passed in by the parent trace and cast them back to
+    // the types of the AOT values they represent.
+    Value *LiveVals = JITFunc->getArg(JITFUNC_ARG_LIVEVALS_PTR_IDX);
+    for (size_t I = 0; I < STI->LiveValsLen; I++) {
+      AOTInfo &AI = STI->LiveVals[I];
+      Function *F = AOTMod->getFunction(AI.FName);
+      if (F == nullptr)
+        return false;
+      Instruction *AOTVar = getAOTInstr(F, AI.BBIdx, AI.InstrIdx);
+      if (AOTVar == TraceInputs) {
+        // The control point struct is passed directly to every trace.
+        continue;
+      }
+      Value *Ptr = Builder.CreateGEP(PointerSizedIntTy, LiveVals,
+                                     ConstantInt::get(PointerSizedIntTy, I));
+      Value *Raw = Builder.CreateLoad(PointerSizedIntTy, Ptr);
+      Type *Ty = AOTVar->getType();
+      Value *JITVar;
+      if (Ty->isPointerTy()) {
+        JITVar = Builder.CreateIntToPtr(Raw, Ty);
+      } else if (Ty->isIntegerTy() && (Ty->getIntegerBitWidth() <=
+                                       PointerSizedIntTy->getBitWidth())) {
+        JITVar = Builder.CreateTrunc(Raw, Ty);
+      } else {
+        // FIXME: Support floating point and aggregate live values.
+        return false;
+      }
+      VMap[AOTVar] = JITVar;
+      AOTMap[JITVar] = {AI.BBIdx, AI.InstrIdx, AOTVar, AI.FrameIdx};
+    }
+
+    // The arguments of inlined callees were mapped directly to the values
+    // passed by their callers, so they aren't live values in their own right.
+    // Map them again here.
+    for (size_t I = 0; I + 1 < CallStack.size(); I++) {
+      MappableFrame *Caller = CallStack.getFrame(I).getMappableFrame();
+      MappableFrame *Callee = CallStack.getFrame(I + 1).getMappableFrame();
+      CallInst *CI = dyn_cast<CallInst>(Caller->getLastCallInstruction());
+      if (CI == nullptr)
+        return false;
+      Function *CF = CI->getCalledFunction();
+      if ((CF != nullptr) && (CF != Callee->Func))
+        return false;
+      for (unsigned int J = 0; J < Callee->Func->arg_size(); J++) {
+        Value *Var = CI->getArgOperand(J);
+        handleOperand(Var);
+        VMap[Callee->Func->getArg(J)] = getMappedValue(Var);
+      }
+    }
+    return true;
   }
 
 #ifdef YK_TESTING
   static JITModBuilder CreateMocked(Module *AOTMod, char *FuncNames[],
-                                    size_t BBs[], size_t TraceLen,
-                                    char *FAddrKeys[], void *FAddrVals[],
-                                    size_t FAddrLen) {
+                                    size_t BBs[], void *BlockVAddrs[],
+                                    size_t TraceLen, char *FAddrKeys[],
+                                    void *FAddrVals[], size_t FAddrLen) {
     LLVMContext &Context = AOTMod->getContext();
 
     // The trace compiler expects to be given a) a call to a control point, and
@@ -1503,9 +1793,9 @@ public:
     // first frame's `BlockResumePoint`. This means that the compiler will
     // start copying instructions from the beginning of the first block in the
     // trace, instead of after the return from the control point.
-    JITModBuilder JB(AOTMod, FuncNames, BBs, TraceLen, &NewFAddrKeys[0],
-                     &NewFAddrVals[0], NewFAddrKeys.size(), CPCI, {},
-                     TraceInputs);
+    JITModBuilder JB(AOTMod, FuncNames, BBs, BlockVAddrs, TraceLen,
+                     &NewFAddrKeys[0], &NewFAddrVals[0], NewFAddrKeys.size(),
+                     CPCI, {}, TraceInputs);
 
     return JB;
   }
@@ -1520,6 +1810,13 @@ public:
       // mappable).
       TraceLoc Loc = InpTrace[Idx];
 
+      if (Loc.isLongjmp()) {
+        // Control entered `longjmp()` from foreign code (a call from mappable
+        // code returns from the trace before reaching here). We have no way
+        // of knowing where it will jump to, so abort this trace.
+        return nullptr;
+      }
+
       if (UnmappableRegion *UR = Loc.getUnmappableRegion()) {
         // The trace entered a region of unmappable foreign code.
         //
@@ -1537,10 +1834,14 @@ public:
           continue;
         }
 
+        if (InpTrace[Idx + 1].isLongjmp()) {
+          // The foreign code called `longjmp()`, so its stack adjustment
+          // doesn't describe a return to, or a call into, mappable code.
+          continue;
+        }
+
         assert(!CallStack.curMappableFrame());
 
-        // FIXME: think about what do do in the face of setjmp/longjmp. How
-        // would we find the stack adjustment value for such code?
         if (UR->StackAdjust < 0) {
           // The stack got smaller as a result of executing the foreign code,
           // so we must be returning to mappable code.
@@ -1724,7 +2025,6 @@ public:
               } else {
                 CF = nullptr;
               }
-              // FIXME Don't inline indirect calls unless promoted.
               handleCallInst(CI, CF, CurBBIdx, CurInstrIdx);
               break;
             }
@@ -1741,11 +2041,33 @@ public:
             break;
           } else {
             StringRef S = CF->getName();
-            if (S == "_setjmp" || S == "_longjmp") {
-              // FIXME: We currently can't deal with traces containing
-              // setjmp/longjmp, so for now simply abort this trace.
-              // See: https://github.com/ykjit/yk/issues/610
-              return nullptr;
+            if (S == YK_TRACE_FUNCTION) {
+              // Software tracing instrumentation only records the trace and
+              // has no effect on the semantics of the program, so it has no
+              // place in the JITMod.
+              continue;
+            }
+            if (S == "setjmp" || S == "_setjmp" || S == "__sigsetjmp") {
+              if (!Outlining) {
+                // The `jmp_buf` would capture the state of the trace's own
+                // frame, which no longer exists once the trace has been left
+                // (e.g. by a guard failure), so a later `longjmp()` to it
+                // couldn't work. Abort this trace.
+                // See: https://github.com/ykjit/yk/issues/610
+                return nullptr;
+              }
+            } else if (S == "longjmp" || S == "_longjmp" || S == "siglongjmp" ||
+                       S == "__longjmp_chk") {
+              if (Outlining) {
+                // The outlined callee would jump out of the trace without us
+                // getting a chance to leave it cleanly.
+                return nullptr;
+              }
+              // Control can't come back from `longjmp()`, so this is the end
+              // of the trace.
+              handleLongjmp(CI, CF);
+              finalise(AOTMod, &Builder);
+              return JITMod;
             }
             handleCallInst(CI, CF, CurBBIdx, CurInstrIdx);
             break;
@@ -1790,7 +2112,9 @@ public:
               continue;
             }
           }
-          handleBranchingControlFlow(&*I, Idx, JITFunc, CurBBIdx, CurInstrIdx);
+          if (!handleBranchingControlFlow(&*I, Idx, JITFunc, CurBBIdx,
+                                          CurInstrIdx))
+            return nullptr;
           break;
         }
 
@@ -1806,7 +2130,13 @@ public:
           // YkCtrlPointVars loads and stores inbetween come from tracing over
           // the control point and aren't needed. We remove them here to reduce
           // the size of traces.
-          if (isa<GetElementPtrInst>(I)) {
+          //
+          // A side-trace is the exception: it doesn't loop, but returns to
+          // the interpreter at the control point, which then reloads the
+          // YkCtrlPointVars. We thus need to copy the stores at the end of the
+          // trace.
+          if (isa<GetElementPtrInst>(I) &&
+              !(IsSideTrace && (Idx == InpTrace.Length() - 2))) {
             GetElementPtrInst *GEP = cast<GetElementPtrInst>(I);
             if (GEP->getPointerOperand() == TraceInputs) {
               // Collect stores
@@ -1909,10 +2239,13 @@ public:
     // If the trace succeeded, loop back to the top. The only way to leave the
     // trace is via a guard failure.
     if (LoopEntryBB) {
+      assert(!IsSideTrace);
       Builder.CreateBr(LoopEntryBB);
     } else {
-      // This is here only because some of our `.ll` tests don't contain a
-      // control point, so the loop-entry block is never created.
+      // A side-trace ends at the control point, returning to the interpreter
+      // which continues as if the control point had done nothing. Apart from
+      // that, this is here only because some of our `.ll` tests don't contain
+      // a control point, so the loop-entry block is never created.
       Builder.CreateRet(
           ConstantPointerNull::get(PointerType::get(JITMod->getContext(), 0)));
     }
@@ -1921,25 +2254,42 @@ public:
   }
 };
 
-tuple<Module *, string, std::map<GlobalValue *, void *>, void *, size_t>
-createModule(Module *AOTMod, char *FuncNames[], size_t BBs[], size_t TraceLen,
-             char *FAddrKeys[], void *FAddrVals[], size_t FAddrLen) {
-  JITModBuilder JB = JITModBuilder::Create(AOTMod, FuncNames, BBs, TraceLen,
-                                           FAddrKeys, FAddrVals, FAddrLen);
+tuple<Module *, string, std::map<GlobalValue *, void *>, void *, size_t,
+      GuardInfo *>
+createModule(Module *AOTMod, char *FuncNames[], size_t BBs[],
+             void *BlockVAddrs[], size_t TraceLen, char *FAddrKeys[],
+             void *FAddrVals[], size_t FAddrLen, SideTraceInfo *STI) {
+  InputTrace InpTrace(FuncNames, BBs, BlockVAddrs, TraceLen);
+  auto ControlPointInfo =
+      JITModBuilder::GetControlPointInfo(AOTMod, InpTrace, STI != nullptr);
+  if (!ControlPointInfo.has_value())
+    return make_tuple(nullptr, "", std::map<GlobalValue *, void *>(), nullptr,
+                      0, nullptr);
+  JITModBuilder JB = JITModBuilder::Create(
+      AOTMod, FuncNames, BBs, BlockVAddrs, TraceLen, FAddrKeys, FAddrVals,
+      FAddrLen, STI, *ControlPointInfo);
+  if ((STI != nullptr) && !JB.initSideTrace(STI))
+    return make_tuple(nullptr, std::move(JB.TraceName),
+                      std::move(JB.GlobalMappings), nullptr, 0, nullptr);
   auto JITMod = JB.createModule();
   return make_tuple(JITMod, std::move(JB.TraceName),
                     std::move(JB.GlobalMappings), JB.LiveAOTArray,
-                    JB.GuardCount);
+                    JB.GuardCount, JB.Guards);
 }
 
 #ifdef YK_TESTING
-tuple<Module *, string, std::map<GlobalValue *, void *>, void *, size_t>
+tuple<Module *, string, std::map<GlobalValue *, void *>, void *, size_t,
+      GuardInfo *>
 createModuleForTraceCompilerTests(Module *AOTMod, char *FuncNames[],
-                                  size_t BBs[], size_t TraceLen,
-                                  char *FAddrKeys[], void *FAddrVals[],
-                                  size_t FAddrLen) {
-  JITModBuilder JB = JITModBuilder::CreateMocked(
-      AOTMod, FuncNames, BBs, TraceLen, FAddrKeys, FAddrVals, FAddrLen);
+                                  size_t BBs[], void *BlockVAddrs[],
+                                  size_t TraceLen, char *FAddrKeys[],
+                                  void *FAddrVals[], size_t FAddrLen,
+                                  SideTraceInfo *STI) {
+  // The trace compiler tests don't compile side-traces.
+  assert(STI == nullptr);
+  JITModBuilder JB =
+      JITModBuilder::CreateMocked(AOTMod, FuncNames, BBs, BlockVAddrs,
+                                  TraceLen, FAddrKeys, FAddrVals, FAddrLen);
 
   auto JITMod = JB.createModule();
 
@@ -1974,6 +2324,6 @@ createModuleForTraceCompilerTests(Module *AOTMod, char *FuncNames[],
   DOBuilder.CreateUnreachable();
 
   return make_tuple(JITMod, std::move(JB.TraceName),
-                    std::move(JB.GlobalMappings), nullptr, 0);
+                    std::move(JB.GlobalMappings), nullptr, 0, nullptr);
 }
 #endif
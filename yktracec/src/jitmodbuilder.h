@@ -10,16 +10,60 @@
 
 using namespace llvm;
 
+// Describes an active AOT frame at a guard failure. Mirrors the `FrameInfo`
+// struct in ykrt/src/frame/mod.rs.
+struct FrameInfo {
+  size_t BBIdx;
+  size_t InstrIdx;
+  const char *FName;
+};
+
+// Struct to store a live AOT value.
+struct AOTInfo {
+  size_t BBIdx;
+  size_t InstrIdx;
+  const char *FName;
+  size_t FrameIdx;
+};
+
+// Describes how to deoptimise at a guard. Mirrors the `GuardInfoC` struct in
+// ykrt/src/trace/mod.rs.
+struct GuardInfo {
+  // The frames active at the guard failure, oldest first. Allocated with
+  // malloc().
+  FrameInfo *Frames;
+  size_t FramesLen;
+  // The index of the guard's first live AOT value in the trace's array of live
+  // AOT values, and the number of live AOT values.
+  size_t LiveValsIdx;
+  size_t LiveValsLen;
+};
+
+// Describes the state of the program at the guard failure from which a
+// side-trace starts. Mirrors the `SideTraceInfoC` struct in
+// ykrt/src/trace/mod.rs.
+struct SideTraceInfo {
+  // The frames active at the guard failure, oldest first.
+  FrameInfo *Frames;
+  size_t FramesLen;
+  // The AOT values live at the guard failure. The side-trace is passed the
+  // run-time values of these (in the same order) by the failing guard.
+  AOTInfo *LiveVals;
+  size_t LiveValsLen;
+};
+
 std::tuple<Module *, std::string, std::map<GlobalValue *, void *>, void *,
-           size_t>
-createModule(Module *AOTMod, char *FuncNames[], size_t BBs[], size_t TraceLen,
-             char *FAddrKeys[], void *FAddrVals[], size_t FAddrLen);
+           size_t, GuardInfo *>
+createModule(Module *AOTMod, char *FuncNames[], size_t BBs[],
+             void *BlockVAddrs[], size_t TraceLen, char *FAddrKeys[],
+             void *FAddrVals[], size_t FAddrLen, SideTraceInfo *STI);
 #ifdef YK_TESTING
 std::tuple<Module *, std::string, std::map<GlobalValue *, void *>, void *,
-           size_t>
+           size_t, GuardInfo *>
 createModuleForTraceCompilerTests(Module *AOTMod, char *FuncNames[],
-                                  size_t BBs[], size_t TraceLen,
-                                  char *FAddrKeys[], void *FAddrVals[],
-                                  size_t FAddrLen);
+                                  size_t BBs[], void *BlockVAddrs[],
+                                  size_t TraceLen, char *FAddrKeys[],
+                                  void *FAddrVals[], size_t FAddrLen,
+                                  SideTraceInfo *STI);
 #endif // YK_TESTING
 #endif
@@ -12,6 +12,7 @@ extern "C" {
     pub fn __yktracec_irtrace_compile(
         func_names: *const *const c_char,
         bbs: *const size_t,
+        block_vaddrs: *const *const c_void,
         trace_len: size_t,
         faddr_keys: *const *const c_char,
         faddr_vals: *const *const c_void,
@@ -20,12 +21,16 @@ extern "C" {
         llvmbc_len: u64,
         debuginfo_fd: c_int,
         debuginfo_path: *const c_char,
+        sidetrace_info: *const c_void,
     ) -> *const c_void;
 
+    pub fn __yktracec_free_compiled_trace(handle: *const c_void);
+
     #[cfg(feature = "yk_testing")]
     pub fn __yktracec_irtrace_compile_for_tc_tests(
         func_names: *const *const c_char,
         bbs: *const size_t,
+        block_vaddrs: *const *const c_void,
         trace_len: size_t,
         faddr_keys: *const *const c_char,
         faddr_vals: *const *const c_void,
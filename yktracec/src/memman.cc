@@ -61,6 +61,18 @@ void MemMan::freeMemory() {
       errx(EXIT_FAILURE, "Failed to unmap memory.");
     }
   }
+  code.clear();
+  data.clear();
+}
+
+// Returns the number of bytes (of code and data) currently allocated.
+size_t MemMan::allocatedSize() {
+  size_t Size = 0;
+  for (const AllocMem &Value : code)
+    Size += Value.Size;
+  for (const AllocMem &Value : data)
+    Size += Value.Size;
+  return Size;
 }
 
 void MemMan::setStackMapStore(AllocMem *Ptr) { SMR = Ptr; }
@@ -27,6 +27,7 @@ public:
                                bool isReadOnly) override;
   bool finalizeMemory(std::string *ErrMsg) override;
   void freeMemory();
+  size_t allocatedSize();
   void setStackMapStore(AllocMem *Ptr);
 };
 
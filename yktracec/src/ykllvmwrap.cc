@@ -26,10 +26,13 @@
 #include <err.h>
 #include <filesystem>
 #include <link.h>
+#include <map>
+#include <mutex>
 #include <optional>
 #include <stdlib.h>
 #include <string.h>
 #include <unistd.h>
+#include <vector>
 
 #include "jitmodbuilder.h"
 #include "memman.h"
@@ -100,28 +103,42 @@ public:
   }
 };
 
-// The bitcode module loaded from the .llvmbc section of the currently-running
-// binary. This cannot be shared across threads and used concurrently without
-// acquiring a lock, and since we do want to allow parallel compilation, each
-// thread takes a copy of this module.
-ThreadSafeModule GlobalAOTMod;
+// The bitcode modules loaded from the .llvmbc sections of the objects (built
+// with ykllvm) in the currently-running process, keyed by the address of the
+// section. A module cannot be shared across threads and used concurrently
+// without acquiring a lock, and since we do want to allow parallel
+// compilation, each thread takes a copy of the modules it needs.
+//
+// If an object is unloaded, another may be loaded in its place, so the modules
+// are discarded whenever the loader has unloaded an object (see
+// getThreadAOTMod()).
+map<void *, ThreadSafeModule> GlobalAOTMods;
+
+// The number of objects that the loader had unloaded when GlobalAOTMods was
+// last checked for staleness.
+uint64_t GlobalAOTModsSubs = 0;
 
-// Flag used to ensure that GlobalAOTMod is loaded only once.
-once_flag GlobalAOTModLoaded;
+// Lock guarding GlobalAOTMods and GlobalAOTModsSubs.
+mutex GlobalAOTModsLock;
 
-// A copy of GlobalAOTMod for use by a single thread.
+// Copies of modules in GlobalAOTMods for use by a single thread.
 //
 // A thread should never access this directly, but should instead go via
 // getThreadAOTMod() which deals with the necessary lazy initialisation.
 //
-// PERF: Copying GlobalAOTMod is quite expensive (cloneToNewContext()
-// serialises and deserializes). When a compilation thread dies, we should
-// return its ThreadAOTMod to a pool and transfer ownership to the next thread
-// that needs its own copy of GlobalAOTMod.
-thread_local ThreadSafeModule ThreadAOTMod;
+// PERF: Copying a module is quite expensive (cloneToNewContext() serialises
+// and deserializes). When a compilation thread dies, we should return its
+// ThreadAOTMods to a pool and transfer ownership to the next thread that needs
+// its own copies.
+thread_local map<void *, ThreadSafeModule> ThreadAOTMods;
 
-// A flag indicating whether GlobalAOTMod has been copied into the thread yet.
-thread_local bool ThreadAOTModInitialized = false;
+// As GlobalAOTModsSubs, but for ThreadAOTMods.
+thread_local uint64_t ThreadAOTModsSubs = 0;
+
+// Modules discarded from ThreadAOTMods because they may be stale. Compiled
+// traces point into (e.g. the function names of) the modules they were
+// compiled from, so these can't be freed.
+thread_local vector<ThreadSafeModule> StaleThreadAOTMods;
 
 // Flag used to ensure that LLVM is initialised only once.
 once_flag LLVMInitialised;
@@ -197,10 +214,10 @@ void initLLVM(void *Unused) {
   InitializeNativeTargetAsmParser();
 }
 
-// Load the GlobalAOTMod.
+// Load the module from `Bitcode`.
 //
-// This must only be called from getAOTMod() for correct synchronisation.
-void loadAOTMod(struct BitcodeSection &Bitcode) {
+// This must only be called from getThreadAOTMod() for correct synchronisation.
+ThreadSafeModule loadAOTMod(struct BitcodeSection &Bitcode) {
   auto Sf = StringRef((const char *)Bitcode.data, Bitcode.len);
   auto Mb = MemoryBufferRef(Sf, "");
   SMDiagnostic Error;
@@ -210,33 +227,82 @@ void loadAOTMod(struct BitcodeSection &Bitcode) {
     Error.print("", errs(), false);
     errx(EXIT_FAILURE, "Can't load module.");
   }
-  GlobalAOTMod = ThreadSafeModule(std::move(M), std::move(AOTCtx));
+  return ThreadSafeModule(std::move(M), std::move(AOTCtx));
+}
+
+// Returns the number of objects that the loader has unloaded so far.
+uint64_t loaderSubs() {
+  uint64_t Subs = 0;
+  dl_iterate_phdr(
+      [](struct dl_phdr_info *Info, size_t Size, void *Data) {
+        *static_cast<uint64_t *>(Data) = Info->dlpi_subs;
+        // Every object reports the same count.
+        return 1;
+      },
+      &Subs);
+  return Subs;
 }
 
-// Get a thread-safe handle on the LLVM module stored in the .llvmbc section of
-// the binary. The module is loaded if we haven't yet done so.
+// Get a thread-safe handle on the LLVM module stored in the .llvmbc section
+// `Bitcode`. The module is loaded if we haven't yet done so.
 ThreadSafeModule *getThreadAOTMod(struct BitcodeSection &Bitcode) {
-  std::call_once(GlobalAOTModLoaded, loadAOTMod, Bitcode);
-  if (!ThreadAOTModInitialized) {
-    ThreadAOTMod = cloneToNewContext(GlobalAOTMod);
-    ThreadAOTModInitialized = true;
+  // A module keyed by the address of `Bitcode` may belong to an object that
+  // has since been unloaded.
+  uint64_t Subs = loaderSubs();
+  if (Subs != ThreadAOTModsSubs) {
+    for (auto &KV : ThreadAOTMods)
+      StaleThreadAOTMods.push_back(std::move(KV.second));
+    ThreadAOTMods.clear();
+    ThreadAOTModsSubs = Subs;
+  }
+  auto It = ThreadAOTMods.find(Bitcode.data);
+  if (It == ThreadAOTMods.end()) {
+    lock_guard<mutex> Guard(GlobalAOTModsLock);
+    // Another thread may have seen a later count than us, in which case it has
+    // already discarded the stale modules.
+    if (Subs > GlobalAOTModsSubs) {
+      GlobalAOTMods.clear();
+      GlobalAOTModsSubs = Subs;
+    }
+    auto GIt = GlobalAOTMods.find(Bitcode.data);
+    if (GIt == GlobalAOTMods.end())
+      GIt = GlobalAOTMods.emplace(Bitcode.data, loadAOTMod(Bitcode)).first;
+    It = ThreadAOTMods.emplace(Bitcode.data, cloneToNewContext(GIt->second))
+             .first;
   }
-  return &ThreadAOTMod;
+  return &It->second;
 }
 
-// Exposes `getThreadAOTMod` so we can get a thread-safe copy of the
-// AOT IR from within Rust.
-extern "C" LLVMModuleRef
-LLVMGetThreadSafeModule(struct BitcodeSection &Bitcode) {
-  ThreadSafeModule *ThreadAOTMod = getThreadAOTMod(Bitcode);
-  Module *AOTMod = ThreadAOTMod->getModuleUnlocked();
-  return llvm::wrap(AOTMod);
+// Exposes `getThreadAOTMod` so we can use a thread-safe copy of the AOT IR
+// from within Rust: `F` is called with the module and `Data` while the
+// module's context is locked (see `compileIRTrace`).
+extern "C" void LLVMWithThreadSafeModule(struct BitcodeSection *Bitcode,
+                                         void (*F)(LLVMModuleRef, void *),
+                                         void *Data) {
+  ThreadSafeModule *ThreadAOTMod = getThreadAOTMod(*Bitcode);
+  ThreadAOTMod->withModuleDo(
+      [&](Module &AOTMod) { F(llvm::wrap(&AOTMod), Data); });
 }
 
-// Compile a module in-memory and return a pointer to its function.
+// The LLVM objects behind a compiled trace, which must live as long as the
+// trace does. They are freed by `__yktracec_free_compiled_trace`.
+struct CompiledTraceHandle {
+  // The execution engine, which owns the trace's module and `MM`.
+  ExecutionEngine *EE;
+  // The memory manager which allocated the trace's code and data.
+  MemMan *MM;
+  // The context of the trace's module. Holding a reference to it keeps it
+  // alive after the thread which compiled the trace has exited.
+  ThreadSafeContext Ctx;
+};
+
+// Compile a module, whose context is `Ctx`, in-memory and return a pointer to
+// its function.
 extern "C" void *compileModule(string TraceName, Module *M,
+                               ThreadSafeContext Ctx,
                                map<GlobalValue *, void *> GlobalMappings,
-                               void *LiveAOTVals, size_t GuardCount) {
+                               void *LiveAOTVals, size_t GuardCount,
+                               GuardInfo *Guards) {
   std::call_once(LLVMInitialised, initLLVM, nullptr);
 
   // Use our own memory manager to keep track of stackmap address.
@@ -268,19 +334,41 @@ extern "C" void *compileModule(string TraceName, Module *M,
     errx(EXIT_FAILURE, "Couldn't compile trace: %s",
          EE->getErrorMessage().c_str());
 
-  // Allocate space for compiled trace address, stackmap address, and stackmap
-  // size.
+  // Allocate space for compiled trace address, stackmap address, stackmap
+  // size, live AOT values, guard count, the handle owning the trace's LLVM
+  // objects, the number of bytes allocated by the memory manager, and the
+  // guards' deoptimisation info.
   // FIXME This is a temporary hack until the redesigned hot location is up.
-  uintptr_t *ptr = (uintptr_t *)malloc(sizeof(uintptr_t) * 5);
+  uintptr_t *ptr = (uintptr_t *)malloc(sizeof(uintptr_t) * 8);
   ptr[0] = EE->getFunctionAddress(TraceName);
   ptr[1] = reinterpret_cast<uintptr_t>(SMR.Ptr);
   ptr[2] = SMR.Size;
   ptr[3] = reinterpret_cast<uintptr_t>(LiveAOTVals);
   ptr[4] = GuardCount;
+  ptr[5] = reinterpret_cast<uintptr_t>(
+      new CompiledTraceHandle{EE, memman, std::move(Ctx)});
+  ptr[6] = memman->allocatedSize();
+  ptr[7] = reinterpret_cast<uintptr_t>(Guards);
 
   return ptr;
 }
 
+// Unmap the code and data of a compiled trace, whose handle is `Handle`, and
+// free its LLVM objects. The caller must ensure that no thread is executing,
+// or will execute, the trace.
+extern "C" void __yktracec_free_compiled_trace(void *Handle) {
+  auto *H = static_cast<CompiledTraceHandle *>(Handle);
+  H->MM->freeMemory();
+  {
+    // The module belongs to the context of the thread which compiled the
+    // trace, which may be compiling another trace right now: deleting the
+    // module must not race with that.
+    auto Lock = H->Ctx.getLock();
+    delete H->EE;
+  }
+  delete H;
+}
+
 /// Write the string `S` in its entirety to the file descriptor `FD`.
 void writeString(int FD, string S) {
   const char *Buf = S.c_str();
@@ -380,22 +468,30 @@ void rewriteDebugInfo(Module *M, string TraceName, int FD,
 
 // Compile an IRTrace to executable code in memory.
 //
-// The trace to compile is passed in as two arrays of length Len. Then each
+// The trace to compile is passed in as three arrays of length Len. Then each
 // (FuncName[I], BBs[I]) pair identifies the LLVM block at position `I` in the
-// trace.
+// trace, and BlockVAddrs[I] is the virtual address at which the block was
+// entered (or null if that isn't known).
+//
+// If `STI` is not null, the trace is compiled as a side-trace starting from
+// the guard failure it describes.
 //
 // Returns a pointer to the compiled function.
 template <typename FN>
-void *compileIRTrace(FN Func, char *FuncNames[], size_t BBs[], size_t TraceLen,
-                     char *FAddrKeys[], void *FAddrVals[], size_t FAddrLen,
-                     void *BitcodeData, size_t BitcodeLen, int DebugInfoFD,
-                     char *DebugInfoPath) {
+void *compileIRTrace(FN Func, char *FuncNames[], size_t BBs[],
+                     void *BlockVAddrs[], size_t TraceLen, char *FAddrKeys[],
+                     void *FAddrVals[], size_t FAddrLen, void *BitcodeData,
+                     size_t BitcodeLen, int DebugInfoFD, char *DebugInfoPath,
+                     SideTraceInfo *STI) {
   DebugIRPrinter DIP;
 
   struct BitcodeSection Bitcode = {BitcodeData, BitcodeLen};
   ThreadSafeModule *ThreadAOTMod = getThreadAOTMod(Bitcode);
-  // Getting the module without acquiring the context lock is safe in this
-  // instance since ThreadAOTMod is not shared between threads.
+  // Although ThreadAOTMod is not shared between threads, the modules of the
+  // traces we compile are in its context and may be freed by any thread (see
+  // `__yktracec_free_compiled_trace`), so we must hold the context lock.
+  ThreadSafeContext Ctx = ThreadAOTMod->getContext();
+  auto Lock = Ctx.getLock();
   Module *AOTMod = ThreadAOTMod->getModuleUnlocked();
 
   DIP.print(DebugIR::AOT, AOTMod);
@@ -405,17 +501,27 @@ void *compileIRTrace(FN Func, char *FuncNames[], size_t BBs[], size_t TraceLen,
   std::map<GlobalValue *, void *> GlobalMappings;
   void *AOTMappingVec;
   size_t GuardCount;
-  std::tie(JITMod, TraceName, GlobalMappings, AOTMappingVec, GuardCount) =
-      Func(AOTMod, FuncNames, BBs, TraceLen, FAddrKeys, FAddrVals, FAddrLen);
+  GuardInfo *Guards;
+  std::tie(JITMod, TraceName, GlobalMappings, AOTMappingVec, GuardCount,
+           Guards) = Func(AOTMod, FuncNames, BBs, BlockVAddrs, TraceLen,
+                          FAddrKeys, FAddrVals, FAddrLen, STI);
 
   // If we failed to build the trace, return null.
   if (JITMod == nullptr) {
+    free(AOTMappingVec);
+    for (size_t I = 0; I < GuardCount; I++)
+      free(Guards[I].Frames);
+    free(Guards);
     return nullptr;
   }
 
   DIP.print(DebugIR::JITPreOpt, JITMod);
 #ifndef NDEBUG
-  llvm::verifyModule(*JITMod, &llvm::errs());
+  // A broken trace (e.g. one where a value is used on a path that its
+  // definition doesn't dominate) would otherwise only be noticed, if at all,
+  // when the optimiser or code generator trips over it.
+  if (llvm::verifyModule(*JITMod, &llvm::errs()))
+    errx(EXIT_FAILURE, "Trace module failed to verify.");
 #endif
 
   // The MCJIT code-gen does no optimisations itself, so we must do it
@@ -436,26 +542,28 @@ void *compileIRTrace(FN Func, char *FuncNames[], size_t BBs[], size_t TraceLen,
                      filesystem::path(DebugInfoPath));
 
   // Compile IR trace and return a pointer to its function.
-  return compileModule(TraceName, JITMod, GlobalMappings, AOTMappingVec,
-                       GuardCount);
+  return compileModule(TraceName, JITMod, Ctx, GlobalMappings, AOTMappingVec,
+                       GuardCount, Guards);
 }
 
 extern "C" void *__yktracec_irtrace_compile(
-    char *FuncNames[], size_t BBs[], size_t TraceLen, char *FAddrKeys[],
-    void *FAddrVals[], size_t FAddrLen, void *BitcodeData, uint64_t BitcodeLen,
-    int DebugInfoFD, char *DebugInfoPath) {
-  return compileIRTrace(createModule, FuncNames, BBs, TraceLen, FAddrKeys,
-                        FAddrVals, FAddrLen, BitcodeData, BitcodeLen,
-                        DebugInfoFD, DebugInfoPath);
+    char *FuncNames[], size_t BBs[], void *BlockVAddrs[], size_t TraceLen,
+    char *FAddrKeys[], void *FAddrVals[], size_t FAddrLen, void *BitcodeData,
+    uint64_t BitcodeLen, int DebugInfoFD, char *DebugInfoPath,
+    SideTraceInfo *STI) {
+  return compileIRTrace(createModule, FuncNames, BBs, BlockVAddrs, TraceLen,
+                        FAddrKeys, FAddrVals, FAddrLen, BitcodeData,
+                        BitcodeLen, DebugInfoFD, DebugInfoPath, STI);
 }
 
 #ifdef YK_TESTING
 extern "C" void *__yktracec_irtrace_compile_for_tc_tests(
-    char *FuncNames[], size_t BBs[], size_t TraceLen, char *FAddrKeys[],
-    void *FAddrVals[], size_t FAddrLen, void *BitcodeData, uint64_t BitcodeLen,
-    int DebugInfoFD, char *DebugInfoPath) {
+    char *FuncNames[], size_t BBs[], void *BlockVAddrs[], size_t TraceLen,
+    char *FAddrKeys[], void *FAddrVals[], size_t FAddrLen, void *BitcodeData,
+    uint64_t BitcodeLen, int DebugInfoFD, char *DebugInfoPath) {
   return compileIRTrace(createModuleForTraceCompilerTests, FuncNames, BBs,
-                        TraceLen, FAddrKeys, FAddrVals, FAddrLen, BitcodeData,
-                        BitcodeLen, DebugInfoFD, DebugInfoPath);
+                        BlockVAddrs, TraceLen, FAddrKeys, FAddrVals, FAddrLen,
+                        BitcodeData, BitcodeLen, DebugInfoFD, DebugInfoPath,
+                        nullptr);
 }
 #endif
@@ -1,7 +1,7 @@
 //! Address utilities.
 
-use crate::obj::{PHDR_OBJECT_CACHE, SELF_BIN_PATH};
-use cached::proc_macro::cached;
+use crate::obj::{ObjectMap, PHDR_OBJECT_CACHE};
+use cached::{proc_macro::cached, Cached};
 use libc::{self, c_void, Dl_info};
 use std::mem::MaybeUninit;
 use std::{
@@ -12,8 +12,8 @@ use std::{
 
 /// A Rust wrapper around `libc::Dl_info` using FFI types.
 ///
-/// The strings inside are handed out by the loader and can (for now, since we don't support
-/// dlclose) be considered of static lifetime. This makes the struct thread safe, and thus
+/// The strings inside are handed out by the loader and can be considered of static lifetime for as
+/// long as the object they came from remains loaded. This makes the struct thread safe, and thus
 /// cacheable using `#[cached]`.
 #[derive(Debug, Clone)]
 pub struct DLInfo {
@@ -65,7 +65,7 @@ impl DLInfo {
 ///
 /// Returns `Err` if the underlying call to `libc::dlddr` fails.
 ///
-/// FIXME: This cache should be invalidated (in part, if possible) when a object is loaded or
+/// The cache is cleared when `PHDR_OBJECT_CACHE` notices that an object has been loaded or
 /// unloaded from the address space.
 ///
 /// FIXME: Consider using a LRU cache to limit memory consumption. The cached crate can do this for
@@ -82,27 +82,34 @@ pub fn dladdr(vaddr: usize) -> Result<DLInfo, ()> {
     }
 }
 
+/// Forget all cached `dladdr()` results.
+pub(crate) fn clear_dladdr_cache() {
+    DLADDR.lock().unwrap().cache_clear();
+}
+
 /// Given a virtual address, returns a pair indicating the object in which the address originated
 /// and the byte offset.
+///
+/// Returns `None` if the address doesn't belong to a loaded object.
 pub fn vaddr_to_obj_and_off(vaddr: usize) -> Option<(PathBuf, u64)> {
+    // Getting the objects first ensures that stale cached `dladdr()` results are discarded.
+    vaddr_to_obj_and_off_in(&PHDR_OBJECT_CACHE.get(), vaddr)
+}
+
+/// Like [vaddr_to_obj_and_off], but looks the address up in `objs`, a snapshot previously
+/// obtained from `PHDR_OBJECT_CACHE`, rather than checking for newly (un)loaded objects first.
+///
+/// This is for callers doing many lookups in a row (e.g. once per block of a trace), for whom
+/// repeatedly checking for (un)loaded objects would be needlessly expensive.
+pub fn vaddr_to_obj_and_off_in(objs: &ObjectMap, vaddr: usize) -> Option<(PathBuf, u64)> {
     // Find the object file from which the virtual address was loaded.
-    let info = dladdr(vaddr).unwrap();
+    let info = dladdr(vaddr).ok()?;
     let containing_obj = PathBuf::from(info.dli_fname.unwrap().to_str().unwrap());
 
     // Find the corresponding byte offset of the virtual address in the object.
-    for obj in PHDR_OBJECT_CACHE.iter() {
-        let obj_name = obj.name();
-        let obj_name: &Path = if unsafe { *obj_name.as_ptr() } == 0 {
-            SELF_BIN_PATH.as_path()
-        } else {
-            Path::new(obj_name.to_str().unwrap())
-        };
-        if obj_name != containing_obj {
-            continue;
-        }
-        return Some((containing_obj, u64::try_from(vaddr).unwrap() - obj.addr()));
-    }
-    None
+    let obj = objs.find(&containing_obj)?;
+    let off = u64::try_from(vaddr).unwrap() - obj.addr();
+    Some((containing_obj, off))
 }
 
 /// Find the virtual address of the offset `off` in the object `containing_obj`.
@@ -114,7 +121,7 @@ pub fn vaddr_to_obj_and_off(vaddr: usize) -> Option<(PathBuf, u64)> {
 /// in the same form as it appears in the program header table. This function makes no attempt to
 /// canonicalise equivalent, but different (in terms of string equality) object paths.
 pub fn off_to_vaddr(containing_obj: &Path, off: u64) -> Option<usize> {
-    for obj in PHDR_OBJECT_CACHE.iter() {
+    for obj in PHDR_OBJECT_CACHE.get().iter() {
         if Path::new(obj.name().to_str().unwrap()) != containing_obj {
             continue;
         }
@@ -143,7 +150,7 @@ pub fn vaddr_to_sym_and_obj(vaddr: usize) -> Option<DLInfo> {
 mod tests {
     use super::{off_to_vaddr, vaddr_to_obj_and_off, vaddr_to_sym_and_obj, MaybeUninit};
     use crate::obj::PHDR_MAIN_OBJ;
-    use libc::{self, dlsym, Dl_info};
+    use libc::{self, dlopen, dlsym, Dl_info, RTLD_NOW};
     use std::{ffi::CString, path::PathBuf, ptr};
 
     #[test]
@@ -162,6 +169,29 @@ mod tests {
         assert!(vaddr_to_obj_and_off(vaddr as usize).is_some());
     }
 
+    /// Check that objects loaded at runtime can be mapped.
+    #[test]
+    fn map_dlopened() {
+        // Take a snapshot of the objects before loading the library.
+        assert!(off_to_vaddr(&PHDR_MAIN_OBJ, 0).is_some());
+
+        let lib = CString::new("libm.so.6").unwrap();
+        let handle = unsafe { dlopen(lib.as_ptr(), RTLD_NOW) };
+        assert_ne!(handle, ptr::null_mut());
+        let func = CString::new("cbrt").unwrap();
+        let vaddr = unsafe { dlsym(handle, func.as_ptr()) };
+        assert_ne!(vaddr, ptr::null_mut());
+
+        let (obj, off) = vaddr_to_obj_and_off(vaddr as usize).unwrap();
+        assert!(obj
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("libm"));
+        assert_eq!(off_to_vaddr(&obj, off).unwrap(), vaddr as usize);
+    }
+
     /// Check that converting a virtual address (from a shared object) to a file offset and back to
     /// a virtual address correctly round-trips.
     #[test]
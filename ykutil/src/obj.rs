@@ -1,7 +1,10 @@
 //! Utilities for dealing with object files.
 
-use crate::addr::dladdr;
-use libc::c_void;
+use crate::addr::{clear_dladdr_cache, dladdr};
+use libc::{
+    c_int, c_void, dl_iterate_phdr, dl_phdr_info, dlclose, dlopen, dlsym, size_t, PT_LOAD,
+    RTLD_LAZY, RTLD_NOLOAD,
+};
 #[cfg(target_pointer_width = "64")]
 use libc::{
     Elf64_Addr as Elf_Addr, Elf64_Off as Elf_Off, Elf64_Word as Elf_Word, Elf64_Xword as Elf_Xword,
@@ -9,9 +12,9 @@ use libc::{
 use phdrs;
 use std::{
     ffi::{CStr, CString},
-    path::PathBuf,
-    ptr,
-    sync::LazyLock,
+    path::{Path, PathBuf},
+    ptr, slice,
+    sync::{Arc, LazyLock, Mutex},
 };
 
 /// A thread-safe (containing no raw pointers) version of `phdrs::ProgramHeader`.
@@ -82,6 +85,15 @@ impl Object {
         &self.name
     }
 
+    /// The path of the object, named as per [crate::addr::vaddr_to_obj_and_off].
+    pub fn path(&self) -> &Path {
+        if self.name.to_bytes().is_empty() {
+            SELF_BIN_PATH.as_path()
+        } else {
+            Path::new(self.name.to_str().unwrap())
+        }
+    }
+
     pub fn phdrs(&self) -> &Vec<ProgramHeader> {
         &self.phdrs
     }
@@ -97,18 +109,97 @@ impl From<&phdrs::Object> for Object {
     }
 }
 
+/// Returns the number of times the loader has loaded and unloaded objects (in that order).
+///
+/// The pair changes whenever an object is loaded (e.g. by `dlopen(3)`) or unloaded (e.g. by
+/// `dlclose(3)`).
+fn loader_generation() -> (u64, u64) {
+    unsafe extern "C" fn cb(info: *mut dl_phdr_info, _size: size_t, data: *mut c_void) -> c_int {
+        let info = unsafe { &*info };
+        unsafe { *(data as *mut (u64, u64)) = (info.dlpi_adds, info.dlpi_subs) };
+        // Every object reports the same counts, so there's no need to look any further.
+        1
+    }
+
+    let mut gen = (0, 0);
+    unsafe { dl_iterate_phdr(Some(cb), &mut gen as *mut (u64, u64) as *mut c_void) };
+    gen
+}
+
+/// A snapshot of the objects loaded into the address space.
+pub struct ObjectMap {
+    /// The result of `loader_generation()` when the snapshot was taken.
+    generation: (u64, u64),
+    objs: Vec<Object>,
+}
+
+impl ObjectMap {
+    fn new() -> Self {
+        // If an object is loaded or unloaded while the snapshot is being taken, then the snapshot
+        // will be considered stale, and retaken when next it's needed.
+        let generation = loader_generation();
+        let objs = phdrs::objects()
+            .iter()
+            .map(|p| p.into())
+            .collect::<Vec<Object>>();
+        Self { generation, objs }
+    }
+
+    /// Returns `true` if no object has been loaded or unloaded since the snapshot was taken.
+    pub fn is_current(&self) -> bool {
+        self.generation == loader_generation()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Object> {
+        self.objs.iter()
+    }
+
+    /// Returns the object `obj` (named as per [crate::addr::vaddr_to_obj_and_off]), or `None` if
+    /// it isn't in the snapshot.
+    pub fn find(&self, obj: &Path) -> Option<&Object> {
+        self.objs.iter().find(|o| o.path() == obj)
+    }
+}
+
 /// A program header cache.
 ///
 /// This stashes the result of `dl_iterate_phdr(3)` (via the `phdr` crate), thus avoiding a (slow)
 /// chain of C callbacks each time we want to inspect the program headers.
 ///
-/// Since (for now) we assume that there can be no dlopen/dlclose, the cache is immutable.
-pub static PHDR_OBJECT_CACHE: LazyLock<Vec<Object>> = LazyLock::new(|| {
-    phdrs::objects()
-        .iter()
-        .map(|p| p.into())
-        .collect::<Vec<Object>>()
-});
+/// The cache is refreshed when objects are loaded or unloaded at runtime.
+pub struct ObjectCache {
+    snapshot: Mutex<Arc<ObjectMap>>,
+}
+
+impl ObjectCache {
+    fn new() -> Self {
+        Self {
+            snapshot: Mutex::new(Arc::new(ObjectMap::new())),
+        }
+    }
+
+    /// Returns a snapshot of the objects currently loaded, refreshing the cache first if objects
+    /// have been loaded or unloaded since it was last refreshed.
+    ///
+    /// The snapshot itself is never updated, so it's fine to hold on to it (e.g. for the duration
+    /// of decoding a trace), but an object loaded in the meantime won't appear in it.
+    ///
+    /// Checking for loaded and unloaded objects means calling `dl_iterate_phdr(3)` (which takes
+    /// the loader's lock), so code doing many lookups should take one snapshot up front rather
+    /// than calling this for each lookup.
+    pub fn get(&self) -> Arc<ObjectMap> {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if !snapshot.is_current() {
+            *snapshot = Arc::new(ObjectMap::new());
+            // Cached `dladdr()` results may now be wrong.
+            clear_dladdr_cache();
+        }
+        Arc::clone(&snapshot)
+    }
+}
+
+/// The program header cache for this process.
+pub static PHDR_OBJECT_CACHE: LazyLock<ObjectCache> = LazyLock::new(ObjectCache::new);
 
 // The name of the main object as it appears in the program headers.
 //
@@ -154,3 +245,59 @@ pub fn llvmbc_section() -> (*const u8, u64) {
     let bc = unsafe { &LLVMBC };
     (&bc.first_byte_of_bitcode as *const u8, bc.len)
 }
+
+/// Returns a pointer to (and the size of) the raw LLVM bitcode embedded in the loaded object `obj`
+/// (named as per [crate::addr::vaddr_to_obj_and_off]), or `None` if `obj` wasn't built with
+/// ykllvm.
+pub fn llvmbc_section_of(obj: &Path) -> Option<(*const u8, u64)> {
+    let bc = unsafe { &*(obj_dlsym(obj, "llvm.embedded.module")? as *const EmbeddedModule) };
+    Some((&bc.first_byte_of_bitcode as *const u8, bc.len))
+}
+
+/// Look up the address of the symbol `sym` defined by the loaded object `obj` (named as per
+/// [crate::addr::vaddr_to_obj_and_off]).
+///
+/// Unlike a plain `dlsym(3)`, definitions of `sym` in other objects (e.g. dependencies of `obj`)
+/// are ignored. Returns `None` if `obj` isn't loaded or doesn't define `sym`.
+pub fn obj_dlsym(obj: &Path, sym: &str) -> Option<*const c_void> {
+    let is_main = obj == SELF_BIN_PATH.as_path();
+    let handle = if is_main {
+        unsafe { dlopen(ptr::null(), RTLD_LAZY) }
+    } else {
+        // `RTLD_NOLOAD` ensures we only get a handle if `obj` is already loaded.
+        let path = CString::new(obj.to_str().unwrap()).unwrap();
+        unsafe { dlopen(path.as_ptr(), RTLD_LAZY | RTLD_NOLOAD) }
+    };
+    if handle.is_null() {
+        return None;
+    }
+    let sym = CString::new(sym).unwrap();
+    let addr = unsafe { dlsym(handle, sym.as_ptr()) } as *const c_void;
+    // This only drops the reference that `dlopen()` took: `obj` remains loaded.
+    unsafe { dlclose(handle) };
+    if addr.is_null() {
+        return None;
+    }
+
+    // `dlsym()` also searches the dependencies of `obj`, so check that the symbol's address falls
+    // within `obj`. Symbols marking the end of a section may point just past the end of a segment,
+    // hence the inclusive range.
+    let phdr_name = if is_main {
+        PHDR_MAIN_OBJ.as_path()
+    } else {
+        obj
+    };
+    let vaddr = addr as u64; // pointer to `u64` cast always safe.
+    let defined_in_obj = PHDR_OBJECT_CACHE.get().iter().any(|o| {
+        Path::new(o.name().to_str().unwrap()) == phdr_name
+            && o.phdrs().iter().any(|h| {
+                let start = o.addr() + h.vaddr();
+                h.type_() == PT_LOAD && (start..=start + h.memsz()).contains(&vaddr)
+            })
+    });
+    if defined_in_obj {
+        Some(addr)
+    } else {
+        None
+    }
+}